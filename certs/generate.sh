@@ -31,5 +31,5 @@ openssl x509 -req \
 
 cat server.crt ca.crt > server.chain
 openssl asn1parse -in ca.crt -out ca.der
-openssl rsa -in server.key -out server.rsa
+openssl rsa -in server.key -out server.rsa -traditional
 rm *.csr ca.crt ca.key server.crt server.key
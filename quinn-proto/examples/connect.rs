@@ -9,7 +9,7 @@ extern crate slog_term;
 extern crate webpki_roots;
 
 use std::io::{self, Write};
-use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -35,13 +35,6 @@ fn main() {
     ::std::process::exit(code);
 }
 
-fn normalize(x: SocketAddr) -> SocketAddrV6 {
-    match x {
-        SocketAddr::V6(x) => x,
-        SocketAddr::V4(x) => SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0),
-    }
-}
-
 type Result<T> = ::std::result::Result<T, Error>;
 
 fn run(log: Logger) -> Result<()> {
@@ -58,21 +51,24 @@ struct Context {
     socket: UdpSocket,
     client: Endpoint,
     remote_host: String,
-    remote: SocketAddrV6,
+    remote: SocketAddr,
     loss_timer: Option<u64>,
     close_timer: Option<u64>,
     idle_timer: Option<u64>,
+    pacing_timer: Option<u64>,
 }
 
 impl Context {
     fn new(log: Logger, mut remote_host: String) -> Result<Self> {
-        let socket = UdpSocket::bind("[::]:0")?;
-        let remote = normalize(
-            remote_host
-                .to_socket_addrs()?
-                .next()
-                .ok_or(format_err!("couldn't resolve to an address"))?,
-        );
+        let remote = remote_host
+            .to_socket_addrs()?
+            .next()
+            .ok_or(format_err!("couldn't resolve to an address"))?;
+        let socket = UdpSocket::bind(if remote.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
         if let Some(x) = remote_host.rfind(':') {
             remote_host.truncate(x);
         }
@@ -87,17 +83,18 @@ impl Context {
             loss_timer: None,
             close_timer: None,
             idle_timer: None,
+            pacing_timer: None,
         })
     }
 
     fn run(&mut self) -> Result<()> {
         let epoch = Instant::now();
-        let mut config = quinn::ClientConfig::new();
-        config
+        let mut tls_config = rustls::ClientConfig::new();
+        tls_config
             .root_store
             .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-        config.versions = vec![ProtocolVersion::TLSv1_3];
-        let config = Arc::new(config);
+        tls_config.versions = vec![ProtocolVersion::TLSv1_3];
+        let config = quinn::ClientConfig::new(Arc::new(tls_config));
         let c = self
             .client
             .connect(self.remote, &config, &self.remote_host)?;
@@ -159,6 +156,7 @@ impl Context {
                     Io::Transmit {
                         destination,
                         packet,
+                        ..
                     } => {
                         sent += 1;
                         self.socket.send_to(&packet, destination)?;
@@ -184,6 +182,13 @@ impl Context {
                     } => {
                         self.idle_timer = Some(time);
                     }
+                    Io::TimerStart {
+                        timer: Timer::Pacing,
+                        time,
+                        ..
+                    } => {
+                        self.pacing_timer = Some(time);
+                    }
                     Io::TimerStop {
                         timer: Timer::LossDetection,
                         ..
@@ -196,19 +201,32 @@ impl Context {
                     } => {
                         self.close_timer = None;
                     }
+                    Io::TimerStop {
+                        timer: Timer::Pacing,
+                        ..
+                    } => {
+                        self.pacing_timer = None;
+                    }
                     Io::TimerStop {
                         timer: Timer::Idle, ..
                     } => unreachable!(),
+                    // Only servers validate the addresses of migrating peers
+                    Io::TimerStart {
+                        timer: Timer::PathValidation,
+                        ..
+                    } => unreachable!(),
+                    Io::TimerStop {
+                        timer: Timer::PathValidation,
+                        ..
+                    } => {}
                 }
             }
             let mut buf = [0; 2048];
-            let (timeout, timer) = (
-                self.loss_timer.unwrap_or(u64::max_value()),
-                Timer::LossDetection,
-            )
-                .min((self.close_timer.unwrap_or(u64::max_value()), Timer::Close))
-                .min((self.idle_timer.unwrap_or(u64::max_value()), Timer::Idle));
-            if timeout != u64::max_value() {
+            let (timeout, timer) = (self.loss_timer.unwrap_or(u64::MAX), Timer::LossDetection)
+                .min((self.close_timer.unwrap_or(u64::MAX), Timer::Close))
+                .min((self.idle_timer.unwrap_or(u64::MAX), Timer::Idle))
+                .min((self.pacing_timer.unwrap_or(u64::MAX), Timer::Pacing));
+            if timeout != u64::MAX {
                 trace!(self.log, "setting timeout"; "type" => ?timer, "time" => time);
                 let dt = timeout - time;
                 let seconds = dt / (1000 * 1000);
@@ -225,8 +243,7 @@ impl Context {
             match r {
                 Ok((n, addr)) => {
                     recvd += 1;
-                    self.client
-                        .handle(time, normalize(addr), (&buf[0..n]).into());
+                    self.client.handle(time, addr, None, (&buf[0..n]).into());
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     trace!(self.log, "timeout"; "type" => ?timer);
@@ -234,6 +251,8 @@ impl Context {
                     match timer {
                         Timer::LossDetection => self.loss_timer = None,
                         Timer::Idle => self.idle_timer = None,
+                        Timer::Pacing => self.pacing_timer = None,
+                        Timer::PathValidation => unreachable!(),
                         Timer::Close => {
                             self.close_timer = None;
                             info!(self.log, "done"; "sent packets" => sent, "received packets" => recvd);
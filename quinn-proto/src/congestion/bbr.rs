@@ -0,0 +1,311 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+
+use super::CongestionController;
+use endpoint::TransportConfig;
+
+/// Pacing and window gain in startup, 2/ln(2): enough to double the delivery rate every round trip
+const HIGH_GAIN: f64 = 2.885;
+/// Window gain once the bottleneck bandwidth has been found
+const CWND_GAIN: f64 = 2.0;
+/// Pacing gains applied over successive min RTTs while probing for more bandwidth
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Number of round trips over which the maximum delivery rate is tracked
+const BANDWIDTH_WINDOW_ROUNDS: u64 = 10;
+/// Age at which the min RTT estimate must be refreshed (μs)
+const MIN_RTT_EXPIRY: u64 = 10 * 1000 * 1000;
+/// Time spent draining the path to measure the min RTT (μs)
+const PROBE_RTT_DURATION: u64 = 200 * 1000;
+/// Smallest window, in segments
+const MIN_PIPE_CWND_SEGMENTS: u64 = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    /// Rapidly searching for the bottleneck bandwidth
+    Startup,
+    /// Draining the queue built up during startup
+    Drain,
+    /// Cruising at the bottleneck bandwidth, periodically probing for more
+    ProbeBw,
+    /// Briefly minimizing data in flight to refresh the min RTT estimate
+    ProbeRtt,
+}
+
+/// Delivery progress when a packet was sent, from which its acknowledgement yields a delivery rate sample
+struct SentState {
+    delivered: u64,
+    delivered_time: u64,
+}
+
+/// Bottleneck Bandwidth and Round-trip propagation time congestion control
+///
+/// Rather than reacting to loss, BBR models the path by its maximum recent delivery rate and its minimum RTT, and
+/// paces transmissions at the estimated bandwidth while keeping about one bandwidth-delay product in flight.
+pub struct Bbr {
+    mss: u64,
+    initial_window: u64,
+    mode: Mode,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    /// Total bytes acknowledged
+    delivered: u64,
+    /// Time of the most recent acknowledgement
+    delivered_time: u64,
+    /// Delivery progress for each outstanding packet
+    sent: BTreeMap<u64, SentState>,
+    /// Largest delivery rate sample in bytes per second for each recent round trip
+    bw_samples: VecDeque<(u64, u64)>,
+    /// Estimated bottleneck bandwidth in bytes per second
+    max_bw: u64,
+    /// Number of round trips completed
+    round: u64,
+    /// Value of `delivered` at which the current round trip ends
+    next_round_delivered: u64,
+    min_rtt: u64,
+    min_rtt_stamp: u64,
+    min_rtt_expired: bool,
+    /// Whether startup has found the bottleneck bandwidth
+    filled_pipe: bool,
+    /// Largest bandwidth seen in startup
+    full_bw: u64,
+    /// Number of round trips in which the bandwidth failed to grow significantly
+    full_bw_rounds: u8,
+    cycle_index: usize,
+    cycle_stamp: u64,
+    /// When enough time will have been spent in `ProbeRtt`, once in-flight data has drained
+    probe_rtt_done: Option<u64>,
+}
+
+impl Bbr {
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            mss: config.default_mss,
+            initial_window: config.initial_window,
+            mode: Mode::Startup,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            delivered: 0,
+            delivered_time: 0,
+            sent: BTreeMap::new(),
+            bw_samples: VecDeque::new(),
+            max_bw: 0,
+            round: 0,
+            next_round_delivered: 0,
+            min_rtt: u64::MAX,
+            min_rtt_stamp: 0,
+            min_rtt_expired: false,
+            filled_pipe: false,
+            full_bw: 0,
+            full_bw_rounds: 0,
+            cycle_index: 0,
+            cycle_stamp: 0,
+            probe_rtt_done: None,
+        }
+    }
+
+    fn min_window(&self) -> u64 {
+        MIN_PIPE_CWND_SEGMENTS * self.mss
+    }
+
+    /// Estimated bandwidth-delay product of the path
+    fn bdp(&self) -> u64 {
+        if self.max_bw == 0 || self.min_rtt == u64::MAX {
+            return self.initial_window;
+        }
+        self.max_bw * self.min_rtt / 1_000_000
+    }
+
+    fn update_bandwidth(&mut self, bw: u64) {
+        let current = self.round;
+        if self
+            .bw_samples
+            .back()
+            .is_some_and(|&(round, _)| round == current)
+        {
+            let sample = &mut self.bw_samples.back_mut().unwrap().1;
+            *sample = cmp::max(*sample, bw);
+        } else {
+            self.bw_samples.push_back((current, bw));
+        }
+        while self
+            .bw_samples
+            .front()
+            .is_some_and(|&(round, _)| round + BANDWIDTH_WINDOW_ROUNDS <= self.round)
+        {
+            self.bw_samples.pop_front();
+        }
+        self.max_bw = self.bw_samples.iter().map(|&(_, x)| x).max().unwrap_or(0);
+    }
+
+    fn check_full_pipe(&mut self) {
+        if self.max_bw >= self.full_bw * 5 / 4 {
+            self.full_bw = self.max_bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= 3 {
+            self.filled_pipe = true;
+            self.mode = Mode::Drain;
+            self.pacing_gain = 1.0 / HIGH_GAIN;
+            self.cwnd_gain = HIGH_GAIN;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: u64) {
+        self.mode = Mode::ProbeBw;
+        // Start out cruising rather than probing, so as not to immediately refill the queue we just drained
+        self.cycle_index = 2;
+        self.cycle_stamp = now;
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.cwnd_gain = CWND_GAIN;
+    }
+
+    fn enter_startup(&mut self) {
+        self.mode = Mode::Startup;
+        self.pacing_gain = HIGH_GAIN;
+        self.cwnd_gain = HIGH_GAIN;
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_sent(&mut self, now: u64, packet: u64, _bytes: u64) {
+        if self.sent.is_empty() {
+            // Don't count idle time against the delivery rate
+            self.delivered_time = now;
+        }
+        self.sent.insert(
+            packet,
+            SentState {
+                delivered: self.delivered,
+                delivered_time: self.delivered_time,
+            },
+        );
+    }
+
+    fn on_ack(
+        &mut self,
+        now: u64,
+        packet: u64,
+        bytes: u64,
+        bytes_in_flight: u64,
+        app_limited: bool,
+    ) {
+        let state = if let Some(x) = self.sent.remove(&packet) {
+            x
+        } else {
+            return;
+        };
+        self.delivered += bytes;
+        self.delivered_time = now;
+
+        let round_start = state.delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = self.delivered;
+            self.round += 1;
+        }
+        let interval = now - state.delivered_time;
+        if let Some(bw) = ((self.delivered - state.delivered) * 1_000_000).checked_div(interval) {
+            // Samples taken while the application wasn't keeping the path busy underestimate its capacity
+            if !app_limited || bw >= self.max_bw {
+                self.update_bandwidth(bw);
+            }
+        }
+
+        if round_start && self.mode == Mode::Startup {
+            self.check_full_pipe();
+        }
+        if self.mode == Mode::Drain && bytes_in_flight <= self.bdp() {
+            self.enter_probe_bw(now);
+        }
+        if self.mode == Mode::ProbeBw && now - self.cycle_stamp > self.min_rtt {
+            self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+            self.cycle_stamp = now;
+            self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        }
+
+        if self.min_rtt_expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.probe_rtt_done = None;
+        }
+        self.min_rtt_expired = false;
+        if self.mode == Mode::ProbeRtt {
+            match self.probe_rtt_done {
+                None if bytes_in_flight <= self.min_window() => {
+                    self.probe_rtt_done = Some(now + PROBE_RTT_DURATION);
+                }
+                Some(done) if now >= done => {
+                    self.min_rtt_stamp = now;
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.enter_startup();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn on_congestion_event(&mut self, _now: u64, largest_lost: u64, _largest_sent: u64) {
+        // Loss doesn't alter the path model; a shrinking delivery rate will. Older packets may still be acked out of
+        // order up until they're declared lost, so only forget them then.
+        self.sent = self.sent.split_off(&(largest_lost + 1));
+    }
+
+    fn on_retransmission_timeout_verified(&mut self, _now: u64) {
+        self.sent.clear();
+    }
+
+    fn on_rtt_update(&mut self, now: u64, latest: u64, _smoothed: u64, _min: u64) {
+        let expired = self.min_rtt != u64::MAX && now - self.min_rtt_stamp > MIN_RTT_EXPIRY;
+        if latest <= self.min_rtt || expired {
+            self.min_rtt = latest;
+            self.min_rtt_stamp = now;
+        }
+        self.min_rtt_expired |= expired;
+    }
+
+    fn window(&self) -> u64 {
+        if self.mode == Mode::ProbeRtt {
+            return self.min_window();
+        }
+        if self.max_bw == 0 {
+            return self.initial_window;
+        }
+        cmp::max(
+            (self.cwnd_gain * self.bdp() as f64) as u64,
+            self.min_window(),
+        )
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        if self.max_bw == 0 {
+            return None;
+        }
+        Some((self.pacing_gain * self.max_bw as f64) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multi_range_ack() {
+        let mut bbr = Bbr::new(&TransportConfig::default());
+        for packet in 0..10 {
+            bbr.on_sent(0, packet, 1000);
+        }
+        // A single ACK frame covering 0-3 and 6-9, processed largest range first as the connection does
+        for &packet in &[6, 7, 8, 9, 0, 1, 2, 3] {
+            bbr.on_ack(1000, packet, 1000, 0, false);
+        }
+        assert_eq!(bbr.delivered, 8000);
+        assert_eq!(bbr.sent.keys().cloned().collect::<Vec<_>>(), [4, 5]);
+        bbr.on_congestion_event(1000, 5, 9);
+        assert!(bbr.sent.is_empty());
+    }
+}
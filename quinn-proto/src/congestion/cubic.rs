@@ -0,0 +1,136 @@
+use std::cmp;
+
+use super::CongestionController;
+use endpoint::TransportConfig;
+
+/// Multiplicative window decrease factor
+const BETA: f64 = 0.7;
+/// Scaling constant determining the aggressiveness of window growth
+const C: f64 = 0.4;
+
+/// CUBIC congestion control, as specified in RFC 8312
+///
+/// After a loss, the window grows as a cubic function of the time elapsed rather than of the number of round trips,
+/// quickly reclaiming capacity on paths with a large bandwidth-delay product.
+pub struct Cubic {
+    mss: u64,
+    minimum_window: u64,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes.
+    ssthresh: u64,
+    /// The largest packet number sent when a loss was detected. Losses up to this point belong to the same event.
+    end_of_recovery: u64,
+    /// Window just before the most recent reduction, in segments
+    w_max: f64,
+    /// Time the window takes to grow back to `w_max` in the current epoch (s)
+    k: f64,
+    /// Start of the current congestion avoidance epoch
+    epoch_start: Option<u64>,
+    /// Window a NewReno flow would have reached in the current epoch, in segments
+    w_est: f64,
+    min_rtt: u64,
+}
+
+impl Cubic {
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            mss: config.default_mss,
+            minimum_window: config.minimum_window,
+            window: config.initial_window,
+            ssthresh: u64::MAX,
+            end_of_recovery: 0,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+            min_rtt: 0,
+        }
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_sent(&mut self, _now: u64, _packet: u64, _bytes: u64) {}
+
+    fn on_ack(
+        &mut self,
+        now: u64,
+        packet: u64,
+        bytes: u64,
+        _bytes_in_flight: u64,
+        app_limited: bool,
+    ) {
+        if app_limited || packet <= self.end_of_recovery {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+            return;
+        }
+
+        let cwnd = self.window as f64 / self.mss as f64;
+        let acked = bytes as f64 / self.mss as f64;
+        let start = match self.epoch_start {
+            Some(x) => x,
+            None => {
+                self.epoch_start = Some(now);
+                if cwnd < self.w_max {
+                    self.k = ((self.w_max - cwnd) / C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = cwnd;
+                }
+                self.w_est = cwnd;
+                now
+            }
+        };
+        // Aim for the window we should have one RTT from now
+        let t = (now - start + self.min_rtt) as f64 / 1_000_000.0;
+        let target = C * (t - self.k).powi(3) + self.w_max;
+        self.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * acked / cwnd;
+        let new = if target < self.w_est {
+            // TCP-friendly region
+            self.w_est
+        } else {
+            // Concave and convex regions
+            let target = target.min(1.5 * cwnd);
+            cwnd + (target - cwnd).max(0.0) / cwnd * acked
+        };
+        self.window = cmp::max(self.window, (new * self.mss as f64) as u64);
+    }
+
+    fn on_congestion_event(&mut self, _now: u64, largest_lost: u64, largest_sent: u64) {
+        if largest_lost <= self.end_of_recovery {
+            return;
+        }
+        self.end_of_recovery = largest_sent;
+        self.epoch_start = None;
+        let cwnd = self.window as f64 / self.mss as f64;
+        // Fast convergence: if we're losing ground, leave room for newly arrived flows
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.window = cmp::max((self.window as f64 * BETA) as u64, self.minimum_window);
+        self.ssthresh = self.window;
+    }
+
+    fn on_retransmission_timeout_verified(&mut self, _now: u64) {
+        self.window = self.minimum_window;
+        self.epoch_start = None;
+    }
+
+    fn on_rtt_update(&mut self, _now: u64, _latest: u64, _smoothed: u64, min: u64) {
+        self.min_rtt = min;
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,86 @@
+//! Congestion control algorithms
+//!
+//! Each connection owns a `CongestionController`, selected by `TransportConfigBuilder::congestion_controller`, which
+//! it informs of the fate of the packets it sends and consults before sending anything but acks.
+
+use std::fmt;
+use std::sync::Arc;
+
+use endpoint::TransportConfig;
+
+mod bbr;
+mod cubic;
+mod new_reno;
+
+pub use self::bbr::Bbr;
+pub use self::cubic::Cubic;
+pub use self::new_reno::NewReno;
+
+/// Decides how much data a connection may have in flight at once
+///
+/// All times are in μs, and sizes in bytes. Only packets containing retransmittable frames are reported.
+pub trait CongestionController: Send {
+    /// A packet carrying `bytes` was sent
+    fn on_sent(&mut self, now: u64, packet: u64, bytes: u64);
+    /// A packet carrying `bytes` was acknowledged, leaving `bytes_in_flight` outstanding
+    ///
+    /// `app_limited` indicates that the connection recently ran out of data to send before filling the window, so
+    /// the acknowledgement says little about the capacity of the path.
+    fn on_ack(
+        &mut self,
+        now: u64,
+        packet: u64,
+        bytes: u64,
+        bytes_in_flight: u64,
+        app_limited: bool,
+    );
+    /// Packets up to and including `largest_lost` were declared lost while `largest_sent` was the newest packet sent
+    fn on_congestion_event(&mut self, now: u64, largest_lost: u64, largest_sent: u64);
+    /// A retransmission timeout was verified, implying that everything in flight was lost
+    fn on_retransmission_timeout_verified(&mut self, now: u64);
+    /// The connection's RTT estimates were updated
+    fn on_rtt_update(&mut self, now: u64, latest: u64, smoothed: u64, min: u64);
+    /// Number of bytes that may be in flight
+    fn window(&self) -> u64;
+    /// Rate at which to pace transmissions in bytes per second, if the algorithm calls for pacing
+    fn pacing_rate(&self) -> Option<u64>;
+}
+
+/// Congestion control algorithms that may be selected for new connections
+#[derive(Clone)]
+pub enum CongestionAlgorithm {
+    /// The loss-based algorithm of RFC 6582, as described by the QUIC recovery draft
+    NewReno,
+    /// RFC 8312, whose window growth is independent of RTT and so better suited to long fat networks
+    Cubic,
+    /// Model-based Bottleneck Bandwidth and Round-trip propagation time, which paces at the estimated bandwidth
+    Bbr,
+    /// An algorithm supplied by the application, constructed afresh for each connection
+    Custom(Arc<ControllerFactory>),
+}
+
+/// Constructs a `CongestionController` for a new connection
+pub type ControllerFactory =
+    dyn Fn(&TransportConfig) -> Box<dyn CongestionController> + Send + Sync;
+
+impl CongestionAlgorithm {
+    pub(crate) fn build(&self, config: &TransportConfig) -> Box<dyn CongestionController> {
+        match *self {
+            CongestionAlgorithm::NewReno => Box::new(NewReno::new(config)),
+            CongestionAlgorithm::Cubic => Box::new(Cubic::new(config)),
+            CongestionAlgorithm::Bbr => Box::new(Bbr::new(config)),
+            CongestionAlgorithm::Custom(ref factory) => factory(config),
+        }
+    }
+}
+
+impl fmt::Debug for CongestionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CongestionAlgorithm::NewReno => f.write_str("NewReno"),
+            CongestionAlgorithm::Cubic => f.write_str("Cubic"),
+            CongestionAlgorithm::Bbr => f.write_str("Bbr"),
+            CongestionAlgorithm::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::cmp;
+
+use super::CongestionController;
+use endpoint::TransportConfig;
+
+/// A simple, standard congestion controller
+pub struct NewReno {
+    mss: u64,
+    minimum_window: u64,
+    /// Reduction in window when a new loss event is detected. 0.16 format
+    loss_reduction_factor: u16,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is slow start and the
+    /// window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    /// The largest packet number sent when QUIC detects a loss. When a larger packet is acknowledged, QUIC exits
+    /// recovery.
+    end_of_recovery: u64,
+}
+
+impl NewReno {
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            mss: config.default_mss,
+            minimum_window: config.minimum_window,
+            loss_reduction_factor: config.loss_reduction_factor,
+            window: config.initial_window,
+            ssthresh: u64::MAX,
+            end_of_recovery: 0,
+        }
+    }
+}
+
+impl CongestionController for NewReno {
+    fn on_sent(&mut self, _now: u64, _packet: u64, _bytes: u64) {}
+
+    fn on_ack(
+        &mut self,
+        _now: u64,
+        packet: u64,
+        bytes: u64,
+        _bytes_in_flight: u64,
+        app_limited: bool,
+    ) {
+        // Do not increase congestion window in recovery period, or when it isn't being used.
+        if app_limited || packet <= self.end_of_recovery {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+        } else {
+            // Congestion avoidance.
+            self.window += self.mss * bytes / self.window;
+        }
+    }
+
+    fn on_congestion_event(&mut self, _now: u64, largest_lost: u64, largest_sent: u64) {
+        // Start a new recovery epoch if the lost packet is larger than the end of the previous recovery epoch.
+        if largest_lost <= self.end_of_recovery {
+            return;
+        }
+        self.end_of_recovery = largest_sent;
+        // *= factor
+        self.window = (self.window * self.loss_reduction_factor as u64) >> 16;
+        self.window = cmp::max(self.window, self.minimum_window);
+        self.ssthresh = self.window;
+    }
+
+    fn on_retransmission_timeout_verified(&mut self, _now: u64) {
+        self.window = self.minimum_window;
+    }
+
+    fn on_rtt_update(&mut self, _now: u64, _latest: u64, _smoothed: u64, _min: u64) {}
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
@@ -1,18 +1,23 @@
-use std::collections::{hash_map, BTreeMap, VecDeque};
-use std::net::SocketAddrV6;
+use std::collections::{btree_map, hash_map, BTreeMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{cmp, io, mem};
+use std::{cmp, io, iter, mem};
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes};
 use fnv::{FnvHashMap, FnvHashSet};
-use rand::distributions::Distribution;
+use rand::Rng;
 use slog::Logger;
 
 use coding::{BufExt, BufMutExt};
+use congestion::CongestionController;
 use crypto::{
-    reset_token_for, ClientConfig, ConnectError, Crypto, TLSError, TlsSession, ACK_DELAY_EXPONENT,
+    pad_for_header_protection, reset_token_for, ClientConfig, ConnectError, Crypto, SessionStore,
+    TLSError, TlsSession, TRANSPORT_PARAMETERS_KEY,
 };
-use endpoint::{Config, Context, Event, Io, Timer};
+use endpoint::{handshake_close, Context, Event, Io, Limit, Timer, TransportConfig};
+use flow_control;
+use mtud::MtuDiscovery;
+use pacing::Pacer;
 use packet::{
     set_payload_length, types, ConnectionId, Header, Packet, PacketNumber, AEAD_TAG_SIZE,
 };
@@ -20,8 +25,8 @@ use range_set::RangeSet;
 use stream::{self, Stream};
 use transport_parameters::{self, TransportParameters};
 use {
-    frame, Directionality, Frame, Side, StreamId, TransportError, MIN_INITIAL_SIZE, MIN_MTU,
-    VERSION,
+    frame, Directionality, EcnCodepoint, Frame, Side, StreamId, TransportError, Version,
+    MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE,
 };
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -34,22 +39,43 @@ impl From<ConnectionHandle> for usize {
 }
 
 pub struct Connection {
+    pub config: Arc<TransportConfig>,
     pub app_closed: bool,
+    /// QUIC version in use
+    pub version: Version,
     /// DCID of Initial packet
     pub initial_id: ConnectionId,
     pub local_id: ConnectionId,
     pub remote_id: ConnectionId,
-    pub remote: SocketAddrV6,
+    /// Connection IDs we've issued to the peer, by sequence number. Sequence number 0 is `local_id`.
+    pub loc_cids: FnvHashMap<u64, ConnectionId>,
+    /// Spare connection IDs issued by the peer, by sequence number
+    pub rem_cids: BTreeMap<u64, IssuedCid>,
+    pub remote: SocketAddr,
+    /// Token of the most recent PATH_CHALLENGE we sent, if any
+    pub path_challenge: Option<u64>,
+    /// Traffic exchanged with the peer's current address, if it has yet to be validated
+    pub unvalidated_path: Option<UnvalidatedPath>,
     pub state: Option<State>,
     pub side: Side,
     pub handle: ConnectionHandle,
+    /// Largest UDP payload to send, as determined by `mtud`
     pub mtu: u16,
-    pub rx_packet: u64,
-    pub rx_packet_time: u64,
-    pub crypto: Option<Crypto>,
-    pub prev_crypto: Option<(u64, Crypto)>,
-    //pub zero_rtt_crypto: Option<Crypto>,
+    pub mtud: MtuDiscovery,
+    /// Packet number spaces, indexed by `SpaceId`
+    pub spaces: [PacketSpace; 3],
+    /// The space in which TLS is currently writing handshake messages
+    pub highest_space: SpaceId,
+    /// 1-RTT keys in use before the most recent key update, until every packet protected with them is behind us
+    pub prev_crypto: Option<PrevCrypto>,
+    pub zero_rtt_crypto: Option<Crypto>,
     pub key_phase: bool,
+    /// Whether the application asked for the 1-RTT keys to be replaced
+    pub key_update_requested: bool,
+    /// Packets protected with the current 1-RTT keys
+    pub key_packets_sent: u64,
+    /// Bytes protected with the current 1-RTT keys
+    pub key_bytes_sent: u64,
     pub params: TransportParameters,
     /// Streams with data buffered for reading by the application
     pub readable_streams: FnvHashSet<StreamId>,
@@ -58,12 +84,16 @@ pub struct Connection {
     /// Limit on outgoing data, dictated by peer
     pub max_data: u64,
     pub data_sent: u64,
+    /// Connection-level limit most recently reported in a BLOCKED frame
+    pub blocked_reported: Option<u64>,
     /// Sum of end offsets of all streams. Includes gaps, so it's an upper bound.
     pub data_recvd: u64,
     /// Limit on incoming data
-    pub local_max_data: u64,
+    pub receive_window: flow_control::Window,
     /// Server name (for client-side)
     pub server_name: Option<String>,
+    /// Where to remember the server's transport parameters (for client-side)
+    pub session_store: Option<Arc<dyn SessionStore>>,
 
     //
     // Loss Detection
@@ -77,9 +107,6 @@ pub struct Connection {
     /// The largest packet number gap between the largest acked retransmittable packet and an unacknowledged
     /// retransmittable packet before it is declared lost.
     pub reordering_threshold: u32,
-    /// The time at which the next packet will be considered lost based on early transmit or exceeding the reordering
-    /// window in time.
-    pub loss_time: u64,
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet. μs
     pub latest_rtt: u64,
     /// The smoothed RTT of the connection, computed as described in RFC6298. μs
@@ -98,12 +125,6 @@ pub struct Connection {
     pub time_of_last_sent_retransmittable_packet: u64,
     /// The time the most recently sent handshake packet was sent.
     pub time_of_last_sent_handshake_packet: u64,
-    /// The packet number of the most recently sent packet.
-    pub largest_sent_packet: u64,
-    /// The largest packet number the remote peer acknowledged in an ACK frame.
-    pub largest_acked_packet: u64,
-    /// Transmitted but not acked
-    pub sent_packets: BTreeMap<u64, SentPacket>,
 
     //
     // Congestion Control
@@ -114,35 +135,33 @@ pub struct Connection {
     /// The size does not include IP or UDP overhead. Packets only containing ACK frames do not count towards
     /// bytes_in_flight to ensure congestion control does not impede congestion feedback.
     pub bytes_in_flight: u64,
-    /// Maximum number of bytes in flight that may be sent.
-    pub congestion_window: u64,
-    /// The largest packet number sent when QUIC detects a loss. When a larger packet is acknowledged, QUIC exits recovery.
-    pub end_of_recovery: u64,
-    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is slow start and the
-    /// window grows by the number of bytes acknowledged.
-    pub ssthresh: u64,
+    /// Determines the maximum number of bytes in flight that may be sent.
+    pub congestion: Box<dyn CongestionController>,
+    /// Whether we most recently stopped sending for lack of data rather than of congestion window
+    pub app_limited: bool,
+    pub pacer: Pacer,
 
     //
-    // Handshake retransmit state
+    // ECN
     //
-    /// Whether we've sent handshake packets that have not been either explicitly acknowledged or rendered moot by
-    /// handshake completion, i.e. whether we're waiting for proof that the peer has advanced their handshake state
-    /// machine.
-    pub awaiting_handshake: bool,
-    pub handshake_pending: Retransmits,
-    pub handshake_crypto: Crypto,
+    /// Whether we mark outgoing packets as ECN-capable, i.e. the path and peer haven't been found wanting
+    pub sending_ecn: bool,
+    /// Number of packets received with each ECN codepoint, reported to the peer in ACK frames
+    pub ecn_counts: frame::EcnCounts,
+    /// The largest ECN counts reported by the peer
+    pub ecn_feedback: frame::EcnCounts,
 
     //
     // Transmit queue
     //
-    pub pending: Retransmits,
-    pub pending_acks: RangeSet,
-    /// Set iff we have received a non-ack frame since the last ack-only packet we sent
-    pub permit_ack_only: bool,
+    /// Unreliable datagrams awaiting transmission, which are never retransmitted
+    pub datagrams: VecDeque<Bytes>,
 
     // Timer updates: None if no change, Some(None) to stop, Some(Some(_)) to reset
     pub set_idle: Option<Option<u64>>,
     pub set_loss_detection: Option<Option<u64>>,
+    pub set_pacing: Option<Option<u64>>,
+    pub set_path_validation: Option<Option<u64>>,
 
     //
     // Stream states
@@ -156,16 +175,115 @@ pub struct Connection {
     // Remotely initiated
     pub max_remote_uni_streams: u64,
     pub max_remote_bi_streams: u64,
+    /// One more than the index of the highest remotely initiated unidirectional stream the peer has referred to
+    ///
+    /// State is created when the peer first refers to a stream, rather than when it's permitted, so granting a large
+    /// limit is cheap.
+    pub next_remote_uni_stream: u64,
+    pub next_remote_bi_stream: u64,
+    /// Indices of remotely initiated unidirectional streams implicitly opened by the peer's use of a higher-numbered
+    /// stream, whose state hasn't been created yet
+    pub implicit_remote_uni_streams: RangeSet,
+    pub implicit_remote_bi_streams: RangeSet,
+    /// Number of remotely initiated unidirectional streams permitted but not yet closed
+    pub open_remote_uni_streams: u64,
+    pub open_remote_bi_streams: u64,
+    /// Number of remotely initiated unidirectional streams the peer may have open at once
+    pub concurrent_remote_uni_streams: u64,
+    pub concurrent_remote_bi_streams: u64,
     pub finished_streams: Vec<StreamId>,
 }
 
+/// Identifies a packet number space, each of which corresponds to a level of packet protection
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SpaceId {
+    /// Packets protected with keys derived from the client's first destination connection ID
+    Initial = 0,
+    /// Packets protected with the handshake traffic secrets
+    Handshake = 1,
+    /// 0-RTT and 1-RTT packets
+    Data = 2,
+}
+
+impl SpaceId {
+    /// The long header packet type of packets in this space, if any
+    fn packet_type(self) -> Option<u8> {
+        match self {
+            SpaceId::Initial => Some(types::INITIAL),
+            SpaceId::Handshake => Some(types::HANDSHAKE),
+            SpaceId::Data => None,
+        }
+    }
+
+    pub fn iter() -> impl Iterator<Item = SpaceId> {
+        [SpaceId::Initial, SpaceId::Handshake, SpaceId::Data]
+            .iter()
+            .cloned()
+    }
+}
+
+/// Packet number, acknowledgement, and handshake data state that's kept separately for each `SpaceId`
+pub struct PacketSpace {
+    /// Keys protecting packets in this space, if we have them. 1-RTT keys live here once derived; 0-RTT keys are kept
+    /// in `Connection::zero_rtt_crypto`.
+    pub crypto: Option<Crypto>,
+    /// Frames awaiting transmission
+    pub pending: Retransmits,
+    /// Packets received and yet to be acknowledged
+    pub pending_acks: RangeSet,
+    /// Set iff we have received a non-ack frame since the last ack-only packet we sent
+    pub permit_ack_only: bool,
+    /// The number of the next packet we'll send
+    pub next_packet_number: u64,
+    /// The largest packet number the remote peer acknowledged in an ACK frame.
+    pub largest_acked_packet: u64,
+    /// Transmitted but not acked
+    pub sent_packets: BTreeMap<u64, SentPacket>,
+    /// The largest packet number received
+    pub rx_packet: u64,
+    /// When `rx_packet` was received
+    pub rx_packet_time: u64,
+    /// The time at which the next packet will be considered lost based on early transmit or exceeding the reordering
+    /// window in time.
+    pub loss_time: u64,
+    /// Incoming handshake data, reassembled into order for TLS
+    pub crypto_stream: stream::Assembler,
+    /// Offset at which our next handshake data will begin
+    pub crypto_offset: u64,
+}
+
+impl PacketSpace {
+    fn new(next_packet_number: u64) -> Self {
+        Self {
+            crypto: None,
+            pending: Retransmits::default(),
+            pending_acks: RangeSet::new(),
+            permit_ack_only: false,
+            next_packet_number,
+            largest_acked_packet: 0,
+            sent_packets: BTreeMap::new(),
+            rx_packet: 0,
+            rx_packet_time: 0,
+            loss_time: 0,
+            crypto_stream: stream::Assembler::new(),
+            crypto_offset: 0,
+        }
+    }
+
+    /// Whether there's anything to send, given whether ACK-only packets are allowed
+    fn can_send(&self) -> bool {
+        !self.pending.is_empty() || (self.permit_ack_only && !self.pending_acks.is_empty())
+    }
+}
+
 /// Represents one or more packets subject to retransmission
 #[derive(Debug, Clone)]
 pub struct SentPacket {
     pub time: u64,
     /// 0 iff ack-only
     pub bytes: u16,
-    pub handshake: bool,
+    /// Whether the packet was marked ECN-capable
+    pub ecn: bool,
     pub acks: RangeSet,
     pub retransmits: Retransmits,
 }
@@ -176,19 +294,69 @@ impl SentPacket {
     }
 }
 
+/// Traffic exchanged with a peer address that hasn't been validated yet
+pub struct UnvalidatedPath {
+    /// Bytes received from the address
+    pub received: u64,
+    /// Bytes sent to the address
+    pub sent: u64,
+    /// When to give up on the address if the peer hasn't answered our PATH_CHALLENGE
+    pub deadline: u64,
+    /// The validated address we migrated away from, restored if validation fails
+    pub prev_remote: SocketAddr,
+    /// Congestion state for `prev_remote`, if the migration reset it
+    pub prev_congestion: Option<PathCongestion>,
+}
+
+/// Congestion control and RTT estimates, which are only meaningful for the network path they were measured on
+pub struct PathCongestion {
+    congestion: Box<dyn CongestionController>,
+    latest_rtt: u64,
+    smoothed_rtt: u64,
+    rttvar: u64,
+    min_rtt: u64,
+    max_ack_delay: u64,
+}
+
+/// Keys replaced by a key update, retained to read packets that were delayed in transit
+pub struct PrevCrypto {
+    pub crypto: Crypto,
+    /// Number of the first packet received under the new keys, if the peer has used them yet
+    pub end_packet: Option<u64>,
+    /// Number of the first packet we sent under the new keys
+    pub update_sent: u64,
+}
+
+/// A connection ID along with the stateless reset token that accompanies it
+#[derive(Debug, Copy, Clone)]
+pub struct IssuedCid {
+    pub sequence: u64,
+    pub id: ConnectionId,
+    pub reset_token: [u8; RESET_TOKEN_SIZE],
+}
+
 #[derive(Debug, Clone)]
 pub struct Retransmits {
     pub max_data: bool,
     pub max_uni_stream_id: bool,
     pub max_bi_stream_id: bool,
     pub ping: bool,
-    pub new_connection_id: Option<ConnectionId>,
-    pub stream: VecDeque<frame::Stream>,
+    /// Whether to challenge the peer's unvalidated address
+    pub path_challenge: bool,
+    pub new_cids: Vec<IssuedCid>,
+    /// Handshake data, in order of transmission
+    pub crypto: VecDeque<frame::Crypto>,
+    pub stream: stream::Queue,
     /// packet number, token
     pub path_response: Option<(u64, u64)>,
     pub rst_stream: Vec<(StreamId, u16)>,
     pub stop_sending: Vec<(StreamId, u16)>,
     pub max_stream_data: FnvHashSet<StreamId>,
+    /// Whether to tell the peer that the connection-level flow control limit is holding us up
+    pub blocked: bool,
+    pub stream_blocked: FnvHashSet<StreamId>,
+    pub uni_stream_id_blocked: bool,
+    pub bi_stream_id_blocked: bool,
 }
 
 impl Retransmits {
@@ -197,12 +365,18 @@ impl Retransmits {
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
             && !self.ping
-            && self.new_connection_id.is_none()
+            && !self.path_challenge
+            && self.new_cids.is_empty()
+            && self.crypto.is_empty()
             && self.stream.is_empty()
             && self.path_response.is_none()
             && self.rst_stream.is_empty()
             && self.stop_sending.is_empty()
             && self.max_stream_data.is_empty()
+            && !self.blocked
+            && self.stream_blocked.is_empty()
+            && !self.uni_stream_id_blocked
+            && !self.bi_stream_id_blocked
     }
 
     pub fn path_challenge(&mut self, packet: u64, token: u64) {
@@ -225,12 +399,18 @@ impl Default for Retransmits {
             max_uni_stream_id: false,
             max_bi_stream_id: false,
             ping: false,
-            new_connection_id: None,
-            stream: VecDeque::new(),
+            path_challenge: false,
+            new_cids: Vec::new(),
+            crypto: VecDeque::new(),
+            stream: stream::Queue::default(),
             path_response: None,
             rst_stream: Vec::new(),
             stop_sending: Vec::new(),
             max_stream_data: FnvHashSet::default(),
+            blocked: false,
+            stream_blocked: FnvHashSet::default(),
+            uni_stream_id_blocked: false,
+            bi_stream_id_blocked: false,
         }
     }
 }
@@ -239,18 +419,22 @@ impl ::std::ops::AddAssign for Retransmits {
     fn add_assign(&mut self, rhs: Self) {
         self.max_data |= rhs.max_data;
         self.ping |= rhs.ping;
+        self.path_challenge |= rhs.path_challenge;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
-        if let Some(x) = rhs.new_connection_id {
-            self.new_connection_id = Some(x);
-        }
-        self.stream.extend(rhs.stream.into_iter());
+        self.new_cids.extend_from_slice(&rhs.new_cids);
+        self.crypto.extend(rhs.crypto);
+        self.stream.append(rhs.stream);
         if let Some((packet, token)) = rhs.path_response {
             self.path_challenge(packet, token);
         }
         self.rst_stream.extend_from_slice(&rhs.rst_stream);
         self.stop_sending.extend_from_slice(&rhs.stop_sending);
         self.max_stream_data.extend(&rhs.max_stream_data);
+        self.blocked |= rhs.blocked;
+        self.stream_blocked.extend(&rhs.stream_blocked);
+        self.uni_stream_id_blocked |= rhs.uni_stream_id_blocked;
+        self.bi_stream_id_blocked |= rhs.bi_stream_id_blocked;
     }
 }
 
@@ -268,66 +452,57 @@ impl ::std::iter::FromIterator<Retransmits> for Retransmits {
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        version: Version,
         initial_id: ConnectionId,
         local_id: ConnectionId,
         remote_id: ConnectionId,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         initial_packet_number: u64,
         side: Side,
-        config: &Config,
+        config: Arc<TransportConfig>,
         handle: ConnectionHandle,
     ) -> Self {
-        let handshake_crypto = Crypto::new_handshake(&initial_id, side);
-        let mut streams = FnvHashMap::default();
-        for i in 0..config.max_remote_uni_streams {
-            streams.insert(
-                StreamId::new(!side, Directionality::Uni, u64::from(i)),
-                stream::Recv::new(u64::from(config.stream_receive_window)).into(),
-            );
-        }
-        streams.insert(
-            StreamId(0),
-            Stream::new_bi(u64::from(config.stream_receive_window)),
-        );
-        let max_remote_bi_streams = config.max_remote_bi_streams as u64 + match side {
-            Side::Server => 1,
-            _ => 0,
-        };
-        for i in match side {
-            Side::Server => 1,
-            _ => 0,
-        }..max_remote_bi_streams
-        {
-            streams.insert(
-                StreamId::new(!side, Directionality::Bi, i as u64),
-                Stream::new_bi(config.stream_receive_window as u64),
-            );
-        }
+        let mut initial = PacketSpace::new(initial_packet_number);
+        initial.crypto = Some(Crypto::new_initial(version, &initial_id, side));
         Self {
             app_closed: false,
+            version,
             initial_id,
             local_id,
             remote_id,
+            loc_cids: iter::once((0, local_id)).collect(),
+            rem_cids: BTreeMap::new(),
             remote,
+            path_challenge: None,
+            unvalidated_path: None,
             side,
             handle,
             state: None,
             mtu: MIN_MTU,
-            rx_packet: 0,
-            rx_packet_time: 0,
-            crypto: None,
+            mtud: MtuDiscovery::new(config.max_mtu),
+            spaces: [initial, PacketSpace::new(0), PacketSpace::new(0)],
+            highest_space: SpaceId::Initial,
             prev_crypto: None,
-            //zero_rtt_crypto: None,
+            zero_rtt_crypto: None,
             key_phase: false,
-            params: TransportParameters::new(config),
+            key_update_requested: false,
+            key_packets_sent: 0,
+            key_bytes_sent: 0,
+            params: TransportParameters::new(&config),
             readable_streams: FnvHashSet::default(),
             blocked_streams: FnvHashSet::default(),
             max_data: 0,
             data_sent: 0,
+            blocked_reported: None,
             data_recvd: 0,
-            local_max_data: config.receive_window as u64,
+            receive_window: flow_control::Window::new(
+                u64::from(config.receive_window),
+                u64::from(config.max_receive_window),
+            ),
             server_name: None,
+            session_store: None,
 
             handshake_count: 0,
             tlp_count: 0,
@@ -337,7 +512,6 @@ impl Connection {
             } else {
                 config.reordering_threshold
             },
-            loss_time: 0,
             latest_rtt: 0,
             smoothed_rtt: 0,
             rttvar: 0,
@@ -346,37 +520,40 @@ impl Connection {
             largest_sent_before_rto: 0,
             time_of_last_sent_retransmittable_packet: 0,
             time_of_last_sent_handshake_packet: 0,
-            largest_sent_packet: initial_packet_number.overflowing_sub(1).0,
-            largest_acked_packet: 0,
-            sent_packets: BTreeMap::new(),
 
             bytes_in_flight: 0,
-            congestion_window: config.initial_window,
-            end_of_recovery: 0,
-            ssthresh: u64::max_value(),
+            congestion: config.congestion_controller.build(&config),
+            app_limited: false,
+            pacer: Pacer::new(),
 
-            awaiting_handshake: false,
-            handshake_pending: Retransmits::default(),
-            handshake_crypto,
+            sending_ecn: config.ecn,
+            ecn_counts: frame::EcnCounts::default(),
+            ecn_feedback: frame::EcnCounts::default(),
 
-            pending: Retransmits::default(),
-            pending_acks: RangeSet::new(),
-            permit_ack_only: false,
+            datagrams: VecDeque::new(),
 
             set_idle: None,
             set_loss_detection: None,
+            set_pacing: None,
+            set_path_validation: None,
 
-            streams,
+            streams: FnvHashMap::default(),
             next_uni_stream: 0,
-            next_bi_stream: match side {
-                Side::Client => 1,
-                Side::Server => 0,
-            },
+            next_bi_stream: 0,
             max_uni_streams: 0,
             max_bi_streams: 0,
             max_remote_uni_streams: config.max_remote_uni_streams as u64,
-            max_remote_bi_streams,
+            max_remote_bi_streams: config.max_remote_bi_streams as u64,
+            next_remote_uni_stream: 0,
+            next_remote_bi_stream: 0,
+            implicit_remote_uni_streams: RangeSet::new(),
+            implicit_remote_bi_streams: RangeSet::new(),
+            open_remote_uni_streams: config.max_remote_uni_streams as u64,
+            open_remote_bi_streams: config.max_remote_bi_streams as u64,
+            concurrent_remote_uni_streams: config.max_remote_uni_streams as u64,
+            concurrent_remote_bi_streams: config.max_remote_bi_streams as u64,
             finished_streams: Vec::new(),
+            config,
         }
     }
 
@@ -384,16 +561,37 @@ impl Connection {
     pub fn connect(
         &mut self,
         ctx: &Context,
-        config: &Arc<ClientConfig>,
+        config: &ClientConfig,
         server_name: &str,
     ) -> Result<(), ConnectError> {
-        let mut tls =
-            TlsSession::new_client(config, server_name, &TransportParameters::new(&ctx.config))
-                .unwrap();
+        let mut tls = TlsSession::new_client(
+            config,
+            server_name,
+            self.version,
+            &TransportParameters::new(&self.config),
+        ).unwrap();
         self.server_name = Some(server_name.into());
-        let mut outgoing = Vec::new();
-        tls.write_tls(&mut outgoing).unwrap();
-        self.transmit_handshake(&outgoing);
+        self.session_store = Some(config.session_store.clone());
+        self.write_tls(&mut tls);
+        let params = config
+            .session_store
+            .get(server_name, TRANSPORT_PARAMETERS_KEY)
+            .and_then(|x| {
+                TransportParameters::read(
+                    Side::Client,
+                    self.version,
+                    &ctx.config.supported_versions,
+                    &mut io::Cursor::new(x),
+                ).ok()
+            });
+        if let Some(params) = params {
+            self.zero_rtt_crypto = Crypto::new_0rtt(&tls);
+            if self.zero_rtt_crypto.is_some() {
+                trace!(ctx.log, "sending 0-RTT data");
+                // Assume the server's limits are unchanged until we learn otherwise
+                self.set_params(params);
+            }
+        }
         self.state = Some(State::Handshake(state::Handshake {
             tls,
             clienthello_packet: None,
@@ -402,83 +600,238 @@ impl Connection {
         Ok(())
     }
 
-    pub fn handshake_complete(
-        &mut self,
-        ctx: &mut Context,
-        mut tls: TlsSession,
-        params: TransportParameters,
-        //zero_rtt_crypto: Option<Crypto>,
-        now: u64,
-        packet_number: u64,
-    ) {
-        //self.zero_rtt_crypto = zero_rtt_crypto;
-        self.on_packet_authenticated(ctx, now, packet_number);
-        let mut outgoing = Vec::new();
-        tls.write_tls(&mut outgoing).unwrap();
-        self.transmit_handshake(&outgoing);
-        self.state = Some(State::Handshake(state::Handshake {
-            tls,
-            clienthello_packet: None,
-            remote_id_set: true,
-        }));
-        self.set_params(params);
-        ctx.dirty_conns.insert(self.handle);
-        ctx.incoming_handshakes += 1;
+    /// Start sending to the peer's new address, pending validation
+    fn migrate(&mut self, ctx: &mut Context, now: u64, remote: SocketAddr, received: u64) {
+        debug!(ctx.log, "peer migrated"; "connection" => %self.local_id, "from" => %self.remote, "to" => %remote);
+        // If the previous address was never validated either, the one before it is still the one to fall back to
+        let (prev_remote, mut prev_congestion) = match self.unvalidated_path.take() {
+            Some(path) => (path.prev_remote, path.prev_congestion),
+            None => (self.remote, None),
+        };
+        // A change of port alone is most likely NAT rebinding, which leaves the path itself intact
+        if remote.ip() != self.remote.ip() {
+            let old = self.reset_congestion_state();
+            if prev_congestion.is_none() {
+                prev_congestion = Some(old);
+            }
+        }
+        self.remote = remote;
+        // Avoid linking the peer's old and new addresses
+        self.switch_remote_cid();
+        let token = ctx.rng.gen();
+        self.path_challenge = Some(token);
+        let timeout = cmp::max(3 * self.rto(), 6 * self.config.default_initial_rtt);
+        self.unvalidated_path = Some(UnvalidatedPath {
+            received,
+            sent: 0,
+            deadline: now + timeout,
+            prev_remote,
+            prev_congestion,
+        });
+        self.space_mut(SpaceId::Data).pending.path_challenge = true;
+        self.set_path_validation = Some(Some(now + cmp::min(self.rto(), timeout)));
+    }
+
+    /// Handle the path validation timer expiring
+    ///
+    /// Resends PATH_CHALLENGE until the peer answers or the deadline passes, in which case the address is presumed
+    /// spoofed or unreachable and we return to the last validated one.
+    pub fn path_validation_timeout(&mut self, log: &Logger, now: u64) {
+        let deadline = match self.unvalidated_path {
+            Some(ref path) => path.deadline,
+            None => return,
+        };
+        if now < deadline {
+            self.space_mut(SpaceId::Data).pending.path_challenge = true;
+            self.set_path_validation = Some(Some(cmp::min(now + self.rto(), deadline)));
+            return;
+        }
+        let path = self.unvalidated_path.take().unwrap();
+        debug!(log, "path validation failed"; "connection" => %self.local_id, "remote" => %self.remote, "reverting to" => %path.prev_remote);
+        self.remote = path.prev_remote;
+        if let Some(x) = path.prev_congestion {
+            self.restore_congestion_state(x);
+        }
+    }
+
+    /// Prepare to send from a new local address
+    pub fn handle_rebind(&mut self) {
+        self.switch_remote_cid();
+        self.reset_congestion_state();
+        // Let the peer know about the new address promptly
+        self.space_mut(SpaceId::Data).pending.ping = true;
+    }
+
+    /// Switch to a spare connection ID issued by the peer, if any
+    fn switch_remote_cid(&mut self) {
+        let sequence = if let Some(&sequence) = self.rem_cids.keys().next() {
+            sequence
+        } else {
+            return;
+        };
+        self.remote_id = self.rem_cids.remove(&sequence).unwrap().id;
+    }
+
+    /// Forget what we know about the network path, returning it
+    fn reset_congestion_state(&mut self) -> PathCongestion {
+        PathCongestion {
+            latest_rtt: mem::replace(&mut self.latest_rtt, 0),
+            smoothed_rtt: mem::replace(&mut self.smoothed_rtt, 0),
+            rttvar: mem::replace(&mut self.rttvar, 0),
+            min_rtt: mem::replace(&mut self.min_rtt, u64::MAX),
+            max_ack_delay: mem::replace(&mut self.max_ack_delay, 0),
+            congestion: mem::replace(
+                &mut self.congestion,
+                self.config.congestion_controller.build(&self.config),
+            ),
+        }
+    }
+
+    /// Resume using what we learned about a network path before `reset_congestion_state`
+    fn restore_congestion_state(&mut self, state: PathCongestion) {
+        self.latest_rtt = state.latest_rtt;
+        self.smoothed_rtt = state.smoothed_rtt;
+        self.rttvar = state.rttvar;
+        self.min_rtt = state.min_rtt;
+        self.max_ack_delay = state.max_ack_delay;
+        self.congestion = state.congestion;
+    }
+
+    /// Restart the handshake towards the connection ID supplied by a stateless Retry
+    fn retry(&mut self, remote_id: ConnectionId) {
+        self.initial_id = remote_id;
+        self.remote_id = remote_id;
+        self.restart_handshake();
+    }
+
+    /// Resend everything sent so far under freshly derived handshake keys
+    ///
+    /// Used when the server discarded our Initial without keeping any state, e.g. to validate our address or to switch
+    /// versions.
+    fn restart_handshake(&mut self) {
+        self.space_mut(SpaceId::Initial).crypto = Some(Crypto::new_initial(
+            self.version,
+            &self.initial_id,
+            Side::Client,
+        ));
+        for space in &mut self.spaces {
+            for (_, packet) in mem::take(&mut space.sent_packets) {
+                self.bytes_in_flight -= u64::from(packet.bytes);
+                space.pending += packet.retransmits;
+            }
+        }
+    }
+
+    /// Hand a fresh connection ID routing to this connection out to the peer
+    pub fn issue_cid(&mut self, id: ConnectionId, reset_token: [u8; RESET_TOKEN_SIZE]) {
+        let sequence = self.loc_cids.len() as u64;
+        self.loc_cids.insert(sequence, id);
+        self.space_mut(SpaceId::Data)
+            .pending
+            .new_cids
+            .push(IssuedCid {
+                sequence,
+                id,
+                reset_token,
+            });
+    }
+
+    pub fn space(&self, id: SpaceId) -> &PacketSpace {
+        &self.spaces[id as usize]
     }
 
-    pub fn get_tx_number(&mut self) -> u64 {
-        self.largest_sent_packet = self.largest_sent_packet.overflowing_add(1).0;
+    pub fn space_mut(&mut self, id: SpaceId) -> &mut PacketSpace {
+        &mut self.spaces[id as usize]
+    }
+
+    pub fn get_tx_number(&mut self, space: SpaceId) -> u64 {
+        let space = self.space_mut(space);
+        let number = space.next_packet_number;
+        space.next_packet_number += 1;
         // TODO: Handle packet number overflow gracefully
-        assert!(self.largest_sent_packet < 2u64.pow(62));
-        self.largest_sent_packet
+        assert!(number < 2u64.pow(62));
+        number
     }
 
     pub fn on_packet_sent(
         &mut self,
-        config: &Config,
         now: u64,
+        space: SpaceId,
         packet_number: u64,
         packet: SentPacket,
     ) {
-        self.largest_sent_packet = packet_number;
         let bytes = packet.bytes;
-        let handshake = packet.handshake;
-        if handshake {
-            self.awaiting_handshake = true;
-        }
-        self.sent_packets.insert(packet_number, packet);
+        self.space_mut(space)
+            .sent_packets
+            .insert(packet_number, packet);
         if bytes != 0 {
             self.time_of_last_sent_retransmittable_packet = now;
-            if handshake {
+            // Congestion controllers reason in packet numbers, which are only comparable within a space
+            if space == SpaceId::Data {
+                self.congestion.on_sent(now, packet_number, bytes as u64);
+            } else {
                 self.time_of_last_sent_handshake_packet = now;
             }
             self.bytes_in_flight += bytes as u64;
-            self.set_loss_detection_alarm(config);
+            self.set_loss_detection_alarm();
         }
     }
 
-    pub fn on_ack_received(&mut self, ctx: &mut Context, now: u64, ack: frame::Ack) {
-        trace!(ctx.log, "got ack"; "ranges" => ?ack.iter().collect::<Vec<_>>());
+    pub fn on_ack_received(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        space: SpaceId,
+        ack: frame::Ack,
+    ) {
+        trace!(ctx.log, "got ack"; "space" => ?space, "ranges" => ?ack.iter().collect::<Vec<_>>());
         let was_blocked = self.blocked();
-        self.largest_acked_packet = cmp::max(self.largest_acked_packet, ack.largest); // TODO: Validate
-        if let Some(info) = self.sent_packets.get(&ack.largest).cloned() {
-            self.latest_rtt = now - info.time;
-            let delay = ack.delay << self.params.ack_delay_exponent;
-            self.update_rtt(delay, info.ack_only());
+        {
+            let space = self.space_mut(space);
+            // TODO: Validate
+            space.largest_acked_packet = cmp::max(space.largest_acked_packet, ack.largest);
         }
+        let new_largest =
+            if let Some(info) = self.space(space).sent_packets.get(&ack.largest).cloned() {
+                self.latest_rtt = now - info.time;
+                let delay = ack.delay << self.params.ack_delay_exponent;
+                self.update_rtt(now, delay, info.ack_only());
+                true
+            } else {
+                false
+            };
+        let mut newly_acked_ecn = 0;
         for range in &ack {
             // Avoid DoS from unreasonably huge ack ranges
             let packets = self
+                .space(space)
                 .sent_packets
                 .range(range)
-                .map(|(&n, _)| n)
+                .map(|(&n, info)| (n, info.ecn))
                 .collect::<Vec<_>>();
-            for packet in packets {
-                self.on_packet_acked(&ctx.config, packet);
+            for (packet, ecn) in packets {
+                if ecn {
+                    newly_acked_ecn += 1;
+                }
+                self.on_packet_acked(now, space, packet);
             }
         }
-        self.detect_lost_packets(&ctx.config, now, ack.largest);
-        self.set_loss_detection_alarm(&ctx.config);
+        // Older ACK frames may have been reordered, and so carry stale counts
+        if new_largest && self.sending_ecn && space == SpaceId::Data {
+            self.process_ecn(&ctx.log, now, newly_acked_ecn, ack.largest, ack.ecn);
+        }
+        self.detect_lost_packets(now, space);
+        self.set_loss_detection_alarm();
+        let largest_acked = self.space(SpaceId::Data).largest_acked_packet;
+        if self
+            .prev_crypto
+            .as_ref()
+            .is_some_and(|x| x.end_packet.is_some() && largest_acked >= x.update_sent)
+        {
+            // Both peers have moved on to the new keys, and anything sent under the old ones is a full round trip stale
+            trace!(ctx.log, "discarding previous keys");
+            self.prev_crypto = None;
+        }
         if was_blocked && !self.blocked() {
             for stream in self.blocked_streams.drain() {
                 ctx.events
@@ -487,7 +840,58 @@ impl Connection {
         }
     }
 
-    pub fn update_rtt(&mut self, ack_delay: u64, ack_only: bool) {
+    /// Check that the peer's ECN counts account for the ECN-capable packets it acknowledged, and treat any newly
+    /// reported congestion as though packets had been lost
+    ///
+    /// If the peer doesn't report counts, or the counts don't add up, some device on the path is likely clearing or
+    /// mangling the ECN bits, so we stop setting them.
+    fn process_ecn(
+        &mut self,
+        log: &Logger,
+        now: u64,
+        newly_acked: u64,
+        largest_acked: u64,
+        ecn: Option<frame::EcnCounts>,
+    ) {
+        let ecn = match ecn {
+            Some(x) => x,
+            None => {
+                if newly_acked != 0 {
+                    debug!(log, "disabling ECN: peer isn't reporting ECN counts");
+                    self.sending_ecn = false;
+                }
+                return;
+            }
+        };
+        let prev = self.ecn_feedback;
+        if ecn.ect0 < prev.ect0
+            || ecn.ect1 < prev.ect1
+            || ecn.ce < prev.ce
+            || (ecn.ect0 - prev.ect0) + (ecn.ce - prev.ce) < newly_acked
+        {
+            debug!(log, "disabling ECN: peer's ECN counts are inconsistent"; "counts" => ?ecn, "previous" => ?prev, "newly acked" => newly_acked);
+            self.sending_ecn = false;
+            return;
+        }
+        self.ecn_feedback = ecn;
+        if ecn.ce > prev.ce {
+            trace!(log, "congestion experienced"; "marks" => ecn.ce - prev.ce);
+            let largest_sent = self.space(SpaceId::Data).next_packet_number - 1;
+            self.congestion
+                .on_congestion_event(now, largest_acked, largest_sent);
+        }
+    }
+
+    /// ECN codepoint to mark outgoing packets with
+    pub fn ecn(&self) -> Option<EcnCodepoint> {
+        if self.sending_ecn {
+            Some(EcnCodepoint::Ect0)
+        } else {
+            None
+        }
+    }
+
+    pub fn update_rtt(&mut self, now: u64, ack_delay: u64, ack_only: bool) {
         self.min_rtt = cmp::min(self.min_rtt, self.latest_rtt);
         if self.latest_rtt - self.min_rtt > ack_delay {
             self.latest_rtt -= ack_delay;
@@ -503,37 +907,42 @@ impl Connection {
             self.rttvar = (3 * self.rttvar + rttvar_sample) / 4;
             self.smoothed_rtt = (7 * self.smoothed_rtt + self.latest_rtt) / 8;
         }
+        self.congestion
+            .on_rtt_update(now, self.latest_rtt, self.smoothed_rtt, self.min_rtt);
     }
 
-    // Not timing-aware, so it's safe to call this for inferred acks, such as arise from high-latency handshakes
-    pub fn on_packet_acked(&mut self, config: &Config, packet: u64) {
-        let info = if let Some(x) = self.sent_packets.remove(&packet) {
+    // Doesn't take an RTT sample, so it's safe to call this for inferred acks, such as arise from high-latency
+    // handshakes
+    pub fn on_packet_acked(&mut self, now: u64, space: SpaceId, packet: u64) {
+        let info = if let Some(x) = self.space_mut(space).sent_packets.remove(&packet) {
             x
         } else {
             return;
         };
         if info.bytes != 0 {
-            // Congestion control
             self.bytes_in_flight -= info.bytes as u64;
-            // Do not increase congestion window in recovery period.
-            if !self.in_recovery(packet) {
-                if self.congestion_window < self.ssthresh {
-                    // Slow start.
-                    self.congestion_window += info.bytes as u64;
-                } else {
-                    // Congestion avoidance.
-                    self.congestion_window +=
-                        config.default_mss * info.bytes as u64 / self.congestion_window;
-                }
+        }
+        // MTU probes and congestion controllers reason in packet numbers, which are only comparable within a space
+        if space == SpaceId::Data {
+            self.mtud.on_acked(packet, info.bytes);
+            self.mtu = self.mtud.current();
+            if info.bytes != 0 {
+                self.congestion.on_ack(
+                    now,
+                    packet,
+                    info.bytes as u64,
+                    self.bytes_in_flight,
+                    self.app_limited,
+                );
             }
         }
 
         // Loss recovery
 
         // If a packet sent prior to RTO was acked, then the RTO was spurious.  Otherwise, inform congestion control.
-        if self.rto_count > 0 && packet > self.largest_sent_before_rto {
+        if space == SpaceId::Data && self.rto_count > 0 && packet > self.largest_sent_before_rto {
             // Retransmission timeout verified
-            self.congestion_window = config.minimum_window;
+            self.congestion.on_retransmission_timeout_verified(now);
         }
 
         self.handshake_count = 0;
@@ -548,11 +957,11 @@ impl Connection {
                 self.streams.get_mut(&id).unwrap().send_mut().unwrap().state =
                     stream::SendState::ResetRecvd { stop_reason };
                 if stop_reason.is_none() {
-                    self.maybe_cleanup(config, id);
+                    self.maybe_cleanup(id);
                 }
             }
         }
-        for frame in info.retransmits.stream {
+        for frame in info.retransmits.stream.into_frames() {
             let recvd = {
                 let ss = if let Some(x) = self.streams.get_mut(&frame.id) {
                     x.send_mut().unwrap()
@@ -568,83 +977,80 @@ impl Connection {
                 }
             };
             if recvd {
-                self.maybe_cleanup(config, frame.id);
+                self.maybe_cleanup(frame.id);
                 self.finished_streams.push(frame.id);
             }
         }
-        self.pending_acks.subtract(&info.acks);
+        self.space_mut(space).pending_acks.subtract(&info.acks);
     }
 
-    pub fn detect_lost_packets(&mut self, config: &Config, now: u64, largest_acked: u64) {
-        self.loss_time = 0;
+    pub fn detect_lost_packets(&mut self, now: u64, space: SpaceId) {
+        let largest_acked = self.space(space).largest_acked_packet;
+        let largest_sent = self.space(space).next_packet_number.saturating_sub(1);
+        let mut loss_time = 0;
         let mut lost_packets = Vec::<u64>::new();
         let delay_until_lost;
         let rtt = cmp::max(self.latest_rtt, self.smoothed_rtt);
-        if config.using_time_loss_detection {
+        if self.config.using_time_loss_detection {
             // factor * (1 + fraction)
-            delay_until_lost = (rtt + (rtt * config.time_reordering_fraction as u64)) >> 16;
-        } else if largest_acked == self.largest_sent_packet {
+            delay_until_lost = (rtt + (rtt * self.config.time_reordering_fraction as u64)) >> 16;
+        } else if largest_acked == largest_sent {
             // Early retransmit alarm.
             delay_until_lost = (5 * rtt) / 4;
         } else {
-            delay_until_lost = u64::max_value();
+            delay_until_lost = u64::MAX;
         }
-        for (&packet, info) in self.sent_packets.range(0..largest_acked) {
+        for (&packet, info) in self.space(space).sent_packets.range(0..largest_acked) {
             let time_since_sent = now - info.time;
             let delta = largest_acked - packet;
             // Use of >= for time comparison here is critical so that we successfully detect lost packets in testing
             // when rtt = 0
             if time_since_sent >= delay_until_lost || delta > self.reordering_threshold as u64 {
                 lost_packets.push(packet);
-            } else if self.loss_time == 0 && delay_until_lost != u64::max_value() {
-                self.loss_time = now + delay_until_lost - time_since_sent;
+            } else if loss_time == 0 && delay_until_lost != u64::MAX {
+                loss_time = now + delay_until_lost - time_since_sent;
             }
         }
+        self.space_mut(space).loss_time = loss_time;
 
         if let Some(largest_lost) = lost_packets.last().cloned() {
-            let old_bytes_in_flight = self.bytes_in_flight;
+            // Don't apply congestion penalty for lost ack-only packets or MTU probes
+            let mut lost_nonack = false;
             for packet in lost_packets {
-                let mut info = self.sent_packets.remove(&packet).unwrap();
-                if info.handshake {
-                    self.handshake_pending += info.retransmits;
-                } else {
-                    self.pending += info.retransmits;
-                }
+                let info = self.space_mut(space).sent_packets.remove(&packet).unwrap();
                 self.bytes_in_flight -= info.bytes as u64;
+                if space == SpaceId::Data {
+                    let probe = self.mtud.on_lost(packet, info.bytes);
+                    lost_nonack |= info.bytes != 0 && !probe;
+                }
+                self.space_mut(space).pending += info.retransmits;
             }
-            // Don't apply congestion penalty for lost ack-only packets
-            let lost_nonack = old_bytes_in_flight != self.bytes_in_flight;
-            // Start a new recovery epoch if the lost packet is larger than the end of the previous recovery epoch.
-            if lost_nonack && !self.in_recovery(largest_lost) {
-                self.end_of_recovery = self.largest_sent_packet;
-                // *= factor
-                self.congestion_window =
-                    (self.congestion_window * config.loss_reduction_factor as u64) >> 16;
-                self.congestion_window = cmp::max(self.congestion_window, config.minimum_window);
-                self.ssthresh = self.congestion_window;
+            self.mtu = self.mtud.current();
+            if lost_nonack {
+                self.congestion
+                    .on_congestion_event(now, largest_lost, largest_sent);
             }
         }
     }
 
-    pub fn in_recovery(&self, packet: u64) -> bool {
-        packet <= self.end_of_recovery
-    }
-
-    pub fn set_loss_detection_alarm(&mut self, config: &Config) {
+    pub fn set_loss_detection_alarm(&mut self) {
         if self.bytes_in_flight == 0 {
             self.set_loss_detection = Some(None);
             return;
         }
 
         let mut alarm_duration: u64;
-        if self.awaiting_handshake {
+        if self.awaiting_handshake() {
             // Handshake retransmission alarm.
             if self.smoothed_rtt == 0 {
-                alarm_duration = 2 * config.default_initial_rtt;
+                alarm_duration = 2 * self.config.default_initial_rtt;
             } else {
                 alarm_duration = 2 * self.smoothed_rtt;
             }
-            alarm_duration = cmp::max(alarm_duration + self.max_ack_delay, config.min_tlp_timeout);
+            alarm_duration = cmp::max(
+                alarm_duration + self.max_ack_delay,
+                self.config.min_tlp_timeout,
+            );
             alarm_duration *= 2u64.pow(self.handshake_count);
             self.set_loss_detection = Some(Some(
                 self.time_of_last_sent_handshake_packet + alarm_duration,
@@ -652,17 +1058,18 @@ impl Connection {
             return;
         }
 
-        if self.loss_time != 0 {
+        let loss_time = self.loss_time();
+        if loss_time != 0 {
             // Early retransmit timer or time loss detection.
-            alarm_duration = self.loss_time - self.time_of_last_sent_retransmittable_packet;
+            alarm_duration = loss_time - self.time_of_last_sent_retransmittable_packet;
         } else {
             // TLP or RTO alarm
-            alarm_duration = self.rto(config);
-            if self.tlp_count < config.max_tlps {
+            alarm_duration = self.rto();
+            if self.tlp_count < self.config.max_tlps {
                 // Tail Loss Probe
                 let tlp_duration = cmp::max(
                     (3 * self.smoothed_rtt) / 2 + self.max_ack_delay,
-                    config.min_tlp_timeout,
+                    self.config.min_tlp_timeout,
                 );
                 alarm_duration = cmp::min(alarm_duration, tlp_duration);
             }
@@ -672,101 +1079,145 @@ impl Connection {
         ));
     }
 
+    /// Whether any handshake data we sent is yet to be acknowledged
+    pub fn awaiting_handshake(&self) -> bool {
+        self.spaces[..SpaceId::Data as usize]
+            .iter()
+            .any(|x| x.sent_packets.values().any(|packet| !packet.ack_only()))
+    }
+
+    /// The earliest time at which a packet in any space will be considered lost, or 0 if none
+    pub fn loss_time(&self) -> u64 {
+        self.spaces
+            .iter()
+            .map(|x| x.loss_time)
+            .filter(|&x| x != 0)
+            .min()
+            .unwrap_or(0)
+    }
+
     /// Retransmit time-out
-    pub fn rto(&self, config: &Config) -> u64 {
+    pub fn rto(&self) -> u64 {
         let computed = self.smoothed_rtt + 4 * self.rttvar + self.max_ack_delay;
-        cmp::max(computed, config.min_rto_timeout) * 2u64.pow(self.rto_count)
+        cmp::max(computed, self.config.min_rto_timeout) * 2u64.pow(self.rto_count)
     }
 
-    pub fn on_packet_authenticated(&mut self, ctx: &mut Context, now: u64, packet: u64) {
-        trace!(ctx.log, "packet authenticated"; "connection" => %self.local_id, "pn" => packet);
-        self.reset_idle_timeout(&ctx.config, now);
-        self.pending_acks.insert_one(packet);
-        if self.pending_acks.len() > MAX_ACK_BLOCKS {
-            self.pending_acks.pop_min();
+    pub fn on_packet_authenticated(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        space: SpaceId,
+        packet: u64,
+        ecn: Option<EcnCodepoint>,
+    ) {
+        trace!(ctx.log, "packet authenticated"; "connection" => %self.local_id, "space" => ?space, "pn" => packet);
+        self.reset_idle_timeout(now);
+        // ECN counts are only reported in ACKs of the data space
+        if space == SpaceId::Data {
+            if let Some(x) = ecn {
+                self.ecn_counts += x;
+            }
+        }
+        let space = self.space_mut(space);
+        space.pending_acks.insert_one(packet);
+        if space.pending_acks.len() > MAX_ACK_BLOCKS {
+            space.pending_acks.pop_min();
         }
-        if packet > self.rx_packet {
-            self.rx_packet = packet;
-            self.rx_packet_time = now;
+        if packet > space.rx_packet {
+            space.rx_packet = packet;
+            space.rx_packet_time = now;
         }
     }
 
-    pub fn reset_idle_timeout(&mut self, config: &Config, now: u64) {
-        let dt = if config.idle_timeout == 0 || self.params.idle_timeout == 0 {
-            cmp::max(config.idle_timeout, self.params.idle_timeout)
+    pub fn reset_idle_timeout(&mut self, now: u64) {
+        let dt = if self.config.idle_timeout == 0 || self.params.idle_timeout == 0 {
+            cmp::max(self.config.idle_timeout, self.params.idle_timeout)
         } else {
-            cmp::min(config.idle_timeout, self.params.idle_timeout)
+            cmp::min(self.config.idle_timeout, self.params.idle_timeout)
         };
         self.set_idle = Some(Some(now + dt as u64 * 1_000_000));
     }
 
-    /// Consider all previously transmitted handshake packets to be delivered. Called when we receive a new handshake packet.
-    pub fn handshake_cleanup(&mut self, config: &Config) {
-        if !self.awaiting_handshake {
-            return;
+    /// Queue everything sent in the Initial and Handshake spaces for retransmission, following a handshake timeout
+    pub fn retransmit_handshake(&mut self) {
+        for space in &mut self.spaces[..SpaceId::Data as usize] {
+            for (_, packet) in mem::take(&mut space.sent_packets) {
+                self.bytes_in_flight -= u64::from(packet.bytes);
+                space.pending += packet.retransmits;
+            }
         }
-        self.awaiting_handshake = false;
-        self.handshake_pending = Retransmits::default();
-        let mut packets = Vec::new();
-        for (&packet, info) in &self.sent_packets {
-            if info.handshake {
-                packets.push(packet);
+        self.handshake_count += 1;
+    }
+
+    /// Drop the keys and all other state of a handshake space the peer no longer needs
+    fn discard_space(&mut self, space: SpaceId) {
+        {
+            let space = &mut self.spaces[space as usize];
+            for (_, packet) in mem::take(&mut space.sent_packets) {
+                self.bytes_in_flight -= u64::from(packet.bytes);
             }
+            space.crypto = None;
+            space.pending = Retransmits::default();
+            space.pending_acks = RangeSet::new();
+            space.loss_time = 0;
         }
-        for packet in packets {
-            self.on_packet_acked(config, packet);
+        self.set_loss_detection_alarm();
+    }
+
+    /// Requeue data sent in 0-RTT packets for transmission under 1-RTT keys
+    ///
+    /// Called when the server declines our early data.
+    pub fn reject_0rtt(&mut self) {
+        // Before the handshake completes, 0-RTT packets are the only data space packets a client sends
+        let space = &mut self.spaces[SpaceId::Data as usize];
+        for (_, packet) in mem::take(&mut space.sent_packets) {
+            self.bytes_in_flight -= u64::from(packet.bytes);
+            space.pending += packet.retransmits;
         }
-        self.set_loss_detection_alarm(config);
     }
 
-    pub fn update_keys(
-        &mut self,
-        packet: u64,
-        header: &[u8],
-        payload: &mut BytesMut,
-    ) -> Result<(), ()> {
-        let new = self.crypto.as_mut().unwrap().update(self.side);
-        new.decrypt(packet, header, payload)?;
-        let old = mem::replace(self.crypto.as_mut().unwrap(), new);
-        self.prev_crypto = Some((packet, old));
+    /// Switch to `new` 1-RTT keys, given the first packet the peer sent under them if it initiated the update
+    pub fn commit_key_update(&mut self, new: Crypto, end_packet: Option<u64>) {
+        let old = mem::replace(self.space_mut(SpaceId::Data).crypto.as_mut().unwrap(), new);
+        self.prev_crypto = Some(PrevCrypto {
+            crypto: old,
+            end_packet,
+            update_sent: self.space(SpaceId::Data).next_packet_number,
+        });
         self.key_phase = !self.key_phase;
-        Ok(())
+        self.key_update_requested = false;
+        self.key_packets_sent = 0;
+        self.key_bytes_sent = 0;
     }
 
-    pub fn decrypt(
-        &self,
-        handshake: bool,
-        packet: u64,
-        header: &[u8],
-        payload: &mut BytesMut,
-    ) -> Result<(), ()> {
-        match (handshake, &self.prev_crypto) {
-            (true, _) => &self.handshake_crypto,
-            (false, &Some((boundary, ref prev))) if packet < boundary => prev,
-            _ => self.crypto.as_ref().unwrap(),
-        }.decrypt(packet, header, payload)
-    }
-
-    pub fn transmit_handshake(&mut self, messages: &[u8]) {
-        let offset = {
-            let ss = self
-                .streams
-                .get_mut(&StreamId(0))
-                .unwrap()
-                .send_mut()
-                .unwrap();
-            let x = ss.offset;
-            ss.offset += messages.len() as u64;
-            ss.bytes_in_flight += messages.len() as u64;
-            x
-        };
-        self.handshake_pending.stream.push_back(frame::Stream {
-            id: StreamId(0),
-            fin: false,
+    /// Whether we should replace our 1-RTT keys before sending another packet
+    fn key_update_due(&self) -> bool {
+        if self
+            .prev_crypto
+            .as_ref()
+            .is_some_and(|x| x.end_packet.is_none())
+        {
+            // The peer hasn't used the keys from our last update yet
+            return false;
+        }
+        let crypto = self.space(SpaceId::Data).crypto.as_ref().unwrap();
+        self.key_update_requested
+            || self.key_packets_sent >= crypto.confidentiality_limit()
+            || (self.config.key_update_packets != 0
+                && self.key_packets_sent >= self.config.key_update_packets)
+            || (self.config.key_update_bytes != 0
+                && self.key_bytes_sent >= self.config.key_update_bytes)
+    }
+
+    /// Queue TLS handshake messages for transmission in `space`
+    fn transmit_handshake(&mut self, space: SpaceId, messages: &[u8]) {
+        let space = self.space_mut(space);
+        let offset = space.crypto_offset;
+        space.crypto_offset += messages.len() as u64;
+        space.pending.crypto.push_back(frame::Crypto {
             offset,
             data: messages.into(),
         });
-        self.awaiting_handshake = true;
     }
 
     pub fn transmit(&mut self, stream: StreamId, data: Bytes) {
@@ -775,15 +1226,19 @@ impl Connection {
         let offset = ss.offset;
         ss.offset += data.len() as u64;
         ss.bytes_in_flight += data.len() as u64;
-        if stream != StreamId(0) {
-            self.data_sent += data.len() as u64;
-        }
-        self.pending.stream.push_back(frame::Stream {
-            offset,
-            fin: false,
-            data,
-            id: stream,
-        });
+        self.data_sent += data.len() as u64;
+        self.spaces[SpaceId::Data as usize]
+            .pending
+            .stream
+            .push_back(
+                ss.priority,
+                frame::Stream {
+                    offset,
+                    fin: false,
+                    data,
+                    id: stream,
+                },
+            );
     }
 
     /// Abandon transmitting data on a stream
@@ -812,417 +1267,437 @@ impl Connection {
             }
             stream.state = stream::SendState::ResetSent { stop_reason: None };
         }
-        self.pending.rst_stream.push((stream, error_code));
+        self.space_mut(SpaceId::Data)
+            .pending
+            .rst_stream
+            .push((stream, error_code));
         ctx.dirty_conns.insert(self.handle);
     }
 
-    pub fn drive_tls(
-        &mut self,
-        ctx: &mut Context,
-        tls: &mut TlsSession,
-    ) -> Result<(), TransportError> {
-        trace!(ctx.log, "processed stream 0 bytes");
-        /* Process any new session tickets that might have been delivered
-        {
-            let mut buffer = ctx.session_ticket_buffer.lock().unwrap();
-            for session in buffer.drain(..) {
-                if let Ok(session) = session {
-                    trace!(
-                        ctx.log,
-                        "{connection} got session ticket",
-                        connection = self.local_id.clone()
-                    );
-
-                    let params = &self.params;
-                    let session = session
-                        .to_der()
-                        .expect("failed to serialize session ticket");
-
-                    let mut buf = Vec::new();
-                    buf.put_u16_be(session.len() as u16);
-                    buf.extend_from_slice(&session);
-                    params.write(Side::Server, &mut buf);
-
-                    ctx.events
-                        .push_back((conn, Event::NewSessionTicket { ticket: buf.into() }));
-                } else {
-                    debug!(
-                        ctx.log,
-                        "{connection} got malformed session ticket",
-                        connection = self.local_id.clone()
-                    );
-                    ctx.events.push_back((
-                        conn,
-                        Event::ConnectionLost {
-                            reason: TransportError::PROTOCOL_VIOLATION.into(),
-                        },
-                    ));
-                    return Err(TransportError::PROTOCOL_VIOLATION.into());
-                }
-            }
-        }
-        */
-
-        match tls.process_new_packets() {
-            Ok(()) => Ok(()),
-            Err(e @ TLSError::AlertReceived(_)) => {
-                debug!(ctx.log, "TLS error {}", e);
-                ctx.events.push_back((
-                    self.handle,
-                    Event::ConnectionLost {
-                        reason: TransportError::TLS_FATAL_ALERT_RECEIVED.into(),
-                    },
-                ));
-                Err(TransportError::TLS_FATAL_ALERT_RECEIVED)
-            }
-            Err(e) => {
-                debug!(ctx.log, "TLS error {}", e);
-                ctx.events.push_back((
-                    self.handle,
-                    Event::ConnectionLost {
-                        reason: TransportError::PROTOCOL_VIOLATION.into(),
-                    },
-                ));
-                Err(TransportError::PROTOCOL_VIOLATION)
-            }
-        }
-    }
-
     pub fn handle_initial(
         &mut self,
         ctx: &mut Context,
         now: u64,
         packet_number: u64,
+        ecn: Option<EcnCodepoint>,
         payload: Bytes,
     ) -> Result<(), TransportError> {
-        let frame = if let Ok(Some(frame)) = parse_initial(&ctx.log, payload) {
-            frame
-        } else {
-            return Ok(());
-        }; // TODO: Send close?
-
         trace!(ctx.log, "got initial");
         let server_params = TransportParameters {
             stateless_reset_token: Some(reset_token_for(
                 &ctx.listen_keys.as_ref().unwrap().reset,
                 &self.local_id,
             )),
-            ..TransportParameters::new(&ctx.config)
+            ..TransportParameters::new(&self.config)
         };
-        let mut tls = TlsSession::new_server(&ctx.config.tls_server_config, &server_params);
-        self.read_tls(&mut tls, &frame);
-        if tls.process_new_packets().is_err() {
-            return Err(TransportError::TLS_HANDSHAKE_FAILED);
-        }
-        let params = TransportParameters::read(
-            Side::Server,
-            &mut io::Cursor::new(tls.get_quic_transport_parameters().unwrap()),
-        )?;
-        self.handshake_complete(ctx, tls, params, now, packet_number);
-        Ok(())
-    }
-
-    fn read_tls(&mut self, tls: &mut TlsSession, frame: &frame::Stream) {
-        let rs = self
-            .get_recv_stream(StreamId(0))
-            .unwrap()
-            .unwrap()
-            .recv_mut()
-            .unwrap();
-        let mut buf = [0; 8192];
-        rs.assembler.insert(frame.offset, &frame.data);
-        let num = rs.assembler.read(&mut buf);
-        tls.read_tls(&mut io::Cursor::new(&buf[..num])).unwrap();
-    }
-
+        let mut tls = TlsSession::new_server(
+            &ctx.config.tls_server_config,
+            self.version,
+            &ctx.config.supported_versions,
+            &server_params,
+        );
+        if !ctx.config.accept_zero_rtt {
+            tls.reject_early_data();
+        }
+        self.on_packet_authenticated(ctx, now, SpaceId::Initial, packet_number, ecn);
+        self.process_early_payload(ctx, now, SpaceId::Initial, payload, &mut tls)?;
+        let params = match tls.get_quic_transport_parameters() {
+            Some(x) => TransportParameters::read(
+                Side::Server,
+                self.version,
+                &ctx.config.supported_versions,
+                &mut io::Cursor::new(x),
+            )?,
+            None => {
+                debug!(ctx.log, "initial packet lacks a complete ClientHello");
+                return Err(TransportError::TLS_HANDSHAKE_FAILED);
+            }
+        };
+        self.zero_rtt_crypto = Crypto::new_0rtt(&tls);
+        if self.zero_rtt_crypto.is_some() {
+            trace!(ctx.log, "accepting 0-RTT data");
+        }
+        self.write_tls(&mut tls);
+        self.state = Some(State::Handshake(state::Handshake {
+            tls,
+            clienthello_packet: None,
+            remote_id_set: true,
+        }));
+        self.set_params(params);
+        ctx.dirty_conns.insert(self.handle);
+        ctx.incoming_handshakes += 1;
+        Ok(())
+    }
+
+    /// Pass newly contiguous handshake data received in `space` to TLS
+    fn read_crypto(
+        &mut self,
+        ctx: &mut Context,
+        space: SpaceId,
+        crypto: &frame::Crypto,
+        tls: &mut TlsSession,
+    ) -> Result<(), TransportError> {
+        let space = &mut self.spaces[space as usize];
+        let end = crypto.offset + crypto.data.len() as u64;
+        if end > space.crypto_stream.offset() + MAX_CRYPTO_BUFFER as u64 {
+            debug!(ctx.log, "received too much handshake data out of order"; "end" => end);
+            return Err(TransportError::PROTOCOL_VIOLATION);
+        }
+        space.crypto_stream.insert(crypto.offset, &crypto.data);
+        let mut buf = [0; 8192];
+        loop {
+            let n = space.crypto_stream.read(&mut buf);
+            if n == 0 {
+                return Ok(());
+            }
+            trace!(ctx.log, "read handshake data"; "len" => n);
+            match tls.read_hs(&buf[..n]) {
+                Ok(()) => {}
+                Err(e @ TLSError::AlertReceived(_)) => {
+                    debug!(ctx.log, "TLS error {}", e);
+                    return Err(TransportError::TLS_FATAL_ALERT_RECEIVED);
+                }
+                Err(e) => {
+                    debug!(ctx.log, "TLS error {}", e);
+                    return Err(TransportError::TLS_HANDSHAKE_FAILED);
+                }
+            }
+        }
+    }
+
+    /// Queue any handshake messages TLS has ready to send, and install the keys for each new level it reaches
+    fn write_tls(&mut self, tls: &mut TlsSession) {
+        loop {
+            let space = self.highest_space;
+            let mut outgoing = Vec::new();
+            // rustls pauses at each change of keys, so everything written by one call belongs in the same space
+            let secrets = tls.write_hs(&mut outgoing);
+            if !outgoing.is_empty() {
+                self.transmit_handshake(space, &outgoing);
+            }
+            let secrets = match secrets {
+                Some(x) => x,
+                None => return,
+            };
+            match space {
+                SpaceId::Initial => {
+                    self.space_mut(SpaceId::Handshake).crypto =
+                        Some(Crypto::new_handshake(tls, self.side, &secrets));
+                    self.highest_space = SpaceId::Handshake;
+                }
+                SpaceId::Handshake => {
+                    self.space_mut(SpaceId::Data).crypto =
+                        Some(Crypto::new_1rtt(tls, self.side, &secrets));
+                    self.highest_space = SpaceId::Data;
+                }
+                SpaceId::Data => unreachable!("no keys follow 1-RTT"),
+            }
+        }
+    }
+
+    /// Process the frames of a packet protected with Initial or Handshake keys
+    ///
+    /// Returns whether the peer closed the connection.
+    fn process_early_payload(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        space: SpaceId,
+        payload: Bytes,
+        tls: &mut TlsSession,
+    ) -> Result<bool, TransportError> {
+        for frame in frame::Iter::new(payload) {
+            match frame {
+                Frame::Ack(_) => {}
+                _ => {
+                    self.space_mut(space).permit_ack_only = true;
+                }
+            }
+            match frame {
+                Frame::Padding | Frame::Ping => {}
+                Frame::Crypto(frame) => {
+                    self.read_crypto(ctx, space, &frame, tls)?;
+                }
+                Frame::Ack(ack) => {
+                    self.on_ack_received(ctx, now, space, ack);
+                }
+                Frame::ConnectionClose(reason) => {
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::ConnectionLost {
+                            reason: ConnectionError::ConnectionClosed { reason },
+                        },
+                    ));
+                    return Ok(true);
+                }
+                Frame::ApplicationClose(reason) => {
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::ConnectionLost {
+                            reason: ConnectionError::ApplicationClosed { reason },
+                        },
+                    ));
+                    return Ok(true);
+                }
+                _ => {
+                    debug!(ctx.log, "unexpected frame in handshake packet"; "space" => ?space, "type" => %frame.ty());
+                    return Err(TransportError::PROTOCOL_VIOLATION);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Authenticate a packet protected with the Initial or Handshake keys of `space`, returning its number
+    fn decrypt_handshake(&self, space: SpaceId, packet: &mut Packet) -> Option<u64> {
+        let crypto = self.space(space).crypto.as_ref()?;
+        let number = crypto.unprotect_long(packet)?;
+        crypto
+            .decrypt(number, &packet.header_data, &mut packet.payload)
+            .ok()?;
+        Some(number)
+    }
+
     pub fn handle_connected_inner(
         &mut self,
         ctx: &mut Context,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
         mut packet: Packet,
         state: State,
     ) -> Result<State, ConnectionError> {
         match state {
             State::Handshake(mut state) => {
+                if let Header::Long { version, .. } = packet.header {
+                    if version != self.version {
+                        trace!(ctx.log, "dropping packet for another version"; "version" => version);
+                        return Ok(State::Handshake(state));
+                    }
+                }
                 match packet.header {
                     Header::Long {
                         ty: types::RETRY,
-                        number,
                         destination_id: conn_id,
                         source_id: remote_id,
                         ..
                     } => {
                         // FIXME: the below guards fail to handle repeated retries resulting from retransmitted initials
-                        if state.clienthello_packet.is_none() {
-                            // Received Retry as a server
+                        if self.side == Side::Server {
                             debug!(ctx.log, "received retry from client"; "connection" => %conn_id);
                             Err(TransportError::PROTOCOL_VIOLATION.into())
-                        } else if state.clienthello_packet.unwrap() > number {
-                            // Retry corresponds to an outdated Initial; must be a duplicate, so ignore it
-                            Ok(State::Handshake(state))
-                        } else if self
-                            .decrypt(
-                                true,
-                                number as u64,
-                                &packet.header_data,
-                                &mut packet.payload,
-                            ).is_ok()
+                        } else if let Some(number) =
+                            self.decrypt_handshake(SpaceId::Initial, &mut packet)
                         {
-                            if let Ok(Some(frame)) = parse_initial(&ctx.log, packet.payload.into())
+                            if state
+                                .clienthello_packet
+                                .is_none_or(|x| u64::from(x) > number)
                             {
-                                self.read_tls(&mut state.tls, &frame);
-                            } else {
+                                // Retry corresponds to an outdated Initial; must be a duplicate, so ignore it
+                                return Ok(State::Handshake(state));
+                            }
+                            if !packet.payload.is_empty() {
                                 debug!(ctx.log, "invalid retry payload");
                                 return Err(TransportError::PROTOCOL_VIOLATION.into());
                             }
-                            match state.tls.process_new_packets() {
-                                Ok(()) => {
-                                    self.on_packet_authenticated(ctx, now, number as u64);
-                                    trace!(ctx.log, "resending ClientHello"; "remote_id" => %remote_id);
-                                    // Discard transport state
-                                    let mut new = Connection::new(
-                                        remote_id,
-                                        self.local_id,
-                                        remote_id,
-                                        remote,
-                                        ctx.initial_packet_number.sample(&mut ctx.rng),
-                                        Side::Client,
-                                        &ctx.config,
-                                        self.handle,
-                                    );
-                                    mem::replace(self, new);
-                                    // Send updated ClientHello
-                                    let mut outgoing = Vec::new();
-                                    state.tls.write_tls(&mut outgoing).unwrap();
-                                    self.transmit_handshake(&outgoing);
-                                    // Prepare to receive Handshake packets that start stream 0 from offset 0
-                                    Ok(State::Handshake(state::Handshake {
-                                        tls: state.tls,
-                                        clienthello_packet: state.clienthello_packet,
-                                        remote_id_set: state.remote_id_set,
-                                    }))
-                                }
-                                Err(e) => {
-                                    debug!(ctx.log, "handshake failed"; "reason" => %e);
-                                    Err(TransportError::TLS_HANDSHAKE_FAILED.into())
-                                }
-                            }
+                            // Stateless address validation; the new connection ID is our token
+                            trace!(ctx.log, "retrying with address validation token"; "remote_id" => %remote_id);
+                            self.retry(remote_id);
+                            Ok(State::Handshake(state::Handshake {
+                                clienthello_packet: None,
+                                ..state
+                            }))
                         } else {
                             debug!(ctx.log, "failed to authenticate retry packet");
                             Ok(State::Handshake(state))
                         }
                     }
                     Header::Long {
-                        ty: types::HANDSHAKE,
+                        ty,
                         destination_id: id,
                         source_id: remote_id,
-                        number,
                         ..
-                    } => {
+                    }
+                        if ty == types::INITIAL || ty == types::HANDSHAKE =>
+                    {
+                        let space = if ty == types::INITIAL {
+                            SpaceId::Initial
+                        } else {
+                            SpaceId::Handshake
+                        };
+                        let number = if let Some(x) = self.decrypt_handshake(space, &mut packet) {
+                            x
+                        } else {
+                            debug!(ctx.log, "failed to authenticate handshake packet"; "space" => ?space);
+                            return Ok(State::Handshake(state));
+                        };
                         if !state.remote_id_set {
                             trace!(ctx.log, "got remote connection id"; "connection" => %id, "remote_id" => %remote_id);
                             self.remote_id = remote_id;
                             state.remote_id_set = true;
                         }
-                        if self
-                            .decrypt(
-                                true,
-                                number as u64,
-                                &packet.header_data,
-                                &mut packet.payload,
-                            ).is_err()
-                        {
-                            debug!(ctx.log, "failed to authenticate handshake packet");
+                        self.on_packet_authenticated(ctx, now, space, number, ecn);
+                        if self.process_early_payload(
+                            ctx,
+                            now,
+                            space,
+                            packet.payload.into(),
+                            &mut state.tls,
+                        )? {
+                            return Ok(State::Draining);
+                        }
+                        if self.side == Side::Server && space == SpaceId::Handshake {
+                            // The client only sends Handshake packets once it's done with Initial ones
+                            self.discard_space(SpaceId::Initial);
+                        }
+                        self.write_tls(&mut state.tls);
+                        if state.tls.is_handshaking() {
+                            trace!(ctx.log, "handshake ongoing"; "connection" => %id);
                             return Ok(State::Handshake(state));
-                        };
-                        self.on_packet_authenticated(ctx, now, number as u64);
-                        // Complete handshake (and ultimately send Finished)
-                        for frame in frame::Iter::new(packet.payload.into()) {
-                            match frame {
-                                Frame::Ack(_) => {}
-                                _ => {
-                                    self.permit_ack_only = true;
-                                }
-                            }
-                            match frame {
-                                Frame::Padding => {}
-                                Frame::Stream(
-                                    frame @ frame::Stream {
-                                        id: StreamId(0), ..
-                                    },
-                                ) => self.read_tls(&mut state.tls, &frame),
-                                Frame::Stream(frame::Stream { .. }) => {
-                                    debug!(ctx.log, "non-stream-0 stream frame in handshake");
-                                    return Err(TransportError::PROTOCOL_VIOLATION.into());
-                                }
-                                Frame::Ack(ack) => {
-                                    self.on_ack_received(ctx, now, ack);
-                                }
-                                Frame::ConnectionClose(reason) => {
-                                    ctx.events.push_back((
-                                        self.handle,
-                                        Event::ConnectionLost {
-                                            reason: ConnectionError::ConnectionClosed { reason },
-                                        },
-                                    ));
-                                    return Ok(State::Draining);
-                                }
-                                Frame::ApplicationClose(reason) => {
-                                    ctx.events.push_back((
-                                        self.handle,
-                                        Event::ConnectionLost {
-                                            reason: ConnectionError::ApplicationClosed { reason },
-                                        },
-                                    ));
-                                    return Ok(State::Draining);
-                                }
-                                Frame::PathChallenge(value) => {
-                                    self.handshake_pending.path_challenge(number as u64, value);
-                                }
-                                _ => {
-                                    debug!(ctx.log, "unexpected frame type in handshake"; "connection" => %id, "type" => %frame.ty());
-                                    return Err(TransportError::PROTOCOL_VIOLATION.into());
-                                }
-                            }
                         }
 
-                        match state.tls.process_new_packets() {
-                            Ok(()) if !state.tls.is_handshaking() => {
-                                trace!(ctx.log, "no longer handshaking");
-                                if let Some(params) = state.tls.get_quic_transport_parameters() {
-                                    let params = TransportParameters::read(
-                                        self.side,
-                                        &mut io::Cursor::new(params),
-                                    )?;
-                                    self.set_params(params);
-                                } else {
-                                    debug!(ctx.log, "remote didn't send transport params");
-                                    return Err(TransportError::TLS_HANDSHAKE_FAILED.into());
-                                }
-                                trace!(ctx.log, "{connection} established", connection = id);
-                                self.handshake_cleanup(&ctx.config);
-                                let mut msgs = Vec::new();
-                                state.tls.write_tls(&mut msgs).unwrap();
-                                if self.side == Side::Client {
-                                    self.transmit_handshake(&msgs);
-                                } else {
-                                    self.transmit(StreamId(0), msgs.into());
-                                }
-                                match self.side {
-                                    Side::Client => {
-                                        ctx.events.push_back((
-                                            self.handle,
-                                            Event::Connected {
-                                                protocol: state
-                                                    .tls
-                                                    .get_alpn_protocol()
-                                                    .map(|x| x.into()),
-                                            },
-                                        ));
-                                    }
-                                    Side::Server => {
-                                        ctx.incoming_handshakes -= 1;
-                                        ctx.incoming.push_back(self.handle);
-                                    }
-                                }
-                                self.crypto = Some(Crypto::new_1rtt(&state.tls, self.side));
-                                Ok(State::Established(state::Established { tls: state.tls }))
-                            }
-                            Ok(()) => {
-                                trace!(ctx.log, "handshake ongoing"; "connection" => %id);
-                                self.handshake_cleanup(&ctx.config);
-                                let mut response = Vec::new();
-                                state.tls.write_tls(&mut response).unwrap();
-                                if !response.is_empty() {
-                                    self.transmit_handshake(&response);
+                        trace!(ctx.log, "no longer handshaking");
+                        if let Some(params) = state.tls.get_quic_transport_parameters() {
+                            let params = TransportParameters::read(
+                                self.side,
+                                self.version,
+                                &ctx.config.supported_versions,
+                                &mut io::Cursor::new(params),
+                            )?;
+                            self.set_params(params);
+                        } else {
+                            debug!(ctx.log, "remote didn't send transport params");
+                            return Err(TransportError::TLS_HANDSHAKE_FAILED.into());
+                        }
+                        trace!(ctx.log, "{connection} established", connection = id);
+                        match self.side {
+                            Side::Client => {
+                                if self.zero_rtt_crypto.is_some()
+                                    && !state.tls.is_early_data_accepted()
+                                {
+                                    debug!(ctx.log, "0-RTT rejected");
+                                    self.reject_0rtt();
                                 }
-                                Ok(State::Handshake(state::Handshake {
-                                    tls: state.tls,
-                                    clienthello_packet: state.clienthello_packet,
-                                    remote_id_set: state.remote_id_set,
-                                }))
+                                let mut buf = Vec::new();
+                                self.params.write(
+                                    Side::Server,
+                                    self.version,
+                                    &[self.version],
+                                    &mut buf,
+                                );
+                                self.session_store.as_ref().unwrap().put(
+                                    self.server_name.as_ref().unwrap(),
+                                    TRANSPORT_PARAMETERS_KEY.into(),
+                                    buf,
+                                );
+                                ctx.events.push_back((
+                                    self.handle,
+                                    Event::Connected {
+                                        protocol: state
+                                            .tls
+                                            .get_alpn_protocol()
+                                            .map(|x| String::from_utf8_lossy(x).into()),
+                                    },
+                                ));
                             }
-                            Err(e) => {
-                                debug!(ctx.log, "handshake failed"; "reason" => %e);
-                                Err(TransportError::TLS_HANDSHAKE_FAILED.into())
+                            Side::Server => {
+                                ctx.incoming_handshakes -= 1;
+                                ctx.incoming.push_back(self.handle);
+                                // The client's Finished confirms it has everything we sent
+                                self.discard_space(SpaceId::Handshake);
                             }
                         }
+                        self.zero_rtt_crypto = None;
+                        Ok(State::Established(state::Established { tls: state.tls }))
                     }
                     Header::Long {
-                        ty: types::INITIAL, ..
-                    }
-                        if self.side == Side::Server =>
-                    {
-                        trace!(ctx.log, "dropping duplicate Initial");
-                        Ok(State::Handshake(state))
-                    }
-                    /*Header::Long {
                         ty: types::ZERO_RTT,
-                        number,
-                        destination_id: ref id,
+                        destination_id: id,
                         ..
-                    } if self.side == Side::Server =>
+                    }
+                        if self.side == Side::Server =>
                     {
-                        if let Some(ref crypto) = self.zero_rtt_crypto {
-                            if crypto
-                                .decrypt(number as u64, &packet.header_data, &mut packet.payload)
-                                .is_err()
-                            {
-                                debug!(
-                                    ctx.log,
-                                    "{connection} failed to authenticate 0-RTT packet",
-                                    connection = id.clone()
-                                );
-                                return State::Handshake(state);
-                            }
+                        let number = if let Some(ref crypto) = self.zero_rtt_crypto {
+                            crypto.unprotect_long(&mut packet).and_then(|number| {
+                                crypto
+                                    .decrypt(number, &packet.header_data, &mut packet.payload)
+                                    .ok()
+                                    .map(|()| number)
+                            })
                         } else {
-                            debug!(
-                                ctx.log,
-                                "{connection} ignoring unsupported 0-RTT packet",
-                                connection = id.clone()
-                            );
-                            return State::Handshake(state);
+                            debug!(ctx.log, "ignoring unsupported 0-RTT packet"; "connection" => %id);
+                            return Ok(State::Handshake(state));
+                        };
+                        let number = if let Some(x) = number {
+                            x
+                        } else {
+                            debug!(ctx.log, "failed to authenticate 0-RTT packet"; "connection" => %id);
+                            return Ok(State::Handshake(state));
                         };
-                        self.on_packet_authenticated(ctx, now, number as u64);
-                        match self.process_payload(
+                        self.on_packet_authenticated(ctx, now, SpaceId::Data, number, ecn);
+                        let closed = self.process_payload(
                             ctx,
                             now,
-                            conn,
-                            number as u64,
+                            number,
+                            true,
                             packet.payload.into(),
-                            state.tls.get_mut(),
-                        ) {
-                            Err(e) => State::HandshakeFailed(state::HandshakeFailed {
-                                reason: e,
-                                app_closed: false,
-                                alert: None,
-                            }),
-                            Ok(true) => State::Draining(state.into()),
-                            Ok(false) => State::Handshake(state),
-                        }
-                    }*/
+                            &mut state.tls,
+                        )?;
+                        Ok(if closed {
+                            State::Draining
+                        } else {
+                            State::Handshake(state)
+                        })
+                    }
                     Header::Long { ty, .. } => {
                         debug!(ctx.log, "unexpected packet type"; "type" => format!("{:02X}", ty));
                         Err(TransportError::PROTOCOL_VIOLATION.into())
                     }
                     Header::VersionNegotiate {
-                        destination_id: id, ..
+                        destination_id: id,
+                        source_id,
+                        ..
                     } => {
+                        if self.side == Side::Server
+                            || state.remote_id_set
+                            || id != self.local_id
+                            || source_id != self.initial_id
+                        {
+                            // Not a response to our Initial, or the server has already accepted our version
+                            trace!(ctx.log, "ignoring spurious version negotiation"; "connection" => %id);
+                            return Ok(State::Handshake(state));
+                        }
                         let mut payload = io::Cursor::new(&packet.payload[..]);
                         if packet.payload.len() % 4 != 0 {
                             debug!(ctx.log, "malformed version negotiation"; "connection" => %id);
                             return Err(TransportError::PROTOCOL_VIOLATION.into());
                         }
+                        let mut offered = Vec::with_capacity(packet.payload.len() / 4);
                         while payload.has_remaining() {
                             let version = payload.get::<u32>().unwrap();
-                            if version == VERSION {
+                            if version == self.version.to_wire() {
                                 // Our version is supported, so this packet is spurious
                                 return Ok(State::Handshake(state));
                             }
+                            offered.push(version);
+                        }
+                        let mutual = ctx
+                            .config
+                            .supported_versions
+                            .iter()
+                            .cloned()
+                            .find(|x| offered.contains(&x.to_wire()));
+                        if let Some(version) = mutual {
+                            debug!(ctx.log, "switching version"; "from" => self.version, "to" => version);
+                            self.version = version;
+                            self.restart_handshake();
+                            return Ok(State::Handshake(state::Handshake {
+                                clienthello_packet: None,
+                                ..state
+                            }));
                         }
-                        debug!(ctx.log, "remote doesn't support our version");
-                        Err(ConnectionError::VersionMismatch)
+                        debug!(ctx.log, "remote doesn't support our version"; "offered" => ?offered);
+                        Err(ConnectionError::VersionMismatch { offered })
                     }
                     // TODO: SHOULD buffer these to improve reordering tolerance.
                     Header::Short { .. } => {
@@ -1233,11 +1708,38 @@ impl Connection {
             }
             State::Established(mut state) => {
                 let id = self.local_id;
-                if let Header::Long { .. } = packet.header {
-                    trace!(ctx.log, "discarding unprotected packet"; "connection" => %id);
-                    return Ok(State::Established(state));
+                if let Header::Long { ty, .. } = packet.header {
+                    let space = match ty {
+                        types::INITIAL => SpaceId::Initial,
+                        types::HANDSHAKE => SpaceId::Handshake,
+                        _ => {
+                            trace!(ctx.log, "discarding unexpected long header packet"; "connection" => %id);
+                            return Ok(State::Established(state));
+                        }
+                    };
+                    // The peer may still be retransmitting handshake data we've already received
+                    let number = if let Some(x) = self.decrypt_handshake(space, &mut packet) {
+                        x
+                    } else {
+                        trace!(ctx.log, "discarding handshake packet"; "connection" => %id, "space" => ?space);
+                        return Ok(State::Established(state));
+                    };
+                    self.on_packet_authenticated(ctx, now, space, number, ecn);
+                    let closed = self.process_early_payload(
+                        ctx,
+                        now,
+                        space,
+                        packet.payload.into(),
+                        &mut state.tls,
+                    )?;
+                    return Ok(if closed {
+                        State::Draining
+                    } else {
+                        State::Established(state)
+                    });
                 }
-                let (payload, number) = match self.decrypt_packet(false, packet) {
+                let len = (packet.header_data.len() + packet.payload.len()) as u64;
+                let (payload, number) = match self.decrypt_packet(packet) {
                     Ok(x) => x,
                     Err(None) => {
                         trace!(ctx.log, "failed to authenticate packet"; "connection" => %id);
@@ -1248,19 +1750,27 @@ impl Connection {
                         return Err(e.into());
                     }
                 };
-                self.on_packet_authenticated(ctx, now, number);
-                if self.awaiting_handshake {
+                self.on_packet_authenticated(ctx, now, SpaceId::Data, number, ecn);
+                if self.space(SpaceId::Handshake).crypto.is_some() {
                     assert_eq!(
                         self.side,
                         Side::Client,
                         "only the client confirms handshake completion based on a protected packet"
                     );
-                    // Forget about unacknowledged handshake packets
-                    self.handshake_cleanup(&ctx.config);
+                    // The server only sends 1-RTT packets once it has our Finished
+                    self.discard_space(SpaceId::Handshake);
+                }
+                if remote == self.remote {
+                    if let Some(ref mut path) = self.unvalidated_path {
+                        path.received += len;
+                    }
+                } else if self.side == Side::Server && number == self.space(SpaceId::Data).rx_packet
+                {
+                    // Reordered packets from an old address don't indicate migration
+                    self.migrate(ctx, now, remote, len);
                 }
                 let closed =
-                    self.process_payload(ctx, now, number, payload.into(), &mut state.tls)?;
-                self.drive_tls(ctx, &mut state.tls)?;
+                    self.process_payload(ctx, now, number, false, payload.into(), &mut state.tls)?;
                 Ok(if closed {
                     State::Draining
                 } else {
@@ -1268,8 +1778,18 @@ impl Connection {
                 })
             }
             State::HandshakeFailed(state) => {
-                if let Ok((payload, _)) = self.decrypt_packet(true, packet) {
-                    for frame in frame::Iter::new(payload.into()) {
+                let space = match packet.header {
+                    Header::Long {
+                        ty: types::INITIAL, ..
+                    } => SpaceId::Initial,
+                    Header::Long {
+                        ty: types::HANDSHAKE,
+                        ..
+                    } => SpaceId::Handshake,
+                    _ => return Ok(State::HandshakeFailed(state)),
+                };
+                if self.decrypt_handshake(space, &mut packet).is_some() {
+                    for frame in frame::Iter::new(packet.payload.into()) {
                         match frame {
                             Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
                                 trace!(ctx.log, "draining");
@@ -1282,7 +1802,7 @@ impl Connection {
                 Ok(State::HandshakeFailed(state))
             }
             State::Closed(state) => {
-                if let Ok((payload, _)) = self.decrypt_packet(false, packet) {
+                if let Ok((payload, _)) = self.decrypt_packet(packet) {
                     for frame in frame::Iter::new(payload.into()) {
                         match frame {
                             Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
@@ -1300,11 +1820,13 @@ impl Connection {
         }
     }
 
+    /// Handle the frames of a 0-RTT or 1-RTT packet, returning whether the connection is now draining
     pub fn process_payload(
         &mut self,
         ctx: &mut Context,
         now: u64,
         number: u64,
+        zero_rtt: bool,
         payload: Bytes,
         tls: &mut TlsSession,
     ) -> Result<bool, state::CloseReason> {
@@ -1319,14 +1841,22 @@ impl Connection {
             match frame {
                 Frame::Ack(_) => {}
                 _ => {
-                    self.permit_ack_only = true;
+                    self.space_mut(SpaceId::Data).permit_ack_only = true;
                 }
             }
             match frame {
+                Frame::Crypto(frame) => {
+                    // Post-handshake messages, e.g. session tickets
+                    if let Err(e) = self.read_crypto(ctx, SpaceId::Data, &frame, tls) {
+                        ctx.events
+                            .push_back((self.handle, Event::ConnectionLost { reason: e.into() }));
+                        return Err(e.into());
+                    }
+                }
                 Frame::Stream(frame) => {
                     trace!(ctx.log, "got stream"; "id" => frame.id.0, "offset" => frame.offset, "len" => frame.data.len(), "fin" => frame.fin);
                     let data_recvd = self.data_recvd;
-                    let max_data = self.local_max_data;
+                    let max_data = self.receive_window.max_data();
                     let stream = {
                         match self.get_recv_stream(frame.id) {
                             Err(e) => {
@@ -1363,10 +1893,10 @@ impl Connection {
                         }
                         let prev_end = rs.limit();
                         let new_bytes = end.saturating_sub(prev_end);
-                        if end > rs.max_data || data_recvd + new_bytes > max_data {
+                        if end > rs.window.max_data() || data_recvd + new_bytes > max_data {
                             debug!(ctx.log, "flow control error";
                                    "stream" => frame.id.0, "recvd" => data_recvd, "new bytes" => new_bytes,
-                                   "max data" => max_data, "end" => end, "stream max data" => rs.max_data);
+                                   "max data" => max_data, "end" => end, "stream max data" => rs.window.max_data());
                             ctx.events.push_back((
                                 self.handle,
                                 Event::ConnectionLost {
@@ -1381,46 +1911,20 @@ impl Connection {
                             }
                         }
                         rs.recvd.insert(frame.offset..end);
-                        if frame.id == StreamId(0) && frame.fin {
-                            debug!(ctx.log, "got fin on stream 0"; "connection" => cid);
-                            ctx.events.push_back((
-                                self.handle,
-                                Event::ConnectionLost {
-                                    reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                },
-                            ));
-                            return Err(TransportError::PROTOCOL_VIOLATION.into());
-                        }
                         rs.buffer(frame.data, frame.offset);
                         if let stream::RecvState::Recv { size: Some(size) } = rs.state {
                             if rs.recvd.len() == 1 && rs.recvd.iter().next().unwrap() == (0..size) {
                                 rs.state = stream::RecvState::DataRecvd { size };
                             }
                         }
-
-                        if frame.id == StreamId(0) {
-                            let mut buf = vec![0; 8192];
-                            loop {
-                                let new_bytes = rs.assembler.read(&mut buf);
-                                tls.read_tls(&mut io::Cursor::new(&buf[..new_bytes]))
-                                    .unwrap();
-                                rs.max_data += new_bytes as u64;
-                                self.pending.max_stream_data.insert(StreamId(0));
-                                if new_bytes < 8192 {
-                                    break;
-                                }
-                            }
-                        }
                         new_bytes
                     };
-                    if frame.id != StreamId(0) {
-                        self.readable_streams.insert(frame.id);
-                        ctx.readable_conns.insert(self.handle);
-                    }
+                    self.readable_streams.insert(frame.id);
+                    ctx.readable_conns.insert(self.handle);
                     self.data_recvd += new_bytes;
                 }
                 Frame::Ack(ack) => {
-                    self.on_ack_received(ctx, now, ack);
+                    self.on_ack_received(ctx, now, SpaceId::Data, ack);
                     for stream in self.finished_streams.drain(..) {
                         ctx.events
                             .push_back((self.handle, Event::StreamFinished { stream }));
@@ -1456,7 +1960,46 @@ impl Connection {
                     return Err(TransportError::frame(ty).into());
                 }
                 Frame::PathChallenge(x) => {
-                    self.pending.path_challenge(number, x);
+                    self.space_mut(SpaceId::Data)
+                        .pending
+                        .path_challenge(number, x);
+                }
+                Frame::Datagram(datagram) => {
+                    if zero_rtt {
+                        debug!(ctx.log, "received datagram in 0-RTT packet");
+                        ctx.events.push_back((
+                            self.handle,
+                            Event::ConnectionLost {
+                                reason: TransportError::PROTOCOL_VIOLATION.into(),
+                            },
+                        ));
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    if datagram.size() > self.config.max_datagram_frame_size as usize {
+                        debug!(ctx.log, "received unwanted or oversized datagram"; "len" => datagram.data.len());
+                        ctx.events.push_back((
+                            self.handle,
+                            Event::ConnectionLost {
+                                reason: TransportError::PROTOCOL_VIOLATION.into(),
+                            },
+                        ));
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::DatagramReceived {
+                            data: datagram.data,
+                        },
+                    ));
+                }
+                Frame::PathResponse(token) if Some(token) == self.path_challenge => {
+                    if self.unvalidated_path.take().is_some() {
+                        debug!(ctx.log, "peer address validated"; "remote" => %self.remote);
+                        self.set_path_validation = Some(None);
+                    }
+                }
+                Frame::PathResponse(_) if self.path_challenge.is_some() => {
+                    trace!(ctx.log, "ignoring PATH_RESPONSE to an outdated challenge");
                 }
                 Frame::PathResponse(_) => {
                     debug!(ctx.log, "unsolicited PATH_RESPONSE");
@@ -1489,6 +2032,10 @@ impl Connection {
                         ));
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
+                    if id.initiator() != self.side {
+                        // Implicitly opens the stream, if it's permitted
+                        let _ = self.get_recv_stream(id);
+                    }
                     if let Some(stream) = self.streams.get_mut(&id) {
                         let ss = stream.send_mut().unwrap();
                         if offset > ss.max_data {
@@ -1532,16 +2079,6 @@ impl Connection {
                     error_code,
                     final_offset,
                 }) => {
-                    if id == StreamId(0) {
-                        debug!(ctx.log, "got RST_STREAM on stream 0");
-                        ctx.events.push_back((
-                            self.handle,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
-                        return Err(TransportError::PROTOCOL_VIOLATION.into());
-                    }
                     let conn = self.handle;
                     let offset = match self.get_recv_stream(id) {
                         Err(e) => {
@@ -1582,12 +2119,30 @@ impl Connection {
                 }
                 Frame::Blocked { offset } => {
                     debug!(ctx.log, "peer claims to be blocked at connection level"; "offset" => offset);
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::PeerBlocked {
+                            limit: Limit::Data(offset),
+                        },
+                    ));
                 }
                 Frame::StreamBlocked { id, offset } => {
                     debug!(ctx.log, "peer claims to be blocked at stream level"; "stream" => id, "offset" => offset);
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::PeerBlocked {
+                            limit: Limit::StreamData { stream: id, offset },
+                        },
+                    ));
                 }
                 Frame::StreamIdBlocked { id } => {
                     debug!(ctx.log, "peer claims to be blocked at stream ID level"; "stream" => id);
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::PeerBlocked {
+                            limit: Limit::StreamId(id),
+                        },
+                    ));
                 }
                 Frame::StopSending { id, error_code } => {
                     if self
@@ -1610,7 +2165,11 @@ impl Connection {
                             stop_reason: Some(error_code),
                         };
                 }
-                Frame::NewConnectionId { .. } => {
+                Frame::NewConnectionId {
+                    sequence,
+                    id,
+                    reset_token,
+                } => {
                     if self.remote_id.is_empty() {
                         debug!(ctx.log, "got NEW_CONNECTION_ID for connection {connection} with empty remote ID",
                                connection=self.local_id);
@@ -1622,14 +2181,68 @@ impl Connection {
                         ));
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
-                    trace!(ctx.log, "ignoring NEW_CONNECTION_ID (unimplemented)");
+                    let cid = IssuedCid {
+                        sequence,
+                        id,
+                        reset_token,
+                    };
+                    match self.rem_cids.entry(sequence) {
+                        btree_map::Entry::Vacant(e) => {
+                            trace!(ctx.log, "got new connection ID"; "sequence" => sequence, "id" => %id);
+                            e.insert(cid);
+                        }
+                        // Retransmissions are harmless, but a sequence number must never be reused
+                        btree_map::Entry::Occupied(ref e) if e.get().id == id => {}
+                        btree_map::Entry::Occupied(_) => {
+                            debug!(ctx.log, "peer reused a connection ID sequence number"; "sequence" => sequence);
+                            ctx.events.push_back((
+                                self.handle,
+                                Event::ConnectionLost {
+                                    reason: TransportError::PROTOCOL_VIOLATION.into(),
+                                },
+                            ));
+                            return Err(TransportError::PROTOCOL_VIOLATION.into());
+                        }
+                    }
                 }
             }
         }
         Ok(false)
     }
 
-    pub fn next_packet(&mut self, log: &Logger, config: &Config, now: u64) -> Option<Vec<u8>> {
+    /// Assemble the next UDP datagram to send, coalescing as many packets into it as will fit
+    ///
+    /// Long-header packets carry an explicit length, so another packet may follow them in the same datagram. Short-header
+    /// packets extend to the end of the datagram, so they can only come last.
+    pub fn next_datagram(&mut self, log: &Logger, now: u64) -> Option<Vec<u8>> {
+        let mut datagram = self.next_packet(log, now, self.mtu as usize)?;
+        // A short-header packet has no length field, so nothing can follow it
+        let mut last_long = datagram[0] & 0x80 != 0;
+        while last_long {
+            let space = self.mtu as usize - datagram.len();
+            // Each ACK range costs at most two 8-byte varints
+            let ack_ranges = self
+                .spaces
+                .iter()
+                .map(|x| x.pending_acks.len())
+                .sum::<usize>();
+            if space < MIN_COALESCED_PACKET_SIZE + 16 * ack_ranges {
+                break;
+            }
+            match self.next_packet(log, now, space) {
+                Some(packet) => {
+                    trace!(log, "coalescing packet"; "len" => packet.len());
+                    last_long = packet[0] & 0x80 != 0;
+                    datagram.extend_from_slice(&packet);
+                }
+                None => break,
+            }
+        }
+        Some(datagram)
+    }
+
+    /// Assemble a single packet no larger than `space` bytes
+    fn next_packet(&mut self, log: &Logger, now: u64, space: usize) -> Option<Vec<u8>> {
         let established = match *self.state.as_ref().unwrap() {
             State::Handshake(_) => false,
             State::Established(_) => true,
@@ -1637,91 +2250,144 @@ impl Connection {
                 assert!(e.is_closed());
                 return None;
             }
-        };
-
-        let mut buf = Vec::new();
-        let mut sent = Retransmits::default();
-        let acks;
-        let number;
-        let ack_only;
-        let is_initial;
-        let header_len;
-        let handshake;
-
-        {
-            let crypto;
-            let pending;
-            if (!established || self.awaiting_handshake)
-                && (!self.handshake_pending.is_empty()
-                    || (!self.pending_acks.is_empty() && self.permit_ack_only))
-            {
-                // (re)transmit handshake data in long-header packets
-                buf.reserve_exact(self.mtu as usize);
-                number = self.get_tx_number();
-                trace!(log, "sending handshake packet"; "pn" => number);
-                let ty = if self.side == Side::Client && self
-                    .handshake_pending
-                    .stream
-                    .front()
-                    .map_or(false, |x| x.offset == 0)
+        };
+        // Don't let a spoofed source address turn us into an amplifier, but keep acknowledging what it sends
+        let amplification_limited = self
+            .unvalidated_path
+            .as_ref()
+            .is_some_and(|path| path.sent >= MAX_UNVALIDATED_AMPLIFICATION * path.received);
+        if established && self.key_update_due() {
+            trace!(log, "initiating key update");
+            let new = self
+                .space(SpaceId::Data)
+                .crypto
+                .as_ref()
+                .unwrap()
+                .update(self.side);
+            self.commit_key_update(new, None);
+            // Make sure the peer promptly sees the new key phase
+            self.space_mut(SpaceId::Data).pending.ping = true;
+        }
+
+        let space_id = if let Some(id) = [SpaceId::Initial, SpaceId::Handshake]
+            .iter()
+            .cloned()
+            .find(|&x| self.space(x).crypto.is_some() && self.space(x).can_send())
+        {
+            // (re)transmit handshake data in long-header packets
+            id
+        } else if amplification_limited {
+            let space = self.space(SpaceId::Data);
+            if !space.permit_ack_only || space.pending_acks.is_empty() {
+                trace!(log, "blocked by unvalidated peer address");
+                return None;
+            }
+            SpaceId::Data
+        } else if established || (self.zero_rtt_crypto.is_some() && self.side == Side::Client) {
+            // Send 0RTT or 1RTT data
+            if self.congestion_blocked() {
+                self.app_limited = false;
+                return None;
+            }
+            let datagrams = established && !self.datagrams.is_empty();
+            let pending_empty = self.space(SpaceId::Data).pending.is_empty();
+            if pending_empty
+                && !datagrams
+                && (!established || !self.space(SpaceId::Data).can_send())
+            {
+                self.app_limited = true;
+                return None;
+            }
+            if !pending_empty || datagrams {
+                if let Some(rate) = self.pacing_rate() {
+                    if let Some(time) = self.pacer.delay(now, rate, self.mtu as u64) {
+                        trace!(log, "pacing"; "until" => time);
+                        self.set_pacing = Some(Some(time));
+                        return None;
+                    }
+                }
+            }
+            SpaceId::Data
+        } else {
+            return None;
+        };
+
+        let mut buf = Vec::new();
+        let mut sent = Retransmits::default();
+        let acks;
+        let ack_only;
+        let header_len;
+
+        let number = self.get_tx_number(space_id);
+        buf.reserve_exact(space);
+        match space_id {
+            SpaceId::Data if established => {
+                trace!(log, "sending protected packet"; "pn" => number);
+                Header::Short {
+                    id: self.remote_id,
+                    number: PacketNumber::new(
+                        number,
+                        self.space(SpaceId::Data).largest_acked_packet,
+                    ),
+                    key_phase: self.key_phase,
+                }.encode(&mut buf);
+            }
+            SpaceId::Data => {
+                trace!(log, "sending 0-RTT packet"; "pn" => number);
+                Header::Long {
+                    ty: types::ZERO_RTT,
+                    version: self.version,
+                    number: number as u32,
+                    source_id: self.local_id,
+                    destination_id: self.initial_id,
+                }.encode(&mut buf);
+            }
+            _ => {
+                trace!(log, "sending handshake packet"; "space" => ?space_id, "pn" => number);
+                if space_id == SpaceId::Initial
+                    && self.side == Side::Client
+                    && self
+                        .space(space_id)
+                        .pending
+                        .crypto
+                        .front()
+                        .is_some_and(|x| x.offset == 0)
                 {
                     if let State::Handshake(ref mut state) = self.state.as_mut().unwrap() {
                         if state.clienthello_packet.is_none() {
                             state.clienthello_packet = Some(number as u32);
                         }
                     }
-                    is_initial = true;
-                    types::INITIAL
-                } else {
-                    is_initial = false;
-                    types::HANDSHAKE
-                };
+                }
                 Header::Long {
-                    ty,
+                    ty: space_id.packet_type().unwrap(),
+                    version: self.version,
                     number: number as u32,
                     source_id: self.local_id,
                     destination_id: self.remote_id,
                 }.encode(&mut buf);
-                pending = &mut self.handshake_pending;
-                crypto = &self.handshake_crypto;
-            } else if established {
-                //|| (self.zero_rtt_crypto.is_some() && self.side == Side::Client) {
-                // Send 0RTT or 1RTT data
-                is_initial = false;
-                if self.congestion_blocked()
-                    || self.pending.is_empty()
-                        && (!self.permit_ack_only || self.pending_acks.is_empty())
-                {
-                    return None;
-                }
-                number = self.get_tx_number();
-                buf.reserve_exact(self.mtu as usize);
-                trace!(log, "sending protected packet"; "pn" => number);
-
-                /*if !established {
-                    crypto = self.zero_rtt_crypto.as_ref().unwrap();
-                    Header::Long {
-                        ty: types::ZERO_RTT,
-                        number: number as u32,
-                        source_id: self.local_id.clone(),
-                        destination_id: self.initial_id.clone(),
-                    }.encode(&mut buf);
-                } else {*/
-                crypto = self.crypto.as_ref().unwrap();
-                Header::Short {
-                    id: self.remote_id,
-                    number: PacketNumber::new(number, self.largest_acked_packet),
-                    key_phase: self.key_phase,
-                }.encode(&mut buf);
-                //}
+            }
+        }
+        // Only the client needs to pad, to limit the amplification an unvalidated server can be tricked into
+        let is_initial = space_id == SpaceId::Initial && self.side == Side::Client;
 
-                pending = &mut self.pending;
+        {
+            let packet_space = &mut self.spaces[space_id as usize];
+            let crypto = if space_id == SpaceId::Data && !established {
+                self.zero_rtt_crypto.as_ref().unwrap()
             } else {
-                return None;
-            }
-            ack_only = pending.is_empty();
+                packet_space.crypto.as_ref().unwrap()
+            };
+            let mut deferred = Retransmits::default();
+            let pending = if amplification_limited {
+                &mut deferred
+            } else {
+                &mut packet_space.pending
+            };
+            ack_only = pending.is_empty()
+                && (!crypto.is_1rtt() || amplification_limited || self.datagrams.is_empty());
             header_len = buf.len() as u16;
-            let max_size = self.mtu as usize - AEAD_TAG_SIZE;
+            let max_size = space - AEAD_TAG_SIZE;
 
             // PING
             if pending.ping {
@@ -1732,19 +2398,56 @@ impl Connection {
             }
 
             // ACK
-            // We will never ack protected packets in handshake packets because handshake_cleanup ensures we never send
-            // handshake packets after receiving protected packets.
-            // 0-RTT packets must never carry acks (which would have to be of handshake packets)
-            if !self.pending_acks.is_empty() {
-                //&& !crypto.is_0rtt() {
-                let delay = (now - self.rx_packet_time) >> ACK_DELAY_EXPONENT;
-                trace!(log, "ACK"; "ranges" => ?self.pending_acks.iter().collect::<Vec<_>>(), "delay" => delay);
-                frame::Ack::encode(delay, &self.pending_acks, &mut buf);
-                acks = self.pending_acks.clone();
+            // 0-RTT packets must never carry acks (which would have to be of 1-RTT packets)
+            if !packet_space.pending_acks.is_empty() && !crypto.is_0rtt() {
+                let delay = (now - packet_space.rx_packet_time) >> self.config.ack_delay_exponent;
+                trace!(log, "ACK"; "ranges" => ?packet_space.pending_acks.iter().collect::<Vec<_>>(), "delay" => delay);
+                let ecn = if space_id != SpaceId::Data || self.ecn_counts.is_empty() {
+                    None
+                } else {
+                    Some(&self.ecn_counts)
+                };
+                frame::Ack::encode(delay, &packet_space.pending_acks, ecn, &mut buf);
+                acks = packet_space.pending_acks.clone();
             } else {
                 acks = RangeSet::new();
             }
 
+            // CRYPTO, ahead of stream data of any priority
+            while buf.len() + 17 < max_size {
+                let mut frame = if let Some(x) = pending.crypto.pop_front() {
+                    x
+                } else {
+                    break;
+                };
+                let len = cmp::min(frame.data.len(), max_size - buf.len() - 17);
+                let data = frame.data.split_to(len);
+                let truncated = frame::Crypto {
+                    offset: frame.offset,
+                    data,
+                };
+                trace!(log, "CRYPTO"; "off" => truncated.offset, "len" => len);
+                truncated.encode(&mut buf);
+                sent.crypto.push_back(truncated);
+                if !frame.data.is_empty() {
+                    frame.offset += len as u64;
+                    pending.crypto.push_front(frame);
+                }
+            }
+
+            // PATH_CHALLENGE
+            if pending.path_challenge && buf.len() + 9 < max_size {
+                pending.path_challenge = false;
+                // Validation may have finished since this was queued
+                if self.unvalidated_path.is_some() {
+                    let token = self.path_challenge.unwrap();
+                    trace!(log, "PATH_CHALLENGE"; "value" => format!("{:08x}", token));
+                    sent.path_challenge = true;
+                    buf.write(frame::Type::PATH_CHALLENGE);
+                    buf.write(token);
+                }
+            }
+
             // PATH_RESPONSE
             if buf.len() + 9 < max_size {
                 // No need to retransmit these, so we don't save the value after encoding it.
@@ -1798,13 +2501,37 @@ impl Connection {
                 buf.write(error_code);
             }
 
+            // NEW_CONNECTION_ID
+            while buf.len() + 44 < max_size {
+                let cid = if let Some(x) = pending.new_cids.pop() {
+                    x
+                } else {
+                    break;
+                };
+                trace!(log, "NEW_CONNECTION_ID"; "sequence" => cid.sequence, "id" => %cid.id);
+                buf.write(frame::Type::NEW_CONNECTION_ID);
+                buf.write_var(cid.sequence);
+                buf.write(cid.id.len() as u8);
+                buf.put_slice(&cid.id);
+                buf.put_slice(&cid.reset_token);
+                sent.new_cids.push(cid);
+            }
+
+            // Yardstick for how quickly the application is consuming data, when issuing flow control credit
+            let rtt = if self.smoothed_rtt == 0 {
+                self.config.default_initial_rtt
+            } else {
+                self.smoothed_rtt
+            };
+
             // MAX_DATA
             if pending.max_data && buf.len() + 9 < max_size {
-                trace!(log, "MAX_DATA"; "value" => self.local_max_data);
+                let max_data = self.receive_window.issue(now, rtt);
+                trace!(log, "MAX_DATA"; "value" => max_data);
                 pending.max_data = false;
                 sent.max_data = true;
                 buf.write(frame::Type::MAX_DATA);
-                buf.write_var(self.local_max_data);
+                buf.write_var(max_data);
             }
 
             // MAX_STREAM_DATA
@@ -1815,19 +2542,20 @@ impl Connection {
                     break;
                 };
                 pending.max_stream_data.remove(&id);
-                let rs = if let Some(x) = self.streams.get(&id) {
-                    x.recv().unwrap()
+                let rs = if let Some(x) = self.streams.get_mut(&id) {
+                    x.recv_mut().unwrap()
                 } else {
                     continue;
                 };
                 if rs.is_finished() {
                     continue;
                 }
+                let max_data = rs.window.issue(now, rtt);
                 sent.max_stream_data.insert(id);
-                trace!(log, "MAX_STREAM_DATA"; "stream" => id.0, "value" => rs.max_data);
+                trace!(log, "MAX_STREAM_DATA"; "stream" => id.0, "value" => max_data);
                 buf.write(frame::Type::MAX_STREAM_DATA);
                 buf.write(id);
-                buf.write_var(rs.max_data);
+                buf.write_var(max_data);
             }
 
             // MAX_STREAM_ID uni
@@ -1856,17 +2584,105 @@ impl Connection {
                 ));
             }
 
+            // BLOCKED
+            if pending.blocked && buf.len() + 9 < max_size {
+                pending.blocked = false;
+                // Don't report a limit that has since been raised
+                if self.data_sent >= self.max_data {
+                    trace!(log, "BLOCKED"; "offset" => self.max_data);
+                    sent.blocked = true;
+                    buf.write(frame::Type::BLOCKED);
+                    buf.write_var(self.max_data);
+                }
+            }
+
+            // STREAM_BLOCKED
+            while buf.len() + 17 < max_size {
+                let id = if let Some(x) = pending.stream_blocked.iter().next() {
+                    *x
+                } else {
+                    break;
+                };
+                pending.stream_blocked.remove(&id);
+                let ss = if let Some(x) = self.streams.get(&id) {
+                    x.send().unwrap()
+                } else {
+                    continue;
+                };
+                if ss.offset < ss.max_data || ss.state != stream::SendState::Ready {
+                    continue;
+                }
+                sent.stream_blocked.insert(id);
+                trace!(log, "STREAM_BLOCKED"; "stream" => id.0, "offset" => ss.max_data);
+                buf.write(frame::Type::STREAM_BLOCKED);
+                buf.write(id);
+                buf.write_var(ss.max_data);
+            }
+
+            // STREAM_ID_BLOCKED uni
+            if pending.uni_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.uni_stream_id_blocked = false;
+                if self.max_uni_streams != 0 && self.next_uni_stream >= self.max_uni_streams {
+                    sent.uni_stream_id_blocked = true;
+                    trace!(log, "STREAM_ID_BLOCKED (unidirectional)"; "value" => self.max_uni_streams - 1);
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(
+                        self.side,
+                        Directionality::Uni,
+                        self.max_uni_streams - 1,
+                    ));
+                }
+            }
+
+            // STREAM_ID_BLOCKED bi
+            if pending.bi_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.bi_stream_id_blocked = false;
+                if self.max_bi_streams != 0 && self.next_bi_stream >= self.max_bi_streams {
+                    sent.bi_stream_id_blocked = true;
+                    trace!(log, "STREAM_ID_BLOCKED (bidirectional)"; "value" => self.max_bi_streams - 1);
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(
+                        self.side,
+                        Directionality::Bi,
+                        self.max_bi_streams - 1,
+                    ));
+                }
+            }
+
+            // DATAGRAM
+            while crypto.is_1rtt() && !amplification_limited {
+                let len = match self.datagrams.front() {
+                    Some(x) => x.len(),
+                    None => break,
+                };
+                // Allow for the frame type and the longest length field
+                if header_len as usize + 5 + len > self.mtu as usize - AEAD_TAG_SIZE {
+                    // The MTU shrank after the datagram was queued
+                    trace!(log, "dropping oversized datagram"; "len" => len);
+                    self.datagrams.pop_front();
+                    continue;
+                }
+                if buf.len() + 5 + len > max_size {
+                    break;
+                }
+                trace!(log, "DATAGRAM"; "len" => len);
+                frame::Datagram {
+                    length: true,
+                    data: self.datagrams.pop_front().unwrap(),
+                }.encode(&mut buf);
+            }
+
             // STREAM
             while buf.len() + 25 < max_size {
-                let mut stream = if let Some(x) = pending.stream.pop_front() {
+                let (priority, mut stream) = if let Some(x) = pending.stream.pop() {
                     x
                 } else {
                     break;
                 };
-                if stream.id != StreamId(0) && self
+                if self
                     .streams
                     .get(&stream.id)
-                    .map_or(true, |s| s.send().unwrap().state.was_reset())
+                    .is_none_or(|s| s.send().unwrap().state.was_reset())
                 {
                     continue;
                 }
@@ -1881,13 +2697,13 @@ impl Connection {
                     data,
                 };
                 frame.encode(true, &mut buf);
-                sent.stream.push_back(frame);
+                sent.stream.push_back(priority, frame);
                 if !stream.data.is_empty() {
                     let stream = frame::Stream {
                         offset: stream.offset + len as u64,
                         ..stream
                     };
-                    pending.stream.push_front(stream);
+                    pending.stream.push_front(priority, stream);
                 }
             }
 
@@ -1897,57 +2713,161 @@ impl Connection {
                     frame::Type::PADDING.into(),
                 );
             }
+            let pn_offset = if crypto.is_1rtt() {
+                1 + self.remote_id.len()
+            } else {
+                // Long headers end with a 4-byte packet number
+                header_len as usize - 4
+            };
+            pad_for_header_protection(pn_offset, &mut buf);
             if !crypto.is_1rtt() {
                 set_payload_length(&mut buf, header_len as usize);
             }
             crypto.encrypt(number, &mut buf, header_len as usize);
-            handshake = crypto.is_handshake();
+            crypto.protect_header(pn_offset, &mut buf);
+            if crypto.is_1rtt() {
+                self.key_packets_sent += 1;
+                self.key_bytes_sent += buf.len() as u64;
+            }
+        }
+
+        if let Some(ref mut path) = self.unvalidated_path {
+            path.sent += buf.len() as u64;
+        }
+        if !ack_only && space_id == SpaceId::Data {
+            self.pacer.on_sent(buf.len() as u64);
         }
 
         // If we sent any acks, don't immediately resend them.  Setting this even if ack_only is false needlessly
         // prevents us from ACKing the next packet if it's ACK-only, but saves the need for subtler logic to avoid
         // double-transmitting acks all the time.
-        self.permit_ack_only &= acks.is_empty();
+        self.space_mut(space_id).permit_ack_only &= acks.is_empty();
 
         self.on_packet_sent(
-            config,
             now,
+            space_id,
             number,
             SentPacket {
                 acks,
                 time: now,
                 bytes: if ack_only { 0 } else { buf.len() as u16 },
-                handshake,
+                ecn: space_id == SpaceId::Data && self.sending_ecn,
                 retransmits: sent,
             },
         );
 
+        if self.side == Side::Client
+            && space_id == SpaceId::Handshake
+            && self.space(SpaceId::Initial).crypto.is_some()
+        {
+            // Once the server sees this packet it knows we have its Initial, so neither of us needs that space again
+            self.discard_space(SpaceId::Initial);
+        }
+
         Some(buf)
     }
 
-    // TLP/RTO transmit
-    pub fn force_transmit(&mut self, config: &Config, now: u64) -> Box<[u8]> {
-        let number = self.get_tx_number();
-        let mut buf = Vec::new();
+    /// Construct a packet testing whether the path carries larger datagrams, if one is due
+    pub fn mtu_probe(&mut self, now: u64) -> Option<Box<[u8]>> {
+        match *self.state.as_ref().unwrap() {
+            State::Established(_) => {}
+            _ => {
+                return None;
+            }
+        }
+        if self.unvalidated_path.is_some() {
+            return None;
+        }
+        let size = self.mtud.next_probe_size(now)?;
+        if self
+            .congestion
+            .window()
+            .saturating_sub(self.bytes_in_flight)
+            < size as u64
+        {
+            return None;
+        }
+        let number = self.get_tx_number(SpaceId::Data);
+        let mut buf = Vec::with_capacity(size as usize);
         Header::Short {
             id: self.remote_id,
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            number: PacketNumber::new(number, self.space(SpaceId::Data).largest_acked_packet),
             key_phase: self.key_phase,
         }.encode(&mut buf);
-        let header_len = buf.len() as u16;
+        let header_len = buf.len();
         buf.push(frame::Type::PING.into());
-        self.crypto
-            .as_ref()
-            .unwrap()
-            .encrypt(number, &mut buf, header_len as usize);
+        buf.resize(size as usize - AEAD_TAG_SIZE, frame::Type::PADDING.into());
+        {
+            let crypto = self.space(SpaceId::Data).crypto.as_ref().unwrap();
+            crypto.encrypt(number, &mut buf, header_len);
+            crypto.protect_header(1 + self.remote_id.len(), &mut buf);
+        }
+        self.key_packets_sent += 1;
+        self.key_bytes_sent += buf.len() as u64;
+        self.mtud.on_probe_sent(number, size);
+        self.on_packet_sent(
+            now,
+            SpaceId::Data,
+            number,
+            SentPacket {
+                time: now,
+                bytes: size,
+                ecn: self.sending_ecn,
+                acks: RangeSet::new(),
+                retransmits: Retransmits::default(),
+            },
+        );
+        Some(buf.into())
+    }
+
+    // TLP/RTO transmit
+    pub fn force_transmit(&mut self, now: u64) -> Box<[u8]> {
+        let number = self.get_tx_number(SpaceId::Data);
+        let mut buf = Vec::new();
+        {
+            let space = &self.spaces[SpaceId::Data as usize];
+            let crypto = if let Some(ref crypto) = space.crypto {
+                Header::Short {
+                    id: self.remote_id,
+                    number: PacketNumber::new(number, space.largest_acked_packet),
+                    key_phase: self.key_phase,
+                }.encode(&mut buf);
+                crypto
+            } else {
+                // Probing for lost 0-RTT data before the handshake completes
+                Header::Long {
+                    ty: types::ZERO_RTT,
+                    version: self.version,
+                    number: number as u32,
+                    source_id: self.local_id,
+                    destination_id: self.initial_id,
+                }.encode(&mut buf);
+                self.zero_rtt_crypto.as_ref().unwrap()
+            };
+            let header_len = buf.len();
+            buf.push(frame::Type::PING.into());
+            let pn_offset = if crypto.is_0rtt() {
+                set_payload_length(&mut buf, header_len);
+                header_len - 4
+            } else {
+                pad_for_header_protection(1 + self.remote_id.len(), &mut buf);
+                1 + self.remote_id.len()
+            };
+            crypto.encrypt(number, &mut buf, header_len);
+            crypto.protect_header(pn_offset, &mut buf);
+            if crypto.is_1rtt() {
+                self.key_packets_sent += 1;
+                self.key_bytes_sent += buf.len() as u64;
+            }
+        }
         self.on_packet_sent(
-            config,
             now,
+            SpaceId::Data,
             number,
             SentPacket {
                 time: now,
                 bytes: buf.len() as u16,
-                handshake: false,
+                ecn: self.sending_ecn,
                 acks: RangeSet::new(),
                 retransmits: Retransmits::default(),
             },
@@ -1956,11 +2876,11 @@ impl Connection {
     }
 
     pub fn make_close(&mut self, reason: &state::CloseReason) -> Box<[u8]> {
-        let number = self.get_tx_number();
+        let number = self.get_tx_number(SpaceId::Data);
         let mut buf = Vec::new();
         Header::Short {
             id: self.remote_id,
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            number: PacketNumber::new(number, self.space(SpaceId::Data).largest_acked_packet),
             key_phase: self.key_phase,
         }.encode(&mut buf);
         let header_len = buf.len() as u16;
@@ -1969,13 +2889,35 @@ impl Connection {
             state::CloseReason::Application(ref x) => x.encode(&mut buf, max_len),
             state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
         }
-        self.crypto
-            .as_ref()
-            .unwrap_or_else(|| &self.handshake_crypto)
-            .encrypt(number, &mut buf, header_len as usize);
+        let pn_offset = 1 + self.remote_id.len();
+        pad_for_header_protection(pn_offset, &mut buf);
+        let crypto = self.space(SpaceId::Data).crypto.as_ref().unwrap();
+        crypto.encrypt(number, &mut buf, header_len as usize);
+        crypto.protect_header(pn_offset, &mut buf);
         buf.into()
     }
 
+    /// Construct a packet closing the connection before the handshake has completed
+    ///
+    /// Protected with the most recent handshake keys we have, which the peer is the most likely to still be using.
+    pub fn make_handshake_close(&mut self, reason: &state::CloseReason) -> Box<[u8]> {
+        let space = if self.space(SpaceId::Handshake).crypto.is_some() {
+            SpaceId::Handshake
+        } else {
+            SpaceId::Initial
+        };
+        let number = self.get_tx_number(space);
+        handshake_close(
+            self.space(space).crypto.as_ref().unwrap(),
+            space.packet_type().unwrap(),
+            self.version,
+            &self.remote_id,
+            &self.local_id,
+            number as u32,
+            reason.clone(),
+        )
+    }
+
     /// Close a connection immediately
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility to call this only when
@@ -1986,20 +2928,23 @@ impl Connection {
             state::CloseReason::Application(frame::ApplicationClose { error_code, reason });
         if !was_closed {
             self.close_common(ctx, now);
+            let packet = if let State::Handshake(_) = *self.state.as_ref().unwrap() {
+                self.make_handshake_close(&reason)
+            } else {
+                self.make_close(&reason)
+            };
             ctx.io.push_back(Io::Transmit {
                 destination: self.remote,
-                packet: self.make_close(&reason),
+                ecn: None,
+                packet,
             });
-            self.reset_idle_timeout(&ctx.config, now);
+            self.reset_idle_timeout(now);
             ctx.dirty_conns.insert(self.handle);
         }
 
         self.app_closed = true;
         self.state = Some(match self.state.take().unwrap() {
-            State::Handshake(_) => State::HandshakeFailed(state::HandshakeFailed {
-                reason,
-                alert: None,
-            }),
+            State::Handshake(_) => State::HandshakeFailed(state::HandshakeFailed { reason }),
             State::HandshakeFailed(x) => State::HandshakeFailed(x),
             State::Established(_) => State::Closed(state::Closed { reason }),
             State::Closed(x) => State::Closed(x),
@@ -2011,33 +2956,35 @@ impl Connection {
     pub fn close_common(&mut self, ctx: &mut Context, now: u64) {
         trace!(ctx.log, "connection closed");
         self.set_loss_detection = Some(None);
+        self.set_pacing = Some(None);
+        self.set_path_validation = Some(None);
         ctx.io.push_back(Io::TimerStart {
             connection: self.handle,
             timer: Timer::Close,
-            time: now + 3 * self.rto(&ctx.config),
+            time: now + 3 * self.rto(),
         });
     }
 
     pub fn set_params(&mut self, params: TransportParameters) {
         self.max_bi_streams = params.initial_max_streams_bidi as u64;
-        if self.side == Side::Client {
-            self.max_bi_streams += 1;
-        } // Account for TLS stream
         self.max_uni_streams = params.initial_max_streams_uni as u64;
         self.max_data = params.initial_max_data as u64;
-        for i in 0..self.max_remote_bi_streams {
-            let id = StreamId::new(!self.side, Directionality::Bi, i as u64);
-            self.streams
-                .get_mut(&id)
-                .unwrap()
-                .send_mut()
-                .unwrap()
-                .max_data = params.initial_max_stream_data as u64;
+        if let Some(x) = params.max_packet_size {
+            self.mtud.set_peer_max(x);
+        }
+        let side = self.side;
+        for (id, stream) in &mut self.streams {
+            if id.initiator() == side || id.directionality() != Directionality::Bi {
+                continue;
+            }
+            if let Some(ss) = stream.send_mut() {
+                ss.max_data = params.initial_max_stream_data as u64;
+            }
         }
         self.params = params;
     }
 
-    pub fn open(&mut self, config: &Config, direction: Directionality) -> Option<StreamId> {
+    pub fn open(&mut self, direction: Directionality) -> Option<StreamId> {
         let (id, mut stream) = match direction {
             Directionality::Uni if self.next_uni_stream < self.max_uni_streams => {
                 self.next_uni_stream += 1;
@@ -2050,12 +2997,17 @@ impl Connection {
                 self.next_bi_stream += 1;
                 (
                     StreamId::new(self.side, direction, self.next_bi_stream - 1),
-                    Stream::new_bi(config.stream_receive_window as u64),
+                    Stream::new_bi(stream_window(&self.config)),
                 )
             }
-            _ => {
+            Directionality::Uni => {
+                self.space_mut(SpaceId::Data).pending.uni_stream_id_blocked = true;
+                return None;
+            }
+            Directionality::Bi => {
+                self.space_mut(SpaceId::Data).pending.bi_stream_id_blocked = true;
                 return None;
-            } // TODO: Queue STREAM_ID_BLOCKED
+            }
         };
         stream.send_mut().unwrap().max_data = self.params.initial_max_stream_data as u64;
         let old = self.streams.insert(id, stream);
@@ -2066,51 +3018,86 @@ impl Connection {
     /// Discard state for a stream if it's fully closed.
     ///
     /// Called when one side of a stream transitions to a closed state
-    pub fn maybe_cleanup(&mut self, config: &Config, id: StreamId) {
-        let new = match self.streams.entry(id) {
+    pub fn maybe_cleanup(&mut self, id: StreamId) {
+        match self.streams.entry(id) {
             hash_map::Entry::Vacant(_) => unreachable!(),
             hash_map::Entry::Occupied(e) => {
-                if e.get().is_closed() {
-                    e.remove_entry();
-                    if id.initiator() != self.side {
-                        Some(match id.directionality() {
-                            Directionality::Uni => {
-                                self.max_remote_uni_streams += 1;
-                                self.pending.max_uni_stream_id = true;
-                                (
-                                    StreamId::new(
-                                        !self.side,
-                                        Directionality::Uni,
-                                        self.max_remote_uni_streams - 1,
-                                    ),
-                                    stream::Recv::new(u64::from(
-                                        config.stream_receive_window as u64,
-                                    )).into(),
-                                )
-                            }
-                            Directionality::Bi => {
-                                self.max_remote_bi_streams += 1;
-                                self.pending.max_bi_stream_id = true;
-                                (
-                                    StreamId::new(
-                                        !self.side,
-                                        Directionality::Bi,
-                                        self.max_remote_bi_streams - 1,
-                                    ),
-                                    Stream::new_bi(config.stream_receive_window as u64),
-                                )
-                            }
-                        })
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+                if !e.get().is_closed() {
+                    return;
                 }
+                e.remove_entry();
+            }
+        }
+        if id.initiator() == self.side {
+            return;
+        }
+        match id.directionality() {
+            Directionality::Uni => {
+                self.open_remote_uni_streams -= 1;
+            }
+            Directionality::Bi => {
+                self.open_remote_bi_streams -= 1;
             }
+        }
+        self.grant_remote_streams(id.directionality());
+    }
+
+    /// Change the number of streams the peer may have open at once
+    ///
+    /// A lowered limit takes effect as the peer's existing streams are closed, since stream IDs can't be revoked.
+    pub fn set_max_remote_streams(&mut self, direction: Directionality, count: u64) {
+        let count = cmp::min(count, MAX_STREAM_COUNT);
+        match direction {
+            Directionality::Uni => {
+                self.concurrent_remote_uni_streams = count;
+            }
+            Directionality::Bi => {
+                self.concurrent_remote_bi_streams = count;
+            }
+        }
+        self.grant_remote_streams(direction);
+    }
+
+    /// Permit the peer to open new streams until it's back up to its concurrency limit
+    fn grant_remote_streams(&mut self, direction: Directionality) {
+        let (open, concurrent, max) = match direction {
+            Directionality::Uni => (
+                &mut self.open_remote_uni_streams,
+                self.concurrent_remote_uni_streams,
+                &mut self.max_remote_uni_streams,
+            ),
+            Directionality::Bi => (
+                &mut self.open_remote_bi_streams,
+                self.concurrent_remote_bi_streams,
+                &mut self.max_remote_bi_streams,
+            ),
+        };
+        // Stream IDs are varints, so the peer can't be permitted more than 2^60 streams of each type in total
+        let grant = cmp::min(concurrent.saturating_sub(*open), MAX_STREAM_COUNT - *max);
+        if grant == 0 {
+            return;
+        }
+        *open += grant;
+        *max += grant;
+        let pending = &mut self.spaces[SpaceId::Data as usize].pending;
+        match direction {
+            Directionality::Uni => pending.max_uni_stream_id = true,
+            Directionality::Bi => pending.max_bi_stream_id = true,
+        }
+    }
+
+    /// Change the flow control window for data received on a stream
+    ///
+    /// The window no longer grows automatically afterwards. A smaller window takes effect as the application reads
+    /// data, since credit can't be revoked.
+    pub fn set_stream_receive_window(&mut self, id: StreamId, window: u64) {
+        let rs = match self.streams.get_mut(&id) {
+            Some(x) => x.recv_mut().expect("send-only stream"),
+            // Closed, or not yet opened by the peer
+            None => return,
         };
-        if let Some((id, stream)) = new {
-            self.streams.insert(id, stream);
+        if rs.window.set_size(window) && !rs.is_finished() {
+            self.space_mut(SpaceId::Data).pending.max_stream_data.insert(id);
         }
     }
 
@@ -2123,35 +3110,59 @@ impl Connection {
             .expect("recv-only stream");
         assert_eq!(ss.state, stream::SendState::Ready);
         ss.state = stream::SendState::DataSent;
-        for frame in &mut self.pending.stream {
-            if frame.id == id && frame.offset + frame.data.len() as u64 == ss.offset {
+        let pending = &mut self.spaces[SpaceId::Data as usize].pending;
+        if let Some(frame) = pending.stream.back_mut(id) {
+            if frame.offset + frame.data.len() as u64 == ss.offset {
                 frame.fin = true;
                 return;
             }
         }
-        self.pending.stream.push_back(frame::Stream {
-            id,
-            data: Bytes::new(),
-            offset: ss.offset,
-            fin: true,
-        });
+        pending.stream.push_back(
+            ss.priority,
+            frame::Stream {
+                id,
+                data: Bytes::new(),
+                offset: ss.offset,
+                fin: true,
+            },
+        );
+    }
+
+    pub fn set_priority(&mut self, id: StreamId, priority: i32) {
+        match self.streams.get_mut(&id) {
+            Some(stream) => {
+                stream.send_mut().expect("recv-only stream").priority = priority;
+            }
+            // Already closed, so there's nothing left to send
+            None => {
+                return;
+            }
+        }
+        let space = self.space_mut(SpaceId::Data);
+        space.pending.stream.set_priority(id, priority);
+        // Keep data that's in flight at the new priority should it need retransmitting
+        for packet in space.sent_packets.values_mut() {
+            packet.retransmits.stream.set_priority(id, priority);
+        }
     }
 
     pub fn read_unordered(&mut self, id: StreamId) -> Result<(Bytes, u64), ReadError> {
-        assert_ne!(id, StreamId(0), "cannot read an internal stream");
         let rs = self.streams.get_mut(&id).unwrap().recv_mut().unwrap();
         rs.unordered = true;
         // TODO: Drain rs.assembler to handle ordered-then-unordered reads reliably
 
         // Return data we already have buffered, regardless of state
         if let Some(x) = rs.buffered.pop_front() {
-            // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to reduce overhead
-            self.local_max_data += x.0.len() as u64; // BUG: Don't issue credit for already-received data!
-            self.pending.max_data = true;
+            // BUG: Don't issue credit for already-received data!
+            let pending = &mut self.spaces[SpaceId::Data as usize].pending;
+            if self.receive_window.consume(x.0.len() as u64) {
+                pending.max_data = true;
+            }
             // Only bother issuing stream credit if the peer wants to send more
             if let stream::RecvState::Recv { size: None } = rs.state {
-                rs.max_data += x.0.len() as u64;
-                self.pending.max_stream_data.insert(id);
+                if rs.window.consume(x.0.len() as u64) {
+                    pending.max_stream_data.insert(id);
+                }
             }
             Ok(x)
         } else {
@@ -2171,7 +3182,6 @@ impl Connection {
     }
 
     pub fn read(&mut self, id: StreamId, buf: &mut [u8]) -> Result<usize, ReadError> {
-        assert_ne!(id, StreamId(0), "cannot read an internal stream");
         let rs = self.streams.get_mut(&id).unwrap().recv_mut().unwrap();
         assert!(
             !rs.unordered,
@@ -2184,13 +3194,15 @@ impl Connection {
 
         if !rs.assembler.blocked() {
             let n = rs.assembler.read(buf);
-            // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to reduce overhead
-            self.local_max_data += n as u64;
-            self.pending.max_data = true;
+            let pending = &mut self.spaces[SpaceId::Data as usize].pending;
+            if self.receive_window.consume(n as u64) {
+                pending.max_data = true;
+            }
             // Only bother issuing stream credit if the peer wants to send more
             if let stream::RecvState::Recv { size: None } = rs.state {
-                rs.max_data += n as u64;
-                self.pending.max_stream_data.insert(id);
+                if rs.window.consume(n as u64) {
+                    pending.max_stream_data.insert(id);
+                }
             }
             Ok(n)
         } else {
@@ -2222,60 +3234,96 @@ impl Connection {
             .unwrap();
         // Only bother if there's data we haven't received yet
         if !stream.is_finished() {
-            self.pending.stop_sending.push((id, error_code));
+            self.spaces[SpaceId::Data as usize]
+                .pending
+                .stop_sending
+                .push((id, error_code));
+        }
+    }
+
+    /// Rate at which to send packets that consume congestion window, in bytes per second
+    ///
+    /// Unless the congestion controller calls for a specific rate, the window is spread over slightly less than a
+    /// round trip, leaving room for it to grow.
+    fn pacing_rate(&self) -> Option<u64> {
+        if let Some(rate) = self.congestion.pacing_rate() {
+            return if rate == 0 { None } else { Some(rate) };
+        }
+        if self.smoothed_rtt == 0 {
+            return None;
         }
+        Some(self.congestion.window() * 1_000_000 / self.smoothed_rtt * 5 / 4)
     }
 
     pub fn congestion_blocked(&self) -> bool {
-        self.congestion_window.saturating_sub(self.bytes_in_flight) < self.mtu as u64
+        self.congestion
+            .window()
+            .saturating_sub(self.bytes_in_flight)
+            < self.mtu as u64
     }
 
     pub fn blocked(&self) -> bool {
         self.data_sent >= self.max_data || self.congestion_blocked()
     }
 
+    /// Authenticate a 1-RTT packet, returning its payload and full packet number
     pub fn decrypt_packet(
         &mut self,
-        handshake: bool,
         mut packet: Packet,
     ) -> Result<(Vec<u8>, u64), Option<TransportError>> {
         let (key_phase, number) = match packet.header {
             Header::Short {
                 key_phase, number, ..
-            }
-                if !handshake =>
-            {
-                (key_phase, number)
-            }
-            Header::Long { number, .. } if handshake => (false, PacketNumber::U32(number)),
+            } => (key_phase, number),
             _ => {
                 return Err(None);
             }
         };
-        let number = number.expand(self.rx_packet);
-        if key_phase != self.key_phase {
-            if number <= self.rx_packet {
-                // Illegal key update
-                return Err(Some(TransportError::PROTOCOL_VIOLATION));
-            }
-            if self
-                .update_keys(number, &packet.header_data, &mut packet.payload)
-                .is_ok()
-            {
-                Ok((packet.payload.to_vec(), number))
-            } else {
-                // Invalid key update
-                Err(None)
+        let rx_packet = self.space(SpaceId::Data).rx_packet;
+        let number = number.expand(rx_packet);
+        let header = &packet.header_data;
+        let payload = &mut packet.payload;
+        if key_phase == self.key_phase {
+            // Unable to authenticate
+            self.space(SpaceId::Data)
+                .crypto
+                .as_ref()
+                .unwrap()
+                .decrypt(number, header, payload)
+                .map_err(|()| None)?;
+            if let Some(ref mut prev) = self.prev_crypto {
+                if prev.end_packet.is_none() {
+                    // The peer has picked up the keys from our update
+                    prev.end_packet = Some(number);
+                }
             }
         } else if self
-            .decrypt(handshake, number, &packet.header_data, &mut packet.payload)
-            .is_ok()
+            .prev_crypto
+            .as_ref()
+            .is_some_and(|x| x.end_packet.is_none_or(|end| number < end))
         {
-            Ok((packet.payload.to_vec(), number))
+            // Sent before the most recent key update
+            self.prev_crypto
+                .as_ref()
+                .unwrap()
+                .crypto
+                .decrypt(number, header, payload)
+                .map_err(|()| None)?;
+        } else if number > rx_packet {
+            // The peer is initiating a key update
+            let new = self
+                .space(SpaceId::Data)
+                .crypto
+                .as_ref()
+                .unwrap()
+                .update(self.side);
+            new.decrypt(number, header, payload).map_err(|()| None)?;
+            self.commit_key_update(new, Some(number));
         } else {
-            // Unable to authenticate
-            Err(None)
+            // Sent under keys we've already discarded
+            return Err(None);
         }
+        Ok((payload.to_vec(), number))
     }
 
     pub fn get_recv_stream(&mut self, id: StreamId) -> Result<Option<&mut Stream>, TransportError> {
@@ -2297,21 +3345,60 @@ impl Connection {
             if id.index() >= limit {
                 return Err(TransportError::STREAM_ID_ERROR);
             }
+            self.create_remote_stream(id);
         }
         Ok(self.streams.get_mut(&id))
     }
 
-    pub fn write(
-        &mut self,
-        config: &Config,
-        stream: StreamId,
-        data: &[u8],
-    ) -> Result<usize, WriteError> {
+    /// Create state for a permitted remote stream, unless it's been created before
+    ///
+    /// Lower-numbered streams that the peer implicitly opens are only recorded, and get state when the peer first refers
+    /// to them, so that a single frame can't make us allocate for an arbitrary number of streams.
+    fn create_remote_stream(&mut self, id: StreamId) {
+        {
+            let (next, implicit) = match id.directionality() {
+                Directionality::Uni => (
+                    &mut self.next_remote_uni_stream,
+                    &mut self.implicit_remote_uni_streams,
+                ),
+                Directionality::Bi => (
+                    &mut self.next_remote_bi_stream,
+                    &mut self.implicit_remote_bi_streams,
+                ),
+            };
+            let index = id.index();
+            if index >= *next {
+                if index > *next {
+                    implicit.insert(*next..index);
+                }
+                *next = index + 1;
+            } else if !implicit.remove(index..index + 1) {
+                // Already created, and possibly since closed
+                return;
+            }
+        }
+        let stream = match id.directionality() {
+            Directionality::Uni => stream::Recv::new(stream_window(&self.config)).into(),
+            Directionality::Bi => {
+                let mut stream = Stream::new_bi(stream_window(&self.config));
+                stream.send_mut().unwrap().max_data = self.params.initial_max_stream_data as u64;
+                stream
+            }
+        };
+        self.streams.insert(id, stream);
+    }
+
+    pub fn write(&mut self, stream: StreamId, data: &[u8]) -> Result<usize, WriteError> {
         if self.state.as_ref().unwrap().is_closed() {
             return Err(WriteError::Blocked);
         }
         assert!(stream.directionality() == Directionality::Bi || stream.initiator() == self.side);
         if self.blocked() {
+            // Each limit need only be reported once; retransmission takes care of loss
+            if self.data_sent >= self.max_data && self.blocked_reported != Some(self.max_data) {
+                self.blocked_reported = Some(self.max_data);
+                self.space_mut(SpaceId::Data).pending.blocked = true;
+            }
             self.blocked_streams.insert(stream);
             return Err(WriteError::Blocked);
         }
@@ -2337,11 +3424,19 @@ impl Connection {
         };
 
         if let Some(error_code) = stop_reason {
-            self.maybe_cleanup(config, stream);
+            self.maybe_cleanup(stream);
             return Err(WriteError::Stopped { error_code });
         }
 
         if stream_budget == 0 {
+            let ss = self.streams.get_mut(&stream).unwrap().send_mut().unwrap();
+            if ss.blocked_reported != Some(ss.max_data) {
+                ss.blocked_reported = Some(ss.max_data);
+                self.spaces[SpaceId::Data as usize]
+                    .pending
+                    .stream_blocked
+                    .insert(stream);
+            }
             return Err(WriteError::Blocked);
         }
 
@@ -2350,30 +3445,55 @@ impl Connection {
         self.transmit(stream, (&data[0..n]).into());
         Ok(n)
     }
+
+    /// Largest datagram that may currently be sent, if the peer accepts datagrams at all
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let limit = self.params.max_datagram_frame_size? as usize;
+        // Allow for the longest short header, and a DATAGRAM frame's type and longest length field
+        let packet_space = self.mtu as usize - (1 + self.remote_id.len() + 4) - AEAD_TAG_SIZE;
+        Some(cmp::min(limit, packet_space).saturating_sub(1 + 4))
+    }
+
+    pub fn send_datagram(&mut self, data: Bytes) -> Result<(), SendDatagramError> {
+        let max = self
+            .max_datagram_size()
+            .ok_or(SendDatagramError::UnsupportedByPeer)?;
+        if data.len() > max {
+            return Err(SendDatagramError::TooLarge);
+        }
+        if self.datagrams.len() == MAX_PENDING_DATAGRAMS {
+            // The oldest datagram is the least likely to still be of use
+            self.datagrams.pop_front();
+        }
+        self.datagrams.push_back(data);
+        Ok(())
+    }
 }
 
-/// Extract stream 0 data from an Initial or Retry packet payload
-pub fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Stream>, ()> {
+/// Initial flow control state for a stream the peer may send on
+fn stream_window(config: &TransportConfig) -> flow_control::Window {
+    flow_control::Window::new(
+        u64::from(config.stream_receive_window),
+        u64::from(config.max_stream_receive_window),
+    )
+}
+
+/// Extract the start of the handshake from an Initial packet payload
+pub fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Crypto>, ()> {
     let mut result = None;
     for frame in frame::Iter::new(payload) {
         match frame {
             Frame::Padding => {}
             Frame::Ack(_) => {}
-            Frame::Stream(
-                frame @ frame::Stream {
-                    id: StreamId(0),
-                    fin: false,
-                    ..
-                },
-            ) => {
+            Frame::Crypto(frame) => {
                 if frame.offset != 0 {
-                    debug!(log, "frame offset in initial stream 0 frame"; "offset" => frame.offset);
+                    debug!(log, "nonzero offset in initial crypto frame"; "offset" => frame.offset);
                     return Err(());
                 }
                 result = Some(frame);
             }
             x => {
-                debug!(log, "unexpected frame in initial/retry packet"; "ty" => %x.ty());
+                debug!(log, "unexpected frame in initial packet"; "ty" => %x.ty());
                 return Err(());
             } // Invalid packet
         }
@@ -2385,8 +3505,14 @@ pub fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Strea
 #[derive(Debug, Clone, Fail)]
 pub enum ConnectionError {
     /// The peer doesn't implement any supported version.
-    #[fail(display = "peer doesn't implement any supported version")]
-    VersionMismatch,
+    #[fail(
+        display = "peer doesn't implement any supported version; it offered {:x?}",
+        offered
+    )]
+    VersionMismatch {
+        /// The versions listed in the peer's Version Negotiation packet
+        offered: Vec<u32>,
+    },
     /// The peer violated the QUIC specification as understood by this implementation.
     #[fail(display = "{}", error_code)]
     TransportError { error_code: TransportError },
@@ -2424,10 +3550,10 @@ impl From<ConnectionError> for io::Error {
                 io::ErrorKind::ConnectionAborted,
                 format!("peer detected an error: {}", reason),
             ),
-            TransportError { error_code } => {
-                io::Error::new(io::ErrorKind::Other, format!("{}", error_code))
+            TransportError { error_code } => io::Error::other(format!("{}", error_code)),
+            VersionMismatch { offered } => {
+                io::Error::other(format!("version mismatch; peer offered {:x?}", offered))
             }
-            VersionMismatch => io::Error::new(io::ErrorKind::Other, "version mismatch"),
         }
     }
 }
@@ -2472,6 +3598,16 @@ pub enum WriteError {
     Stopped { error_code: u16 },
 }
 
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SendDatagramError {
+    /// The peer doesn't accept datagrams, or hasn't said whether it does because the handshake is incomplete.
+    #[fail(display = "datagrams not supported by peer")]
+    UnsupportedByPeer,
+    /// The datagram is larger than the peer will accept, or than fits in a packet on the current path.
+    #[fail(display = "datagram too large")]
+    TooLarge,
+}
+
 pub enum State {
     Handshake(state::Handshake),
     Established(state::Established),
@@ -2489,13 +3625,9 @@ impl State {
         })
     }
 
-    pub fn handshake_failed<R: Into<state::CloseReason>>(
-        reason: R,
-        alert: Option<Box<[u8]>>,
-    ) -> Self {
+    pub fn handshake_failed<R: Into<state::CloseReason>>(reason: R) -> Self {
         State::HandshakeFailed(state::HandshakeFailed {
             reason: reason.into(),
-            alert,
         })
     }
 
@@ -2536,7 +3668,6 @@ pub mod state {
     pub struct HandshakeFailed {
         // Closed
         pub reason: CloseReason,
-        pub alert: Option<Box<[u8]>>,
     }
 
     #[derive(Clone)]
@@ -2568,3 +3699,17 @@ pub mod state {
 
 /// Ensures we can always fit all our ACKs in a single minimum-MTU packet with room to spare
 const MAX_ACK_BLOCKS: usize = 64;
+/// Maximum ratio of bytes sent to bytes received for a peer address that hasn't been validated
+const MAX_UNVALIDATED_AMPLIFICATION: u64 = 3;
+/// Maximum amount of handshake data to buffer ahead of what TLS has consumed, in each space
+const MAX_CRYPTO_BUFFER: usize = 64 * 1024;
+
+/// Smallest space worth filling with another packet when coalescing
+///
+/// Leaves room for a long header, an AEAD tag, and an ACK frame's fixed fields.
+const MIN_COALESCED_PACKET_SIZE: usize = 128;
+
+/// Number of distinct stream IDs of each type, since stream IDs are 62-bit varints with two bits of type
+const MAX_STREAM_COUNT: u64 = 1 << 60;
+/// Maximum number of datagrams to queue for transmission before discarding the oldest
+const MAX_PENDING_DATAGRAMS: usize = 1024;
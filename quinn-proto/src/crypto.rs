@@ -1,67 +1,143 @@
-use std::net::SocketAddrV6;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
-use std::{io, str};
+use std::sync::{Arc, Mutex};
+use std::{cmp, io, str};
 
 use blake2::{
     digest::{Input, VariableOutput},
     VarBlake2b,
 };
+use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, BufMut, BytesMut};
+use constant_time_eq::constant_time_eq;
+use fnv::FnvHashMap;
 use ring::aead;
 use ring::digest;
-use ring::hkdf;
-use ring::hmac::SigningKey;
+use ring::hkdf::{self, KeyType};
+use rustls::internal::msgs::codec::{Codec, Reader};
+use rustls::internal::msgs::handshake::{
+    ConvertProtocolNameList, ConvertServerNameList, HandshakeMessagePayload, HandshakePayload,
+};
+use rustls::internal::msgs::persist::{ClientSessionKey, ClientSessionValue};
+pub use rustls::quic::Secrets;
 use rustls::quic::{ClientQuicExt, ServerQuicExt};
-pub use rustls::{Certificate, NoClientAuth, PrivateKey, TLSError};
-pub use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
+pub use rustls::{ClientSession, ServerConfig, ServerSession, Session};
+pub use rustls::{NoClientAuth, TLSError, Ticketer};
+use rustls::{ProtocolVersion, StoresClientSessions, SupportedCipherSuite};
 use webpki::DNSNameRef;
 
-use endpoint::EndpointError;
-use packet::{ConnectionId, AEAD_TAG_SIZE};
+use endpoint::{EndpointError, TransportConfig};
+use packet::{
+    ConnectionId, Header, Packet, PacketNumber, AEAD_TAG_SIZE, SHORT_HEADER_PROTECTED_BITS,
+};
 use transport_parameters::TransportParameters;
-use {Side, RESET_TOKEN_SIZE};
+use {Side, Version, MAX_CID_SIZE, RESET_TOKEN_SIZE};
 
 pub enum TlsSession {
-    Client(ClientSession),
+    /// A client session, and the cipher suite of the session it's attempting to resume with early data
+    Client(ClientSession, Option<&'static SupportedCipherSuite>),
     Server(ServerSession),
 }
 
 impl TlsSession {
     pub fn new_client(
-        config: &Arc<ClientConfig>,
+        config: &ClientConfig,
         hostname: &str,
+        version: Version,
         params: &TransportParameters,
     ) -> Result<TlsSession, EndpointError> {
         let pki_server_name = DNSNameRef::try_from_ascii_str(hostname)
             .map_err(|_| EndpointError::InvalidDnsName(hostname.into()))?;
-        Ok(TlsSession::Client(ClientSession::new_quic(
-            &config,
-            pki_server_name,
-            to_vec(Side::Client, params),
-        )))
+        // rustls doesn't tell its session storage which server a session belongs to, so bind that here.
+        let mut tls_config = (*config.tls_config).clone();
+        let sessions = ServerSessions {
+            server_name: hostname.into(),
+            store: config.session_store.clone(),
+        };
+        // rustls keeps the cipher suite it resumes with to itself until the server replies, but 0-RTT keys need it now
+        let resuming_suite = sessions
+            .get(&ClientSessionKey::session_for_dns_name(pki_server_name).get_encoding())
+            .and_then(|x| ClientSessionValue::read(&mut Reader::init(&x)))
+            .and_then(|x| {
+                tls_config
+                    .ciphersuites
+                    .iter()
+                    .cloned()
+                    .find(|suite| suite.suite == x.cipher_suite)
+            });
+        tls_config.session_persistence = Arc::new(sessions);
+        Ok(TlsSession::Client(
+            ClientSession::new_quic(
+                &Arc::new(tls_config),
+                pki_server_name,
+                to_vec(Side::Client, version, &[], params),
+            ),
+            resuming_suite,
+        ))
     }
 
-    pub fn new_server(config: &Arc<ServerConfig>, params: &TransportParameters) -> TlsSession {
+    pub fn new_server(
+        config: &Arc<ServerConfig>,
+        version: Version,
+        supported: &[Version],
+        params: &TransportParameters,
+    ) -> TlsSession {
         TlsSession::Server(ServerSession::new_quic(
             config,
-            to_vec(Side::Server, params),
+            to_vec(Side::Server, version, supported, params),
         ))
     }
 
     pub fn get_sni_hostname(&self) -> Option<&str> {
         match *self {
-            TlsSession::Client(_) => None,
+            TlsSession::Client(..) => None,
             TlsSession::Server(ref session) => session.get_sni_hostname(),
         }
     }
+
+    /// Cipher suite protecting early data, if any is in use
+    fn get_early_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
+        self.get_early_secret()?;
+        match *self {
+            TlsSession::Client(_, suite) => suite,
+            TlsSession::Server(ref session) => session.get_negotiated_ciphersuite(),
+        }
+    }
+
+    /// Whether the server accepted the early data we offered
+    pub fn is_early_data_accepted(&self) -> bool {
+        match *self {
+            TlsSession::Client(ref session, _) => session.is_early_data_accepted(),
+            TlsSession::Server(_) => false,
+        }
+    }
+
+    /// Whether the handshake resumed a previous session rather than authenticating the peer from scratch
+    pub fn is_resumed(&self) -> bool {
+        match *self {
+            // Resumed TLS 1.3 handshakes don't present the server's certificates again
+            TlsSession::Client(ref session, _) => {
+                !session.is_handshaking() && session.get_peer_certificates().is_none()
+            }
+            TlsSession::Server(ref session) => session.received_resumption_data().is_some(),
+        }
+    }
+
+    /// Refuse any early data offered by the client
+    ///
+    /// Must be called before the ClientHello is processed.
+    pub fn reject_early_data(&mut self) {
+        if let TlsSession::Server(ref mut session) = *self {
+            session.reject_early_data();
+        }
+    }
 }
 
 impl Deref for TlsSession {
     type Target = dyn Session;
     fn deref(&self) -> &Self::Target {
         match *self {
-            TlsSession::Client(ref session) => session,
+            TlsSession::Client(ref session, _) => session,
             TlsSession::Server(ref session) => session,
         }
     }
@@ -70,26 +146,166 @@ impl Deref for TlsSession {
 impl DerefMut for TlsSession {
     fn deref_mut(&mut self) -> &mut (dyn Session + 'static) {
         match *self {
-            TlsSession::Client(ref mut session) => session,
+            TlsSession::Client(ref mut session, _) => session,
             TlsSession::Server(ref mut session) => session,
         }
     }
 }
 
+/// Configuration for outgoing connections
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// TLS configuration to use.
+    ///
+    /// `versions` *must* be `vec![ProtocolVersion::TLSv1_3]`. `session_persistence` is ignored in favor of
+    /// `session_store`.
+    pub tls_config: Arc<::rustls::ClientConfig>,
+    /// Storage for the state needed to resume sessions with previously contacted servers
+    pub session_store: Arc<dyn SessionStore>,
+    /// Transport configuration for connections made with this configuration
+    pub transport: Arc<TransportConfig>,
+}
+
+impl ClientConfig {
+    /// Construct a configuration that keeps resumption state in memory
+    pub fn new(tls_config: Arc<::rustls::ClientConfig>) -> Self {
+        Self {
+            tls_config,
+            session_store: Arc::new(InMemorySessionStore::default()),
+            transport: Arc::new(TransportConfig::default()),
+        }
+    }
+}
+
+/// The parts of a client's TLS ClientHello that a server might choose how to serve it by
+pub struct ClientHello {
+    /// Server name indication
+    pub server_name: Option<String>,
+    /// Application-layer protocols offered, most preferred first
+    pub protocols: Vec<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Parse the ClientHello that begins a client's handshake stream
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let msg = HandshakeMessagePayload::read(&mut Reader::init(data))?;
+        let hello = match msg.payload {
+            HandshakePayload::ClientHello(ref x) => x,
+            _ => return None,
+        };
+        Some(Self {
+            server_name: hello
+                .get_sni_extension()
+                .and_then(|x| x.get_single_hostname())
+                .map(|x| <&str>::from(x).into()),
+            protocols: hello.get_alpn_extension().map_or_else(Vec::new, |x| {
+                x.to_slices().into_iter().map(|x| x.to_vec()).collect()
+            }),
+        })
+    }
+}
+
+/// Storage for the state needed to resume sessions with previously contacted servers
+///
+/// Entries are keyed by the server name passed to `Endpoint::connect`, plus an opaque key distinguishing the different
+/// kinds of state kept for each server. Sharing a store between client configurations allows them to resume each
+/// other's sessions.
+pub trait SessionStore: Send + Sync {
+    /// Store `value` under `key` for `server_name`, returning whether it was stored
+    fn put(&self, server_name: &str, key: Vec<u8>, value: Vec<u8>) -> bool;
+    /// Look up the value most recently stored under `key` for `server_name`
+    fn get(&self, server_name: &str, key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// A `SessionStore` that keeps state for a limited number of servers in memory
+pub struct InMemorySessionStore {
+    capacity: usize,
+    servers: Mutex<FnvHashMap<String, ServerSessionState>>,
+}
+
+/// Resumption state for a single server, keyed however rustls sees fit
+type ServerSessionState = FnvHashMap<Vec<u8>, Vec<u8>>;
+
+impl InMemorySessionStore {
+    /// Construct a store that remembers up to `capacity` servers
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            servers: Mutex::new(FnvHashMap::default()),
+        }
+    }
+}
+
+impl Default for InMemorySessionStore {
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn put(&self, server_name: &str, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let mut servers = self.servers.lock().unwrap();
+        if !servers.contains_key(server_name) && servers.len() >= self.capacity {
+            // Evict an arbitrary server to make room
+            let victim = servers.keys().next().cloned().unwrap();
+            servers.remove(&victim);
+        }
+        servers
+            .entry(server_name.into())
+            .or_default()
+            .insert(key, value);
+        true
+    }
+
+    fn get(&self, server_name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let servers = self.servers.lock().unwrap();
+        servers.get(server_name).and_then(|x| x.get(key)).cloned()
+    }
+}
+
+/// Adapts a `SessionStore` to rustls for the duration of a single connection
+struct ServerSessions {
+    server_name: String,
+    store: Arc<dyn SessionStore>,
+}
+
+impl StoresClientSessions for ServerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.store.put(&self.server_name, key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(&self.server_name, key)
+    }
+}
+
+/// `SessionStore` key under which a server's transport parameters are remembered for use in 0-RTT
+pub const TRANSPORT_PARAMETERS_KEY: &[u8] = b"quic transport parameters";
+
 pub fn build_server_config() -> ServerConfig {
-    ServerConfig::new(NoClientAuth::new())
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.versions = vec![ProtocolVersion::TLSv1_3];
+    config.ticketer = Ticketer::new();
+    config.max_early_data_size = TLS_MAX_EARLY_DATA;
+    config
 }
 
-fn to_vec(side: Side, params: &TransportParameters) -> Vec<u8> {
+fn to_vec(
+    side: Side,
+    version: Version,
+    supported: &[Version],
+    params: &TransportParameters,
+) -> Vec<u8> {
     let mut bytes = Vec::new();
-    params.write(side, &mut bytes);
+    params.write(side, version, supported, &mut bytes);
     bytes
 }
 
-/// Value used in ACKs we transmit
-pub const ACK_DELAY_EXPONENT: u8 = 3;
 /// Magic value used to indicate 0-RTT support in NewSessionTicket
-//pub const TLS_MAX_EARLY_DATA: u32 = 0xffff_ffff;
+pub const TLS_MAX_EARLY_DATA: u32 = 0xffff_ffff;
 
 pub fn reset_token_for(key: &[u8], id: &ConnectionId) -> [u8; RESET_TOKEN_SIZE] {
     let mut mac = VarBlake2b::new_keyed(key, RESET_TOKEN_SIZE);
@@ -102,101 +318,58 @@ pub fn reset_token_for(key: &[u8], id: &ConnectionId) -> [u8; RESET_TOKEN_SIZE]
 
 #[derive(Clone)]
 pub enum Crypto {
-    // ZeroRtt(ZeroRttCrypto),
+    ZeroRtt(ZeroRttCrypto),
     Handshake(CryptoContext),
     OneRtt(CryptoContext),
 }
 
 impl Crypto {
-    /*
-    pub fn new_0rtt(tls: &TlsSide) -> Self {
-        let suite = tls.get_negotiated_ciphersuite().unwrap();
-        let tls_cipher = tls.current_cipher().unwrap();
-        let digest = tls_cipher.handshake_digest().unwrap();
-        let cipher = Cipher::from_nid(tls_cipher.cipher_nid().unwrap()).unwrap();
-
-        const LABEL: &str = "EXPORTER-QUIC 0rtt";
-
-        let mut secret = vec![0; digest.size()];
-        tls.export_keying_material_early(&mut secret, &LABEL, b"")
-            .unwrap();
-        Crypto::ZeroRtt(ZeroRttCrypto {
-            state: CryptoState::new(digest, cipher, secret.into()),
+    /// Derive 0-RTT keys, if the TLS session is offering or accepting early data
+    pub fn new_0rtt(tls: &TlsSession) -> Option<Self> {
+        let suite = tls.get_early_ciphersuite()?;
+        let (cipher, digest) = (suite.get_aead_alg(), hkdf_algorithm(suite));
+        let mut secret = vec![0; digest.len()];
+        qhkdf_expand(tls.get_early_secret()?, b"client 0rtt", &mut secret);
+        let secret = hkdf::Prk::new_less_safe(digest, &secret);
+        Some(Crypto::ZeroRtt(ZeroRttCrypto {
+            header: HeaderKey::new(cipher, &secret),
+            state: CryptoState::new(cipher, secret),
             cipher,
-        })
+        }))
     }
-    */
 
-    pub fn new_handshake(id: &ConnectionId, side: Side) -> Self {
-        let (digest, cipher) = (&digest::SHA256, &aead::AES_128_GCM);
+    /// Derive the keys protecting Initial packets from the connection ID the client first addressed
+    pub fn new_initial(version: Version, id: &ConnectionId, side: Side) -> Self {
+        let (digest, cipher) = (hkdf::HKDF_SHA256, &aead::AES_128_GCM);
         let (local_label, remote_label) = if side == Side::Client {
             (b"client hs", b"server hs")
         } else {
             (b"server hs", b"client hs")
         };
-        let hs_secret = handshake_secret(id);
-        let local = CryptoState::new(
-            digest,
-            cipher,
-            expanded_handshake_secret(&hs_secret, local_label),
-        );
-        let remote = CryptoState::new(
-            digest,
-            cipher,
-            expanded_handshake_secret(&hs_secret, remote_label),
-        );
-        Crypto::Handshake(CryptoContext {
-            local,
-            remote,
-            digest,
-            cipher,
-        })
+        let hs_secret = handshake_secret(version, id);
+        let local =
+            hkdf::Prk::new_less_safe(digest, &expanded_handshake_secret(&hs_secret, local_label));
+        let remote =
+            hkdf::Prk::new_less_safe(digest, &expanded_handshake_secret(&hs_secret, remote_label));
+        Crypto::Handshake(CryptoContext::from_secrets(cipher, digest, local, remote))
     }
 
-    pub fn new_1rtt(tls: &TlsSession, side: Side) -> Self {
-        let suite = tls.get_negotiated_ciphersuite().unwrap();
-        let (cipher, digest) = (suite.get_aead_alg(), suite.get_hash());
-
-        const SERVER_LABEL: &[u8] = b"EXPORTER-QUIC server 1rtt";
-        const CLIENT_LABEL: &[u8] = b"EXPORTER-QUIC client 1rtt";
-
-        let (local_label, remote_label) = if side == Side::Client {
-            (CLIENT_LABEL, SERVER_LABEL)
-        } else {
-            (SERVER_LABEL, CLIENT_LABEL)
-        };
-        let mut local_secret = vec![0; digest.output_len];
-        tls.export_keying_material(&mut local_secret, local_label, None)
-            .unwrap();
-        let local = CryptoState::new(digest, cipher, local_secret);
+    /// Derive the keys protecting Handshake packets from the handshake traffic secrets negotiated by TLS
+    pub fn new_handshake(tls: &TlsSession, side: Side, secrets: &Secrets) -> Self {
+        Crypto::Handshake(CryptoContext::new(tls, side, secrets))
+    }
 
-        let mut remote_secret = vec![0; digest.output_len];
-        tls.export_keying_material(&mut remote_secret, remote_label, None)
-            .unwrap();
-        let remote = CryptoState::new(digest, cipher, remote_secret);
-        Crypto::OneRtt(CryptoContext {
-            local,
-            remote,
-            digest,
-            cipher,
-        })
+    /// Derive 1-RTT keys from the application traffic secrets negotiated by TLS
+    pub fn new_1rtt(tls: &TlsSession, side: Side, secrets: &Secrets) -> Self {
+        Crypto::OneRtt(CryptoContext::new(tls, side, secrets))
     }
 
-    /*
     pub fn is_0rtt(&self) -> bool {
         match *self {
             Crypto::ZeroRtt(_) => true,
             _ => false,
         }
     }
-    */
-
-    pub fn is_handshake(&self) -> bool {
-        match *self {
-            Crypto::Handshake(_) => true,
-            _ => false,
-        }
-    }
 
     pub fn is_1rtt(&self) -> bool {
         match *self {
@@ -205,38 +378,38 @@ impl Crypto {
         }
     }
 
-    pub fn write_nonce(&self, state: &CryptoState, number: u64, out: &mut [u8]) {
-        let out = {
-            let mut write = io::Cursor::new(out);
+    pub fn write_nonce(&self, state: &CryptoState, number: u64) -> aead::Nonce {
+        let mut out = [0; aead::NONCE_LEN];
+        {
+            let mut write = io::Cursor::new(&mut out[..]);
             write.put_u32_be(0);
             write.put_u64_be(number);
             debug_assert_eq!(write.remaining(), 0);
-            write.into_inner()
-        };
+        }
         debug_assert_eq!(out.len(), state.iv.len());
         for (out, inp) in out.iter_mut().zip(state.iv.iter()) {
             *out ^= inp;
         }
+        aead::Nonce::assume_unique_for_key(out)
     }
 
     pub fn encrypt(&self, packet: u64, buf: &mut Vec<u8>, header_len: usize) {
         // FIXME: retain crypter
         let (cipher, state) = match *self {
-            //Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
+            Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
             Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
                 (crypto.cipher, &crypto.local)
             }
         };
 
-        let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
-        let nonce = &mut nonce_buf[..cipher.nonce_len()];
-        self.write_nonce(&state, packet, nonce);
-        let tag = vec![0; cipher.tag_len()];
-        buf.extend(tag);
-
-        let key = aead::SealingKey::new(cipher, &state.key).unwrap();
-        let (header, payload) = buf.split_at_mut(header_len);
-        aead::seal_in_place(&key, &*nonce, header, payload, cipher.tag_len()).unwrap();
+        let nonce = self.write_nonce(state, packet);
+        let key = aead::LessSafeKey::new(aead::UnboundKey::new(cipher, &state.key).unwrap());
+        let tag = {
+            let (header, payload) = buf.split_at_mut(header_len);
+            key.seal_in_place_separate_tag(nonce, aead::Aad::from(header), payload)
+                .unwrap()
+        };
+        buf.extend_from_slice(tag.as_ref());
     }
 
     pub fn decrypt(&self, packet: u64, header: &[u8], payload: &mut BytesMut) -> Result<(), ()> {
@@ -245,19 +418,18 @@ impl Crypto {
         }
 
         let (cipher, state) = match *self {
-            //Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
+            Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
             Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
                 (crypto.cipher, &crypto.remote)
             }
         };
 
-        let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
-        let nonce = &mut nonce_buf[..cipher.nonce_len()];
-        self.write_nonce(&state, packet, nonce);
+        let nonce = self.write_nonce(state, packet);
         let payload_len = payload.len();
 
-        let key = aead::OpeningKey::new(cipher, &state.key).unwrap();
-        aead::open_in_place(&key, &*nonce, header, 0, payload.as_mut()).map_err(|_| ())?;
+        let key = aead::LessSafeKey::new(aead::UnboundKey::new(cipher, &state.key).unwrap());
+        key.open_in_place(nonce, aead::Aad::from(header), payload.as_mut())
+            .map_err(|_| ())?;
         payload.split_off(payload_len - cipher.tag_len());
         Ok(())
     }
@@ -266,98 +438,139 @@ impl Crypto {
         match *self {
             Crypto::OneRtt(ref crypto) => Crypto::OneRtt(CryptoContext {
                 local: crypto.local.update(crypto.digest, crypto.cipher, side),
-                remote: crypto.local.update(crypto.digest, crypto.cipher, !side),
+                remote: crypto.remote.update(crypto.digest, crypto.cipher, !side),
+                local_header: crypto.local_header.clone(),
+                remote_header: crypto.remote_header.clone(),
                 digest: crypto.digest,
                 cipher: crypto.cipher,
             }),
             _ => unreachable!(),
         }
     }
+
+    /// Number of packets that may be protected with these keys before confidentiality is at risk
+    pub fn confidentiality_limit(&self) -> u64 {
+        match *self {
+            Crypto::OneRtt(ref crypto) if crypto.cipher == &aead::CHACHA20_POLY1305 => u64::MAX,
+            _ => AES_GCM_CONFIDENTIALITY_LIMIT,
+        }
+    }
+
+    /// Conceal the header of a packet we've encrypted with these keys
+    pub fn protect_header(&self, pn_offset: usize, packet: &mut [u8]) {
+        match *self {
+            Crypto::ZeroRtt(ref crypto) => crypto.header.protect(pn_offset, packet),
+            Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
+                crypto.local_header.protect(pn_offset, packet)
+            }
+        }
+    }
+
+    /// Reveal the header of a packet the peer protected with these keys, returning false if it's malformed
+    pub fn unprotect_header(&self, pn_offset: usize, packet: &mut [u8]) -> bool {
+        match *self {
+            Crypto::ZeroRtt(ref crypto) => crypto.header.unprotect(pn_offset, packet),
+            Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
+                crypto.remote_header.unprotect(pn_offset, packet)
+            }
+        }
+    }
+
+    /// Reveal the packet number of a decoded long-header packet, returning it
+    ///
+    /// The packet number ends the header, so the sample is taken from the start of the payload.
+    pub fn unprotect_long(&self, packet: &mut Packet) -> Option<u64> {
+        let header_len = packet.header_data.len();
+        let sample_len = cmp::min(packet.payload.len(), HEADER_SAMPLE_SIZE);
+        let mut buf = BytesMut::with_capacity(header_len + sample_len);
+        buf.extend_from_slice(&packet.header_data);
+        buf.extend_from_slice(&packet.payload[..sample_len]);
+        if !self.unprotect_header(header_len - 4, &mut buf) {
+            return None;
+        }
+        buf.truncate(header_len);
+        let number = BigEndian::read_u32(&buf[header_len - 4..]);
+        if let Header::Long {
+            number: ref mut x, ..
+        } = packet.header
+        {
+            *x = number;
+        }
+        packet.header_data = buf.freeze();
+        Some(u64::from(number))
+    }
 }
 
-/*
+/// Packets that may be sealed under one AES-GCM key, per the AEAD limits analysis in the QUIC-TLS draft
+const AES_GCM_CONFIDENTIALITY_LIMIT: u64 = 1 << 23;
+
+/// Generates and verifies the address validation tokens carried by Retry packets
+///
+/// Tokens are issued as the connection ID the client must use for its next Initial, so validating one requires no
+/// per-client state.
 pub struct CookieFactory {
     mac_key: [u8; 64],
 }
 
-const COOKIE_MAC_BYTES: usize = 64;
+const COOKIE_MAC_BYTES: usize = MAX_CID_SIZE;
 
 impl CookieFactory {
-    fn new(mac_key: [u8; 64]) -> Self {
+    pub fn new(mac_key: [u8; 64]) -> Self {
         Self { mac_key }
     }
 
-    fn generate(&self, conn: &ConnectionInfo, out: &mut [u8]) -> usize {
-        let mac = self.generate_mac(conn);
-        out[0..COOKIE_MAC_BYTES].copy_from_slice(&mac);
-        COOKIE_MAC_BYTES
+    /// Produce a token proving that its bearer can receive packets at `remote`
+    pub fn generate(&self, remote: &SocketAddr) -> ConnectionId {
+        ConnectionId::new(&self.generate_mac(remote))
     }
 
-    fn generate_mac(&self, conn: &ConnectionInfo) -> [u8; COOKIE_MAC_BYTES] {
-        let mut mac = Blake2b::new_keyed(&self.mac_key, COOKIE_MAC_BYTES);
-        mac.process(&conn.remote.ip().octets());
+    fn generate_mac(&self, remote: &SocketAddr) -> [u8; COOKIE_MAC_BYTES] {
+        let mut mac = VarBlake2b::new_keyed(&self.mac_key, COOKIE_MAC_BYTES);
+        match remote.ip() {
+            IpAddr::V4(x) => mac.input(x.octets()),
+            IpAddr::V6(x) => mac.input(x.octets()),
+        }
         {
             let mut buf = [0; 2];
-            BigEndian::write_u16(&mut buf, conn.remote.port());
-            mac.process(&buf);
+            BigEndian::write_u16(&mut buf, remote.port());
+            mac.input(buf);
         }
         let mut result = [0; COOKIE_MAC_BYTES];
-        mac.variable_result(&mut result).unwrap();
+        mac.variable_result(|res| result.copy_from_slice(res));
         result
     }
 
-    fn verify(&self, conn: &ConnectionInfo, cookie_data: &[u8]) -> bool {
-        let expected = self.generate_mac(conn);
-        if !constant_time_eq(cookie_data, &expected) {
-            return false;
-        }
-        true
+    pub fn verify(&self, remote: &SocketAddr, token: &ConnectionId) -> bool {
+        let expected = self.generate_mac(remote);
+        constant_time_eq(token, &expected)
     }
 }
-*/
-
-#[derive(Clone)]
-pub struct ConnectionInfo {
-    pub(crate) id: ConnectionId,
-    pub(crate) remote: SocketAddrV6,
-}
-
-const HANDSHAKE_SALT: [u8; 20] = [
-    0x9c, 0x10, 0x8f, 0x98, 0x52, 0x0a, 0x5c, 0x5c, 0x32, 0x96, 0x8e, 0x95, 0x0e, 0x8a, 0x2c, 0x5f,
-    0xe0, 0x6d, 0x6c, 0x38,
-];
 
 #[derive(Clone)]
 pub struct CryptoState {
-    secret: Vec<u8>,
+    secret: hkdf::Prk,
     key: Vec<u8>,
     iv: Vec<u8>,
 }
 
 impl CryptoState {
-    fn new(
-        digest: &'static digest::Algorithm,
-        cipher: &'static aead::Algorithm,
-        secret: Vec<u8>,
-    ) -> Self {
-        let secret_key = SigningKey::new(digest, &secret);
+    fn new(cipher: &'static aead::Algorithm, secret: hkdf::Prk) -> Self {
         let mut key = vec![0; cipher.key_len()];
-        qhkdf_expand(&secret_key, b"key", &mut key);
+        qhkdf_expand(&secret, b"key", &mut key);
         let mut iv = vec![0; cipher.nonce_len()];
-        qhkdf_expand(&secret_key, b"iv", &mut iv);
+        qhkdf_expand(&secret, b"iv", &mut iv);
         Self { secret, key, iv }
     }
 
     fn update(
         &self,
-        digest: &'static digest::Algorithm,
+        digest: hkdf::Algorithm,
         cipher: &'static aead::Algorithm,
         side: Side,
     ) -> CryptoState {
-        let secret_key = SigningKey::new(digest, &self.secret);
-        let mut new_secret = vec![0; digest.output_len];
+        let mut new_secret = vec![0; digest.len()];
         qhkdf_expand(
-            &secret_key,
+            &self.secret,
             if side == Side::Client {
                 b"client 1rtt"
             } else {
@@ -365,13 +578,15 @@ impl CryptoState {
             },
             &mut new_secret,
         );
-        Self::new(digest, cipher, new_secret)
+        Self::new(cipher, hkdf::Prk::new_less_safe(digest, &new_secret))
     }
 }
 
+/// 0-RTT keys, which only ever protect client-to-server packets
 #[derive(Clone)]
 pub struct ZeroRttCrypto {
     state: CryptoState,
+    header: HeaderKey,
     cipher: &'static aead::Algorithm,
 }
 
@@ -379,16 +594,136 @@ pub struct ZeroRttCrypto {
 pub struct CryptoContext {
     local: CryptoState,
     remote: CryptoState,
-    digest: &'static digest::Algorithm,
+    local_header: HeaderKey,
+    remote_header: HeaderKey,
+    digest: hkdf::Algorithm,
     cipher: &'static aead::Algorithm,
 }
 
+impl CryptoContext {
+    fn new(tls: &TlsSession, side: Side, secrets: &Secrets) -> Self {
+        let suite = tls.get_negotiated_ciphersuite().unwrap();
+        let (cipher, digest) = (suite.get_aead_alg(), hkdf_algorithm(suite));
+        let (local, remote) = if side == Side::Client {
+            (&secrets.client, &secrets.server)
+        } else {
+            (&secrets.server, &secrets.client)
+        };
+        Self::from_secrets(cipher, digest, local.clone(), remote.clone())
+    }
+
+    fn from_secrets(
+        cipher: &'static aead::Algorithm,
+        digest: hkdf::Algorithm,
+        local: hkdf::Prk,
+        remote: hkdf::Prk,
+    ) -> Self {
+        Self {
+            local_header: HeaderKey::new(cipher, &local),
+            remote_header: HeaderKey::new(cipher, &remote),
+            local: CryptoState::new(cipher, local),
+            remote: CryptoState::new(cipher, remote),
+            digest,
+            cipher,
+        }
+    }
+}
+
+/// A key concealing packet numbers, and the first byte of short headers, from on-path observers
+///
+/// Derived once per encryption level; unlike packet protection keys, these are unaffected by key updates.
+#[derive(Clone)]
+pub struct HeaderKey(Arc<aead::quic::HeaderProtectionKey>);
+
+impl HeaderKey {
+    fn new(cipher: &'static aead::Algorithm, secret: &hkdf::Prk) -> Self {
+        let alg = if cipher == &aead::AES_128_GCM {
+            &aead::quic::AES_128
+        } else if cipher == &aead::AES_256_GCM {
+            &aead::quic::AES_256
+        } else if cipher == &aead::CHACHA20_POLY1305 {
+            &aead::quic::CHACHA20
+        } else {
+            unreachable!("TLS 1.3 negotiated an unknown AEAD")
+        };
+        let mut key = vec![0; alg.key_len()];
+        hkdf_expand_label(secret, b"quic hp", &mut key);
+        HeaderKey(Arc::new(
+            aead::quic::HeaderProtectionKey::new(alg, &key).unwrap(),
+        ))
+    }
+
+    fn mask(&self, sample: &[u8]) -> [u8; 5] {
+        self.0.new_mask(sample).unwrap()
+    }
+
+    /// Conceal the header of an encrypted packet whose packet number begins at `pn_offset`
+    pub fn protect(&self, pn_offset: usize, packet: &mut [u8]) {
+        let long = packet[0] & 0x80 != 0;
+        let pn_len = if long {
+            4
+        } else {
+            PacketNumber::short_len(packet[0]).unwrap()
+        };
+        let sample = pn_offset + 4;
+        let mask = self.mask(&packet[sample..sample + HEADER_SAMPLE_SIZE]);
+        if !long {
+            packet[0] ^= mask[0] & SHORT_HEADER_PROTECTED_BITS;
+        }
+        let pn = &mut packet[pn_offset..pn_offset + pn_len];
+        for (x, m) in pn.iter_mut().zip(&mask[1..]) {
+            *x ^= m;
+        }
+    }
+
+    /// Reveal the header of a received packet, returning false if it's malformed
+    ///
+    /// Long headers carry their type in the clear, since it determines which keys to use, so only their packet
+    /// number is concealed.
+    pub fn unprotect(&self, pn_offset: usize, packet: &mut [u8]) -> bool {
+        let sample = pn_offset + 4;
+        if packet.len() < sample + HEADER_SAMPLE_SIZE {
+            return false;
+        }
+        let mask = self.mask(&packet[sample..sample + HEADER_SAMPLE_SIZE]);
+        let pn_len = if packet[0] & 0x80 != 0 {
+            4
+        } else {
+            packet[0] ^= mask[0] & SHORT_HEADER_PROTECTED_BITS;
+            match PacketNumber::short_len(packet[0]) {
+                Some(x) => x,
+                None => return false,
+            }
+        };
+        let pn = &mut packet[pn_offset..pn_offset + pn_len];
+        for (x, m) in pn.iter_mut().zip(&mask[1..]) {
+            *x ^= m;
+        }
+        true
+    }
+}
+
+/// Pad an unencrypted packet so that there's enough ciphertext to sample for header protection once it's encrypted
+pub fn pad_for_header_protection(pn_offset: usize, packet: &mut Vec<u8>) {
+    // Samples are taken as though the packet number were 4 bytes long
+    let min_len = pn_offset + 4 + HEADER_SAMPLE_SIZE - AEAD_TAG_SIZE;
+    if packet.len() < min_len {
+        packet.resize(min_len, 0); // PADDING
+    }
+}
+
+/// Number of bytes of ciphertext used to derive a header protection mask
+const HEADER_SAMPLE_SIZE: usize = 16;
+
 #[derive(Debug, Fail)]
 pub enum ConnectError {
     #[fail(display = "session ticket was malformed")]
     MalformedSession,
     #[fail(display = "TLS error: {}", _0)]
     Tls(TLSError),
+    /// The endpoint's socket can't send to addresses of this family, e.g. IPv6 from an IPv4 socket
+    #[fail(display = "unsupported address family for {}", _0)]
+    UnsupportedAddressFamily(SocketAddr),
 }
 
 impl From<TLSError> for ConnectError {
@@ -397,32 +732,65 @@ impl From<TLSError> for ConnectError {
     }
 }
 
-pub fn expanded_handshake_secret(prk: &SigningKey, label: &[u8]) -> Vec<u8> {
+pub fn expanded_handshake_secret(prk: &hkdf::Prk, label: &[u8]) -> Vec<u8> {
     let mut out = vec![0u8; digest::SHA256.output_len];
     qhkdf_expand(prk, label, &mut out);
     out
 }
 
-pub fn qhkdf_expand(key: &SigningKey, label: &[u8], out: &mut [u8]) {
+pub fn qhkdf_expand(key: &hkdf::Prk, label: &[u8], out: &mut [u8]) {
     let mut info = Vec::with_capacity(2 + 1 + 5 + out.len());
     info.put_u16_be(out.len() as u16);
     info.put_u8(5 + (label.len() as u8));
     info.extend_from_slice(b"QUIC ");
     info.extend_from_slice(&label);
-    hkdf::expand(key, &info, out);
+    key.expand(&[&info], Len(out.len()))
+        .unwrap()
+        .fill(out)
+        .unwrap();
+}
+
+/// TLS 1.3 HKDF-Expand-Label with an empty context, as used to derive header protection keys
+fn hkdf_expand_label(key: &hkdf::Prk, label: &[u8], out: &mut [u8]) {
+    const PREFIX: &[u8] = b"tls13 ";
+    let mut info = Vec::with_capacity(2 + 1 + PREFIX.len() + label.len() + 1);
+    info.put_u16_be(out.len() as u16);
+    info.put_u8((PREFIX.len() + label.len()) as u8);
+    info.extend_from_slice(PREFIX);
+    info.extend_from_slice(label);
+    info.put_u8(0);
+    key.expand(&[&info], Len(out.len()))
+        .unwrap()
+        .fill(out)
+        .unwrap();
+}
+
+/// Output length of an HKDF expansion
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// The HKDF variant matching a TLS 1.3 cipher suite's hash
+fn hkdf_algorithm(suite: &SupportedCipherSuite) -> hkdf::Algorithm {
+    if suite.get_hash() == &digest::SHA384 {
+        hkdf::HKDF_SHA384
+    } else {
+        hkdf::HKDF_SHA256
+    }
 }
 
-fn handshake_secret(conn_id: &ConnectionId) -> SigningKey {
-    let key = SigningKey::new(&digest::SHA256, &HANDSHAKE_SALT);
-    let mut buf = Vec::with_capacity(8);
-    buf.put_slice(conn_id);
-    hkdf::extract(&key, &buf)
+fn handshake_secret(version: Version, conn_id: &ConnectionId) -> hkdf::Prk {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, version.handshake_salt()).extract(conn_id)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use packet::PacketNumber;
+    use packet::{set_payload_length, types};
     use rand;
     use MAX_CID_SIZE;
 
@@ -439,10 +807,10 @@ mod test {
     }
 
     #[test]
-    fn handshake_crypto_roundtrip() {
+    fn initial_crypto_roundtrip() {
         let conn = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
-        let client = Crypto::new_handshake(&conn, Side::Client);
-        let server = Crypto::new_handshake(&conn, Side::Server);
+        let client = Crypto::new_initial(Version::Draft11, &conn, Side::Client);
+        let server = Crypto::new_initial(Version::Draft11, &conn, Side::Server);
 
         let mut buf = b"headerpayload".to_vec();
         client.encrypt(0, &mut buf, 6);
@@ -453,12 +821,78 @@ mod test {
         assert_eq!(&*payload, b"payload");
     }
 
+    #[test]
+    fn header_protection_roundtrip() {
+        let cipher = &aead::AES_128_GCM;
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &[0x42; 32]);
+        let key = HeaderKey::new(cipher, &secret);
+        let id = ConnectionId::random(&mut rand::thread_rng(), 8);
+        let mut packet = Vec::new();
+        Header::Short {
+            id,
+            number: PacketNumber::U16(0x1234),
+            key_phase: true,
+        }.encode(&mut packet);
+        packet.push(0x07); // PING
+        pad_for_header_protection(1 + id.len(), &mut packet);
+        packet.extend_from_slice(&[0xab; AEAD_TAG_SIZE]); // Stand-in for encryption
+        let original = packet.clone();
+
+        key.protect(1 + id.len(), &mut packet);
+        assert_eq!(
+            packet[0] & !SHORT_HEADER_PROTECTED_BITS,
+            original[0] & !SHORT_HEADER_PROTECTED_BITS
+        );
+        assert_eq!(packet[1..1 + id.len()], original[1..1 + id.len()]);
+        assert!(key.unprotect(1 + id.len(), &mut packet));
+        assert_eq!(packet, original);
+    }
+
+    #[test]
+    fn long_header_protection() {
+        let id = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
+        let client = Crypto::new_initial(Version::Draft11, &id, Side::Client);
+        let server = Crypto::new_initial(Version::Draft11, &id, Side::Server);
+
+        let mut buf = Vec::new();
+        Header::Long {
+            ty: types::INITIAL,
+            version: Version::Draft11,
+            source_id: id,
+            destination_id: id,
+            number: 0x0102_0304,
+        }.encode(&mut buf);
+        let header_len = buf.len();
+        buf.extend_from_slice(b"payload");
+        set_payload_length(&mut buf, header_len);
+        client.encrypt(0x0102_0304, &mut buf, header_len);
+        client.protect_header(header_len - 4, &mut buf);
+        assert_ne!(buf[header_len - 4..header_len], [1, 2, 3, 4]);
+        assert_eq!(buf[0], 0x80 | types::INITIAL);
+
+        let (mut packet, rest) =
+            Packet::decode(buf.into(), MAX_CID_SIZE, &[Version::Draft11]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(server.unprotect_long(&mut packet), Some(0x0102_0304));
+        assert_matches!(
+            packet.header,
+            Header::Long {
+                number: 0x0102_0304,
+                ..
+            }
+        );
+        server
+            .decrypt(0x0102_0304, &packet.header_data, &mut packet.payload)
+            .unwrap();
+        assert_eq!(&packet.payload[..], b"payload");
+    }
+
     #[test]
     fn key_derivation() {
         let id = ConnectionId::new(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
-        let digest = &digest::SHA256;
+        let digest = hkdf::HKDF_SHA256;
         let cipher = &aead::AES_128_GCM;
-        let hs_secret = handshake_secret(&id);
+        let hs_secret = handshake_secret(Version::Draft11, &id);
         let client_secret = expanded_handshake_secret(&hs_secret, b"client hs");
         assert_eq!(
             &client_secret[..],
@@ -468,7 +902,8 @@ mod test {
                 0xb5, 0x23, 0x59, 0x1d
             ]
         );
-        let client_state = CryptoState::new(digest, cipher, client_secret);
+        let client_state =
+            CryptoState::new(cipher, hkdf::Prk::new_less_safe(digest, &client_secret));
         assert_eq!(
             &client_state.key[..],
             [
@@ -490,7 +925,8 @@ mod test {
                 0x83, 0x04, 0x83, 0xd6
             ]
         );
-        let server_state = CryptoState::new(digest, cipher, server_secret);
+        let server_state =
+            CryptoState::new(cipher, hkdf::Prk::new_less_safe(digest, &server_secret));
         assert_eq!(
             &server_state.key[..],
             [
@@ -504,5 +940,3 @@ mod test {
         );
     }
 }
-
-//pub type SessionTicketBuffer = Arc<Mutex<Vec<Result<SslSession, ()>>>>;
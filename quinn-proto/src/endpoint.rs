@@ -1,6 +1,7 @@
 use std::collections::VecDeque;
-use std::net::SocketAddrV6;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{cmp, io, mem};
 
 use bytes::{Bytes, BytesMut};
@@ -11,76 +12,257 @@ use slab::Slab;
 use slog::{self, Logger};
 
 use coding::BufMutExt;
+use congestion::CongestionAlgorithm;
 use connection::{
-    state, Connection, ConnectionError, ConnectionHandle, ReadError, State, WriteError,
+    parse_initial, state, Connection, ConnectionError, ConnectionHandle, ReadError,
+    SendDatagramError, SpaceId, State, WriteError,
+};
+use crypto::{
+    self, reset_token_for, ClientConfig, ClientHello, ConnectError, CookieFactory, Crypto,
+    ServerConfig,
 };
-use crypto::{self, reset_token_for, ClientConfig, ConnectError, Crypto, ServerConfig};
 use packet::{
     set_payload_length, types, ConnectionId, Header, HeaderError, Packet, PacketNumber,
     AEAD_TAG_SIZE,
 };
 use {
-    frame, Directionality, Side, StreamId, TransportError, MAX_CID_SIZE, MIN_INITIAL_SIZE, MIN_MTU,
-    RESET_TOKEN_SIZE, VERSION,
+    Directionality, EcnCodepoint, EcnCounts, Side, StreamId, TransportError, Version, MAX_CID_SIZE,
+    MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE,
 };
 
-/// Parameters governing the core QUIC state machine.
+/// Parameters governing the core QUIC state machine, shared by all connections of an endpoint.
+///
+/// Settings that may differ between connections live in `TransportConfig`.
 pub struct Config {
-    /// Maximum number of peer-initiated bidirectional streams that may exist at one time.
-    pub max_remote_bi_streams: u16,
-    /// Maximum number of peer-initiated  unidirectional streams that may exist at one time.
-    pub max_remote_uni_streams: u16,
-    /// Maximum duration of inactivity to accept before timing out the connection (s).
-    ///
-    /// Maximum value is 600 seconds. The actual value used is the minimum of this and the peer's own idle timeout. 0 for none.
-    pub idle_timeout: u16,
-    /// Maximum number of bytes the peer may transmit on any one stream before becoming blocked.
-    ///
-    /// This should be set to at least the expected connection latency multiplied by the maximum desired
-    /// throughput. Setting this smaller than `receive_window` helps ensure that a single stream doesn't monopolize
-    /// receive buffers, which may otherwise occur if the application chooses not to read from a large stream for a time
-    /// while still requiring data on other streams.
-    pub stream_receive_window: u32,
-    /// Maximum number of bytes the peer may transmit across all streams of a connection before becoming blocked.
-    ///
-    /// This should be set to at least the expected connection latency multiplied by the maximum desired
-    /// throughput. Larger values can be useful to allow maximum throughput within a stream while another is blocked.
-    pub receive_window: u32,
     /// Maximum number of incoming connections to buffer.
     ///
     /// Calling `Endpoint::accept` removes a connection from the buffer, so this does not need to be large.
     pub accept_buffer: u32,
 
-    /// Maximum number of tail loss probes before an RTO fires.
-    pub max_tlps: u32,
-    /// Maximum reordering in packet number space before FACK style loss detection considers a packet lost.
-    pub reordering_threshold: u32,
-    /// Maximum reordering in time space before time based loss detection considers a packet lost. 0.16 format
-    pub time_reordering_fraction: u16,
-    /// Whether time based loss detection is in use. If false, uses FACK style loss detection.
-    pub using_time_loss_detection: bool,
-    /// Minimum time in the future a tail loss probe alarm may be set for (μs).
-    pub min_tlp_timeout: u64,
-    /// Minimum time in the future an RTO alarm may be set for (μs).
-    pub min_rto_timeout: u64,
-    /// The length of the peer’s delayed ack timer (μs).
-    pub delayed_ack_timeout: u64,
-    /// The default RTT used before an RTT sample is taken (μs)
-    pub default_initial_rtt: u64,
-
-    /// The default max packet size used for calculating default and minimum congestion windows.
-    pub default_mss: u64,
-    /// Default limit on the amount of outstanding data in bytes.
-    pub initial_window: u64,
-    /// Default minimum congestion window.
-    pub minimum_window: u64,
-    /// Reduction in congestion window when a new loss event is detected. 0.16 format
-    pub loss_reduction_factor: u16,
+    /// Number of spare connection IDs to issue to the peer once a connection is established.
+    ///
+    /// Spare IDs let the peer switch to an ID that can't be linked to the previous one, e.g. when its address changes.
+    pub spare_connection_ids: u8,
+
+    /// Whether to require clients to prove ownership of their address before committing any state to them.
+    ///
+    /// When enabled, the server answers each new client's first Initial packet with a Retry carrying an address
+    /// validation token, costing an extra round trip but protecting against floods from spoofed source addresses.
+    pub use_stateless_retry: bool,
+
+    /// Whether to accept 0-RTT data from clients resuming a previous session.
+    ///
+    /// Early data may be replayed by an attacker, so only enable this if the application protocol is prepared to
+    /// handle that. When disabled, clients retransmit any early data once the handshake completes.
+    pub accept_zero_rtt: bool,
+
+    /// QUIC versions to accept from peers, most preferred first.
+    ///
+    /// Clients open connections using the first version listed, falling back to later ones if the server asks them to.
+    pub supported_versions: Vec<Version>,
 
     pub tls_server_config: Arc<ServerConfig>,
+
+    /// Transport configuration for incoming connections, unless `transport_selector` picks another.
+    pub transport: Arc<TransportConfig>,
+    /// Chooses the transport configuration for each incoming connection, if set.
+    pub transport_selector: Option<Arc<dyn TransportConfigSelector>>,
 }
 
 impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accept_buffer: 1024,
+
+            spare_connection_ids: 4,
+            use_stateless_retry: false,
+
+            accept_zero_rtt: false,
+
+            supported_versions: Version::ALL.to_vec(),
+
+            tls_server_config: Arc::new(crypto::build_server_config()),
+
+            transport: Arc::new(TransportConfig::default()),
+            transport_selector: None,
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.supported_versions.is_empty() {
+            return Err(ConfigError::NoSupportedVersions);
+        }
+        self.transport.validate()
+    }
+}
+
+/// Helper for constructing a valid `Config`
+///
+/// Starts out with the same settings as `Config::default()`. Each setter corresponds to the `Config` field of the same
+/// name.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn accept_buffer(&mut self, value: u32) -> &mut Self {
+        self.config.accept_buffer = value;
+        self
+    }
+
+    pub fn spare_connection_ids(&mut self, value: u8) -> &mut Self {
+        self.config.spare_connection_ids = value;
+        self
+    }
+
+    pub fn use_stateless_retry(&mut self, value: bool) -> &mut Self {
+        self.config.use_stateless_retry = value;
+        self
+    }
+
+    pub fn accept_zero_rtt(&mut self, value: bool) -> &mut Self {
+        self.config.accept_zero_rtt = value;
+        self
+    }
+
+    pub fn supported_versions(&mut self, value: Vec<Version>) -> &mut Self {
+        self.config.supported_versions = value;
+        self
+    }
+
+    pub fn tls_server_config(&mut self, value: Arc<ServerConfig>) -> &mut Self {
+        self.config.tls_server_config = value;
+        self
+    }
+
+    pub fn transport(&mut self, value: Arc<TransportConfig>) -> &mut Self {
+        self.config.transport = value;
+        self
+    }
+
+    pub fn transport_selector(&mut self, value: Arc<dyn TransportConfigSelector>) -> &mut Self {
+        self.config.transport_selector = Some(value);
+        self
+    }
+
+    /// Check the settings for consistency and produce the configuration
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Reasons a `ConfigBuilder` or `TransportConfigBuilder` may reject its settings
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Fail)]
+pub enum ConfigError {
+    #[fail(display = "no supported QUIC versions configured")]
+    NoSupportedVersions,
+    #[fail(display = "idle timeout longer than 600 seconds")]
+    IdleTimeoutTooLong,
+    #[fail(display = "stream receive window larger than its maximum")]
+    StreamReceiveWindowExceedsMax,
+    #[fail(display = "receive window larger than its maximum")]
+    ReceiveWindowExceedsMax,
+    #[fail(display = "initial RTT of zero")]
+    ZeroInitialRtt,
+    #[fail(display = "ACK delay exponent greater than 20")]
+    AckDelayExponentTooLarge,
+    #[fail(display = "maximum segment size of zero")]
+    ZeroMss,
+    #[fail(display = "minimum congestion window larger than the initial window")]
+    MinimumWindowExceedsInitial,
+    #[fail(display = "maximum MTU smaller than the 1232 bytes every QUIC path must support")]
+    MtuTooSmall,
+}
+
+/// Parameters governing the behavior of an individual connection.
+///
+/// Outgoing connections use the configuration in the `ClientConfig` passed to `Endpoint::connect`. Incoming connections
+/// use `Config::transport`, or whatever `Config::transport_selector` chooses. Construct with `TransportConfigBuilder`,
+/// which documents each setting.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    pub(crate) max_remote_bi_streams: u16,
+    pub(crate) max_remote_uni_streams: u16,
+    /// Seconds; 0 for none
+    pub(crate) idle_timeout: u16,
+    pub(crate) stream_receive_window: u32,
+    pub(crate) max_stream_receive_window: u32,
+    pub(crate) receive_window: u32,
+    pub(crate) max_receive_window: u32,
+
+    pub(crate) max_tlps: u32,
+    pub(crate) reordering_threshold: u32,
+    /// 0.16 format
+    pub(crate) time_reordering_fraction: u16,
+    pub(crate) using_time_loss_detection: bool,
+    /// μs
+    pub(crate) min_tlp_timeout: u64,
+    /// μs
+    pub(crate) min_rto_timeout: u64,
+    /// μs; not yet consulted by loss detection
+    #[allow(dead_code)]
+    pub(crate) delayed_ack_timeout: u64,
+    /// μs
+    pub(crate) default_initial_rtt: u64,
+    /// Exponent applied to the delay in ACK frames we send
+    pub(crate) ack_delay_exponent: u8,
+
+    pub(crate) default_mss: u64,
+    pub(crate) initial_window: u64,
+    pub(crate) minimum_window: u64,
+    /// 0.16 format
+    pub(crate) loss_reduction_factor: u16,
+    pub(crate) congestion_controller: CongestionAlgorithm,
+
+    pub(crate) max_mtu: u16,
+    pub(crate) ecn: bool,
+    /// 0 to refuse datagrams
+    pub(crate) max_datagram_frame_size: u16,
+    /// 0 for no limit
+    pub(crate) key_update_packets: u64,
+    /// 0 for no limit
+    pub(crate) key_update_bytes: u64,
+}
+
+impl TransportConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.idle_timeout > 600 {
+            return Err(ConfigError::IdleTimeoutTooLong);
+        }
+        if self.stream_receive_window > self.max_stream_receive_window {
+            return Err(ConfigError::StreamReceiveWindowExceedsMax);
+        }
+        if self.receive_window > self.max_receive_window {
+            return Err(ConfigError::ReceiveWindowExceedsMax);
+        }
+        if self.default_initial_rtt == 0 {
+            return Err(ConfigError::ZeroInitialRtt);
+        }
+        if self.ack_delay_exponent > 20 {
+            return Err(ConfigError::AckDelayExponentTooLarge);
+        }
+        if self.default_mss == 0 {
+            return Err(ConfigError::ZeroMss);
+        }
+        if self.minimum_window > self.initial_window {
+            return Err(ConfigError::MinimumWindowExceedsInitial);
+        }
+        if self.max_mtu < MIN_MTU {
+            return Err(ConfigError::MtuTooSmall);
+        }
+        Ok(())
+    }
+}
+
+impl Default for TransportConfig {
     fn default() -> Self {
         const EXPECTED_RTT: u32 = 100; // ms
         const MAX_STREAM_BANDWIDTH: u32 = 12500 * 1000; // bytes/s
@@ -90,9 +272,10 @@ impl Default for Config {
             max_remote_bi_streams: 0,
             max_remote_uni_streams: 0,
             idle_timeout: 10,
-            stream_receive_window: STREAM_RWND,
-            receive_window: 8 * STREAM_RWND,
-            accept_buffer: 1024,
+            stream_receive_window: STREAM_RWND / 16,
+            max_stream_receive_window: STREAM_RWND,
+            receive_window: STREAM_RWND / 2,
+            max_receive_window: 8 * STREAM_RWND,
 
             max_tlps: 2,
             reordering_threshold: 3,
@@ -102,17 +285,244 @@ impl Default for Config {
             min_rto_timeout: 200 * 1000,
             delayed_ack_timeout: 25 * 1000,
             default_initial_rtt: EXPECTED_RTT as u64 * 1000,
+            ack_delay_exponent: 3,
 
             default_mss: 1460,
             initial_window: 10 * 1460,
             minimum_window: 2 * 1460,
             loss_reduction_factor: 0x8000, // 1/2
+            congestion_controller: CongestionAlgorithm::NewReno,
 
-            tls_server_config: Arc::new(crypto::build_server_config()),
+            max_mtu: 1452, // 1500-byte Ethernet frames, less IPv6 and UDP headers
+            ecn: true,
+            max_datagram_frame_size: u16::MAX, // i.e. limited only by packet size
+
+            key_update_packets: 0,
+            key_update_bytes: 0,
         }
     }
 }
 
+/// Helper for constructing a valid `TransportConfig`
+///
+/// Starts out with the same settings as `TransportConfig::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct TransportConfigBuilder {
+    config: TransportConfig,
+}
+
+impl TransportConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum number of peer-initiated bidirectional streams that may exist at one time.
+    pub fn max_remote_bi_streams(&mut self, value: u16) -> &mut Self {
+        self.config.max_remote_bi_streams = value;
+        self
+    }
+
+    /// Maximum number of peer-initiated unidirectional streams that may exist at one time.
+    pub fn max_remote_uni_streams(&mut self, value: u16) -> &mut Self {
+        self.config.max_remote_uni_streams = value;
+        self
+    }
+
+    /// Maximum duration of inactivity to accept before timing out the connection, rounded up to whole seconds.
+    ///
+    /// Maximum value is 600 seconds. The actual value used is the minimum of this and the peer's own idle timeout. 0 for
+    /// none.
+    pub fn idle_timeout(&mut self, value: Duration) -> &mut Self {
+        let secs = value.as_secs() + if value.subsec_nanos() != 0 { 1 } else { 0 };
+        self.config.idle_timeout = cmp::min(secs, u64::from(u16::MAX)) as u16;
+        self
+    }
+
+    /// Number of bytes the peer may initially transmit on any one stream before becoming blocked.
+    ///
+    /// The window grows automatically, up to `max_stream_receive_window`, while the application reads data from the
+    /// stream faster than it arrives. Small values keep memory use down on streams that are read slowly.
+    pub fn stream_receive_window(&mut self, value: u32) -> &mut Self {
+        self.config.stream_receive_window = value;
+        self
+    }
+
+    /// Maximum number of bytes the peer may transmit on any one stream before becoming blocked.
+    ///
+    /// This should be set to at least the expected connection latency multiplied by the maximum desired
+    /// throughput. Setting this smaller than `max_receive_window` helps ensure that a single stream doesn't monopolize
+    /// receive buffers, which may otherwise occur if the application chooses not to read from a large stream for a time
+    /// while still requiring data on other streams.
+    pub fn max_stream_receive_window(&mut self, value: u32) -> &mut Self {
+        self.config.max_stream_receive_window = value;
+        self
+    }
+
+    /// Number of bytes the peer may initially transmit across all streams of a connection before becoming blocked.
+    ///
+    /// Grows automatically up to `max_receive_window`, like `stream_receive_window`.
+    pub fn receive_window(&mut self, value: u32) -> &mut Self {
+        self.config.receive_window = value;
+        self
+    }
+
+    /// Maximum number of bytes the peer may transmit across all streams of a connection before becoming blocked.
+    ///
+    /// This should be set to at least the expected connection latency multiplied by the maximum desired
+    /// throughput. Larger values can be useful to allow maximum throughput within a stream while another is blocked.
+    pub fn max_receive_window(&mut self, value: u32) -> &mut Self {
+        self.config.max_receive_window = value;
+        self
+    }
+
+    /// Maximum number of tail loss probes before an RTO fires.
+    pub fn max_tlps(&mut self, value: u32) -> &mut Self {
+        self.config.max_tlps = value;
+        self
+    }
+
+    /// Maximum reordering in packet number space before FACK style loss detection considers a packet lost.
+    pub fn reordering_threshold(&mut self, value: u32) -> &mut Self {
+        self.config.reordering_threshold = value;
+        self
+    }
+
+    /// Maximum reordering in time space before time based loss detection considers a packet lost. 0.16 format
+    pub fn time_reordering_fraction(&mut self, value: u16) -> &mut Self {
+        self.config.time_reordering_fraction = value;
+        self
+    }
+
+    /// Whether time based loss detection is in use. If false, uses FACK style loss detection.
+    pub fn using_time_loss_detection(&mut self, value: bool) -> &mut Self {
+        self.config.using_time_loss_detection = value;
+        self
+    }
+
+    /// Minimum time in the future a tail loss probe alarm may be set for.
+    pub fn min_tlp_timeout(&mut self, value: Duration) -> &mut Self {
+        self.config.min_tlp_timeout = micros_from(value);
+        self
+    }
+
+    /// Minimum time in the future an RTO alarm may be set for.
+    pub fn min_rto_timeout(&mut self, value: Duration) -> &mut Self {
+        self.config.min_rto_timeout = micros_from(value);
+        self
+    }
+
+    /// The length of the peer’s delayed ack timer.
+    pub fn delayed_ack_timeout(&mut self, value: Duration) -> &mut Self {
+        self.config.delayed_ack_timeout = micros_from(value);
+        self
+    }
+
+    /// The RTT used before an RTT sample is taken. Must be nonzero.
+    pub fn default_initial_rtt(&mut self, value: Duration) -> &mut Self {
+        self.config.default_initial_rtt = micros_from(value);
+        self
+    }
+
+    /// Exponent the peer must apply to the delays in our ACK frames, trading precision for compactness.
+    ///
+    /// At most 20.
+    pub fn ack_delay_exponent(&mut self, value: u8) -> &mut Self {
+        self.config.ack_delay_exponent = value;
+        self
+    }
+
+    /// The default max packet size used for calculating default and minimum congestion windows. Must be nonzero.
+    pub fn default_mss(&mut self, value: u64) -> &mut Self {
+        self.config.default_mss = value;
+        self
+    }
+
+    /// Default limit on the amount of outstanding data in bytes.
+    pub fn initial_window(&mut self, value: u64) -> &mut Self {
+        self.config.initial_window = value;
+        self
+    }
+
+    /// Default minimum congestion window. At most `initial_window`.
+    pub fn minimum_window(&mut self, value: u64) -> &mut Self {
+        self.config.minimum_window = value;
+        self
+    }
+
+    /// Reduction in congestion window when a new loss event is detected under NewReno. 0.16 format
+    pub fn loss_reduction_factor(&mut self, value: u16) -> &mut Self {
+        self.config.loss_reduction_factor = value;
+        self
+    }
+
+    /// Congestion control algorithm for new connections.
+    pub fn congestion_controller(&mut self, value: CongestionAlgorithm) -> &mut Self {
+        self.config.congestion_controller = value;
+        self
+    }
+
+    /// Largest UDP payload to send, should the path and peer support it.
+    ///
+    /// Connections start out sending packets of 1232 bytes, and probe for larger sizes up to this limit once
+    /// established. Set this to 1232, the minimum, to disable path MTU discovery. `minimum_window` should be at least
+    /// twice this.
+    pub fn max_mtu(&mut self, value: u16) -> &mut Self {
+        self.config.max_mtu = value;
+        self
+    }
+
+    /// Whether to mark outgoing packets as ECN-capable, allowing routers to signal congestion without dropping them.
+    ///
+    /// Marking stops automatically if the peer's acknowledgements show that the markings aren't making it through.
+    pub fn ecn(&mut self, value: bool) -> &mut Self {
+        self.config.ecn = value;
+        self
+    }
+
+    /// Largest DATAGRAM frame, including its type and length fields, the peer may send. 0 to refuse datagrams.
+    ///
+    /// Datagrams are unreliable and unordered, but subject to the connection's congestion control.
+    pub fn max_datagram_frame_size(&mut self, value: u16) -> &mut Self {
+        self.config.max_datagram_frame_size = value;
+        self
+    }
+
+    /// Number of 1-RTT packets to send before replacing the keys that protect them. 0 for no limit.
+    ///
+    /// Keys are always replaced before the negotiated cipher's confidentiality limit is reached, regardless of this
+    /// setting.
+    pub fn key_update_packets(&mut self, value: u64) -> &mut Self {
+        self.config.key_update_packets = value;
+        self
+    }
+
+    /// Number of 1-RTT bytes to send before replacing the keys that protect them. 0 for no limit.
+    pub fn key_update_bytes(&mut self, value: u64) -> &mut Self {
+        self.config.key_update_bytes = value;
+        self
+    }
+
+    /// Check the settings for consistency and produce the configuration
+    pub fn build(self) -> Result<TransportConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Chooses the transport configuration for incoming connections
+///
+/// Consulted when a client's first packet arrives, before the handshake proceeds, so the choice can inform the
+/// transport parameters sent to the client.
+pub trait TransportConfigSelector: Send + Sync {
+    /// Choose a configuration for a client that requested `server_name` and offered `protocols`, as found in its TLS
+    /// ClientHello, or `None` to use `Config::transport`
+    fn select(
+        &self,
+        server_name: Option<&str>,
+        protocols: &[&[u8]],
+    ) -> Option<Arc<TransportConfig>>;
+}
+
 /// The main entry point to the library
 ///
 /// This object performs no I/O whatsoever. Instead, it generates a stream of I/O operations for a backend to perform
@@ -121,7 +531,7 @@ pub struct Endpoint {
     pub(crate) ctx: Context,
     connection_ids_initial: FnvHashMap<ConnectionId, ConnectionHandle>,
     connection_ids: FnvHashMap<ConnectionId, ConnectionHandle>,
-    connection_remotes: FnvHashMap<SocketAddrV6, ConnectionHandle>,
+    connection_remotes: FnvHashMap<SocketAddr, ConnectionHandle>,
     pub(crate) connections: Slab<Connection>,
 }
 
@@ -130,7 +540,6 @@ pub struct Context {
     pub rng: OsRng,
     pub config: Arc<Config>,
     pub io: VecDeque<Io>,
-    // pub session_ticket_buffer: SessionTicketBuffer,
     pub events: VecDeque<(ConnectionHandle, Event)>,
     pub incoming: VecDeque<ConnectionHandle>,
     pub incoming_handshakes: usize,
@@ -146,7 +555,7 @@ impl Context {
     }
 }
 
-const LOCAL_ID_LEN: usize = 8;
+pub(crate) const LOCAL_ID_LEN: usize = 8;
 
 /// Information that should be preserved between restarts for server endpoints.
 ///
@@ -188,6 +597,8 @@ pub enum EndpointError {
     ProtocolTooLong(Box<[u8]>),
     #[fail(display = "invalid DNS name: {}", _0)]
     InvalidDnsName(String),
+    #[fail(display = "invalid configuration: {}", _0)]
+    Config(ConfigError),
 }
 
 impl From<crypto::TLSError> for EndpointError {
@@ -202,6 +613,7 @@ impl Endpoint {
         config: Config,
         listen: Option<ListenKeys>,
     ) -> Result<Self, EndpointError> {
+        config.validate().map_err(EndpointError::Config)?;
         let rng = OsRng::new().unwrap();
         let config = Arc::new(config);
         Ok(Self {
@@ -210,7 +622,6 @@ impl Endpoint {
                 rng,
                 config,
                 io: VecDeque::new(),
-                // session_ticket_buffer,
                 initial_packet_number: distributions::Uniform::from(0..2u64.pow(32) - 1024),
                 events: VecDeque::new(),
                 dirty_conns: FnvHashSet::default(),
@@ -266,41 +677,65 @@ impl Endpoint {
     }
 
     /// Process an incoming UDP datagram
-    pub fn handle(&mut self, now: u64, remote: SocketAddrV6, mut data: BytesMut) {
+    ///
+    /// `ecn` should be the ECN codepoint the datagram was marked with, if any and if the platform can report it.
+    pub fn handle(
+        &mut self,
+        now: u64,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        mut data: BytesMut,
+    ) {
         let datagram_len = data.len();
         while !data.is_empty() {
-            let (packet, rest) = match Packet::decode(data, LOCAL_ID_LEN) {
-                Ok(x) => x,
-                Err(HeaderError::UnsupportedVersion {
-                    source,
-                    destination,
-                }) => {
-                    if !self.listen() {
-                        debug!(self.ctx.log, "dropping packet with unsupported version");
-                        return;
+            if data[0] & 0x80 == 0 && data.len() > LOCAL_ID_LEN {
+                // Short header; undo header protection so the packet number can be decoded
+                let id = ConnectionId::new(&data[1..1 + LOCAL_ID_LEN]);
+                if let Some(&conn) = self.connection_ids.get(&id) {
+                    if let Some(ref crypto) = self.connections[conn.0].space(SpaceId::Data).crypto {
+                        if !crypto.unprotect_header(1 + LOCAL_ID_LEN, &mut data) {
+                            trace!(self.ctx.log, "dropping malformed protected packet"; "connection" => %id);
+                            return;
+                        }
                     }
-                    trace!(self.ctx.log, "sending version negotiation");
-                    // Negotiate versions
-                    let mut buf = Vec::<u8>::new();
-                    Header::VersionNegotiate {
-                        ty: self.ctx.rng.gen(),
-                        source_id: destination,
-                        destination_id: source,
-                    }.encode(&mut buf);
-                    buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                    buf.write(VERSION); // supported version
-                    self.ctx.io.push_back(Io::Transmit {
-                        destination: remote,
-                        packet: buf.into(),
-                    });
-                    return;
-                }
-                Err(e) => {
-                    trace!(self.ctx.log, "unable to process packet"; "reason" => %e);
-                    return;
                 }
-            };
-            self.handle_packet(now, remote, packet, datagram_len);
+            }
+            let (packet, rest) =
+                match Packet::decode(data, LOCAL_ID_LEN, &self.ctx.config.supported_versions) {
+                    Ok(x) => x,
+                    Err(HeaderError::UnsupportedVersion {
+                        source,
+                        destination,
+                    }) => {
+                        if !self.listen() {
+                            debug!(self.ctx.log, "dropping packet with unsupported version");
+                            return;
+                        }
+                        trace!(self.ctx.log, "sending version negotiation");
+                        // Negotiate versions
+                        let mut buf = Vec::<u8>::new();
+                        Header::VersionNegotiate {
+                            ty: self.ctx.rng.gen(),
+                            source_id: destination,
+                            destination_id: source,
+                        }.encode(&mut buf);
+                        buf.write::<u32>(0x0a1a_2a3a); // reserved version
+                        for version in &self.ctx.config.supported_versions {
+                            buf.write(version.to_wire()); // supported version
+                        }
+                        self.ctx.io.push_back(Io::Transmit {
+                            destination: remote,
+                            ecn: None,
+                            packet: buf.into(),
+                        });
+                        return;
+                    }
+                    Err(e) => {
+                        trace!(self.ctx.log, "unable to process packet"; "reason" => %e);
+                        return;
+                    }
+                };
+            self.handle_packet(now, remote, ecn, packet, datagram_len);
             data = rest;
         }
     }
@@ -308,7 +743,8 @@ impl Endpoint {
     fn handle_packet(
         &mut self,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
         packet: Packet,
         datagram_len: usize,
     ) {
@@ -318,47 +754,57 @@ impl Endpoint {
 
         let dest_id = packet.header.destination_id();
         if let Some(&conn) = self.connection_ids.get(&dest_id) {
-            self.handle_connected(now, conn, remote, packet);
+            self.handle_connected(now, conn, remote, ecn, packet);
             return;
         }
         if let Some(&conn) = self.connection_ids_initial.get(&dest_id) {
-            self.handle_connected(now, conn, remote, packet);
+            self.handle_connected(now, conn, remote, ecn, packet);
             return;
         }
         if let Some(&conn) = self.connection_remotes.get(&remote) {
-            if let Some(token) = self.connections[conn.0].params.stateless_reset_token {
-                if packet.payload.len() >= 16
-                    && packet.payload[packet.payload.len() - 16..] == token
+            let is_reset = packet.payload.len() >= RESET_TOKEN_SIZE && {
+                let token = &packet.payload[packet.payload.len() - RESET_TOKEN_SIZE..];
+                let c = &self.connections[conn.0];
+                c.params.stateless_reset_token.is_some_and(|x| x == token)
+                    || c.rem_cids.values().any(|x| x.reset_token == token)
+            };
+            if is_reset {
+                if !self.connections[conn.0]
+                    .state
+                    .as_ref()
+                    .unwrap()
+                    .is_drained()
                 {
-                    if !self.connections[conn.0]
-                        .state
-                        .as_ref()
-                        .unwrap()
-                        .is_drained()
-                    {
-                        debug!(self.ctx.log, "got stateless reset"; "connection" => %self.connections[conn.0].local_id);
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::LossDetection,
-                        });
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::Close,
-                        });
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::Idle,
-                        });
-                        self.ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: ConnectionError::Reset,
-                            },
-                        ));
-                        self.connections[conn.0].state = Some(State::Drained);
-                    }
-                    return;
+                    debug!(self.ctx.log, "got stateless reset"; "connection" => %self.connections[conn.0].local_id);
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::LossDetection,
+                    });
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Close,
+                    });
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Idle,
+                    });
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Pacing,
+                    });
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::PathValidation,
+                    });
+                    self.ctx.events.push_back((
+                        conn,
+                        Event::ConnectionLost {
+                            reason: ConnectionError::Reset,
+                        },
+                    ));
+                    self.connections[conn.0].state = Some(State::Drained);
                 }
+                return;
             }
         }
 
@@ -371,17 +817,13 @@ impl Endpoint {
             return;
         }
         let key_phase = packet.header.key_phase();
-        let Packet {
-            header_data,
-            header,
-            payload,
-        } = packet;
         if let Header::Long {
             ty,
-            ref destination_id,
-            ref source_id,
-            number,
-        } = header
+            version,
+            destination_id,
+            source_id,
+            ..
+        } = packet.header
         {
             match ty {
                 types::INITIAL => {
@@ -389,11 +831,11 @@ impl Endpoint {
                         self.handle_initial(
                             now,
                             remote,
-                            destination_id.clone(),
-                            source_id.clone(),
-                            number,
-                            &header_data,
-                            payload,
+                            ecn,
+                            version,
+                            destination_id,
+                            source_id,
+                            packet,
                         );
                     } else {
                         debug!(
@@ -404,15 +846,15 @@ impl Endpoint {
                     }
                     return;
                 }
-                /*types::ZERO_RTT => {
+                types::ZERO_RTT => {
                     // MAY buffer a limited amount
                     trace!(
                         self.ctx.log,
                         "dropping 0-RTT packet for unknown connection {connection}",
-                        connection = destination_id.clone()
+                        connection = destination_id
                     );
                     return;
-                }*/
+                }
                 _ => {
                     debug!(self.ctx.log, "ignoring packet for unknown connection {connection} with unexpected type {type:02x}",
                            connection=destination_id, type=ty);
@@ -431,7 +873,7 @@ impl Endpoint {
             // Bound padding size to at most 8 bytes larger than input to mitigate amplification attacks
             let padding = self.ctx.rng.gen_range(
                 0,
-                cmp::max(RESET_TOKEN_SIZE + 8, payload.len()) - RESET_TOKEN_SIZE,
+                cmp::max(RESET_TOKEN_SIZE + 8, packet.payload.len()) - RESET_TOKEN_SIZE,
             );
             buf.reserve_exact(1 + MAX_CID_SIZE + 1 + padding + RESET_TOKEN_SIZE);
             Header::Short {
@@ -450,6 +892,7 @@ impl Endpoint {
             ));
             self.ctx.io.push_back(Io::Transmit {
                 destination: remote,
+                ecn: None,
                 packet: buf.into(),
             });
         } else {
@@ -460,29 +903,57 @@ impl Endpoint {
         }
     }
 
+    /// Inform the endpoint that its local address has changed, e.g. due to switching networks
+    ///
+    /// Established outgoing connections switch to fresh connection IDs where possible and promptly send a packet so that
+    /// their peers learn the new address.
+    pub fn handle_rebind(&mut self) {
+        for (i, conn) in self.connections.iter_mut() {
+            if conn.side != Side::Client {
+                continue;
+            }
+            if let Some(State::Established(_)) = conn.state {
+                conn.handle_rebind();
+                self.ctx.dirty_conns.insert(ConnectionHandle(i));
+            }
+        }
+    }
+
     /// Initiate a connection
     pub fn connect(
         &mut self,
-        remote: SocketAddrV6,
-        config: &Arc<ClientConfig>,
+        remote: SocketAddr,
+        config: &ClientConfig,
         server_name: &str,
     ) -> Result<ConnectionHandle, ConnectError> {
         let local_id = ConnectionId::random(&mut self.ctx.rng, LOCAL_ID_LEN as u8);
         let remote_id = ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE as u8);
         trace!(self.ctx.log, "initial dcid"; "value" => %remote_id);
-        let conn = self.add_connection(remote_id, local_id, remote_id, remote, Side::Client);
+        let version = self.ctx.config.supported_versions[0];
+        let conn = self.add_connection(
+            version,
+            remote_id,
+            local_id,
+            remote_id,
+            remote,
+            Side::Client,
+            config.transport.clone(),
+        );
         self.connections[conn.0].connect(&self.ctx, config, server_name)?;
         self.ctx.dirty_conns.insert(conn);
         Ok(conn)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_connection(
         &mut self,
+        version: Version,
         initial_id: ConnectionId,
         local_id: ConnectionId,
         remote_id: ConnectionId,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         side: Side,
+        transport: Arc<TransportConfig>,
     ) -> ConnectionHandle {
         debug_assert!(!local_id.is_empty());
         let packet_num = self.ctx.gen_initial_packet_num();
@@ -490,13 +961,14 @@ impl Endpoint {
             let entry = self.connections.vacant_entry();
             let conn = ConnectionHandle(entry.key());
             entry.insert(Connection::new(
+                version,
                 initial_id,
                 local_id,
                 remote_id,
                 remote,
                 packet_num.into(),
                 side,
-                &self.ctx.config,
+                transport,
                 conn,
             ));
             conn
@@ -509,21 +981,58 @@ impl Endpoint {
     fn handle_initial(
         &mut self,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        version: Version,
         dest_id: ConnectionId,
         source_id: ConnectionId,
-        packet_number: u32,
-        header: &[u8],
-        mut payload: BytesMut,
+        mut packet: Packet,
     ) {
-        let crypto = Crypto::new_handshake(&dest_id, Side::Server);
+        let crypto = Crypto::new_initial(version, &dest_id, Side::Server);
+        let packet_number = match crypto.unprotect_long(&mut packet) {
+            Some(x) => x as u32,
+            None => {
+                debug!(self.ctx.log, "malformed initial packet");
+                return;
+            }
+        };
         if crypto
-            .decrypt(packet_number as u64, header, &mut payload)
-            .is_err()
+            .decrypt(
+                packet_number as u64,
+                &packet.header_data,
+                &mut packet.payload,
+            ).is_err()
         {
             debug!(self.ctx.log, "failed to authenticate initial packet");
             return;
         };
+
+        if self.ctx.config.use_stateless_retry {
+            let cookies = CookieFactory::new(self.ctx.listen_keys.as_ref().unwrap().cookie);
+            if !cookies.verify(&remote, &dest_id) {
+                debug!(self.ctx.log, "sending stateless retry"; "remote" => %remote);
+                let mut buf = Vec::new();
+                Header::Long {
+                    ty: types::RETRY,
+                    version,
+                    number: packet_number,
+                    destination_id: source_id,
+                    source_id: cookies.generate(&remote),
+                }.encode(&mut buf);
+                let header_len = buf.len();
+                set_payload_length(&mut buf, header_len);
+                crypto.encrypt(packet_number as u64, &mut buf, header_len);
+                crypto.protect_header(header_len - 4, &mut buf);
+                self.ctx.io.push_back(Io::Transmit {
+                    destination: remote,
+                    ecn: None,
+                    packet: buf.into(),
+                });
+                return;
+            }
+            trace!(self.ctx.log, "validated client address"; "remote" => %remote);
+        }
+
         let local_id = ConnectionId::random(&mut self.ctx.rng, LOCAL_ID_LEN as u8);
 
         if self.ctx.incoming.len() + self.ctx.incoming_handshakes
@@ -536,25 +1045,38 @@ impl Endpoint {
             let n = self.ctx.gen_initial_packet_num();
             self.ctx.io.push_back(Io::Transmit {
                 destination: remote,
+                ecn: None,
                 packet: handshake_close(
                     &crypto,
+                    types::INITIAL,
+                    version,
                     &source_id,
                     &local_id,
                     n,
                     TransportError::SERVER_BUSY,
-                    None,
                 ),
             });
             return;
         }
 
-        let conn = self.add_connection(dest_id, local_id, source_id, remote, Side::Server);
+        let payload = packet.payload.freeze();
+        let transport = self.select_transport(&payload);
+        let conn = self.add_connection(
+            version,
+            dest_id,
+            local_id,
+            source_id,
+            remote,
+            Side::Server,
+            transport,
+        );
         self.connection_ids_initial.insert(dest_id, conn);
         match self.connections[conn.0].handle_initial(
             &mut self.ctx,
             now,
             packet_number as u64,
-            payload.freeze(),
+            ecn,
+            payload,
         ) {
             Ok(()) => {}
             Err(e) => {
@@ -562,28 +1084,53 @@ impl Endpoint {
                 let n = self.ctx.gen_initial_packet_num();
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
+                    ecn: None,
                     packet: handshake_close(
                         &crypto,
+                        types::INITIAL,
+                        version,
                         &source_id,
                         &local_id,
                         n,
-                        TransportError::TLS_HANDSHAKE_FAILED,
-                        None,
+                        e,
                     ),
                 });
             }
         }
     }
 
+    /// Choose the transport configuration for a new incoming connection from the ClientHello in its first packet
+    fn select_transport(&self, payload: &Bytes) -> Arc<TransportConfig> {
+        let selector = match self.ctx.config.transport_selector {
+            Some(ref x) => x,
+            None => return self.ctx.config.transport.clone(),
+        };
+        let hello = match parse_initial(&self.ctx.log, payload.clone()) {
+            Ok(Some(frame)) => ClientHello::parse(&frame.data),
+            _ => None,
+        };
+        let hello = match hello {
+            Some(x) => x,
+            // The handshake is doomed anyway
+            None => return self.ctx.config.transport.clone(),
+        };
+        let protocols = hello.protocols.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        selector
+            .select(hello.server_name.as_ref().map(|x| &x[..]), &protocols)
+            .unwrap_or_else(|| self.ctx.config.transport.clone())
+    }
+
     fn handle_connected(
         &mut self,
         now: u64,
         conn: ConnectionHandle,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
         packet: Packet,
     ) {
         trace!(self.ctx.log, "connection got packet"; "connection" => %self.connections[conn.0].local_id, "len" => packet.payload.len());
         let was_closed = self.connections[conn.0].state.as_ref().unwrap().is_closed();
+        let prev_remote = self.connections[conn.0].remote;
 
         // State transitions
         let prev_state = self.connections[conn.0].state.take().unwrap();
@@ -595,6 +1142,7 @@ impl Endpoint {
             &mut self.ctx,
             now,
             remote,
+            ecn,
             packet,
             prev_state,
         ) {
@@ -610,14 +1158,14 @@ impl Endpoint {
                 match conn_err {
                     ConnectionError::ApplicationClosed { reason } => {
                         if was_handshake {
-                            State::handshake_failed(reason, None)
+                            State::handshake_failed(reason)
                         } else {
                             State::closed(reason)
                         }
                     }
                     ConnectionError::ConnectionClosed { reason } => {
                         if was_handshake {
-                            State::handshake_failed(reason, None)
+                            State::handshake_failed(reason)
                         } else {
                             State::closed(reason)
                         }
@@ -632,12 +1180,12 @@ impl Endpoint {
                     }
                     ConnectionError::TransportError { error_code } => {
                         if was_handshake {
-                            State::handshake_failed(error_code, None)
+                            State::handshake_failed(error_code)
                         } else {
                             State::closed(error_code)
                         }
                     }
-                    ConnectionError::VersionMismatch => State::Draining,
+                    ConnectionError::VersionMismatch { .. } => State::Draining,
                 }
             }
         };
@@ -652,47 +1200,80 @@ impl Endpoint {
                 if !was_closed && self.connections[conn.0].side == Side::Server {
                     self.ctx.incoming_handshakes -= 1;
                 }
-                let n = self.connections[conn.0].get_tx_number();
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
-                    packet: handshake_close(
-                        &self.connections[conn.0].handshake_crypto,
-                        &self.connections[conn.0].remote_id,
-                        &self.connections[conn.0].local_id,
-                        n as u32,
-                        state.reason.clone(),
-                        state.alert.as_ref().map(|x| &x[..]),
-                    ),
+                    ecn: None,
+                    packet: self.connections[conn.0].make_handshake_close(&state.reason),
                 });
-                self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+                self.connections[conn.0].reset_idle_timeout(now);
             }
             State::Closed(ref state) => {
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
+                    ecn: None,
                     packet: self.connections[conn.0].make_close(&state.reason),
                 });
-                self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+                self.connections[conn.0].reset_idle_timeout(now);
             }
             _ => {}
         }
+        let established = matches!(state, State::Established(_));
         self.connections[conn.0].state = Some(state);
+        if was_handshake && established {
+            self.issue_cids(conn);
+        }
+        if self.connections[conn.0].remote != prev_remote {
+            self.connection_remotes.remove(&prev_remote);
+            self.connection_remotes
+                .insert(self.connections[conn.0].remote, conn);
+        }
 
         self.ctx.dirty_conns.insert(conn);
     }
 
+    /// Supply the peer of a newly established connection with spare connection IDs
+    fn issue_cids(&mut self, conn: ConnectionHandle) {
+        for _ in 0..self.ctx.config.spare_connection_ids {
+            let id = loop {
+                let id = ConnectionId::random(&mut self.ctx.rng, LOCAL_ID_LEN as u8);
+                if !self.connection_ids.contains_key(&id) {
+                    break id;
+                }
+            };
+            self.connection_ids.insert(id, conn);
+            let reset_token = match self.ctx.listen_keys {
+                Some(ref keys) => reset_token_for(&keys.reset, &id),
+                // Clients never send stateless resets, so the token need not be reproducible
+                None => {
+                    let mut token = [0; RESET_TOKEN_SIZE];
+                    self.ctx.rng.fill_bytes(&mut token);
+                    token
+                }
+            };
+            self.connections[conn.0].issue_cid(id, reset_token);
+        }
+    }
+
     fn flush_pending(&mut self, now: u64, conn: ConnectionHandle) {
         let mut sent = false;
-        while let Some(packet) =
-            self.connections[conn.0].next_packet(&self.ctx.log, &self.ctx.config, now)
-        {
+        while let Some(packet) = self.connections[conn.0].next_datagram(&self.ctx.log, now) {
             self.ctx.io.push_back(Io::Transmit {
                 destination: self.connections[conn.0].remote,
+                ecn: self.connections[conn.0].ecn(),
                 packet: packet.into(),
             });
             sent = true;
         }
+        if let Some(packet) = self.connections[conn.0].mtu_probe(now) {
+            self.ctx.io.push_back(Io::Transmit {
+                destination: self.connections[conn.0].remote,
+                ecn: self.connections[conn.0].ecn(),
+                packet,
+            });
+            sent = true;
+        }
         if sent {
-            self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+            self.connections[conn.0].reset_idle_timeout(now);
         }
         {
             let c = &mut self.connections[conn.0];
@@ -724,6 +1305,34 @@ impl Endpoint {
                     });
                 }
             }
+            if let Some(setting) = c.set_pacing.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::Pacing,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Pacing,
+                    });
+                }
+            }
+            if let Some(setting) = c.set_path_validation.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::PathValidation,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::PathValidation,
+                    });
+                }
+            }
         }
     }
 
@@ -732,8 +1341,9 @@ impl Endpoint {
             self.connection_ids_initial
                 .remove(&self.connections[conn.0].initial_id);
         }
-        self.connection_ids
-            .remove(&self.connections[conn.0].local_id);
+        for id in self.connections[conn.0].loc_cids.values() {
+            self.connection_ids.remove(id);
+        }
         self.connection_remotes
             .remove(&self.connections[conn.0].remote);
         self.ctx.dirty_conns.remove(&conn);
@@ -767,60 +1377,68 @@ impl Endpoint {
                 ));
                 self.ctx.dirty_conns.insert(conn); // Ensure the loss detection timer cancellation goes through
             }
+            Timer::Pacing => {
+                self.ctx.dirty_conns.insert(conn);
+            }
+            Timer::PathValidation => {
+                let prev_remote = self.connections[conn.0].remote;
+                self.connections[conn.0].path_validation_timeout(&self.ctx.log, now);
+                if self.connections[conn.0].remote != prev_remote {
+                    self.connection_remotes.remove(&prev_remote);
+                    self.connection_remotes
+                        .insert(self.connections[conn.0].remote, conn);
+                }
+                self.ctx.dirty_conns.insert(conn);
+            }
             Timer::LossDetection => {
-                if self.connections[conn.0].awaiting_handshake {
+                if self.connections[conn.0].awaiting_handshake() {
                     trace!(self.ctx.log, "retransmitting handshake packets"; "connection" => %self.connections[conn.0].local_id);
-                    let packets = self.connections[conn.0]
-                        .sent_packets
-                        .iter()
-                        .filter_map(
-                            |(&packet, info)| if info.handshake { Some(packet) } else { None },
-                        ).collect::<Vec<_>>();
-                    for number in packets {
-                        let mut info = self.connections[conn.0]
-                            .sent_packets
-                            .remove(&number)
-                            .unwrap();
-                        self.connections[conn.0].handshake_pending += info.retransmits;
-                        self.connections[conn.0].bytes_in_flight -= info.bytes as u64;
-                    }
-                    self.connections[conn.0].handshake_count += 1;
-                } else if self.connections[conn.0].loss_time != 0 {
+                    self.connections[conn.0].retransmit_handshake();
+                } else if self.connections[conn.0].loss_time() != 0 {
                     // Early retransmit or Time Loss Detection
-                    let largest = self.connections[conn.0].largest_acked_packet;
-                    self.connections[conn.0].detect_lost_packets(&self.ctx.config, now, largest);
-                } else if self.connections[conn.0].tlp_count < self.ctx.config.max_tlps {
+                    for space in SpaceId::iter() {
+                        if self.connections[conn.0].space(space).loss_time != 0 {
+                            self.connections[conn.0].detect_lost_packets(now, space);
+                        }
+                    }
+                } else if self.connections[conn.0].tlp_count
+                    < self.connections[conn.0].config.max_tlps
+                {
                     trace!(self.ctx.log, "sending TLP {number} in {pn}",
                            number=self.connections[conn.0].tlp_count,
-                           pn=self.connections[conn.0].largest_sent_packet + 1;
-                           "outstanding" => ?self.connections[conn.0].sent_packets.keys().collect::<Vec<_>>(),
+                           pn=self.connections[conn.0].space(SpaceId::Data).next_packet_number;
+                           "outstanding" => ?self.connections[conn.0].space(SpaceId::Data).sent_packets.keys().collect::<Vec<_>>(),
                            "in flight" => self.connections[conn.0].bytes_in_flight);
                     // Tail Loss Probe.
                     self.ctx.io.push_back(Io::Transmit {
                         destination: self.connections[conn.0].remote,
-                        packet: self.connections[conn.0].force_transmit(&self.ctx.config, now),
+                        ecn: self.connections[conn.0].ecn(),
+                        packet: self.connections[conn.0].force_transmit(now),
                     });
-                    self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+                    self.connections[conn.0].reset_idle_timeout(now);
                     self.connections[conn.0].tlp_count += 1;
                 } else {
-                    trace!(self.ctx.log, "RTO fired, retransmitting"; "pn" => self.connections[conn.0].largest_sent_packet + 1,
-                           "outstanding" => ?self.connections[conn.0].sent_packets.keys().collect::<Vec<_>>(),
+                    trace!(self.ctx.log, "RTO fired, retransmitting"; "pn" => self.connections[conn.0].space(SpaceId::Data).next_packet_number,
+                           "outstanding" => ?self.connections[conn.0].space(SpaceId::Data).sent_packets.keys().collect::<Vec<_>>(),
                            "in flight" => self.connections[conn.0].bytes_in_flight);
                     // RTO
                     if self.connections[conn.0].rto_count == 0 {
-                        self.connections[conn.0].largest_sent_before_rto =
-                            self.connections[conn.0].largest_sent_packet;
+                        self.connections[conn.0].largest_sent_before_rto = self.connections[conn.0]
+                            .space(SpaceId::Data)
+                            .next_packet_number
+                            - 1;
                     }
                     for _ in 0..2 {
                         self.ctx.io.push_back(Io::Transmit {
                             destination: self.connections[conn.0].remote,
-                            packet: self.connections[conn.0].force_transmit(&self.ctx.config, now),
+                            ecn: self.connections[conn.0].ecn(),
+                            packet: self.connections[conn.0].force_transmit(now),
                         });
                     }
-                    self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+                    self.connections[conn.0].reset_idle_timeout(now);
                     self.connections[conn.0].rto_count += 1;
                 }
-                self.connections[conn.0].set_loss_detection_alarm(&self.ctx.config);
+                self.connections[conn.0].set_loss_detection_alarm();
                 self.ctx.dirty_conns.insert(conn);
             }
         }
@@ -838,13 +1456,15 @@ impl Endpoint {
         stream: StreamId,
         data: &[u8],
     ) -> Result<usize, WriteError> {
-        let r = self.connections[conn.0].write(&self.ctx.config, stream, data);
+        let r = self.connections[conn.0].write(stream, data);
         match r {
             Ok(n) => {
                 self.ctx.dirty_conns.insert(conn);
                 trace!(self.ctx.log, "write"; "connection" => %self.connections[conn.0].local_id, "stream" => stream.0, "len" => n)
             }
             Err(WriteError::Blocked) => {
+                // A BLOCKED or STREAM_BLOCKED frame may have been queued
+                self.ctx.dirty_conns.insert(conn);
                 if self.connections[conn.0].congestion_blocked() {
                     trace!(self.ctx.log, "write blocked by congestion"; "connection" => %self.connections[conn.0].local_id);
                 } else {
@@ -856,6 +1476,20 @@ impl Endpoint {
         r
     }
 
+    /// Queue an unreliable, unordered datagram for transmission
+    ///
+    /// Datagrams are sent only once the connection is established and the congestion window allows, and are never
+    /// retransmitted. If too many accumulate before they can be sent, the oldest are discarded.
+    pub fn send_datagram(
+        &mut self,
+        conn: ConnectionHandle,
+        data: Bytes,
+    ) -> Result<(), SendDatagramError> {
+        self.connections[conn.0].send_datagram(data)?;
+        self.ctx.dirty_conns.insert(conn);
+        Ok(())
+    }
+
     /// Indicate that no more data will be sent on a stream
     ///
     /// All previously transmitted data will still be delivered. Incoming data on bidirectional streams is unaffected.
@@ -867,6 +1501,49 @@ impl Endpoint {
         self.ctx.dirty_conns.insert(conn);
     }
 
+    /// Set the priority of a stream's outgoing data
+    ///
+    /// Data on streams with higher priority is always sent first. Streams of equal priority share the connection
+    /// fairly. Streams start at priority 0. Handshake data is sent ahead of every stream. Has no effect on a stream
+    /// that's already closed.
+    ///
+    /// # Panics
+    /// - when applied to a stream we can't send on
+    pub fn set_priority(&mut self, conn: ConnectionHandle, stream: StreamId, priority: i32) {
+        self.connections[conn.0].set_priority(stream, priority);
+    }
+
+    /// Change the number of streams of a certain directionality the peer may have open at once
+    ///
+    /// Raising the limit takes effect immediately. Lowering it takes effect as the peer's existing streams close, since
+    /// permission to open a stream can't be revoked.
+    pub fn set_max_remote_streams(
+        &mut self,
+        conn: ConnectionHandle,
+        direction: Directionality,
+        count: u64,
+    ) {
+        self.connections[conn.0].set_max_remote_streams(direction, count);
+        self.ctx.dirty_conns.insert(conn);
+    }
+
+    /// Change the number of bytes the peer may send on a stream beyond what the application has read
+    ///
+    /// Replaces the automatically tuned window, which stops growing. A smaller window takes effect as the application
+    /// reads data. Has no effect on a stream that's already closed, or that the peer hasn't opened yet.
+    ///
+    /// # Panics
+    /// - when applied to a stream the peer can't send on
+    pub fn set_stream_receive_window(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        window: u64,
+    ) {
+        self.connections[conn.0].set_stream_receive_window(stream, window);
+        self.ctx.dirty_conns.insert(conn);
+    }
+
     /// Read data from a stream
     ///
     /// Treats a stream like a simple pipe, similar to a TCP connection. Subject to head-of-line blocking within the
@@ -883,7 +1560,7 @@ impl Endpoint {
         self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
         match self.connections[conn.0].read(stream, buf) {
             x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
-                self.connections[conn.0].maybe_cleanup(&self.ctx.config, stream);
+                self.connections[conn.0].maybe_cleanup(stream);
                 x
             }
             x => x,
@@ -909,7 +1586,7 @@ impl Endpoint {
         self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
         match self.connections[conn.0].read_unordered(stream) {
             x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
-                self.connections[conn.0].maybe_cleanup(&self.ctx.config, stream);
+                self.connections[conn.0].maybe_cleanup(stream);
                 x
             }
             x => x,
@@ -937,14 +1614,31 @@ impl Endpoint {
     ///
     /// Returns `None` if the maximum number of streams currently permitted by the remote endpoint are already open.
     pub fn open(&mut self, conn: ConnectionHandle, direction: Directionality) -> Option<StreamId> {
-        self.connections[conn.0].open(&self.ctx.config, direction)
+        let id = self.connections[conn.0].open(direction);
+        if id.is_none() {
+            // Let the peer know we're waiting on it for more streams
+            self.ctx.dirty_conns.insert(conn);
+        }
+        id
     }
 
     /// Ping the remote endpoint
     ///
     /// Useful for preventing an otherwise idle connection from timing out.
     pub fn ping(&mut self, conn: ConnectionHandle) {
-        self.connections[conn.0].pending.ping = true;
+        self.connections[conn.0]
+            .space_mut(SpaceId::Data)
+            .pending
+            .ping = true;
+        self.ctx.dirty_conns.insert(conn);
+    }
+
+    /// Replace the keys protecting a connection's 1-RTT packets
+    ///
+    /// Takes effect with the next packet sent once the connection is established and the peer has picked up any
+    /// previous key update.
+    pub fn initiate_key_update(&mut self, conn: ConnectionHandle) {
+        self.connections[conn.0].key_update_requested = true;
         self.ctx.dirty_conns.insert(conn);
     }
 
@@ -973,12 +1667,12 @@ impl Endpoint {
     pub fn get_remote_id(&self, conn: ConnectionHandle) -> ConnectionId {
         self.connections[conn.0].remote_id
     }
-    pub fn get_remote_address(&self, conn: ConnectionHandle) -> &SocketAddrV6 {
+    pub fn get_remote_address(&self, conn: ConnectionHandle) -> &SocketAddr {
         &self.connections[conn.0].remote
     }
     pub fn get_protocol(&self, conn: ConnectionHandle) -> Option<&[u8]> {
         if let State::Established(ref state) = *self.connections[conn.0].state.as_ref().unwrap() {
-            state.tls.get_alpn_protocol().map(|p| p.as_bytes())
+            state.tls.get_alpn_protocol()
         } else {
             None
         }
@@ -988,10 +1682,32 @@ impl Endpoint {
         self.connections[conn.0].bytes_in_flight
     }
 
+    /// Largest UDP payload currently sent to the peer, as determined by path MTU discovery
+    pub fn get_mtu(&self, conn: ConnectionHandle) -> u16 {
+        self.connections[conn.0].mtu
+    }
+
+    /// Largest datagram that may currently be sent on `conn`, if the peer accepts datagrams.
+    pub fn get_max_datagram_size(&self, conn: ConnectionHandle) -> Option<usize> {
+        self.connections[conn.0].max_datagram_size()
+    }
+
+    /// ECN codepoint outgoing packets on `conn` are marked with.
+    ///
+    /// None if ECN is disabled, or if the path or peer turned out not to support it.
+    pub fn get_ecn(&self, conn: ConnectionHandle) -> Option<EcnCodepoint> {
+        self.connections[conn.0].ecn()
+    }
+
+    /// Number of packets received on `conn` with each ECN codepoint
+    pub fn get_ecn_counts(&self, conn: ConnectionHandle) -> EcnCounts {
+        self.connections[conn.0].ecn_counts
+    }
+
     /// Number of bytes worth of non-ack-only packets that may be sent.
     pub fn get_congestion_state(&self, conn: ConnectionHandle) -> u64 {
         let c = &self.connections[conn.0];
-        c.congestion_window.saturating_sub(c.bytes_in_flight)
+        c.congestion.window().saturating_sub(c.bytes_in_flight)
     }
 
     /// The name a client supplied via SNI.
@@ -1006,8 +1722,13 @@ impl Endpoint {
     }
 
     /// Whether a previous session was successfully resumed by `conn`.
-    pub fn get_session_resumed(&self, _: ConnectionHandle) -> bool {
-        false // TODO: fixme?
+    ///
+    /// Always false while the handshake is in progress.
+    pub fn get_session_resumed(&self, conn: ConnectionHandle) -> bool {
+        match *self.connections[conn.0].state.as_ref().unwrap() {
+            State::Established(ref state) => state.tls.is_resumed(),
+            _ => false,
+        }
     }
 
     pub fn accept(&mut self) -> Option<ConnectionHandle> {
@@ -1047,16 +1768,34 @@ pub enum Event {
     StreamAvailable {
         directionality: Directionality,
     },
-    NewSessionTicket {
-        ticket: Box<[u8]>,
+    /// An unreliable datagram was received
+    DatagramReceived {
+        data: Bytes,
     },
+    /// The peer reports that it has data to send but is held up by a limit we imposed
+    ///
+    /// Purely diagnostic; limits are raised automatically as the application reads data and closes streams.
+    PeerBlocked { limit: Limit },
+}
+
+/// A flow control or stream count limit the peer reported being blocked by
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Limit {
+    /// Connection-level flow control, at the given offset
+    Data(u64),
+    /// Stream-level flow control
+    StreamData { stream: StreamId, offset: u64 },
+    /// The highest stream ID the peer may open of a certain directionality
+    StreamId(StreamId),
 }
 
 /// I/O operations to be immediately executed the backend.
 #[derive(Debug)]
 pub enum Io {
     Transmit {
-        destination: SocketAddrV6,
+        destination: SocketAddr,
+        /// Explicit Congestion Notification codepoint to mark the datagram with, if supported
+        ecn: Option<EcnCodepoint>,
         packet: Box<[u8]>,
     },
     /// Start or reset a timer
@@ -1077,6 +1816,10 @@ pub enum Timer {
     Close,
     LossDetection,
     Idle,
+    /// Time to send more packets held back to avoid bursts
+    Pacing,
+    /// Time to resend PATH_CHALLENGE, or give up on an unvalidated peer address
+    PathValidation,
 }
 
 impl slog::Value for Timer {
@@ -1090,20 +1833,23 @@ impl slog::Value for Timer {
     }
 }
 
-fn handshake_close<R>(
+/// Construct a packet of type `ty` closing a connection that hasn't finished its handshake
+pub(crate) fn handshake_close<R>(
     crypto: &Crypto,
+    ty: u8,
+    version: Version,
     remote_id: &ConnectionId,
     local_id: &ConnectionId,
     packet_number: u32,
     reason: R,
-    tls_alert: Option<&[u8]>,
 ) -> Box<[u8]>
 where
     R: Into<state::CloseReason>,
 {
     let mut buf = Vec::<u8>::new();
     Header::Long {
-        ty: types::HANDSHAKE,
+        ty,
+        version,
         destination_id: *remote_id,
         source_id: *local_id,
         number: packet_number,
@@ -1114,17 +1860,12 @@ where
         state::CloseReason::Application(ref x) => x.encode(&mut buf, max_len),
         state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
     }
-    if let Some(data) = tls_alert {
-        if !data.is_empty() {
-            frame::Stream {
-                id: StreamId(0),
-                fin: false,
-                offset: 0,
-                data,
-            }.encode(false, &mut buf);
-        }
-    }
     set_payload_length(&mut buf, header_len);
     crypto.encrypt(packet_number as u64, &mut buf, header_len);
+    crypto.protect_header(header_len - 4, &mut buf);
     buf.into()
 }
+
+fn micros_from(x: Duration) -> u64 {
+    x.as_secs() * 1000 * 1000 + x.subsec_micros() as u64
+}
@@ -0,0 +1,104 @@
+use std::cmp;
+
+/// Receive credit extended to the peer, which grows when the application consumes data quickly
+///
+/// Fresh credit is issued in a batch once half the window has been consumed. If that happens within two round trips of
+/// the previous batch, the window is too small to keep the path busy, so it's doubled, up to a configured maximum.
+/// Streams that are read slowly keep their small initial window, and hence little buffered data.
+#[derive(Debug, Copy, Clone)]
+pub struct Window {
+    /// Credit to extend beyond the data consumed so far
+    size: u64,
+    /// Largest size the window may grow to
+    max_size: u64,
+    /// Number of bytes consumed by the application
+    consumed: u64,
+    /// Offset the peer may send data up to
+    max_data: u64,
+    /// Time at which credit was last issued (μs)
+    last_issued: Option<u64>,
+}
+
+impl Window {
+    pub fn new(size: u64, max_size: u64) -> Self {
+        Self {
+            size,
+            max_size: cmp::max(size, max_size),
+            consumed: 0,
+            max_data: size,
+            last_issued: None,
+        }
+    }
+
+    /// Offset the peer may send data up to, as of the most recently issued credit
+    pub fn max_data(&self) -> u64 {
+        self.max_data
+    }
+
+    /// Record that the application consumed `n` bytes, returning whether fresh credit is due
+    pub fn consume(&mut self, n: u64) -> bool {
+        self.consumed += n;
+        self.is_due()
+    }
+
+    /// Fix the window at `size` bytes, returning whether fresh credit is due
+    pub fn set_size(&mut self, size: u64) -> bool {
+        self.size = size;
+        self.max_size = size;
+        self.is_due()
+    }
+
+    fn is_due(&self) -> bool {
+        self.max_data.saturating_sub(self.consumed) <= self.size / 2
+    }
+
+    /// Determine the limit to advertise to the peer, issuing fresh credit if it's due
+    ///
+    /// `rtt` is the current round-trip time estimate (μs).
+    pub fn issue(&mut self, now: u64, rtt: u64) -> u64 {
+        if !self.is_due() {
+            return self.max_data;
+        }
+        if let Some(last) = self.last_issued {
+            if now.saturating_sub(last) < 2 * rtt {
+                self.size = cmp::min(2 * self.size, self.max_size);
+            }
+        }
+        self.last_issued = Some(now);
+        self.max_data = self.consumed + self.size;
+        self.max_data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batching() {
+        let mut window = Window::new(1000, 1000);
+        assert!(!window.consume(400));
+        assert_eq!(window.issue(0, 100), 1000);
+        assert!(window.consume(100));
+        assert_eq!(window.issue(0, 100), 1500);
+        // Nothing new to issue until another half window is consumed
+        assert!(!window.consume(100));
+        assert_eq!(window.issue(1000, 100), 1500);
+    }
+
+    #[test]
+    fn growth() {
+        let mut window = Window::new(1000, 3000);
+        window.consume(500);
+        assert_eq!(window.issue(0, 100), 1500);
+        // Consumed quickly: grow
+        window.consume(500);
+        assert_eq!(window.issue(100, 100), 1000 + 2000);
+        // Consumed quickly again: grow, but no larger than the maximum
+        window.consume(1000);
+        assert_eq!(window.issue(200, 100), 2000 + 3000);
+        // Consumed slowly: stay put
+        window.consume(1500);
+        assert_eq!(window.issue(10_000, 100), 3500 + 3000);
+    }
+}
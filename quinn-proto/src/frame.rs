@@ -6,7 +6,8 @@ use bytes::{Buf, BufMut, Bytes};
 use coding::{self, BufExt, BufMutExt, UnexpectedEnd};
 use range_set::RangeSet;
 use {
-    varint, ConnectionId, StreamId, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE,
+    varint, ConnectionId, EcnCodepoint, StreamId, TransportError, MAX_CID_SIZE, MIN_CID_SIZE,
+    RESET_TOKEN_SIZE,
 };
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -101,6 +102,10 @@ frame_types!{
     ACK = 0x0d,
     PATH_CHALLENGE = 0x0e,
     PATH_RESPONSE = 0x0f,
+    CRYPTO = 0x18,
+    ACK_ECN = 0x1a,
+    DATAGRAM = 0x30,
+    DATAGRAM_LEN = 0x31,
 }
 
 #[derive(Debug)]
@@ -132,6 +137,7 @@ pub enum Frame {
     },
     Ack(Ack),
     Stream(Stream),
+    Crypto(Crypto),
     PathChallenge(u64),
     PathResponse(u64),
     NewConnectionId {
@@ -139,6 +145,7 @@ pub enum Frame {
         id: ConnectionId,
         reset_token: [u8; 16],
     },
+    Datagram(Datagram),
     Invalid(Type),
 }
 
@@ -158,7 +165,13 @@ impl Frame {
             StreamBlocked { .. } => Type::STREAM_BLOCKED,
             StreamIdBlocked { .. } => Type::STREAM_ID_BLOCKED,
             StopSending { .. } => Type::STOP_SENDING,
-            Ack(_) => Type::ACK,
+            Ack(ref x) => {
+                if x.ecn.is_some() {
+                    Type::ACK_ECN
+                } else {
+                    Type::ACK
+                }
+            }
             Stream(ref x) => {
                 let mut ty = 0x10;
                 if x.fin {
@@ -169,9 +182,17 @@ impl Frame {
                 }
                 Type(ty)
             }
+            Crypto(_) => Type::CRYPTO,
             PathChallenge(_) => Type::PATH_CHALLENGE,
             PathResponse(_) => Type::PATH_RESPONSE,
             NewConnectionId { .. } => Type::NEW_CONNECTION_ID,
+            Datagram(ref x) => {
+                if x.length {
+                    Type::DATAGRAM_LEN
+                } else {
+                    Type::DATAGRAM
+                }
+            }
             Invalid(ty) => ty,
         }
     }
@@ -264,6 +285,7 @@ pub struct Ack {
     pub largest: u64,
     pub delay: u64,
     pub additional: Bytes,
+    pub ecn: Option<EcnCounts>,
 }
 
 impl<'a> IntoIterator for &'a Ack {
@@ -276,12 +298,16 @@ impl<'a> IntoIterator for &'a Ack {
 }
 
 impl Ack {
-    pub fn encode<W: BufMut>(delay: u64, ranges: &RangeSet, buf: &mut W) {
+    pub fn encode<W: BufMut>(delay: u64, ranges: &RangeSet, ecn: Option<&EcnCounts>, buf: &mut W) {
         let mut rest = ranges.iter().rev();
         let first = rest.next().unwrap();
         let largest = first.end - 1;
         let first_size = first.end - first.start;
-        buf.write(Type::ACK);
+        buf.write(if ecn.is_some() {
+            Type::ACK_ECN
+        } else {
+            Type::ACK
+        });
         varint::write(largest, buf).unwrap();
         varint::write(delay, buf).unwrap();
         varint::write(ranges.len() as u64 - 1, buf).unwrap();
@@ -293,6 +319,9 @@ impl Ack {
             varint::write(size - 1, buf).unwrap();
             prev = block.start;
         }
+        if let Some(x) = ecn {
+            x.encode(buf);
+        }
     }
 
     pub fn iter(&self) -> AckIter {
@@ -300,6 +329,36 @@ impl Ack {
     }
 }
 
+/// Number of packets received with each ECN codepoint
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCounts {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        varint::write(self.ect0, out).unwrap();
+        varint::write(self.ect1, out).unwrap();
+        varint::write(self.ce, out).unwrap();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ect0 == 0 && self.ect1 == 0 && self.ce == 0
+    }
+}
+
+impl ::std::ops::AddAssign<EcnCodepoint> for EcnCounts {
+    fn add_assign(&mut self, rhs: EcnCodepoint) {
+        match rhs {
+            EcnCodepoint::Ect0 => self.ect0 += 1,
+            EcnCodepoint::Ect1 => self.ect1 += 1,
+            EcnCodepoint::Ce => self.ce += 1,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Stream<T = Bytes> {
     pub id: StreamId,
@@ -335,6 +394,59 @@ where
     }
 }
 
+/// TLS handshake data, sent separately in each packet number space
+#[derive(Debug, Clone)]
+pub struct Crypto<T = Bytes> {
+    pub offset: u64,
+    pub data: T,
+}
+
+impl<T> Crypto<T>
+where
+    T: AsRef<[u8]>,
+{
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::CRYPTO);
+        varint::write(self.offset, out).unwrap();
+        varint::write(self.data.as_ref().len() as u64, out).unwrap();
+        out.put_slice(self.data.as_ref());
+    }
+}
+
+/// An unreliable datagram, as described by the DATAGRAM extension
+#[derive(Debug, Clone)]
+pub struct Datagram<T = Bytes> {
+    /// Whether the frame has a length field, without which it extends to the end of the packet
+    pub length: bool,
+    pub data: T,
+}
+
+impl<T> Datagram<T>
+where
+    T: AsRef<[u8]>,
+{
+    /// Encoded size of the frame, including its type and length fields
+    pub fn size(&self) -> usize {
+        let len = self.data.as_ref().len();
+        let length = if self.length {
+            varint::size(len as u64).unwrap()
+        } else {
+            0
+        };
+        1 + length + len
+    }
+
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        if self.length {
+            out.write(Type::DATAGRAM_LEN);
+            varint::write(self.data.as_ref().len() as u64, out).unwrap();
+        } else {
+            out.write(Type::DATAGRAM);
+        }
+        out.put_slice(self.data.as_ref());
+    }
+}
+
 pub struct Iter {
     // TODO: ditch io::Cursor after bytes 0.5
     bytes: io::Cursor<Bytes>,
@@ -410,7 +522,7 @@ impl Iter {
                 id: self.bytes.get()?,
                 error_code: self.bytes.get()?,
             },
-            Type::ACK => {
+            Type::ACK | Type::ACK_ECN => {
                 let largest = self.bytes.get_var()?;
                 let delay = self.bytes.get_var()?;
                 let extra_blocks = self.bytes.get_var()? as usize;
@@ -418,12 +530,27 @@ impl Iter {
                 let len = scan_ack_blocks(&self.bytes.bytes()[..], largest, extra_blocks)
                     .ok_or(UnexpectedEnd)?;
                 self.bytes.advance(len);
+                let additional = self.bytes.get_ref().slice(start, start + len);
+                let ecn = if ty == Type::ACK_ECN {
+                    Some(EcnCounts {
+                        ect0: self.bytes.get_var()?,
+                        ect1: self.bytes.get_var()?,
+                        ce: self.bytes.get_var()?,
+                    })
+                } else {
+                    None
+                };
                 Frame::Ack(Ack {
                     delay,
                     largest,
-                    additional: self.bytes.get_ref().slice(start, start + len),
+                    additional,
+                    ecn,
                 })
             }
+            Type::CRYPTO => Frame::Crypto(Crypto {
+                offset: self.bytes.get_var()?,
+                data: self.take_len()?,
+            }),
             Type::PATH_CHALLENGE => Frame::PathChallenge(self.bytes.get()?),
             Type::PATH_RESPONSE => Frame::PathResponse(self.bytes.get()?),
             Type::NEW_CONNECTION_ID => {
@@ -449,6 +576,19 @@ impl Iter {
                     reset_token,
                 }
             }
+            Type::DATAGRAM => {
+                let mut data = mem::replace(self.bytes.get_mut(), Bytes::new());
+                data.advance(self.bytes.position() as usize);
+                self.bytes.set_position(0);
+                Frame::Datagram(Datagram {
+                    length: false,
+                    data,
+                })
+            }
+            Type::DATAGRAM_LEN => Frame::Datagram(Datagram {
+                length: true,
+                data: self.take_len()?,
+            }),
             _ => match ty.stream() {
                 Some(s) => Frame::Stream(Stream {
                     id: self.bytes.get()?,
@@ -557,7 +697,7 @@ mod test {
             ranges.insert(packet..packet + 1);
         }
         let mut buf = Vec::new();
-        Ack::encode(42, &ranges, &mut buf);
+        Ack::encode(42, &ranges, None, &mut buf);
         let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
         match frames[0] {
             Frame::Ack(ref ack) => {
@@ -568,4 +708,61 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn crypto_coding() {
+        let mut buf = Vec::new();
+        Crypto {
+            offset: 0,
+            data: &b"hello"[..],
+        }.encode(&mut buf);
+        Crypto {
+            offset: 1234,
+            data: &b"world"[..],
+        }.encode(&mut buf);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Frame::Crypto(ref x) if x.offset == 0 && x.data == b"hello"[..]);
+        assert_matches!(frames[1], Frame::Crypto(ref x) if x.offset == 1234 && x.data == b"world"[..]);
+    }
+
+    #[test]
+    fn ack_ecn_coding() {
+        let mut ranges = RangeSet::new();
+        ranges.insert(3..8);
+        let ecn = EcnCounts {
+            ect0: 4,
+            ect1: 0,
+            ce: 1,
+        };
+        let mut buf = Vec::new();
+        Ack::encode(0, &ranges, Some(&ecn), &mut buf);
+        buf.push(Type::PING.into());
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        match frames[0] {
+            Frame::Ack(ref ack) => {
+                assert_eq!(ack.iter().collect::<Vec<_>>(), vec![3..8]);
+                assert_eq!(ack.ecn, Some(ecn));
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+        assert_matches!(frames[1], Frame::Ping);
+    }
+
+    #[test]
+    fn datagram_coding() {
+        let mut buf = Vec::new();
+        Datagram {
+            length: true,
+            data: &b"foo"[..],
+        }.encode(&mut buf);
+        Datagram {
+            length: false,
+            data: &b"bar"[..],
+        }.encode(&mut buf);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Frame::Datagram(ref x) if x.size() == 5 && x.data == b"foo"[..]);
+        assert_matches!(frames[1], Frame::Datagram(ref x) if x.size() == 4 && x.data == b"bar"[..]);
+    }
 }
@@ -20,13 +20,14 @@ extern crate rustls;
 extern crate slab;
 #[macro_use]
 extern crate slog;
-#[cfg(test)]
-extern crate untrusted;
 extern crate webpki;
 
 use std::fmt;
 
 mod coding;
+mod flow_control;
+mod mtud;
+mod pacing;
 mod range_set;
 mod stream;
 #[cfg(test)]
@@ -34,18 +35,24 @@ mod tests;
 mod transport_parameters;
 mod varint;
 
+pub mod congestion;
+pub use congestion::CongestionAlgorithm;
+
 mod connection;
-pub use connection::{ConnectionError, ConnectionHandle, ReadError, WriteError};
+pub use connection::{ConnectionError, ConnectionHandle, ReadError, SendDatagramError, WriteError};
 
 mod crypto;
-pub use crypto::{ClientConfig, ConnectError};
+pub use crypto::{ClientConfig, ConnectError, InMemorySessionStore, SessionStore};
 
 mod frame;
 use frame::Frame;
-pub use frame::{ApplicationClose, ConnectionClose};
+pub use frame::{ApplicationClose, ConnectionClose, EcnCounts};
 
 mod endpoint;
-pub use endpoint::{Config, Endpoint, EndpointError, Event, Io, ListenKeys, Timer};
+pub use endpoint::{
+    Config, ConfigBuilder, ConfigError, Endpoint, EndpointError, Event, Io, Limit, ListenKeys,
+    Timer, TransportConfig, TransportConfigBuilder, TransportConfigSelector,
+};
 
 mod packet;
 pub use packet::ConnectionId;
@@ -53,10 +60,12 @@ pub use packet::ConnectionId;
 mod transport_error;
 pub use transport_error::Error as TransportError;
 
-/// The QUIC protocol version implemented
-pub const VERSION: u32 = 0xff00_000b;
+mod version;
+pub use version::Version;
 
-/// TLS ALPN value for HTTP over QUIC
+/// TLS ALPN value for HTTP over QUIC at the most preferred version
+///
+/// See `Version::alpn_quic_http` for the values used by other versions.
 pub const ALPN_QUIC_HTTP: &[u8] = b"hq-11";
 
 /// Whether an endpoint was the initiator of a connection
@@ -159,6 +168,32 @@ impl StreamId {
     }
 }
 
+/// Explicit Congestion Notification codepoint, carried in the low two bits of the IP TOS byte or traffic class
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EcnCodepoint {
+    /// ECN-capable transport, codepoint 0
+    Ect0 = 0b10,
+    /// ECN-capable transport, codepoint 1
+    Ect1 = 0b01,
+    /// Congestion experienced, set by routers in place of dropping a packet
+    Ce = 0b11,
+}
+
+impl EcnCodepoint {
+    /// Extract the codepoint from a TOS byte, if any is set
+    pub fn from_bits(x: u8) -> Option<Self> {
+        use self::EcnCodepoint::*;
+        Some(match x & 0b11 {
+            0b10 => Ect0,
+            0b01 => Ect1,
+            0b11 => Ce,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
 impl coding::Codec for StreamId {
     fn decode<B: bytes::Buf>(buf: &mut B) -> coding::Result<StreamId> {
         varint::read(buf).map(StreamId).ok_or(coding::UnexpectedEnd)
@@ -0,0 +1,131 @@
+use std::cmp;
+
+use MIN_MTU;
+
+/// Largest UDP payload that can be carried at all
+const MAX_UDP_PAYLOAD: u16 = 65527;
+/// Smallest gain in MTU worth probing for
+const MIN_PROBE_STEP: u16 = 20;
+/// Number of times a probe of a given size may be lost before that size is deemed too large for the path
+const MAX_PROBES: u8 = 3;
+/// Number of consecutive losses of packets larger than `MIN_MTU` taken to mean the path no longer carries them
+const BLACK_HOLE_THRESHOLD: u8 = 3;
+/// Time after which a completed search is repeated, in case the path's MTU has grown (μs)
+const SEARCH_INTERVAL: u64 = 600 * 1000 * 1000;
+
+/// Datagram packetization layer path MTU discovery, as described in RFC 8899
+///
+/// Sizes between the largest confirmed MTU and the smallest known to fail are binary searched by sending PING frames
+/// padded to the size under test; an acknowledgement confirms that the path carries datagrams that large. If
+/// full-sized packets start to go missing, we fall back to `MIN_MTU` and search again.
+pub struct MtuDiscovery {
+    /// Largest UDP payload known to reach the peer
+    current: u16,
+    /// Largest UDP payload we may probe for
+    max: u16,
+    /// Smallest UDP payload known not to reach the peer, or one more than `max`
+    failed: u16,
+    /// Packet number and size of the outstanding probe, if any
+    probe: Option<(u64, u16)>,
+    /// Number of probes of the next size that have been lost
+    lost_probes: u8,
+    /// Number of packets larger than `MIN_MTU` lost since one was last acknowledged
+    black_hole_losses: u8,
+    /// When to search again, if the current search is complete
+    next_search: Option<u64>,
+}
+
+impl MtuDiscovery {
+    pub fn new(max: u16) -> Self {
+        let max = max.clamp(MIN_MTU, MAX_UDP_PAYLOAD);
+        Self {
+            current: MIN_MTU,
+            max,
+            failed: max + 1,
+            probe: None,
+            lost_probes: 0,
+            black_hole_losses: 0,
+            next_search: None,
+        }
+    }
+
+    /// Largest UDP payload that may currently be sent
+    pub fn current(&self) -> u16 {
+        self.current
+    }
+
+    /// Limit the search to datagrams the peer is willing to receive
+    pub fn set_peer_max(&mut self, max: u16) {
+        self.max = cmp::max(cmp::min(self.max, max), MIN_MTU);
+        self.failed = cmp::min(self.failed, self.max + 1);
+    }
+
+    /// Size of the next probe to send, if one is due
+    pub fn next_probe_size(&mut self, now: u64) -> Option<u16> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if let Some(time) = self.next_search {
+            if now < time {
+                return None;
+            }
+            self.next_search = None;
+            self.failed = self.max + 1;
+        }
+        if self.failed - self.current <= MIN_PROBE_STEP {
+            self.next_search = Some(now + SEARCH_INTERVAL);
+            return None;
+        }
+        Some(self.current + (self.failed - self.current) / 2)
+    }
+
+    pub fn on_probe_sent(&mut self, packet: u64, size: u16) {
+        self.probe = Some((packet, size));
+    }
+
+    /// Account for the acknowledgement of `packet`, carrying `bytes` unless ack-only
+    pub fn on_acked(&mut self, packet: u64, bytes: u16) {
+        match self.probe {
+            Some((probe, size)) if probe == packet => {
+                self.probe = None;
+                self.current = size;
+                self.lost_probes = 0;
+            }
+            _ => {}
+        }
+        if bytes > MIN_MTU {
+            self.black_hole_losses = 0;
+        }
+    }
+
+    /// Account for the loss of `packet`, returning whether it was a probe
+    ///
+    /// Lost probes tell us about the path's MTU, not its congestion, so they shouldn't shrink the congestion window.
+    pub fn on_lost(&mut self, packet: u64, bytes: u16) -> bool {
+        match self.probe {
+            Some((probe, size)) if probe == packet => {
+                self.probe = None;
+                self.lost_probes += 1;
+                if self.lost_probes >= MAX_PROBES {
+                    self.failed = size;
+                    self.lost_probes = 0;
+                }
+                return true;
+            }
+            _ => {}
+        }
+        // Packets sent before an earlier fallback don't tell us anything new
+        if bytes > MIN_MTU && self.current > MIN_MTU {
+            self.black_hole_losses += 1;
+            if self.black_hole_losses >= BLACK_HOLE_THRESHOLD {
+                // The path has stopped carrying datagrams of the size we'd confirmed
+                self.failed = self.current;
+                self.current = MIN_MTU;
+                self.black_hole_losses = 0;
+                self.lost_probes = 0;
+                self.next_search = None;
+            }
+        }
+        false
+    }
+}
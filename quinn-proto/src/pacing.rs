@@ -0,0 +1,54 @@
+use std::cmp;
+
+/// Smallest burst of packets that may be sent back-to-back
+const MIN_BURST_PACKETS: u64 = 2;
+/// Largest burst of packets that may be sent back-to-back
+const MAX_BURST_PACKETS: u64 = 10;
+/// Shortest time worth waiting for a pacing timer (μs)
+///
+/// Operating system timers are imprecise at finer scales, so bursts are sized to cover this much time.
+const TIMER_GRANULARITY: u64 = 1000;
+
+/// Spreads a connection's transmissions over each round trip rather than sending a full window at once
+///
+/// A token bucket which fills at the pacing rate, and holds a small burst's worth of bytes.
+pub struct Pacer {
+    /// Bytes that may be sent immediately
+    tokens: u64,
+    /// Time at which `tokens` was last brought up to date
+    last_update: u64,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Self {
+            tokens: u64::MAX,
+            last_update: 0,
+        }
+    }
+
+    /// Determine when a full-sized packet may next be sent at `rate` bytes per second, if not immediately
+    pub fn delay(&mut self, now: u64, rate: u64, mtu: u64) -> Option<u64> {
+        let capacity = cmp::min(
+            cmp::max(
+                rate * TIMER_GRANULARITY / 1_000_000,
+                MIN_BURST_PACKETS * mtu,
+            ),
+            MAX_BURST_PACKETS * mtu,
+        );
+        let elapsed = now.saturating_sub(self.last_update);
+        let refill = rate.saturating_mul(elapsed) / 1_000_000;
+        self.tokens = cmp::min(capacity, self.tokens.saturating_add(refill));
+        self.last_update = now;
+        if self.tokens >= mtu {
+            return None;
+        }
+        let wait = (mtu - self.tokens) * 1_000_000 / rate;
+        Some(now + cmp::max(wait, TIMER_GRANULARITY))
+    }
+
+    /// Account for a packet of `bytes` having been sent
+    pub fn on_sent(&mut self, bytes: u64) {
+        self.tokens = self.tokens.saturating_sub(bytes);
+    }
+}
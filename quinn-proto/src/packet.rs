@@ -5,12 +5,13 @@ use rand::Rng;
 use slog;
 
 use coding::{self, BufExt, BufMutExt};
-use {MAX_CID_SIZE, MIN_CID_SIZE, VERSION};
+use {Version, MAX_CID_SIZE, MIN_CID_SIZE};
 
 #[derive(Debug, Clone)]
 pub enum Header {
     Long {
         ty: u8,
+        version: Version,
         source_id: ConnectionId,
         destination_id: ConnectionId,
         number: u32,
@@ -70,6 +71,16 @@ impl PacketNumber {
         }
     }
 
+    /// Length of the packet number in a short-header packet beginning with `first`
+    pub fn short_len(first: u8) -> Option<usize> {
+        match first & 0b11 {
+            0x0 => Some(1),
+            0x1 => Some(2),
+            0x2 => Some(4),
+            _ => None,
+        }
+    }
+
     fn ty(self) -> u8 {
         use self::PacketNumber::*;
         match self {
@@ -111,6 +122,8 @@ impl PacketNumber {
 }
 
 const KEY_PHASE_BIT: u8 = 0x40;
+/// Bits of a short header's first byte that are concealed by header protection
+pub const SHORT_HEADER_PROTECTED_BITS: u8 = KEY_PHASE_BIT | 0b11;
 
 impl Header {
     pub fn encode<W: BufMut>(&self, w: &mut W) {
@@ -118,12 +131,13 @@ impl Header {
         match *self {
             Long {
                 ty,
+                version,
                 ref source_id,
                 ref destination_id,
                 number,
             } => {
                 w.write(0b1000_0000 | ty);
-                w.write(VERSION);
+                w.write(version.to_wire());
                 let mut dcil = destination_id.len() as u8;
                 if dcil > 0 {
                     dcil -= 3;
@@ -135,8 +149,17 @@ impl Header {
                 w.write(dcil << 4 | scil);
                 w.put_slice(destination_id);
                 w.put_slice(source_id);
-                w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
-                w.write(number);
+                match version {
+                    Version::Draft11 => {
+                        w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
+                        w.write(number);
+                    }
+                    #[cfg(test)]
+                    Version::Test => {
+                        w.write::<u16>(0);
+                        w.write(number);
+                    }
+                }
             }
             Short {
                 ref id,
@@ -195,9 +218,13 @@ impl From<coding::UnexpectedEnd> for HeaderError {
 }
 
 impl Packet {
+    /// Parse the first packet in `packet`, returning it and whatever data follows it
+    ///
+    /// Long-header packets are only understood if their version appears in `supported`.
     pub fn decode(
         mut packet: BytesMut,
         dest_id_len: usize,
+        supported: &[Version],
     ) -> Result<(Self, BytesMut), HeaderError> {
         let (header_len, payload_len, header) = {
             let mut buf = io::Cursor::new(&packet[..]);
@@ -225,8 +252,8 @@ impl Packet {
                 let destination_id = ConnectionId::new(&cid_stage[..dcil as usize]);
                 buf.copy_to_slice(&mut cid_stage[0..scil as usize]);
                 let source_id = ConnectionId::new(&cid_stage[..scil as usize]);
-                match version {
-                    0 => (
+                if version == 0 {
+                    (
                         buf.position() as usize,
                         packet.len() - buf.position() as usize,
                         Header::VersionNegotiate {
@@ -234,31 +261,37 @@ impl Packet {
                             source_id,
                             destination_id,
                         },
-                    ),
-                    VERSION => {
-                        let len = buf.get_var()?;
-                        let number = buf.get()?;
-                        let header_len = buf.position() as usize;
-                        if buf.position() + len > packet.len() as u64 {
-                            return Err(HeaderError::InvalidHeader("payload longer than packet"));
+                    )
+                } else {
+                    let version = match Version::from_wire(version) {
+                        Some(x) if supported.contains(&x) => x,
+                        _ => {
+                            return Err(HeaderError::UnsupportedVersion {
+                                source: source_id,
+                                destination: destination_id,
+                            })
                         }
-                        (
-                            header_len,
-                            len as usize,
-                            Header::Long {
-                                ty,
-                                source_id,
-                                destination_id,
-                                number,
-                            },
-                        )
-                    }
-                    _ => {
-                        return Err(HeaderError::UnsupportedVersion {
-                            source: source_id,
-                            destination: destination_id,
-                        })
+                    };
+                    let (len, number) = match version {
+                        Version::Draft11 => (buf.get_var()?, buf.get()?),
+                        #[cfg(test)]
+                        Version::Test => (buf.get_var()?, buf.get()?),
+                    };
+                    let header_len = buf.position() as usize;
+                    if buf.position() + len > packet.len() as u64 {
+                        return Err(HeaderError::InvalidHeader("payload longer than packet"));
                     }
+                    (
+                        header_len,
+                        len as usize,
+                        Header::Long {
+                            ty,
+                            version,
+                            source_id,
+                            destination_id,
+                            number,
+                        },
+                    )
                 }
             } else {
                 if buf.remaining() < dest_id_len {
@@ -380,6 +413,6 @@ pub const AEAD_TAG_SIZE: usize = 16;
 pub mod types {
     pub const INITIAL: u8 = 0x7F;
     pub const RETRY: u8 = 0x7E;
-    //pub const ZERO_RTT: u8 = 0x7C;
+    pub const ZERO_RTT: u8 = 0x7C;
     pub const HANDSHAKE: u8 = 0x7D;
 }
@@ -1,8 +1,13 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
 
 use bytes::Bytes;
+use fnv::FnvHashMap;
 
+use flow_control;
+use frame;
 use range_set::RangeSet;
+use StreamId;
 
 #[derive(Debug)]
 pub enum Stream {
@@ -12,7 +17,7 @@ pub enum Stream {
 }
 
 impl Stream {
-    pub fn new_bi(window: u64) -> Self {
+    pub fn new_bi(window: flow_control::Window) -> Self {
         Stream::Both(Send::new(), Recv::new(window))
     }
 
@@ -72,6 +77,10 @@ pub struct Send {
     pub state: SendState,
     /// Number of bytes sent but unacked
     pub bytes_in_flight: u64,
+    /// Streams with higher priority are sent first
+    pub priority: i32,
+    /// Limit most recently reported in a STREAM_BLOCKED frame
+    pub blocked_reported: Option<u64>,
 }
 
 impl Send {
@@ -81,6 +90,8 @@ impl Send {
             max_data: 0,
             state: SendState::Ready,
             bytes_in_flight: 0,
+            priority: 0,
+            blocked_reported: None,
         }
     }
 
@@ -99,8 +110,8 @@ pub struct Recv {
     pub state: RecvState,
     pub recvd: RangeSet,
     pub buffered: VecDeque<(Bytes, u64)>,
-    /// Upper limit dictated by the peer
-    pub max_data: u64,
+    /// Credit extended to the peer
+    pub window: flow_control::Window,
     /// Whether any unordered reads have been performed, making this stream unusable for ordered reads
     pub unordered: bool,
     pub assembler: Assembler,
@@ -109,12 +120,12 @@ pub struct Recv {
 }
 
 impl Recv {
-    pub fn new(max_data: u64) -> Self {
+    pub fn new(window: flow_control::Window) -> Self {
         Self {
             state: RecvState::Recv { size: None },
             recvd: RangeSet::new(),
             buffered: VecDeque::new(),
-            max_data,
+            window,
             unordered: false,
             assembler: Assembler::new(),
             fresh: true,
@@ -205,6 +216,11 @@ impl Assembler {
         }
     }
 
+    /// Offset of the next byte to be read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
     /// Whether `peek` will return at least one nonempty slice
     pub fn blocked(&self) -> bool {
         let mask = !0 >> self.written_offset;
@@ -285,6 +301,119 @@ impl Assembler {
     }
 }
 
+/// Stream frames awaiting transmission, scheduled by stream priority
+///
+/// Higher priorities are always served first. Streams of equal priority take turns, so a bulk transfer can't starve its
+/// peers, while each stream's own frames go out in order.
+#[derive(Debug, Clone, Default)]
+pub struct Queue {
+    /// Streams with frames queued at each priority, in the order they'll be served
+    levels: BTreeMap<i32, VecDeque<StreamId>>,
+    /// Priority and queued frames of each stream
+    streams: FnvHashMap<StreamId, (i32, VecDeque<frame::Stream>)>,
+}
+
+impl Queue {
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Queue `frame` after any others of its stream
+    ///
+    /// `priority` is ignored if the stream already has frames queued.
+    pub fn push_back(&mut self, priority: i32, frame: frame::Stream) {
+        self.entry(priority, frame.id).push_back(frame);
+    }
+
+    /// Queue `frame` ahead of any others of its stream
+    pub fn push_front(&mut self, priority: i32, frame: frame::Stream) {
+        self.entry(priority, frame.id).push_front(frame);
+    }
+
+    fn entry(&mut self, priority: i32, id: StreamId) -> &mut VecDeque<frame::Stream> {
+        let levels = &mut self.levels;
+        let entry = self.streams.entry(id).or_insert_with(|| {
+            levels.entry(priority).or_default().push_back(id);
+            (priority, VecDeque::new())
+        });
+        &mut entry.1
+    }
+
+    /// The frame that `pop` would return
+    #[cfg(test)]
+    fn front(&self) -> Option<&frame::Stream> {
+        let id = self.levels.values().next_back()?.front()?;
+        self.streams[id].1.front()
+    }
+
+    /// The most recently queued frame of stream `id`
+    pub fn back_mut(&mut self, id: StreamId) -> Option<&mut frame::Stream> {
+        self.streams.get_mut(&id)?.1.back_mut()
+    }
+
+    /// Take the next frame to send and the priority it was queued at
+    pub fn pop(&mut self) -> Option<(i32, frame::Stream)> {
+        let (priority, id) = {
+            let (&priority, level) = self.levels.iter_mut().next_back()?;
+            (priority, level.pop_front().unwrap())
+        };
+        let (frame, exhausted) = {
+            let frames = &mut self.streams.get_mut(&id).unwrap().1;
+            (frames.pop_front().unwrap(), frames.is_empty())
+        };
+        if exhausted {
+            self.streams.remove(&id);
+        } else {
+            // Give other streams at this priority a turn before sending more of this one
+            self.levels.get_mut(&priority).unwrap().push_back(id);
+        }
+        if self.levels[&priority].is_empty() {
+            self.levels.remove(&priority);
+        }
+        Some((priority, frame))
+    }
+
+    /// Move any frames queued for `id` to `priority`
+    pub fn set_priority(&mut self, id: StreamId, priority: i32) {
+        let old = match self.streams.get_mut(&id) {
+            Some(x) => mem::replace(&mut x.0, priority),
+            None => {
+                return;
+            }
+        };
+        if old == priority {
+            return;
+        }
+        let empty = {
+            let level = self.levels.get_mut(&old).unwrap();
+            level.retain(|&x| x != id);
+            level.is_empty()
+        };
+        if empty {
+            self.levels.remove(&old);
+        }
+        self.levels.entry(priority).or_default().push_back(id);
+    }
+
+    /// Queue all frames from `other` after those already queued for the same streams
+    pub fn append(&mut self, other: Queue) {
+        let mut other_streams = other.streams;
+        for (_, level) in other.levels.into_iter().rev() {
+            for id in level {
+                let (priority, frames) = other_streams.remove(&id).unwrap();
+                for frame in frames {
+                    self.push_back(priority, frame);
+                }
+            }
+        }
+    }
+
+    /// Consume the queue, yielding every frame
+    pub fn into_frames(self) -> impl Iterator<Item = frame::Stream> {
+        self.streams.into_iter().flat_map(|(_, (_, frames))| frames)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -368,4 +497,57 @@ mod test {
         x.insert(0, b"1234");
         assert_matches!(x.next(), None);
     }
+
+    fn frame(id: u64, offset: u64) -> frame::Stream {
+        frame::Stream {
+            id: StreamId(id),
+            offset,
+            fin: false,
+            data: Bytes::from_static(b"x"),
+        }
+    }
+
+    fn pop_all(x: &mut Queue) -> Vec<(u64, u64)> {
+        let mut result = Vec::new();
+        while let Some((_, frame)) = x.pop() {
+            result.push((frame.id.0, frame.offset));
+        }
+        result
+    }
+
+    #[test]
+    fn queue_round_robin() {
+        let mut x = Queue::default();
+        x.push_back(0, frame(4, 0));
+        x.push_back(0, frame(4, 1));
+        x.push_back(0, frame(4, 2));
+        x.push_back(0, frame(8, 0));
+        x.push_back(0, frame(8, 1));
+        assert_eq!(pop_all(&mut x), [(4, 0), (8, 0), (4, 1), (8, 1), (4, 2)]);
+        assert!(x.is_empty());
+    }
+
+    #[test]
+    fn queue_priority() {
+        let mut x = Queue::default();
+        x.push_back(0, frame(4, 0));
+        x.push_back(0, frame(4, 1));
+        x.push_back(1, frame(8, 0));
+        x.push_back(-1, frame(12, 0));
+        x.push_back(0, frame(16, 0));
+        x.set_priority(StreamId(16), 2);
+        assert_eq!(x.front().map(|x| x.id), Some(StreamId(16)));
+        assert_eq!(pop_all(&mut x), [(16, 0), (8, 0), (4, 0), (4, 1), (12, 0)]);
+    }
+
+    #[test]
+    fn queue_append() {
+        let mut x = Queue::default();
+        x.push_back(0, frame(4, 1));
+        let mut lost = Queue::default();
+        lost.push_back(0, frame(4, 0));
+        lost.push_back(1, frame(8, 0));
+        x.append(lost);
+        assert_eq!(pop_all(&mut x), [(8, 0), (4, 1), (4, 0)]);
+    }
 }
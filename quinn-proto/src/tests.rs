@@ -1,18 +1,18 @@
 use std::collections::VecDeque;
 use std::io::{self, Read, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::ops::RangeFrom;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{fmt, fs, str};
 
 use byteorder::{BigEndian, ByteOrder};
-use bytes::Bytes;
-use rustls::{internal::pemfile, ProtocolVersion};
+use bytes::{Bytes, BytesMut};
+use rustls::{self, internal::pemfile, ProtocolVersion};
 use slog::{Drain, Logger, KV};
-use untrusted::Input;
 
 use super::*;
+use connection::SpaceId;
 
 struct TestDrain;
 
@@ -63,13 +63,24 @@ struct Pair {
     time: u64,
     // One-way
     latency: u64,
+    /// Datagrams larger than this are lost in transit
+    mtu: usize,
+    /// Whether routers mark ECN-capable datagrams as having experienced congestion
+    congestion_experienced: bool,
+    /// Whether the path clears the ECN codepoint of every datagram
+    ecn_bleaching: bool,
 }
 
 impl Default for Pair {
     fn default() -> Self {
-        let mut server_config = server_config();
-        server_config.max_remote_uni_streams = 32;
-        server_config.max_remote_bi_streams = 32;
+        let server_config = Config {
+            transport: Arc::new(TransportConfig {
+                max_remote_uni_streams: 32,
+                max_remote_bi_streams: 32,
+                ..TransportConfig::default()
+            }),
+            ..server_config()
+        };
         Pair::new(server_config, Default::default())
     }
 }
@@ -89,7 +100,7 @@ fn server_config() -> Config {
     };
 
     let mut tls_server_config = crypto::build_server_config();
-    tls_server_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_server_config.set_protocols(&[ALPN_QUIC_HTTP.to_vec()]);
     tls_server_config
         .set_single_cert(certs, keys[0].clone())
         .unwrap();
@@ -99,25 +110,34 @@ fn server_config() -> Config {
     }
 }
 
-fn client_config() -> Arc<ClientConfig> {
+fn client_config() -> ClientConfig {
     let mut f = fs::File::open("../certs/ca.der").expect("cannot open '../certs/ca.der'");
     let mut bytes = Vec::new();
     f.read_to_end(&mut bytes).expect("error while reading");
 
-    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(Input::from(&bytes)).unwrap();
+    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(&bytes).unwrap();
     let anchor_vec = vec![anchor];
 
-    let mut tls_client_config = ClientConfig::new();
+    let mut tls_client_config = rustls::ClientConfig::new();
     tls_client_config.versions = vec![ProtocolVersion::TLSv1_3];
-    tls_client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_client_config.set_protocols(&[ALPN_QUIC_HTTP.to_vec()]);
     tls_client_config
         .root_store
         .add_server_trust_anchors(&webpki::TLSServerTrustAnchors(&anchor_vec));
-    Arc::new(tls_client_config)
+    tls_client_config.enable_early_data = true;
+    ClientConfig::new(Arc::new(tls_client_config))
 }
 
 impl Pair {
     fn new(server_config: Config, client_config: Config) -> Self {
+        Self::with_localhost(
+            server_config,
+            client_config,
+            Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into(),
+        )
+    }
+
+    fn with_localhost(server_config: Config, client_config: Config, localhost: IpAddr) -> Self {
         let log = logger();
         let server = Endpoint::new(
             log.new(o!("side" => "Server")),
@@ -126,25 +146,28 @@ impl Pair {
         ).unwrap();
         let client = Endpoint::new(log.new(o!("side" => "Client")), client_config, None).unwrap();
 
-        let localhost = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
-        let server_addr = SocketAddrV6::new(
-            localhost,
-            SERVER_PORTS.lock().unwrap().next().unwrap(),
-            0,
-            0,
-        );
-        let client_addr = SocketAddrV6::new(
-            localhost,
-            CLIENT_PORTS.lock().unwrap().next().unwrap(),
-            0,
-            0,
-        );
+        let server_addr = SocketAddr::new(localhost, SERVER_PORTS.lock().unwrap().next().unwrap());
+        let client_addr = SocketAddr::new(localhost, CLIENT_PORTS.lock().unwrap().next().unwrap());
         Self {
             log,
             server: TestEndpoint::new(Side::Server, server, server_addr),
             client: TestEndpoint::new(Side::Client, client, client_addr),
             time: 0,
             latency: 0,
+            mtu: usize::MAX,
+            congestion_experienced: false,
+            ecn_bleaching: false,
+        }
+    }
+
+    /// Determine the ECN codepoint a datagram arrives with, given the one it was sent with
+    fn path_ecn(&self, ecn: Option<EcnCodepoint>) -> Option<EcnCodepoint> {
+        if self.ecn_bleaching {
+            None
+        } else if self.congestion_experienced && ecn.is_some() {
+            Some(EcnCodepoint::Ce)
+        } else {
+            ecn
         }
     }
 
@@ -179,36 +202,48 @@ impl Pair {
     fn drive_client(&mut self) {
         trace!(self.log, "client running");
         self.client.drive(&self.log, self.time, self.server.addr);
-        for packet in self.client.outbound.drain(..) {
+        while let Some((destination, ecn, packet)) = self.client.outbound.pop_front() {
             self.client
                 .socket
                 .send_to(&packet, self.server.addr)
                 .unwrap();
+            if packet.len() > self.mtu || destination != self.server.addr {
+                continue;
+            }
+            let ecn = self.path_ecn(ecn);
             self.server
                 .inbound
-                .push_back((self.time + self.latency, packet));
+                .push_back((self.time + self.latency, ecn, packet));
         }
     }
 
     fn drive_server(&mut self) {
         trace!(self.log, "server running");
         self.server.drive(&self.log, self.time, self.client.addr);
-        for packet in self.server.outbound.drain(..) {
+        while let Some((destination, ecn, packet)) = self.server.outbound.pop_front() {
             self.server
                 .socket
                 .send_to(&packet, self.client.addr)
                 .unwrap();
+            if packet.len() > self.mtu || destination != self.client.addr {
+                continue;
+            }
+            let ecn = self.path_ecn(ecn);
             self.client
                 .inbound
-                .push_back((self.time + self.latency, packet));
+                .push_back((self.time + self.latency, ecn, packet));
         }
     }
 
     fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
+        self.connect_with(&client_config())
+    }
+
+    fn connect_with(&mut self, config: &ClientConfig) -> (ConnectionHandle, ConnectionHandle) {
         info!(self.log, "connecting");
         let client_conn = self
             .client
-            .connect(self.server.addr, &client_config(), "localhost")
+            .connect(self.server.addr, config, "localhost")
             .unwrap();
         self.drive();
         let server_conn = if let Some(c) = self.server.accept() {
@@ -224,18 +259,20 @@ impl Pair {
 struct TestEndpoint {
     side: Side,
     endpoint: Endpoint,
-    addr: SocketAddrV6,
+    addr: SocketAddr,
     socket: UdpSocket,
     idle: u64,
     loss: u64,
     close: u64,
+    pacing: u64,
+    path_validation: u64,
     conn: Option<ConnectionHandle>,
-    outbound: VecDeque<Box<[u8]>>,
-    inbound: VecDeque<(u64, Box<[u8]>)>,
+    outbound: VecDeque<(SocketAddr, Option<EcnCodepoint>, Box<[u8]>)>,
+    inbound: VecDeque<(u64, Option<EcnCodepoint>, Box<[u8]>)>,
 }
 
 impl TestEndpoint {
-    fn new(side: Side, endpoint: Endpoint, addr: SocketAddrV6) -> Self {
+    fn new(side: Side, endpoint: Endpoint, addr: SocketAddr) -> Self {
         let socket = UdpSocket::bind(addr).unwrap();
         socket
             .set_read_timeout(Some(Duration::new(0, 10_000_000)))
@@ -245,16 +282,18 @@ impl TestEndpoint {
             endpoint,
             addr,
             socket,
-            idle: u64::max_value(),
-            loss: u64::max_value(),
-            close: u64::max_value(),
+            idle: u64::MAX,
+            loss: u64::MAX,
+            close: u64::MAX,
+            pacing: u64::MAX,
+            path_validation: u64::MAX,
             conn: None,
             outbound: VecDeque::new(),
             inbound: VecDeque::new(),
         }
     }
 
-    fn drive(&mut self, log: &Logger, now: u64, remote: SocketAddrV6) {
+    fn drive(&mut self, log: &Logger, now: u64, remote: SocketAddr) {
         loop {
             let mut buf = [0; 8192];
             if self.socket.recv_from(&mut buf).is_err() {
@@ -269,7 +308,7 @@ impl TestEndpoint {
                     side = self.side,
                     timer = Timer::LossDetection
                 );
-                self.loss = u64::max_value();
+                self.loss = u64::MAX;
                 self.endpoint.timeout(now, conn, Timer::LossDetection);
             }
             if self.idle <= now {
@@ -279,9 +318,29 @@ impl TestEndpoint {
                     side = self.side,
                     timer = Timer::Idle
                 );
-                self.idle = u64::max_value();
+                self.idle = u64::MAX;
                 self.endpoint.timeout(now, conn, Timer::Idle);
             }
+            if self.pacing <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::Pacing
+                );
+                self.pacing = u64::MAX;
+                self.endpoint.timeout(now, conn, Timer::Pacing);
+            }
+            if self.path_validation <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::PathValidation
+                );
+                self.path_validation = u64::MAX;
+                self.endpoint.timeout(now, conn, Timer::PathValidation);
+            }
             if self.close <= now {
                 trace!(
                     log,
@@ -289,21 +348,23 @@ impl TestEndpoint {
                     side = self.side,
                     timer = Timer::Close
                 );
-                self.close = u64::max_value();
+                self.close = u64::MAX;
                 self.endpoint.timeout(now, conn, Timer::Close);
             }
         }
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
-            self.endpoint.handle(
-                now,
-                remote,
-                Vec::from(self.inbound.pop_front().unwrap().1).into(),
-            );
+            let (_, ecn, packet) = self.inbound.pop_front().unwrap();
+            self.endpoint
+                .handle(now, remote, ecn, Vec::from(packet).into());
         }
         while let Some(x) = self.endpoint.poll_io(now) {
             match x {
-                Io::Transmit { packet, .. } => {
-                    self.outbound.push_back(packet);
+                Io::Transmit {
+                    destination,
+                    ecn,
+                    packet,
+                } => {
+                    self.outbound.push_back((destination, ecn, packet));
                 }
                 Io::TimerStart {
                     timer,
@@ -328,6 +389,12 @@ impl TestEndpoint {
                         Timer::Close => {
                             self.close = time;
                         }
+                        Timer::Pacing => {
+                            self.pacing = time;
+                        }
+                        Timer::PathValidation => {
+                            self.path_validation = time;
+                        }
                     }
                 }
                 Io::TimerStop { timer, .. } => {
@@ -339,13 +406,19 @@ impl TestEndpoint {
                     );
                     match timer {
                         Timer::LossDetection => {
-                            self.loss = u64::max_value();
+                            self.loss = u64::MAX;
                         }
                         Timer::Idle => {
-                            self.idle = u64::max_value();
+                            self.idle = u64::MAX;
                         }
                         Timer::Close => {
-                            self.close = u64::max_value();
+                            self.close = u64::MAX;
+                        }
+                        Timer::Pacing => {
+                            self.pacing = u64::MAX;
+                        }
+                        Timer::PathValidation => {
+                            self.path_validation = u64::MAX;
                         }
                     }
                 }
@@ -357,7 +430,9 @@ impl TestEndpoint {
         self.idle
             .min(self.loss)
             .min(self.close)
-            .min(self.inbound.front().map_or(u64::max_value(), |x| x.0))
+            .min(self.pacing)
+            .min(self.path_validation)
+            .min(self.inbound.front().map_or(u64::MAX, |x| x.0))
     }
 }
 
@@ -384,6 +459,7 @@ fn version_negotiate() {
     server.handle(
         0,
         client_addr,
+        None,
         // Long-header packet with reserved version number
         hex!(
             "80 0a1a2a3a
@@ -397,16 +473,66 @@ fn version_negotiate() {
     if let Some(Io::Transmit { packet, .. }) = io {
         assert!(packet[0] | 0x80 != 0);
         assert!(&packet[1..14] == hex!("00000000 11 00000000 00000000"));
-        assert!(
-            packet[14..]
-                .chunks(4)
-                .any(|x| BigEndian::read_u32(x) == VERSION)
-        );
+        for version in Version::ALL {
+            assert!(
+                packet[14..]
+                    .chunks(4)
+                    .any(|x| BigEndian::read_u32(x) == version.to_wire())
+            );
+        }
     }
     assert_matches!(server.poll_io(0), None);
     assert_matches!(server.poll(), None);
 }
 
+#[test]
+fn version_mismatch() {
+    let mut pair = Pair::default();
+    let client_conn = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    let (local_id, initial_id) = {
+        let conn = &pair.client.connections[client_conn.0];
+        (conn.local_id, conn.initial_id)
+    };
+    let mut packet = Vec::new();
+    packet::Header::VersionNegotiate {
+        ty: 0,
+        source_id: initial_id,
+        destination_id: local_id,
+    }.encode(&mut packet);
+    packet.extend_from_slice(&hex!("0a1a2a3a"));
+    let server_addr = pair.server.addr;
+    pair.client
+        .handle(pair.time, server_addr, None, packet[..].into());
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::VersionMismatch { ref offered } }))
+                    if conn == client_conn && offered[..] == [0x0a1a_2a3a]);
+}
+
+#[test]
+fn version_fallback() {
+    let server_config = Config {
+        supported_versions: vec![Version::Draft11],
+        ..server_config()
+    };
+    let client_config = Config {
+        supported_versions: vec![Version::Test, Version::Draft11],
+        ..Config::default()
+    };
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.connections[client_conn.0].version,
+        Version::Draft11
+    );
+    assert_eq!(
+        pair.server.connections[server_conn.0].version,
+        Version::Draft11
+    );
+}
+
 #[test]
 fn lifecycle() {
     let mut pair = Pair::default();
@@ -424,19 +550,73 @@ fn lifecycle() {
     assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionDrained)) if conn == client_conn);
 }
 
-/*
+/// The address validation token carried by `datagram`, if it's a Retry
+fn retry_token(datagram: &[u8]) -> Option<ConnectionId> {
+    let (packet, _) = packet::Packet::decode(
+        BytesMut::from(datagram),
+        endpoint::LOCAL_ID_LEN,
+        Version::ALL,
+    ).ok()?;
+    match packet.header {
+        packet::Header::Long {
+            ty: packet::types::RETRY,
+            source_id,
+            ..
+        } => Some(source_id),
+        _ => None,
+    }
+}
+
 #[test]
 fn stateless_retry() {
     let mut pair = Pair::new(
         Config {
             use_stateless_retry: true,
-            ..Config::default()
+            ..server_config()
         },
         Config::default(),
     );
-    pair.connect();
+    let client_conn = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    // The server answers with a Retry carrying a token for the client's address, and keeps no state
+    // until the token comes back
+    let token = retry_token(&pair.server.outbound.front().expect("no response").2)
+        .expect("expected a retry");
+    assert!(crypto::CookieFactory::new(LISTEN_KEYS.cookie).verify(&pair.client.addr, &token));
+    assert_eq!(pair.server.connections.len(), 0);
+
+    pair.drive();
+    assert!(pair.server.accept().is_some());
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+}
+
+#[test]
+fn stateless_retry_wrong_address() {
+    let mut pair = Pair::new(
+        Config {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+        Config::default(),
+    );
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    // The client's second Initial carries a token, but arrives from an address it wasn't issued to
+    let (_, _, initial) = pair.client.outbound.pop_front().unwrap();
+    pair.server.inbound.push_back((pair.time, None, initial));
+    let spoofed = SocketAddr::new(pair.client.addr.ip(), pair.client.addr.port() + 1);
+    pair.server.drive(&pair.log, pair.time, spoofed);
+    assert_eq!(pair.server.connections.len(), 0);
+    assert!(retry_token(&pair.server.outbound.front().expect("no response").2).is_some());
 }
-*/
 
 #[test]
 fn stateless_reset() {
@@ -453,6 +633,182 @@ fn stateless_reset() {
     assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == client_conn);
 }
 
+#[test]
+fn new_connection_ids() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let spare = Config::default().spare_connection_ids as usize;
+    assert_eq!(pair.client.connections[client_conn.0].rem_cids.len(), spare);
+    assert_eq!(pair.server.connections[server_conn.0].rem_cids.len(), spare);
+
+    // Packets addressed to a spare ID reach the same connection
+    let cid = *pair.client.connections[client_conn.0]
+        .rem_cids
+        .values()
+        .next()
+        .unwrap();
+    pair.client.connections[client_conn.0].remote_id = cid.id;
+    pair.client.ping(client_conn);
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_matches!(pair.client.poll(), None);
+}
+
+#[test]
+fn header_protection() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let number = pair.client.connections[client_conn.0]
+        .space(SpaceId::Data)
+        .next_packet_number;
+    pair.client.ping(client_conn);
+    pair.drive_client();
+
+    // The packet number on the wire is only recoverable with the peer's header protection key
+    let mut data = BytesMut::from(&pair.server.inbound.back().unwrap().2[..]);
+    assert!(
+        pair.server.connections[server_conn.0]
+            .space(SpaceId::Data)
+            .crypto
+            .as_ref()
+            .unwrap()
+            .unprotect_header(1 + endpoint::LOCAL_ID_LEN, &mut data)
+    );
+    let (packet, _) = packet::Packet::decode(data, endpoint::LOCAL_ID_LEN, Version::ALL).unwrap();
+    match packet.header {
+        packet::Header::Short { number: x, .. } => assert_eq!(x.expand(number - 1), number),
+        _ => panic!("expected a short header"),
+    }
+
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+}
+
+#[test]
+fn key_update() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.client.initiate_key_update(client_conn);
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].key_phase);
+    assert!(pair.server.connections[server_conn.0].key_phase);
+
+    // Old keys are discarded once the peer is known to have moved on
+    pair.server.ping(server_conn);
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+    assert!(pair.client.connections[client_conn.0].prev_crypto.is_none());
+    assert!(pair.server.connections[server_conn.0].prev_crypto.is_none());
+}
+
+#[test]
+fn automatic_key_update() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect_with(&ClientConfig {
+        transport: Arc::new(TransportConfig {
+            key_update_packets: 5,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+    assert!(!pair.client.connections[client_conn.0].key_phase);
+    assert!(!pair.server.connections[server_conn.0].key_phase);
+
+    for _ in 0..5 {
+        pair.client.ping(client_conn);
+        pair.drive();
+    }
+    assert!(pair.client.connections[client_conn.0].key_phase);
+    assert!(pair.server.connections[server_conn.0].key_phase);
+    // The keys don't advance again until another 5 packets have been sent under them
+    assert!(pair.client.connections[client_conn.0].key_packets_sent < 5);
+}
+
+#[test]
+fn simultaneous_key_update() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Ensure each side's update is in flight when the other's arrives
+    pair.latency = 10 * 1000;
+    pair.client.initiate_key_update(client_conn);
+    pair.server.initiate_key_update(server_conn);
+    pair.drive();
+    // The updates coincide, so the keys only advance once
+    assert!(pair.client.connections[client_conn.0].key_phase);
+    assert!(pair.server.connections[server_conn.0].key_phase);
+
+    pair.client.ping(client_conn);
+    pair.server.ping(server_conn);
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+    assert!(pair.client.connections[client_conn.0].prev_crypto.is_none());
+    assert!(pair.server.connections[server_conn.0].prev_crypto.is_none());
+}
+
+#[test]
+fn migration() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let old_remote_id = pair.client.get_remote_id(client_conn);
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    info!(pair.log, "rebinding");
+    pair.client.handle_rebind();
+    pair.drive();
+    assert_ne!(pair.client.get_remote_id(client_conn), old_remote_id);
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    assert!(
+        pair.server.connections[server_conn.0]
+            .unvalidated_path
+            .is_none()
+    );
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_matches!(pair.client.poll(), None);
+}
+
+#[test]
+fn spoofed_migration() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    let (_, ecn, packet) = pair.server.inbound.back().unwrap().clone();
+    pair.drive();
+
+    // An attacker replays the client's most recent packet from an address the client doesn't control
+    let spoofed = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 2).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let time = pair.time;
+    pair.server
+        .endpoint
+        .handle(time, spoofed, ecn, Vec::from(packet).into());
+    assert_eq!(*pair.server.get_remote_address(server_conn), spoofed);
+
+    // Nothing answers the challenges sent there, so the server falls back to the validated address
+    while pair.server.connections[server_conn.0]
+        .unvalidated_path
+        .is_some()
+    {
+        assert!(pair.step());
+    }
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    pair.server.ping(server_conn);
+    pair.drive();
+    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+}
+
 #[test]
 fn finish_stream() {
     let mut pair = Pair::default();
@@ -533,15 +889,16 @@ fn stop_stream() {
 
 #[test]
 fn reject_self_signed_cert() {
-    let mut client_config = ClientConfig::new();
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
-    client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    let mut tls_client_config = rustls::ClientConfig::new();
+    tls_client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    tls_client_config.set_protocols(&[ALPN_QUIC_HTTP.to_vec()]);
+    let client_config = ClientConfig::new(Arc::new(tls_client_config));
 
     let mut pair = Pair::default();
     info!(pair.log, "connecting");
     let client_conn = pair
         .client
-        .connect(pair.server.addr, &Arc::new(client_config), "localhost")
+        .connect(pair.server.addr, &client_config, "localhost")
         .unwrap();
     pair.drive();
     assert_matches!(pair.client.poll(),
@@ -576,6 +933,141 @@ fn congestion() {
     pair.client.write(client_conn, s, &[42; 1024]).unwrap();
 }
 
+#[test]
+fn mtu_discovery() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let mtu = pair.client.get_mtu(client_conn);
+    assert!(mtu > MIN_MTU && mtu <= TransportConfig::default().max_mtu);
+    assert!(pair.server.get_mtu(server_conn) > MIN_MTU);
+
+    // The path stops carrying such large datagrams
+    pair.mtu = 1300;
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(8192));
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert!(pair.client.get_mtu(client_conn) <= 1300);
+    assert!(
+        pair.client.connections[client_conn.0]
+            .space(SpaceId::Data)
+            .pending
+            .stream
+            .is_empty()
+    );
+}
+
+#[test]
+fn ecn() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.get_ecn(client_conn), Some(EcnCodepoint::Ect0));
+    assert!(pair.server.get_ecn_counts(server_conn).ect0 > 0);
+
+    // Routers signal congestion by marking packets rather than dropping them
+    pair.congestion_experienced = true;
+    let window = pair.client.connections[client_conn.0].congestion.window();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(8192));
+    pair.drive();
+    assert!(pair.server.connections[server_conn.0].ecn_counts.ce > 0);
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(conn.sending_ecn);
+    assert!(conn.ecn_feedback.ce > 0);
+    assert!(conn.congestion.window() < window);
+    assert_eq!(conn.bytes_in_flight, 0);
+}
+
+#[test]
+fn ecn_disabled() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect_with(&ClientConfig {
+        transport: Arc::new(TransportConfig {
+            ecn: false,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+    assert_eq!(pair.client.get_ecn(client_conn), None);
+    assert_eq!(
+        pair.server.get_ecn_counts(server_conn),
+        EcnCounts::default()
+    );
+    // The server's own markings are unaffected
+    assert!(pair.client.get_ecn_counts(client_conn).ect0 > 0);
+}
+
+#[test]
+fn ecn_bleaching() {
+    let mut pair = Pair {
+        ecn_bleaching: true,
+        ..Pair::default()
+    };
+    let (client_conn, server_conn) = pair.connect();
+    // Neither peer sees its markings reported back, so both stop setting them
+    assert!(!pair.client.connections[client_conn.0].sending_ecn);
+    assert!(!pair.server.connections[server_conn.0].sending_ecn);
+}
+
+#[test]
+fn pacing() {
+    let mut pair = Pair {
+        latency: 100 * 1000,
+        ..Pair::default()
+    };
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(8192));
+    pair.drive_client();
+    // Rather than the whole window being sent at once, a pacing timer spreads the data over the round trip
+    assert!(pair.server.inbound.len() < 8192 / MIN_MTU as usize);
+    assert_ne!(pair.client.pacing, u64::MAX);
+    pair.drive();
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert!(
+        pair.client.connections[client_conn.0]
+            .space(SpaceId::Data)
+            .pending
+            .stream
+            .is_empty()
+    );
+}
+
+#[test]
+fn congestion_algorithms() {
+    let custom = CongestionAlgorithm::Custom(Arc::new(|config: &TransportConfig| {
+        Box::new(congestion::NewReno::new(config)) as Box<dyn congestion::CongestionController>
+    }));
+    for algorithm in &[CongestionAlgorithm::Cubic, CongestionAlgorithm::Bbr, custom] {
+        let mut pair = Pair::default();
+        let (client_conn, _) = pair.connect_with(&ClientConfig {
+            transport: Arc::new(TransportConfig {
+                congestion_controller: algorithm.clone(),
+                ..TransportConfig::default()
+            }),
+            ..client_config()
+        });
+        let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+        // Fill the window, then make sure it reopens once everything has been acknowledged
+        loop {
+            match pair.client.write(client_conn, s, &[42; 1024]) {
+                Ok(_) => {
+                    pair.drive_client();
+                }
+                Err(WriteError::Blocked) => {
+                    break;
+                }
+                Err(e) => {
+                    panic!("unexpected write error: {}", e);
+                }
+            }
+        }
+        pair.drive();
+        assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+        pair.client.write(client_conn, s, &[42; 1024]).unwrap();
+    }
+}
+
 #[test]
 fn high_latency_handshake() {
     let mut pair = Pair::default();
@@ -595,39 +1087,77 @@ fn high_latency_handshake() {
     assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
 }
 
-/*
+fn zero_rtt_pair(accept_zero_rtt: bool) -> Pair {
+    let server_config = Config {
+        accept_zero_rtt,
+        transport: Arc::new(TransportConfig {
+            max_remote_uni_streams: 32,
+            max_remote_bi_streams: 32,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    Pair::new(server_config, Default::default())
+}
+
 #[test]
 fn zero_rtt() {
-    let mut pair = Pair::default();
-    let (c, _) = pair.connect();
-    let ticket = match pair.client.poll() {
-        Some((conn, Event::NewSessionTicket { ref ticket })) if conn == c => ticket.clone(),
-        e => panic!("unexpected poll result: {:?}", e),
+    let mut pair = zero_rtt_pair(true);
+    let config = client_config();
+    let (c, s) = pair.connect_with(&config);
+    assert!(!pair.client.get_session_resumed(c));
+    assert!(!pair.server.get_session_resumed(s));
+    info!(pair.log, "closing");
+    pair.client.close(pair.time, c, 42, Bytes::new());
+    pair.drive();
+    info!(pair.log, "resuming");
+    let cc = pair
+        .client
+        .connect(pair.server.addr, &config, "localhost")
+        .unwrap();
+    let s = pair
+        .client
+        .open(cc, Directionality::Uni)
+        .expect("0-RTT stream limits not restored");
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(cc, s, MSG).unwrap();
+    pair.drive();
+    let sc = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't connect");
     };
-    info!(pair.log, "closing"; "ticket size" => ticket.len());
-    pair.client.close(pair.time, c, 42, (&[][..]).into());
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+    assert_eq!(pair.client.get_bytes_in_flight(cc), 0);
+    assert!(pair.client.get_session_resumed(cc));
+    assert!(pair.server.get_session_resumed(sc));
+}
+
+#[test]
+fn zero_rtt_rejection() {
+    let mut pair = zero_rtt_pair(false);
+    let config = client_config();
+    let (c, _) = pair.connect_with(&config);
+    pair.client.close(pair.time, c, 42, Bytes::new());
     pair.drive();
     info!(pair.log, "resuming");
     let cc = pair
         .client
-        .connect(
-            pair.server.addr,
-            "localhost",
-        )
+        .connect(pair.server.addr, &config, "localhost")
         .unwrap();
     let s = pair.client.open(cc, Directionality::Uni).unwrap();
     const MSG: &[u8] = b"Hello, 0-RTT!";
     pair.client.write(cc, s, MSG).unwrap();
     pair.drive();
-    assert!(pair.client.get_session_resumed(c));
     let sc = if let Some(c) = pair.server.accept() {
         c
     } else {
         panic!("server didn't connect");
     };
+    // Rejected early data is retransmitted under 1-RTT keys
     assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+    assert_eq!(pair.client.get_bytes_in_flight(cc), 0);
 }
-*/
 
 #[test]
 fn close_during_handshake() {
@@ -640,10 +1170,44 @@ fn close_during_handshake() {
     // This never actually sends the client's Initial; we may want to behave better here.
 }
 
+#[test]
+fn handshake_spaces() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Once the handshake is confirmed, neither side has any further use for the handshake keys
+    for conn in &[
+        &pair.client.connections[client_conn.0],
+        &pair.server.connections[server_conn.0],
+    ] {
+        assert!(conn.space(SpaceId::Initial).crypto.is_none());
+        assert!(conn.space(SpaceId::Handshake).crypto.is_none());
+        assert!(!conn.awaiting_handshake());
+    }
+}
+
+#[test]
+fn lost_server_flight() {
+    let mut pair = Pair::default();
+    let client_conn = pair
+        .client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    // Drop the server's Initial and Handshake packets, leaving both sides to retransmit on a timer
+    pair.client.inbound.clear();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert!(pair.server.accept().is_some());
+}
+
 #[test]
 fn stream_id_backpressure() {
     let server_config = Config {
-        max_remote_uni_streams: 1,
+        transport: Arc::new(TransportConfig {
+            max_remote_uni_streams: 1,
+            ..TransportConfig::default()
+        }),
         ..server_config()
     };
     let mut pair = Pair::new(server_config, Default::default());
@@ -663,6 +1227,7 @@ fn stream_id_backpressure() {
     pair.drive();
     assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
     assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), Some((conn, Event::PeerBlocked { limit: Limit::StreamId(stream) })) if conn == server_conn && stream == s);
     assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
     assert_matches!(
         pair.server.read_unordered(server_conn, s),
@@ -688,3 +1253,356 @@ fn stream_id_backpressure() {
         Err(ReadError::Finished)
     );
 }
+
+#[test]
+fn stream_blocked() {
+    let server_config = Config {
+        transport: Arc::new(TransportConfig {
+            max_remote_uni_streams: 1,
+            stream_receive_window: 4096,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(server_config, Default::default());
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(4096));
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+
+    assert_matches!(
+        pair.client.write(client_conn, s, &[42; 4096]),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::PeerBlocked { limit: Limit::StreamData { stream, offset: 4096 } })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+
+    // The same limit isn't reported twice
+    assert_matches!(
+        pair.client.write(client_conn, s, &[42; 4096]),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+fn set_max_remote_streams() {
+    let server_config = Config {
+        transport: Arc::new(TransportConfig {
+            max_remote_uni_streams: 1,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(server_config, Default::default());
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.open(client_conn, Directionality::Uni), None);
+    pair.server
+        .set_max_remote_streams(server_conn, Directionality::Uni, 2);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), None);
+    assert!(pair.client.open(client_conn, Directionality::Uni).is_some());
+
+    // Lowering the limit keeps closed streams from being replaced
+    pair.server
+        .set_max_remote_streams(server_conn, Directionality::Uni, 0);
+    pair.client.finish(client_conn, s);
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::PeerBlocked { .. })) if conn == server_conn);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.poll(), None);
+    assert_eq!(pair.client.open(client_conn, Directionality::Uni), None);
+}
+
+#[test]
+fn unlimited_remote_streams() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.server
+        .set_max_remote_streams(server_conn, Directionality::Uni, u64::MAX);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+}
+
+#[test]
+fn implicitly_opened_streams() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.server
+        .set_max_remote_streams(server_conn, Directionality::Uni, 1 << 40);
+    pair.drive();
+
+    // Using a stream far beyond any used before opens all of those in between, without the server allocating for them
+    pair.client.connections[client_conn.0].next_uni_stream = 1 << 30;
+    let high = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, high, b"hello").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == high);
+    assert_eq!(pair.server.connections[server_conn.0].streams.len(), 1);
+
+    // Implicitly opened streams still get state once the peer uses them
+    pair.client.connections[client_conn.0].next_uni_stream = 5;
+    let low = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, low, b"world").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == low);
+    assert_eq!(pair.server.connections[server_conn.0].streams.len(), 2);
+}
+
+#[test]
+fn set_stream_receive_window() {
+    let server_config = Config {
+        transport: Arc::new(TransportConfig {
+            max_remote_uni_streams: 1,
+            stream_receive_window: 4096,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(server_config, Default::default());
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(4096));
+    pair.drive();
+    pair.server.set_stream_receive_window(server_conn, s, 8192);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamWritable { stream })) if conn == client_conn && stream == s);
+    assert_eq!(pair.client.write(client_conn, s, &[42; 8192]), Ok(4096));
+
+    // Streams the peer hasn't opened, even ones beyond its limit, are left alone
+    let streams = pair.server.connections[server_conn.0].streams.len();
+    for index in 1..3 {
+        let id = StreamId::new(Side::Client, Directionality::Uni, index);
+        pair.server.set_stream_receive_window(server_conn, id, 8192);
+    }
+    assert_eq!(
+        pair.server.connections[server_conn.0].streams.len(),
+        streams
+    );
+}
+
+#[test]
+fn transport_selector() {
+    struct Selector;
+    impl TransportConfigSelector for Selector {
+        fn select(
+            &self,
+            server_name: Option<&str>,
+            protocols: &[&[u8]],
+        ) -> Option<Arc<TransportConfig>> {
+            assert_eq!(server_name, Some("localhost"));
+            assert_eq!(protocols, &[ALPN_QUIC_HTTP]);
+            Some(Arc::new(TransportConfig {
+                max_remote_uni_streams: 1,
+                ..TransportConfig::default()
+            }))
+        }
+    }
+
+    let server_config = Config {
+        transport_selector: Some(Arc::new(Selector)),
+        ..server_config()
+    };
+    let mut pair = Pair::new(server_config, Default::default());
+    let (client_conn, _) = pair.connect();
+    assert!(pair.client.open(client_conn, Directionality::Uni).is_some());
+    assert_eq!(pair.client.open(client_conn, Directionality::Uni), None);
+}
+
+#[test]
+fn config_validation() {
+    let mut transport = TransportConfigBuilder::new();
+    transport.idle_timeout(Duration::from_secs(601));
+    assert_eq!(
+        transport.build().err(),
+        Some(ConfigError::IdleTimeoutTooLong)
+    );
+    let mut transport = TransportConfigBuilder::new();
+    transport.initial_window(4000).minimum_window(8000);
+    assert_eq!(
+        transport.build().err(),
+        Some(ConfigError::MinimumWindowExceedsInitial)
+    );
+    let mut transport = TransportConfigBuilder::new();
+    transport.default_mss(0);
+    assert_eq!(transport.build().err(), Some(ConfigError::ZeroMss));
+    let mut transport = TransportConfigBuilder::new();
+    transport
+        .idle_timeout(Duration::from_millis(599_500))
+        .ack_delay_exponent(20)
+        .default_initial_rtt(Duration::from_millis(50));
+    let transport = transport.build().unwrap();
+    assert_eq!(transport.idle_timeout, 600);
+    assert_eq!(transport.default_initial_rtt, 50_000);
+
+    let mut config = ConfigBuilder::new();
+    config.supported_versions(Vec::new());
+    assert_eq!(config.build().err(), Some(ConfigError::NoSupportedVersions));
+
+    let config = Config {
+        transport: Arc::new(TransportConfig {
+            ack_delay_exponent: 21,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::Config(ConfigError::AckDelayExponentTooLarge))
+    );
+}
+
+#[test]
+fn datagrams() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let max = pair.client.get_max_datagram_size(client_conn).unwrap();
+    assert_matches!(
+        pair.client
+            .send_datagram(client_conn, vec![0; max + 1].into()),
+        Err(SendDatagramError::TooLarge)
+    );
+    pair.client
+        .send_datagram(client_conn, Bytes::from_static(b"hello"))
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::DatagramReceived { ref data })) if conn == server_conn && data == &b"hello"[..]);
+    assert_matches!(pair.server.poll(), None);
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+}
+
+#[test]
+fn datagrams_unsupported() {
+    let server = Config {
+        transport: Arc::new(TransportConfig {
+            max_datagram_frame_size: 0,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(server, Default::default());
+    let (client_conn, _) = pair.connect();
+    assert_eq!(pair.client.get_max_datagram_size(client_conn), None);
+    assert_matches!(
+        pair.client
+            .send_datagram(client_conn, Bytes::from_static(b"hello")),
+        Err(SendDatagramError::UnsupportedByPeer)
+    );
+}
+
+#[test]
+fn stream_priority() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s1 = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let s2 = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    // Too much for one packet, so the first packet carries only the higher priority stream
+    const MSG: &[u8] = &[42; 2000];
+    pair.client.write(client_conn, s1, MSG).unwrap();
+    pair.client.write(client_conn, s2, MSG).unwrap();
+    pair.client.set_priority(client_conn, s2, 1);
+    let server_addr = pair.server.addr;
+    pair.client.drive(&pair.log, pair.time, server_addr);
+    let (_, ecn, packet) = pair.client.outbound.pop_front().unwrap();
+    pair.client.outbound.clear();
+    pair.server.inbound.push_back((pair.time, ecn, packet));
+    pair.drive_server();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s2);
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+fn set_priority_closed_stream() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.finish(client_conn, s);
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
+    pair.client.set_priority(client_conn, s, 1);
+}
+
+/// Number of datagrams queued by `endpoint` that carry more than one packet
+fn count_coalesced(endpoint: &TestEndpoint) -> usize {
+    let mut n = 0;
+    for (_, _, datagram) in &endpoint.outbound {
+        let data = BytesMut::from(&datagram[..]);
+        if let Ok((_, rest)) = packet::Packet::decode(data, endpoint::LOCAL_ID_LEN, Version::ALL) {
+            if !rest.is_empty() {
+                n += 1;
+            }
+        }
+    }
+    n
+}
+
+#[test]
+fn coalesce_handshake() {
+    let mut pair = Pair::default();
+    pair.client
+        .connect(pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    let mut coalesced = 0;
+    loop {
+        let log = pair.log.clone();
+        let (time, client_addr, server_addr) = (pair.time, pair.client.addr, pair.server.addr);
+        pair.client.drive(&log, time, server_addr);
+        coalesced += count_coalesced(&pair.client);
+        pair.server.drive(&log, time, client_addr);
+        coalesced += count_coalesced(&pair.server);
+        if !pair.step() {
+            break;
+        }
+    }
+    // The server's Initial packet, carrying its ServerHello, shares a datagram with its first Handshake packet
+    assert!(coalesced > 0);
+    assert!(pair.server.accept().is_some());
+}
+
+#[test]
+fn ipv4() {
+    let mut pair = Pair::with_localhost(
+        server_config(),
+        Default::default(),
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        *pair.client.get_remote_address(client_conn),
+        pair.server.addr
+    );
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    assert!(pair.server.get_remote_address(server_conn).is_ipv4());
+}
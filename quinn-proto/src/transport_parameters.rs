@@ -1,8 +1,8 @@
 use bytes::{Buf, BufMut};
 
 use coding::{BufExt, BufMutExt};
-use endpoint::Config;
-use {Side, TransportError, VERSION};
+use endpoint::TransportConfig;
+use {Side, TransportError, Version};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct TransportParameters {
@@ -14,21 +14,32 @@ pub struct TransportParameters {
     pub initial_max_streams_uni: u16,
     pub max_packet_size: Option<u16>,
     pub ack_delay_exponent: u8,
+    /// Largest DATAGRAM frame the sender is willing to receive, if any
+    pub max_datagram_frame_size: Option<u16>,
 }
 
 impl TransportParameters {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(config: &TransportConfig) -> Self {
         TransportParameters {
             initial_max_streams_bidi: config.max_remote_bi_streams,
             initial_max_streams_uni: config.max_remote_uni_streams,
             initial_max_data: config.receive_window,
             initial_max_stream_data: config.stream_receive_window,
+            idle_timeout: config.idle_timeout,
+            ack_delay_exponent: config.ack_delay_exponent,
+            max_datagram_frame_size: if config.max_datagram_frame_size == 0 {
+                None
+            } else {
+                Some(config.max_datagram_frame_size)
+            },
             ..Default::default()
         }
     }
 }
 
 const DEFAULT_ACK_DELAY_EXPONENT: u8 = 3;
+/// From the unreliable datagram extension rather than draft 11
+const MAX_DATAGRAM_FRAME_SIZE_ID: u16 = 0x0020;
 
 impl Default for TransportParameters {
     fn default() -> Self {
@@ -42,6 +53,7 @@ impl Default for TransportParameters {
             initial_max_streams_uni: 0,
             max_packet_size: None,
             ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            max_datagram_frame_size: None,
         }
     }
 }
@@ -66,14 +78,20 @@ impl From<Error> for TransportError {
 }
 
 impl TransportParameters {
-    pub fn write<W: BufMut>(&self, side: Side, w: &mut W) {
+    /// Encode the parameters, preceded by the version information for `side`
+    ///
+    /// `version` is the negotiated version for a server, or the version initially attempted for a client. `supported`
+    /// is only advertised by servers.
+    pub fn write<W: BufMut>(&self, side: Side, version: Version, supported: &[Version], w: &mut W) {
         if side == Side::Server {
-            w.write::<u32>(VERSION); // Negotiated version
-            w.write::<u8>(8); // Bytes of supported versions
+            w.write::<u32>(version.to_wire()); // Negotiated version
+            w.write::<u8>(4 * (1 + supported.len() as u8)); // Bytes of supported versions
             w.write::<u32>(0x0a1a_2a3a); // Reserved version
-            w.write::<u32>(VERSION); // Real supported version
+            for x in supported {
+                w.write::<u32>(x.to_wire()); // Real supported version
+            }
         } else {
-            w.write::<u32>(VERSION); // Initially requested version
+            w.write::<u32>(version.to_wire()); // Initially requested version
         }
 
         let mut buf = Vec::with_capacity(22);
@@ -120,27 +138,49 @@ impl TransportParameters {
             buf.write::<u8>(self.ack_delay_exponent);
         }
 
+        if let Some(x) = self.max_datagram_frame_size {
+            buf.write::<u16>(MAX_DATAGRAM_FRAME_SIZE_ID);
+            buf.write::<u16>(2);
+            buf.write::<u16>(x);
+        }
+
         w.write::<u16>(buf.len() as u16);
         w.put_slice(&buf);
     }
 
-    pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
+    /// Decode parameters sent by the peer of `side`
+    ///
+    /// `version` is the version in use on the connection, and `supported` the versions we would have accepted.
+    pub fn read<R: Buf>(
+        side: Side,
+        version: Version,
+        supported: &[Version],
+        r: &mut R,
+    ) -> Result<Self, Error> {
         if side == Side::Server {
             if r.remaining() < 26 {
                 return Err(Error::Malformed);
             }
-            // We only support one version, so there is no validation to do here.
-            r.get::<u32>().unwrap();
+            let initial = r.get::<u32>().unwrap();
+            // If the client gave up on a version we'd have accepted, its version negotiation was tampered with.
+            if initial != version.to_wire()
+                && Version::from_wire(initial).is_some_and(|x| supported.contains(&x))
+            {
+                return Err(Error::VersionNegotiation);
+            }
         } else {
             if r.remaining() < 31 {
                 return Err(Error::Malformed);
             }
             let negotiated = r.get::<u32>().unwrap();
-            if negotiated != VERSION {
+            if negotiated != version.to_wire() {
                 return Err(Error::VersionNegotiation);
             }
             let supported_bytes = r.get::<u8>().unwrap();
-            if supported_bytes < 4 || supported_bytes > 252 || supported_bytes % 4 != 0 {
+            if !(4..=252).contains(&supported_bytes)
+                || supported_bytes % 4 != 0
+                || r.remaining() < supported_bytes as usize
+            {
                 return Err(Error::Malformed);
             }
             let mut found = false;
@@ -232,6 +272,12 @@ impl TransportParameters {
                         return Err(Error::IllegalValue);
                     }
                 }
+                MAX_DATAGRAM_FRAME_SIZE_ID => {
+                    if len != 2 || params.max_datagram_frame_size.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.max_datagram_frame_size = Some(r.get::<u16>().unwrap());
+                }
                 _ => r.advance(len as usize),
             }
         }
@@ -257,11 +303,36 @@ mod test {
             initial_max_streams_uni: 16,
             ack_delay_exponent: 2,
             max_packet_size: Some(1200),
+            max_datagram_frame_size: Some(1200),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Client, Version::Draft11, &[], &mut buf);
+        assert_eq!(
+            TransportParameters::read(
+                Side::Server,
+                Version::Draft11,
+                Version::ALL,
+                &mut buf.into_buf()
+            ).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn server_coding() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            stateless_reset_token: Some([0xab; 16]),
             ..TransportParameters::default()
         };
-        params.write(Side::Client, &mut buf);
+        params.write(Side::Server, Version::Draft11, Version::ALL, &mut buf);
         assert_eq!(
-            TransportParameters::read(Side::Server, &mut buf.into_buf()).unwrap(),
+            TransportParameters::read(
+                Side::Client,
+                Version::Draft11,
+                Version::ALL,
+                &mut buf.into_buf()
+            ).unwrap(),
             params
         );
     }
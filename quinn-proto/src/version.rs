@@ -0,0 +1,71 @@
+use slog;
+
+/// A QUIC wire version implemented by this crate
+///
+/// Everything that differs between drafts, from the handshake salt to the layout of the long header, is looked up
+/// through this type, so that one endpoint can talk to peers implementing any of its `Config::supported_versions`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Version {
+    /// draft-ietf-quic-transport-11
+    Draft11,
+    /// Draft 11 under another version number, so that tests can exercise version negotiation
+    #[cfg(test)]
+    Test,
+}
+
+impl Version {
+    /// Every implemented version, most preferred first
+    #[cfg(not(test))]
+    pub const ALL: &'static [Version] = &[Version::Draft11];
+    /// Every implemented version, most preferred first
+    #[cfg(test)]
+    pub const ALL: &'static [Version] = &[Version::Draft11, Version::Test];
+
+    /// Look up the version identified by `x` on the wire, if implemented
+    pub fn from_wire(x: u32) -> Option<Self> {
+        Self::ALL.iter().cloned().find(|v| v.to_wire() == x)
+    }
+
+    /// The number identifying this version on the wire
+    pub fn to_wire(self) -> u32 {
+        match self {
+            Version::Draft11 => 0xff00_000b,
+            #[cfg(test)]
+            Version::Test => 0x5154_5354,
+        }
+    }
+
+    /// TLS ALPN value for HTTP over QUIC at this version
+    pub fn alpn_quic_http(self) -> &'static [u8] {
+        match self {
+            Version::Draft11 => b"hq-11",
+            #[cfg(test)]
+            Version::Test => b"hq-test",
+        }
+    }
+
+    /// Salt from which Initial and Retry packet protection keys are derived
+    pub(crate) fn handshake_salt(self) -> &'static [u8; 20] {
+        match self {
+            Version::Draft11 => &DRAFT_11_SALT,
+            #[cfg(test)]
+            Version::Test => &DRAFT_11_SALT,
+        }
+    }
+}
+
+impl slog::Value for Version {
+    fn serialize(
+        &self,
+        _: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{:?}", self))
+    }
+}
+
+const DRAFT_11_SALT: [u8; 20] = [
+    0x9c, 0x10, 0x8f, 0x98, 0x52, 0x0a, 0x5c, 0x5c, 0x32, 0x96, 0x8e, 0x95, 0x0e, 0x8a, 0x2c, 0x5f,
+    0xe0, 0x6d, 0x6c, 0x38,
+];
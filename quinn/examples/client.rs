@@ -114,7 +114,11 @@ fn run(log: Logger, options: Opt) -> Result<()> {
 
     let client_config = client_config.build();
 
-    let (endpoint, driver, _) = builder.bind("[::]:0")?;
+    let (endpoint, driver, _) = builder.bind(if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
     let mut runtime = Runtime::new()?;
     runtime.spawn(driver.map_err(|e| eprintln!("IO error: {}", e)));
 
@@ -63,17 +63,21 @@ fn run(log: Logger, options: Opt) -> Result<()> {
     let mut client_config = quinn::ClientConfigBuilder::new();
     client_config.accept_insecure_certs(); // Various interop test servers use self-signed certs
     builder.logger(log.clone());
+    client_config.enable_0rtt();
     if options.keylog {
         client_config.enable_keylog();
     }
     let client_config = client_config.build();
-    let (endpoint, driver, _) = builder.bind("[::]:0")?;
+    let (endpoint, driver, _) = builder.bind(if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
     runtime.spawn(driver.map_err(|e| eprintln!("IO error: {}", e)));
 
     let mut handshake = false;
     let mut stream_data = false;
     let mut close = false;
-    let mut ticket = None;
     let result = runtime.block_on(
         endpoint
             .connect_with(&client_config, &remote, &options.host)?
@@ -81,7 +85,6 @@ fn run(log: Logger, options: Opt) -> Result<()> {
             .and_then(|conn| {
                 println!("connected");
                 handshake = true;
-                let tickets = conn.session_tickets;
                 let conn = conn.connection;
                 let stream = conn.open_bi();
                 stream
@@ -93,15 +96,6 @@ fn run(log: Logger, options: Opt) -> Result<()> {
                         conn.close(0, b"done").map_err(|_| unreachable!())
                     }).map(|()| {
                         close = true;
-                    }).and_then(|()| {
-                        tickets
-                            .into_future()
-                            .map_err(|(e, _)| e.into())
-                            .map(|(x, _)| {
-                                if let Some(x) = x {
-                                    ticket = Some(x);
-                                }
-                            })
                     })
             }),
     );
@@ -131,14 +125,11 @@ fn run(log: Logger, options: Opt) -> Result<()> {
         }
     }
 
-    let resumption = false;
-    /*
-    if let Some(ticket) = ticket {
-        println!("attempting 0-RTT");
-        let (conn, established) = endpoint.connect_zero_rtt(
-            &remote,
-            &options.host,
-        )?;
+    let mut resumption = false;
+    if handshake {
+        println!("attempting resumption with 0-RTT");
+        let (conn, established) =
+            endpoint.connect_zero_rtt_with(&client_config, &remote, &options.host)?;
         let conn = conn.connection;
         let request = conn
             .open_bi()
@@ -158,7 +149,6 @@ fn run(log: Logger, options: Opt) -> Result<()> {
             println!("failure: {}", e);
         }
     }
-    */
 
     if handshake {
         print!("VH");
@@ -17,6 +17,7 @@ use std::fs;
 use std::net::SocketAddr;
 use std::path::{self, Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::{io, str};
 
 use failure::{err_msg, Fail, ResultExt};
@@ -105,10 +106,11 @@ fn run(log: Logger, options: Opt) -> Result<()> {
 
     let mut runtime = Runtime::new()?;
 
-    let mut builder = quinn::EndpointBuilder::from_config(quinn::Config {
-        max_remote_bi_streams: 64,
-        ..Default::default()
-    });
+    let mut transport = quinn::TransportConfigBuilder::new();
+    transport.max_remote_bi_streams(64);
+    let mut config = quinn::ConfigBuilder::new();
+    config.transport(Arc::new(transport.build()?));
+    let mut builder = quinn::EndpointBuilder::from_config(config.build()?);
     builder
         .set_protocols(&[quinn::ALPN_QUIC_HTTP])
         .logger(log.clone())
@@ -145,6 +147,7 @@ fn handle_connection(root: &PathBuf, log: &Logger, conn: quinn::NewConnection) {
     let quinn::NewConnection {
         incoming,
         connection,
+        ..
     } = conn;
     let log = log.new(o!("local_id" => format!("{}", connection.local_id())));
     info!(log, "got connection";
@@ -47,35 +47,43 @@
 //! can be used to provide encryption alone.
 #![warn(missing_docs)]
 
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate assert_matches;
 extern crate bytes;
 #[macro_use]
 extern crate failure;
 extern crate fnv;
 extern crate futures;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate mio;
 extern crate quinn_proto as quinn;
 extern crate rand;
 extern crate rustls;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 #[macro_use]
 extern crate slog;
 extern crate tokio_io;
 extern crate tokio_reactor;
 extern crate tokio_timer;
-extern crate tokio_udp;
-extern crate untrusted;
+#[cfg(all(test, feature = "serde"))]
+extern crate toml;
 extern crate webpki;
 extern crate webpki_roots;
 
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{hash_map, VecDeque};
-use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::rc::Rc;
-use std::str;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::{io, mem};
+use std::{cmp, io, mem};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use fnv::FnvHashMap;
 use futures::stream::FuturesUnordered;
 use futures::task::{self, Task};
@@ -86,11 +94,26 @@ use rustls::{Certificate, KeyLogFile, PrivateKey, ProtocolVersion, TLSError};
 use slog::Logger;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Delay;
-use tokio_udp::UdpSocket;
-
-use quinn::{ConnectionHandle, Directionality, Side, StreamId};
 
-pub use quinn::{Config, ConnectError, ConnectionError, ConnectionId, ListenKeys, ALPN_QUIC_HTTP};
+use quinn::{ConnectionHandle, Directionality, Side};
+
+pub use quinn::{
+    Config, ConfigBuilder, ConfigError, CongestionAlgorithm, ConnectError, ConnectionError,
+    ConnectionId, EcnCodepoint, EcnCounts, InMemorySessionStore, ListenKeys, SendDatagramError,
+    SessionStore, StreamId, TransportConfig, TransportConfigBuilder, TransportConfigSelector,
+    Version, ALPN_QUIC_HTTP,
+};
+
+mod platform;
+use platform::{RecvMeta, Transmit, BATCH_SIZE};
+mod udp;
+use udp::{canonical_addr, UdpSocket};
+#[cfg(feature = "serde")]
+mod settings;
+#[cfg(feature = "serde")]
+pub use settings::{
+    CongestionSetting, EndpointSettings, ListenKeySettings, SettingsError, TransportSettings,
+};
 
 /// Errors that can occur during the construction of an `Endpoint`.
 #[derive(Debug, Fail)]
@@ -113,6 +136,9 @@ pub enum Error {
     /// Errors relating to web PKI infrastructure
     #[fail(display = "webpki failed: {:?}", _0)]
     WebPki(webpki::Error),
+    /// The endpoint configuration was inconsistent
+    #[fail(display = "invalid configuration: {}", _0)]
+    Config(ConfigError),
 }
 
 impl From<quinn::EndpointError> for Error {
@@ -123,6 +149,7 @@ impl From<quinn::EndpointError> for Error {
             Keylog(x) => Error::Keylog(x),
             ProtocolTooLong(x) => Error::ProtocolTooLong(x),
             InvalidDnsName(x) => Error::InvalidDnsName(x),
+            Config(x) => Error::Config(x),
         }
     }
 }
@@ -137,7 +164,9 @@ struct EndpointInner {
     log: Logger,
     socket: UdpSocket,
     inner: quinn::Endpoint,
-    outgoing: VecDeque<(SocketAddrV6, Box<[u8]>)>,
+    outgoing: VecDeque<Transmit>,
+    /// Space for a batch of incoming messages, each of which may hold several datagrams coalesced by the kernel
+    recv_buf: Box<[u8]>,
     epoch: Instant,
     pending: FnvHashMap<ConnectionHandle, Pending>,
     // TODO: Replace this with something custom that avoids using oneshots to cancel
@@ -163,14 +192,16 @@ struct Pending {
     bi_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
     cancel_loss_detect: Option<oneshot::Sender<()>>,
     cancel_idle: Option<oneshot::Sender<()>>,
+    cancel_pacing: Option<oneshot::Sender<()>>,
+    cancel_path_validation: Option<oneshot::Sender<()>>,
     incoming_streams: VecDeque<StreamId>,
     incoming_streams_reader: Option<Task>,
+    incoming_datagrams: VecDeque<Bytes>,
+    incoming_datagrams_reader: Option<Task>,
     finishing: FnvHashMap<StreamId, oneshot::Sender<Option<ConnectionError>>>,
     error: Option<ConnectionError>,
     draining: Option<oneshot::Sender<()>>,
     drained: bool,
-    incoming_session_tickets: VecDeque<Box<[u8]>>,
-    incoming_session_tickets_reader: Option<Task>,
 }
 
 impl Pending {
@@ -183,14 +214,16 @@ impl Pending {
             bi_opening: VecDeque::new(),
             cancel_loss_detect: None,
             cancel_idle: None,
+            cancel_pacing: None,
+            cancel_path_validation: None,
             incoming_streams: VecDeque::new(),
             incoming_streams_reader: None,
+            incoming_datagrams: VecDeque::new(),
+            incoming_datagrams_reader: None,
             finishing: FnvHashMap::default(),
             error: None,
             draining: None,
             drained: false,
-            incoming_session_tickets: VecDeque::new(),
-            incoming_session_tickets_reader: None,
         }
     }
 
@@ -214,12 +247,12 @@ impl Pending {
         if let Some(x) = self.incoming_streams_reader.take() {
             x.notify();
         }
+        if let Some(x) = self.incoming_datagrams_reader.take() {
+            x.notify();
+        }
         for (_, x) in self.finishing.drain() {
             let _ = x.send(Some(reason.clone()));
         }
-        if let Some(x) = self.incoming_session_tickets_reader.take() {
-            x.notify();
-        }
     }
 }
 
@@ -311,11 +344,8 @@ impl<'a> EndpointBuilder<'a> {
     pub fn set_protocols(&mut self, protocols: &[&[u8]]) -> &mut Self {
         {
             let tls_server_config = Arc::get_mut(&mut self.config.tls_server_config).unwrap();
-            let protocols_strings = protocols
-                .iter()
-                .map(|p| str::from_utf8(p).unwrap().into())
-                .collect::<Vec<_>>();
-            tls_server_config.set_protocols(&protocols_strings);
+            let protocols = protocols.iter().map(|p| p.to_vec()).collect::<Vec<_>>();
+            tls_server_config.set_protocols(&protocols);
         }
         self
     }
@@ -344,6 +374,7 @@ impl<'a> EndpointBuilder<'a> {
             socket,
             inner: quinn::Endpoint::new(self.logger, self.config, self.listen)?,
             outgoing: VecDeque::new(),
+            recv_buf: vec![0; BATCH_SIZE * MAX_RECV_MESSAGE_SIZE].into(),
             epoch: Instant::now(),
             pending: FnvHashMap::default(),
             timers: FuturesUnordered::new(),
@@ -380,25 +411,31 @@ impl<'a> Default for EndpointBuilder<'a> {
 
 /// Helper for creating new outgoing connections.
 pub struct ClientConfigBuilder {
-    config: quinn::ClientConfig,
+    config: rustls::ClientConfig,
+    session_store: Option<Arc<dyn SessionStore>>,
+    transport: Option<Arc<TransportConfig>>,
 }
 
 impl ClientConfigBuilder {
     /// Create a new builder with default options set.
     pub fn new() -> Self {
-        let mut config = quinn::ClientConfig::new();
+        let mut config = rustls::ClientConfig::new();
         config
             .root_store
             .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
         config.versions = vec![ProtocolVersion::TLSv1_3];
-        Self { config }
+        Self {
+            config,
+            session_store: None,
+            transport: None,
+        }
     }
 
     /// Add a trusted certificate authority.
     pub fn add_certificate_authority(&mut self, der: &[u8]) -> Result<&mut Self, Error> {
         {
             let anchor =
-                webpki::trust_anchor_util::cert_der_as_trust_anchor(untrusted::Input::from(der))?;
+                webpki::trust_anchor_util::cert_der_as_trust_anchor(der)?;
             self.config
                 .root_store
                 .add_server_trust_anchors(&webpki::TLSServerTrustAnchors(&[anchor]));
@@ -414,15 +451,33 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Attempt to send 0-RTT data when resuming a previous session.
+    ///
+    /// Only takes effect for connections made with `Endpoint::connect_zero_rtt` or `connect_zero_rtt_with`.
+    pub fn enable_0rtt(&mut self) -> &mut Self {
+        self.config.enable_early_data = true;
+        self
+    }
+
+    /// Set where to keep the state needed to resume sessions with previously contacted servers.
+    ///
+    /// Defaults to an `InMemorySessionStore` private to the built configuration.
+    pub fn session_store(&mut self, store: Arc<dyn SessionStore>) -> &mut Self {
+        self.session_store = Some(store);
+        self
+    }
+
+    /// Set the transport configuration for connections made with the built configuration.
+    ///
+    /// Defaults to `TransportConfig::default()`.
+    pub fn transport_config(&mut self, config: Arc<TransportConfig>) -> &mut Self {
+        self.transport = Some(config);
+        self
+    }
+
     /// Set application-layer protocols to declare support for.
     pub fn set_protocols(&mut self, protocols: &[&[u8]]) -> &mut Self {
-        self.config.alpn_protocols = protocols
-            .iter()
-            .map(|p| {
-                str::from_utf8(p)
-                    .expect("non-UTF8 protocols unsupported")
-                    .into()
-            }).collect();
+        self.config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
         self
     }
 
@@ -430,6 +485,12 @@ impl ClientConfigBuilder {
     pub fn build(self) -> ClientConfig {
         ClientConfig {
             tls_config: Arc::new(self.config),
+            session_store: self
+                .session_store
+                .unwrap_or_else(|| Arc::new(InMemorySessionStore::default())),
+            transport: self
+                .transport
+                .unwrap_or_else(|| Arc::new(TransportConfig::default())),
         }
     }
 
@@ -475,8 +536,13 @@ impl Default for ClientConfigBuilder {
 pub struct ClientConfig {
     /// TLS configuration to use.
     ///
-    /// `versions` *must* be `vec![ProtocolVersion::TLSv1_3]`.
-    pub tls_config: Arc<quinn::ClientConfig>,
+    /// `versions` *must* be `vec![ProtocolVersion::TLSv1_3]`. `session_persistence` is ignored in favor of
+    /// `session_store`.
+    pub tls_config: Arc<rustls::ClientConfig>,
+    /// Storage for the state needed to resume sessions with previously contacted servers.
+    pub session_store: Arc<dyn SessionStore>,
+    /// Transport configuration for connections made with this configuration.
+    pub transport: Arc<TransportConfig>,
 }
 
 impl Default for ClientConfig {
@@ -491,6 +557,23 @@ impl Endpoint {
         EndpointBuilder::default()
     }
 
+    /// Switch to a new UDP socket, e.g. because the host moved to a different network.
+    ///
+    /// Outgoing connections migrate to the new socket's address, which their peers validate before relying on it.
+    /// Incoming connections can't migrate, so this is mainly useful for client endpoints.
+    pub fn rebind(
+        &self,
+        socket: std::net::UdpSocket,
+        reactor: &tokio_reactor::Handle,
+    ) -> io::Result<()> {
+        let socket = UdpSocket::from_std(socket, reactor)?;
+        let mut endpoint = self.inner.borrow_mut();
+        endpoint.socket = socket;
+        endpoint.inner.handle_rebind();
+        endpoint.notify();
+        Ok(())
+    }
+
     /// Connect to a remote endpoint.
     ///
     /// May fail immediately due to configuration errors, or in the future if the connection could not be established.
@@ -513,7 +596,7 @@ impl Endpoint {
         server_name: &str,
     ) -> Result<impl Future<Item = NewClientConnection, Error = ConnectionError>, ConnectError>
     {
-        let (fut, conn) = self.connect_inner(addr, &config.tls_config, server_name)?;
+        let (fut, conn) = self.connect_inner(addr, config, server_name)?;
         Ok(fut.map_err(|_| unreachable!()).and_then(move |err| {
             if let Some(err) = err {
                 Err(err)
@@ -523,24 +606,43 @@ impl Endpoint {
         }))
     }
 
-    /*
     /// Connect to a remote endpoint, with support for transmitting data before the connection is established
     ///
     /// Returns a connection that may be used for sending immediately, and a future that will complete when the
     /// connection is established.
     ///
+    /// Data can only be sent early if the default client configuration has 0-RTT enabled and a session with
+    /// `server_name` was previously established; otherwise, streams will become available only once the connection is
+    /// established.
+    ///
     /// Data transmitted this way may be replayed by an attacker until the session ticket expires. Never send non-idempotent
     /// commands as 0-RTT data.
     ///
     /// Servers may reject 0-RTT data, in which case anything sent will be retransmitted after the connection is
     /// established.
-    ///
-    /// # Panics
-    /// - If `config.session_ticket` is `None`. A session ticket is necessary for 0-RTT to be possible.
     pub fn connect_zero_rtt(
         &self,
         addr: &SocketAddr,
-        config: ClientConfig,
+        server_name: &str,
+    ) -> Result<
+        (
+            NewClientConnection,
+            impl Future<Item = (), Error = ConnectionError>,
+        ),
+        ConnectError,
+    > {
+        self.connect_zero_rtt_with(&self.default_client_config, addr, server_name)
+    }
+
+    /// Connect to a remote endpoint using a custom configuration, with support for transmitting data before the
+    /// connection is established
+    ///
+    /// See `connect_zero_rtt` for details.
+    pub fn connect_zero_rtt_with(
+        &self,
+        config: &ClientConfig,
+        addr: &SocketAddr,
+        server_name: &str,
     ) -> Result<
         (
             NewClientConnection,
@@ -548,11 +650,7 @@ impl Endpoint {
         ),
         ConnectError,
     > {
-        assert!(
-            config.session_ticket.is_some(),
-            "a session ticket must be supplied for zero-rtt transmits to be possible"
-        );
-        let (fut, conn) = self.connect_inner(addr, config)?;
+        let (fut, conn) = self.connect_inner(addr, config, server_name)?;
         let conn = NewClientConnection::new(Rc::new(conn));
         Ok((
             conn,
@@ -560,12 +658,11 @@ impl Endpoint {
                 .and_then(move |err| err.map_or(Ok(()), Err)),
         ))
     }
-    */
 
     fn connect_inner(
         &self,
         addr: &SocketAddr,
-        config: &Arc<quinn::ClientConfig>,
+        config: &ClientConfig,
         server_name: &str,
     ) -> Result<
         (
@@ -577,9 +674,20 @@ impl Endpoint {
         let (send, recv) = oneshot::channel();
         let handle = {
             let mut endpoint = self.inner.borrow_mut();
+            if !endpoint.socket.supports_addr(addr) {
+                return Err(ConnectError::UnsupportedAddressFamily(*addr));
+            }
             let handle = endpoint
                 .inner
-                .connect(normalize(*addr), config, server_name)?;
+                .connect(
+                    canonical_addr(*addr),
+                    &quinn::ClientConfig {
+                        tls_config: config.tls_config.clone(),
+                        session_store: config.session_store.clone(),
+                        transport: config.transport.clone(),
+                    },
+                    server_name,
+                )?;
             endpoint.pending.insert(handle, Pending::new(Some(send)));
             handle
         };
@@ -598,6 +706,8 @@ pub struct NewConnection {
     pub connection: Connection,
     /// The stream of QUIC streams initiated by the client.
     pub incoming: IncomingStreams,
+    /// The stream of unreliable datagrams sent by the client.
+    pub datagrams: IncomingDatagrams,
 }
 
 impl NewConnection {
@@ -609,7 +719,8 @@ impl NewConnection {
         });
         NewConnection {
             connection: Connection(conn.clone()),
-            incoming: IncomingStreams(conn),
+            incoming: IncomingStreams(conn.clone()),
+            datagrams: IncomingDatagrams(conn),
         }
     }
 }
@@ -620,8 +731,8 @@ pub struct NewClientConnection {
     pub connection: Connection,
     /// The stream of QUIC streams initiated by the client.
     pub incoming: IncomingStreams,
-    /// The stream of session tickets provided by the server.
-    pub session_tickets: IncomingSessionTickets,
+    /// The stream of unreliable datagrams sent by the server.
+    pub datagrams: IncomingDatagrams,
 }
 
 impl NewClientConnection {
@@ -629,7 +740,7 @@ impl NewClientConnection {
         Self {
             connection: Connection(conn.clone()),
             incoming: IncomingStreams(conn.clone()),
-            session_tickets: IncomingSessionTickets(conn),
+            datagrams: IncomingDatagrams(conn),
         }
     }
 }
@@ -638,7 +749,7 @@ impl Future for Driver {
     type Item = ();
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut buf = [0; 64 * 1024];
+        let mut meta = [RecvMeta::default(); BATCH_SIZE];
         let endpoint = &mut *self.0.borrow_mut();
         if endpoint.driver.is_none() {
             endpoint.driver = Some(task::current());
@@ -646,11 +757,16 @@ impl Future for Driver {
         let now = micros_from(endpoint.epoch.elapsed());
         loop {
             loop {
-                match endpoint.socket.poll_recv_from(&mut buf) {
-                    Ok(Async::Ready((n, addr))) => {
-                        endpoint
-                            .inner
-                            .handle(now, normalize(addr), (&buf[0..n]).into());
+                match endpoint.socket.poll_recv(&mut endpoint.recv_buf, &mut meta) {
+                    Ok(Async::Ready(msgs)) => {
+                        let chunks = endpoint.recv_buf.chunks(MAX_RECV_MESSAGE_SIZE);
+                        for (meta, buf) in meta.iter().zip(chunks).take(msgs) {
+                            let mut data: BytesMut = buf[0..meta.len].into();
+                            while !data.is_empty() {
+                                let datagram = data.split_to(cmp::min(meta.stride, data.len()));
+                                endpoint.inner.handle(now, meta.addr, meta.ecn, datagram);
+                            }
+                        }
                     }
                     Ok(Async::NotReady) => {
                         break;
@@ -668,14 +784,28 @@ impl Future for Driver {
                 use quinn::Event::*;
                 match event {
                     Connected { .. } => {
-                        let _ = endpoint
-                            .pending
-                            .get_mut(&connection)
-                            .unwrap()
+                        let pending = endpoint.pending.get_mut(&connection).unwrap();
+                        let _ = pending
                             .connecting
                             .take()
                             .expect("got Connected event for unknown connection")
                             .send(None);
+                        // Streams requested before the peer's limits were known (e.g. due to rejected or absent
+                        // 0-RTT) may be available now.
+                        for &directionality in &[Directionality::Uni, Directionality::Bi] {
+                            let queue = match directionality {
+                                Directionality::Uni => &mut pending.uni_opening,
+                                Directionality::Bi => &mut pending.bi_opening,
+                            };
+                            while let Some(ch) = queue.pop_front() {
+                                if let Some(id) = endpoint.inner.open(connection, directionality) {
+                                    let _ = ch.send(Ok(id));
+                                } else {
+                                    queue.push_front(ch);
+                                    break;
+                                }
+                            }
+                        }
                     }
                     ConnectionLost { reason } => {
                         // HACK HACK HACK: Handshake currently emits ConnectionLost, which means we might not know about
@@ -730,6 +860,17 @@ impl Future for Driver {
                             }
                         }
                     }
+                    DatagramReceived { data } => {
+                        let pending = endpoint.pending.get_mut(&connection).unwrap();
+                        if pending.incoming_datagrams.len() == MAX_INCOMING_DATAGRAMS {
+                            // The oldest datagram is the least likely to still be of use
+                            pending.incoming_datagrams.pop_front();
+                        }
+                        pending.incoming_datagrams.push_back(data);
+                        if let Some(x) = pending.incoming_datagrams_reader.take() {
+                            x.notify();
+                        }
+                    }
                     StreamFinished { stream } => {
                         let _ = endpoint
                             .pending
@@ -740,64 +881,24 @@ impl Future for Driver {
                             .unwrap()
                             .send(None);
                     }
-                    NewSessionTicket { ticket } => {
-                        let pending = endpoint.pending.get_mut(&connection).unwrap();
-                        const SESSION_TICKET_BUFFER_SIZE: usize = 16;
-                        if pending.incoming_session_tickets.len() >= SESSION_TICKET_BUFFER_SIZE {
-                            pending.incoming_session_tickets.pop_front();
-                        }
-                        pending.incoming_session_tickets.push_back(ticket);
-                        if let Some(x) = pending.incoming_session_tickets_reader.take() {
-                            x.notify();
-                        }
-                    }
-                }
-            }
-            let mut blocked = false;
-            while !endpoint.outgoing.is_empty() {
-                {
-                    let front = endpoint.outgoing.front().unwrap();
-                    match endpoint.socket.poll_send_to(&front.1, &front.0.into()) {
-                        Ok(Async::Ready(_)) => {}
-                        Ok(Async::NotReady) => {
-                            blocked = true;
-                            break;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                            blocked = true;
-                            break;
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
+                    PeerBlocked { limit } => {
+                        trace!(endpoint.log, "peer blocked"; "limit" => ?limit);
                     }
                 }
-                endpoint.outgoing.pop_front();
             }
             while let Some(io) = endpoint.inner.poll_io(now) {
                 use quinn::Io::*;
                 match io {
                     Transmit {
                         destination,
+                        ecn,
                         packet,
                     } => {
-                        if !blocked {
-                            match endpoint.socket.poll_send_to(&packet, &destination.into()) {
-                                Ok(Async::Ready(_)) => {}
-                                Ok(Async::NotReady) => {
-                                    blocked = true;
-                                }
-                                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                                    blocked = true;
-                                }
-                                Err(e) => {
-                                    return Err(e);
-                                }
-                            }
-                        }
-                        if blocked {
-                            endpoint.outgoing.push_front((destination, packet));
-                        }
+                        endpoint.outgoing.push_back(platform::Transmit {
+                            destination: endpoint.socket.native_addr(destination),
+                            ecn,
+                            contents: packet,
+                        });
                     }
                     TimerStart {
                         connection,
@@ -817,7 +918,7 @@ impl Future for Driver {
                         timer,
                         time,
                     } => {
-                        // Loss detection and idle timers start before the connection is established
+                        // Loss detection, idle and pacing timers start before the connection is established
                         let pending = endpoint
                             .pending
                             .entry(connection)
@@ -826,6 +927,8 @@ impl Future for Driver {
                         let mut cancel = match timer {
                             LossDetection => &mut pending.cancel_loss_detect,
                             Idle => &mut pending.cancel_idle,
+                            Pacing => &mut pending.cancel_pacing,
+                            PathValidation => &mut pending.cancel_path_validation,
                             Close => unreachable!(),
                         };
                         let instant = endpoint.epoch + duration_micros(time);
@@ -844,7 +947,7 @@ impl Future for Driver {
                     }
                     TimerStop { connection, timer } => {
                         trace!(endpoint.log, "timer stop"; "timer" => ?timer);
-                        // If a connection was lost, we already canceled its loss/idle timers.
+                        // If a connection was lost, we already canceled its loss/idle/pacing timers.
                         if let Some(pending) = endpoint.pending.get_mut(&connection) {
                             use quinn::Timer::*;
                             match timer {
@@ -856,12 +959,35 @@ impl Future for Driver {
                                 Idle => {
                                     pending.cancel_idle.take().map(|x| x.send(()));
                                 }
+                                Pacing => {
+                                    pending.cancel_pacing.take().map(|x| x.send(()));
+                                }
+                                PathValidation => {
+                                    pending.cancel_path_validation.take().map(|x| x.send(()));
+                                }
                                 Close => {} // Arises from stateless reset
                             }
                         }
                     }
                 }
             }
+            while !endpoint.outgoing.is_empty() {
+                // Only the first contiguous run of the queue can be handed to the socket at once
+                match endpoint.socket.poll_send(endpoint.outgoing.as_slices().0) {
+                    Ok(Async::Ready(n)) => {
+                        endpoint.outgoing.drain(..n);
+                    }
+                    Ok(Async::NotReady) => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            }
             while let Ok(Async::Ready(_)) = endpoint.incoming.poll_ready() {
                 if let Some(x) = endpoint.inner.accept() {
                     if endpoint
@@ -899,6 +1025,13 @@ impl Future for Driver {
     }
 }
 
+/// Largest message a single receive may produce: a UDP datagram of maximum size, or a batch of smaller datagrams
+/// coalesced by the kernel
+const MAX_RECV_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Maximum number of received datagrams to queue for the application before discarding the oldest
+const MAX_INCOMING_DATAGRAMS: usize = 1024;
+
 fn duration_micros(x: u64) -> Duration {
     Duration::new(x / (1000 * 1000), (x % (1000 * 1000)) as u32 * 1000)
 }
@@ -906,13 +1039,6 @@ fn micros_from(x: Duration) -> u64 {
     x.as_secs() * 1000 * 1000 + x.subsec_micros() as u64
 }
 
-fn normalize(x: SocketAddr) -> SocketAddrV6 {
-    match x {
-        SocketAddr::V6(x) => x,
-        SocketAddr::V4(x) => SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0),
-    }
-}
-
 struct ConnectionInner {
     endpoint: Rc<RefCell<EndpointInner>>,
     conn: ConnectionHandle,
@@ -967,6 +1093,61 @@ impl Connection {
             .map(move |stream| Stream::new(conn.clone(), stream))
     }
 
+    /// Transmit `data` as an unreliable, unordered datagram.
+    ///
+    /// Datagrams are never retransmitted, and may be silently dropped if the path is congested or lossy.
+    pub fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError> {
+        let mut endpoint = self.0.endpoint.borrow_mut();
+        endpoint.inner.send_datagram(self.0.conn, data)?;
+        endpoint.notify();
+        Ok(())
+    }
+
+    /// Change the number of unidirectional streams the peer may have open at once.
+    ///
+    /// Lowering the limit takes effect as the peer's existing streams are closed.
+    pub fn set_max_remote_uni_streams(&self, count: u64) {
+        let mut endpoint = self.0.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_max_remote_streams(self.0.conn, Directionality::Uni, count);
+        endpoint.notify();
+    }
+
+    /// Change the number of bidirectional streams the peer may have open at once.
+    ///
+    /// Lowering the limit takes effect as the peer's existing streams are closed.
+    pub fn set_max_remote_bi_streams(&self, count: u64) {
+        let mut endpoint = self.0.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_max_remote_streams(self.0.conn, Directionality::Bi, count);
+        endpoint.notify();
+    }
+
+    /// Set the number of bytes the peer may send on one of this connection's streams beyond what has been read.
+    ///
+    /// See `Stream::set_receive_window`. Has no effect on a stream that's already closed.
+    ///
+    /// # Panics
+    /// - when `stream` is a send-only stream
+    pub fn set_stream_receive_window(&self, stream: StreamId, window: u64) {
+        let mut endpoint = self.0.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_stream_receive_window(self.0.conn, stream, window);
+        endpoint.notify();
+    }
+
+    /// Largest datagram that may currently be sent, or `None` if the peer doesn't accept datagrams.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.0
+            .endpoint
+            .borrow()
+            .inner
+            .get_max_datagram_size(self.0.conn)
+    }
+
     /// Close the connection immediately.
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility to call this only when
@@ -1009,12 +1190,29 @@ impl Connection {
 
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
-        (*self
+        *self
             .0
             .endpoint
             .borrow()
             .inner
-            .get_remote_address(self.0.conn)).into()
+            .get_remote_address(self.0.conn)
+    }
+
+    /// Largest UDP payload currently sent to the peer, as determined by path MTU discovery.
+    pub fn mtu(&self) -> u16 {
+        self.0.endpoint.borrow().inner.get_mtu(self.0.conn)
+    }
+
+    /// ECN codepoint outgoing packets are marked with.
+    ///
+    /// None if ECN was disabled with `TransportConfigBuilder::ecn`, or if the path or peer turned out not to support it.
+    pub fn ecn(&self) -> Option<EcnCodepoint> {
+        self.0.endpoint.borrow().inner.get_ecn(self.0.conn)
+    }
+
+    /// Number of packets received with each ECN codepoint
+    pub fn ecn_counts(&self) -> EcnCounts {
+        self.0.endpoint.borrow().inner.get_ecn_counts(self.0.conn)
     }
 
     /// The `ConnectionId` used for `conn` locally.
@@ -1136,6 +1334,40 @@ impl Stream {
             recvd: false,
         }
     }
+
+    /// Identifies this stream within its connection.
+    pub fn id(&self) -> StreamId {
+        self.stream
+    }
+
+    /// Set the priority of data written to this stream.
+    ///
+    /// Data on streams with higher priority is always sent first, and streams of equal priority share the connection
+    /// fairly. Streams start at priority 0.
+    pub fn set_priority(&self, priority: i32) {
+        if self.finished {
+            return;
+        }
+        let mut endpoint = self.conn.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_priority(self.conn.conn, self.stream, priority);
+    }
+
+    /// Set the number of bytes the peer may send on this stream beyond what has been read.
+    ///
+    /// The window otherwise grows automatically, up to `TransportConfigBuilder::max_stream_receive_window`, while data is
+    /// read quickly.
+    pub fn set_receive_window(&self, window: u64) {
+        if self.recvd {
+            return;
+        }
+        let mut endpoint = self.conn.endpoint.borrow_mut();
+        endpoint
+            .inner
+            .set_stream_receive_window(self.conn.conn, self.stream, window);
+        endpoint.notify();
+    }
 }
 
 impl Write for Stream {
@@ -1345,6 +1577,20 @@ impl AsyncRead for Stream {
 /// A stream that can only be used to send data
 pub struct SendStream(Stream);
 
+impl SendStream {
+    /// Identifies this stream within its connection.
+    pub fn id(&self) -> StreamId {
+        self.0.id()
+    }
+
+    /// Set the priority of data written to this stream.
+    ///
+    /// See `Stream::set_priority`.
+    pub fn set_priority(&self, priority: i32) {
+        self.0.set_priority(priority);
+    }
+}
+
 impl Write for SendStream {
     fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
         Write::poll_write(&mut self.0, buf)
@@ -1375,6 +1621,20 @@ impl AsyncWrite for SendStream {
 /// A stream that can only be used to receive data
 pub struct RecvStream(Stream);
 
+impl RecvStream {
+    /// Identifies this stream within its connection.
+    pub fn id(&self) -> StreamId {
+        self.0.id()
+    }
+
+    /// Set the number of bytes the peer may send on this stream beyond what has been read.
+    ///
+    /// See `Stream::set_receive_window`.
+    pub fn set_receive_window(&self, window: u64) {
+        self.0.set_receive_window(window);
+    }
+}
+
 impl Read for RecvStream {
     fn poll_read_unordered(&mut self) -> Poll<(Bytes, u64), ReadError> {
         self.0.poll_read_unordered()
@@ -1476,24 +1736,24 @@ impl FuturesStream for IncomingStreams {
     }
 }
 
-/// A stream of session tickets supplied by the server.
-pub struct IncomingSessionTickets(Rc<ConnectionInner>);
+/// A stream of unreliable datagrams sent by a remote peer.
+///
+/// If the application falls behind, the oldest unread datagrams are discarded.
+pub struct IncomingDatagrams(Rc<ConnectionInner>);
 
-impl FuturesStream for IncomingSessionTickets {
-    type Item = Box<[u8]>;
+impl FuturesStream for IncomingDatagrams {
+    type Item = Bytes;
     type Error = ConnectionError;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         let mut endpoint = self.0.endpoint.borrow_mut();
         let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-        if let Some(x) = pending.incoming_session_tickets.pop_front() {
+        if let Some(x) = pending.incoming_datagrams.pop_front() {
             return Ok(Async::Ready(Some(x)));
         }
         if let Some(ref x) = pending.error {
             Err(x.clone())
-        } else if pending.drained {
-            return Ok(Async::Ready(None));
         } else {
-            pending.incoming_session_tickets_reader = Some(task::current());
+            pending.incoming_datagrams_reader = Some(task::current());
             Ok(Async::NotReady)
         }
     }
@@ -0,0 +1,46 @@
+use std::cell::Cell;
+use std::io;
+
+use mio::net::UdpSocket;
+
+use super::{Capabilities, RecvMeta, Transmit, UdpExt};
+
+// ECN markings are neither set nor reported here, so peers soon stop setting them too. Datagrams are transferred one
+// per system call.
+impl UdpExt for UdpSocket {
+    fn init_ext(&self) -> io::Result<Capabilities> {
+        Ok(Capabilities {
+            max_gso_segments: 1,
+        })
+    }
+
+    fn send_ext(&self, transmits: &[Transmit], _: &Cell<usize>) -> io::Result<usize> {
+        let mut sent = 0;
+        for transmit in transmits {
+            match self.send_to(&transmit.contents, &transmit.destination) {
+                Ok(_) => {
+                    sent += 1;
+                }
+                Err(ref e) if sent != 0 && e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    fn recv_ext(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let chunk = buf.len() / meta.len();
+        let (len, addr) = self.recv_from(&mut buf[..chunk])?;
+        meta[0] = RecvMeta {
+            addr,
+            len,
+            stride: len,
+            ecn: None,
+        };
+        Ok(1)
+    }
+}
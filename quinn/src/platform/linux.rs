@@ -0,0 +1,409 @@
+use std::cell::Cell;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{cmp, io, mem, ptr};
+
+use libc;
+use mio::net::UdpSocket;
+
+use quinn::EcnCodepoint;
+
+use super::{Capabilities, RecvMeta, Transmit, UdpExt, BATCH_SIZE};
+
+/// Room for an ECN control message alongside a `UDP_SEGMENT` or `UDP_GRO` one, suitably aligned
+type ControlBuffer = [u64; 16];
+
+// Not yet exposed by the libc crate
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+/// Most datagrams the kernel will produce from a single segmentation offload send
+const MAX_GSO_SEGMENTS: usize = 64;
+/// Largest UDP payload an IPv4 datagram can carry, which bounds a segmentation offload send
+const MAX_GSO_BYTES: usize = 65507;
+
+impl UdpExt for UdpSocket {
+    fn init_ext(&self) -> io::Result<Capabilities> {
+        let fd = self.as_raw_fd();
+        let is_ipv6 = self.local_addr()?.is_ipv6();
+        if is_ipv6 {
+            set_socket_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        }
+        // Dual-stack sockets report the TOS byte of IPv4 datagrams separately; IPv6-only sockets may refuse this.
+        let result = set_socket_option(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        if !is_ipv6 {
+            result?;
+        }
+        // Segmentation offload arrived in Linux 4.18 and receive offload in 5.0; older kernels reject the options.
+        let max_gso_segments = if get_socket_option(fd, libc::IPPROTO_UDP, UDP_SEGMENT).is_ok() {
+            MAX_GSO_SEGMENTS
+        } else {
+            1
+        };
+        let _ = set_socket_option(fd, libc::IPPROTO_UDP, UDP_GRO, 1);
+        Ok(Capabilities { max_gso_segments })
+    }
+
+    fn send_ext(&self, transmits: &[Transmit], max_segments: &Cell<usize>) -> io::Result<usize> {
+        let transmits = &transmits[..cmp::min(transmits.len(), BATCH_SIZE)];
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut ctrls = [[0u64; 16]; BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        // Number of datagrams carried by each message
+        let mut segments = [0; BATCH_SIZE];
+        for (iov, transmit) in iovs.iter_mut().zip(transmits) {
+            iov.iov_base = transmit.contents.as_ptr() as *mut libc::c_void;
+            iov.iov_len = transmit.contents.len();
+        }
+        let mut msgs = 0;
+        let mut i = 0;
+        while i < transmits.len() {
+            let start = i;
+            i += gso_batch_len(&transmits[start..], max_segments.get());
+            let first = &transmits[start];
+            let stride = first.contents.len();
+            let (name, namelen) = to_sockaddr(&first.destination);
+            names[msgs] = name;
+            let hdr = &mut hdrs[msgs].msg_hdr;
+            hdr.msg_name = &mut names[msgs] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = namelen;
+            hdr.msg_iov = &mut iovs[start];
+            hdr.msg_iovlen = (i - start) as _;
+            hdr.msg_control = ctrls[msgs].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+            let mut encoder = Encoder::new(hdr);
+            if let Some(ecn) = first.ecn {
+                // IPv4 traffic, including that sent from dual-stack sockets, takes its TOS byte from IP_TOS
+                if is_ipv4(&first.destination) {
+                    encoder.push(libc::IPPROTO_IP, libc::IP_TOS, ecn as libc::c_int);
+                } else {
+                    encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ecn as libc::c_int);
+                }
+            }
+            if i - start > 1 {
+                encoder.push(libc::IPPROTO_UDP, UDP_SEGMENT, stride as u16);
+            }
+            encoder.finish();
+            segments[msgs] = i - start;
+            msgs += 1;
+        }
+        let n =
+            unsafe { libc::sendmmsg(self.as_raw_fd(), hdrs.as_mut_ptr(), msgs as libc::c_uint, 0) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EIO) && segments[..msgs].iter().any(|&x| x > 1) {
+                // The kernel supports segmentation offload, but the network device the route leads through can't
+                // checksum the segments
+                max_segments.set(1);
+                return self.send_ext(transmits, max_segments);
+            }
+            return Err(e);
+        }
+        Ok(segments[..n as usize].iter().sum())
+    }
+
+    fn recv_ext(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let chunk = buf.len() / meta.len();
+        let count = cmp::min(meta.len(), BATCH_SIZE);
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut ctrls = [[0u64; 16]; BATCH_SIZE];
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (i, chunk) in buf.chunks_mut(chunk).take(count).enumerate() {
+            iovs[i].iov_base = chunk.as_mut_ptr() as *mut libc::c_void;
+            iovs[i].iov_len = chunk.len();
+            let hdr = &mut hdrs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = ctrls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+        }
+        let n = unsafe {
+            libc::recvmmsg(
+                self.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                count as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for i in 0..n as usize {
+            let hdr = &hdrs[i].msg_hdr;
+            let len = hdrs[i].msg_len as usize;
+            let mut ecn = None;
+            let mut stride = len;
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+                while !cmsg.is_null() {
+                    let data = libc::CMSG_DATA(cmsg);
+                    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                        (libc::IPPROTO_IP, libc::IP_TOS) => {
+                            ecn = EcnCodepoint::from_bits(*data);
+                        }
+                        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                            let tclass = ptr::read_unaligned(data as *const libc::c_int);
+                            ecn = EcnCodepoint::from_bits(tclass as u8);
+                        }
+                        (libc::IPPROTO_UDP, UDP_GRO) => {
+                            stride = ptr::read_unaligned(data as *const libc::c_int) as usize;
+                        }
+                        _ => {}
+                    }
+                    cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+                }
+            }
+            let addr = match from_sockaddr(&names[i]) {
+                Some(x) => x,
+                None => {
+                    // Nothing we could reply to; discard it without losing the rest of the batch
+                    meta[i] = RecvMeta::default();
+                    continue;
+                }
+            };
+            meta[i] = RecvMeta {
+                addr,
+                len,
+                stride,
+                ecn,
+            };
+        }
+        Ok(n as usize)
+    }
+}
+
+/// Number of datagrams from the start of `transmits` that can be sent as a single segmentation offload batch
+fn gso_batch_len(transmits: &[Transmit], max_segments: usize) -> usize {
+    let first = &transmits[0];
+    let stride = first.contents.len();
+    let mut total = stride;
+    let mut n = 1;
+    // The kernel cuts a message into `stride`-sized datagrams, so only the last may be shorter
+    while n < transmits.len()
+        && n < max_segments
+        && transmits[n].destination == first.destination
+        && transmits[n].ecn == first.ecn
+        && transmits[n - 1].contents.len() == stride
+        && transmits[n].contents.len() <= stride
+        && total + transmits[n].contents.len() <= MAX_GSO_BYTES
+    {
+        total += transmits[n].contents.len();
+        n += 1;
+    }
+    n
+}
+
+/// Helper for appending control messages to a `msghdr` with a zeroed control buffer
+struct Encoder<'a> {
+    hdr: &'a mut libc::msghdr,
+    cmsg: *mut libc::cmsghdr,
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(hdr: &'a mut libc::msghdr) -> Self {
+        let cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+        Self { hdr, cmsg, len: 0 }
+    }
+
+    fn push<T: Copy>(&mut self, level: libc::c_int, ty: libc::c_int, value: T) {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<T>() as u32) } as usize;
+        assert!(
+            self.len + space <= self.hdr.msg_controllen as _,
+            "control buffer too small"
+        );
+        unsafe {
+            (*self.cmsg).cmsg_level = level;
+            (*self.cmsg).cmsg_type = ty;
+            (*self.cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(self.cmsg) as *mut T, value);
+            self.cmsg = (self.cmsg as *mut u8).add(space) as *mut libc::cmsghdr;
+        }
+        self.len += space;
+    }
+
+    /// Trim the control buffer to the messages pushed
+    fn finish(self) {
+        self.hdr.msg_controllen = self.len as _;
+    }
+}
+
+fn get_socket_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn set_socket_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether datagrams to `addr` are carried over IPv4
+fn is_ipv4(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(ref x) => {
+            let segments = x.ip().segments();
+            segments[..5] == [0; 5] && segments[5] == 0xffff
+        }
+    }
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_flowinfo = addr.flowinfo();
+            raw.sin6_addr.s6_addr = addr.ip().octets();
+            raw.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(raw.sin_addr.s_addr)),
+                u16::from_be(raw.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(raw.sin6_addr.s6_addr),
+                u16::from_be(raw.sin6_port),
+                raw.sin6_flowinfo,
+                raw.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transmit(destination: &str, ecn: Option<EcnCodepoint>, len: usize) -> Transmit {
+        Transmit {
+            destination: destination.parse().unwrap(),
+            ecn,
+            contents: vec![0; len].into_boxed_slice(),
+        }
+    }
+
+    const A: &str = "[::1]:4433";
+    const B: &str = "[::1]:4434";
+
+    #[test]
+    fn gso_batch_destination() {
+        let transmits = [
+            transmit(A, None, 1200),
+            transmit(A, None, 1200),
+            transmit(B, None, 1200),
+        ];
+        assert_eq!(gso_batch_len(&transmits, MAX_GSO_SEGMENTS), 2);
+        assert_eq!(gso_batch_len(&transmits[2..], MAX_GSO_SEGMENTS), 1);
+    }
+
+    #[test]
+    fn gso_batch_ecn() {
+        let transmits = [
+            transmit(A, Some(EcnCodepoint::Ect0), 1200),
+            transmit(A, Some(EcnCodepoint::Ect0), 1200),
+            transmit(A, None, 1200),
+        ];
+        assert_eq!(gso_batch_len(&transmits, MAX_GSO_SEGMENTS), 2);
+    }
+
+    #[test]
+    fn gso_batch_length() {
+        // Only the last datagram may be shorter than the first
+        let transmits = [
+            transmit(A, None, 1200),
+            transmit(A, None, 1200),
+            transmit(A, None, 800),
+            transmit(A, None, 800),
+        ];
+        assert_eq!(gso_batch_len(&transmits, MAX_GSO_SEGMENTS), 3);
+        let transmits = [transmit(A, None, 800), transmit(A, None, 1200)];
+        assert_eq!(gso_batch_len(&transmits, MAX_GSO_SEGMENTS), 1);
+    }
+
+    #[test]
+    fn gso_batch_limits() {
+        let transmits = (0..MAX_GSO_SEGMENTS + 1)
+            .map(|_| transmit(A, None, 100))
+            .collect::<Vec<_>>();
+        assert_eq!(gso_batch_len(&transmits, 1), 1);
+        assert_eq!(gso_batch_len(&transmits, 4), 4);
+        assert_eq!(
+            gso_batch_len(&transmits, MAX_GSO_SEGMENTS),
+            MAX_GSO_SEGMENTS
+        );
+        let transmits = (0..MAX_GSO_SEGMENTS)
+            .map(|_| transmit(A, None, 1500))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gso_batch_len(&transmits, MAX_GSO_SEGMENTS),
+            MAX_GSO_BYTES / 1500
+        );
+    }
+
+    #[test]
+    fn sockaddr_family() {
+        let addr = A.parse().unwrap();
+        assert_eq!(from_sockaddr(&to_sockaddr(&addr).0), Some(addr));
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        storage.ss_family = libc::AF_UNIX as libc::sa_family_t;
+        assert_eq!(from_sockaddr(&storage), None);
+    }
+}
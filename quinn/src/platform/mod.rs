@@ -0,0 +1,66 @@
+//! Uniform interface to send and receive batches of UDP datagrams along with their ECN codepoints
+
+use std::cell::Cell;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use quinn::EcnCodepoint;
+
+#[cfg(not(target_os = "linux"))]
+mod fallback;
+#[cfg(target_os = "linux")]
+mod linux;
+
+/// Maximum number of messages transferred by a single `send_ext` or `recv_ext` call
+pub const BATCH_SIZE: usize = 32;
+
+pub trait UdpExt {
+    /// Prepare the socket to report the ECN codepoints of received datagrams, and enable any offloads available
+    fn init_ext(&self) -> io::Result<Capabilities>;
+    /// Send a prefix of `transmits`, returning how many were sent
+    ///
+    /// Consecutive datagrams for the same destination are handed to the kernel as a single segmentation offload batch
+    /// of up to `max_segments` datagrams where possible. `max_segments` is lowered to 1 if the path turns out not to
+    /// support segmentation offload after all.
+    fn send_ext(&self, transmits: &[Transmit], max_segments: &Cell<usize>) -> io::Result<usize>;
+    /// Receive up to `meta.len()` messages into equal-sized chunks of `buf`, returning how many were received
+    fn recv_ext(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> io::Result<usize>;
+}
+
+/// Features of the platform's UDP stack that a socket was set up to use
+#[derive(Debug, Copy, Clone)]
+pub struct Capabilities {
+    /// Maximum number of datagrams the kernel will split a single send into; 1 if segmentation offload is unavailable
+    pub max_gso_segments: usize,
+}
+
+/// A datagram awaiting transmission
+#[derive(Debug)]
+pub struct Transmit {
+    pub destination: SocketAddr,
+    /// Marking for the datagram's TOS byte or traffic class
+    pub ecn: Option<EcnCodepoint>,
+    pub contents: Box<[u8]>,
+}
+
+/// Description of a received message, which may hold several datagrams coalesced by the kernel
+#[derive(Debug, Copy, Clone)]
+pub struct RecvMeta {
+    pub addr: SocketAddr,
+    /// Number of bytes received; 0 if the message was discarded
+    pub len: usize,
+    /// Size of each datagram in the message; only the last may be shorter
+    pub stride: usize,
+    pub ecn: Option<EcnCodepoint>,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0).into(),
+            len: 0,
+            stride: 0,
+            ecn: None,
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Endpoint configuration that can be loaded from files
+//!
+//! `EndpointSettings` mirrors the setters of `EndpointBuilder`, `ConfigBuilder` and `TransportConfigBuilder` in a form
+//! serde can (de)serialize, e.g. from a TOML file:
+//!
+//! ```toml
+//! protocols = ["hq-11"]
+//! certificate_chain = "/etc/quinn/server.chain"
+//! private_key = "/etc/quinn/server.rsa"
+//! listen = true
+//!
+//! [transport]
+//! max_remote_bi_streams = 64
+//! idle_timeout_ms = 30000
+//! congestion_controller = "cubic"
+//! ```
+//!
+//! Settings left out keep their defaults.
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::internal::pemfile;
+use rustls::{PrivateKey, TLSError};
+
+use quinn::{
+    ConfigBuilder, ConfigError, CongestionAlgorithm, ListenKeys, TransportConfig,
+    TransportConfigBuilder,
+};
+use EndpointBuilder;
+
+/// Description of an endpoint, convertible into an `EndpointBuilder`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointSettings {
+    /// See `Config::accept_buffer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_buffer: Option<u32>,
+    /// See `Config::spare_connection_ids`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spare_connection_ids: Option<u8>,
+    /// See `Config::use_stateless_retry`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_stateless_retry: Option<bool>,
+    /// See `Config::accept_zero_rtt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_zero_rtt: Option<bool>,
+    /// Application-layer protocols to accept, as for `EndpointBuilder::set_protocols`
+    pub protocols: Vec<String>,
+    /// PEM file holding the certificate chain to present to clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate_chain: Option<PathBuf>,
+    /// PEM file holding the PKCS #8 or RSA private key for `certificate_chain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<PathBuf>,
+    /// Whether to log TLS keys, as for `EndpointBuilder::enable_keylog`
+    pub keylog: bool,
+    /// Whether to accept incoming connections, using randomly generated keys unless `listen_keys` is set
+    pub listen: bool,
+    /// Keys to accept incoming connections with, implying `listen`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_keys: Option<ListenKeySettings>,
+    /// Settings for the endpoint's connections
+    pub transport: TransportSettings,
+}
+
+/// Hex-encoded `ListenKeys`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenKeySettings {
+    /// 64 bytes as 128 hex digits
+    pub cookie: String,
+    /// 64 bytes as 128 hex digits
+    pub reset: String,
+}
+
+/// Description of a `TransportConfig`; each setting corresponds to the `TransportConfigBuilder` setter of the same name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(missing_docs)]
+pub struct TransportSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_remote_bi_streams: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_remote_uni_streams: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_receive_window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_stream_receive_window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receive_window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_receive_window: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tlps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reordering_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_reordering_fraction: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub using_time_loss_detection: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_tlp_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_rto_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delayed_ack_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_initial_rtt_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack_delay_exponent: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_mss: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loss_reduction_factor: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion_controller: Option<CongestionSetting>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_mtu: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ecn: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_datagram_frame_size: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_update_packets: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_update_bytes: Option<u64>,
+}
+
+/// Serializable name of a `CongestionAlgorithm`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(missing_docs)]
+pub enum CongestionSetting {
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl From<CongestionSetting> for CongestionAlgorithm {
+    fn from(x: CongestionSetting) -> Self {
+        match x {
+            CongestionSetting::NewReno => CongestionAlgorithm::NewReno,
+            CongestionSetting::Cubic => CongestionAlgorithm::Cubic,
+            CongestionSetting::Bbr => CongestionAlgorithm::Bbr,
+        }
+    }
+}
+
+/// Reasons `EndpointSettings` may fail to produce an `EndpointBuilder`
+#[derive(Debug, Fail)]
+pub enum SettingsError {
+    /// A certificate or key file couldn't be read
+    #[fail(display = "failed to read {}: {}", _0, _1)]
+    Read(String, io::Error),
+    /// A certificate or key file held no usable PEM data
+    #[fail(display = "no usable PEM data in {}", _0)]
+    Pem(String),
+    /// Only one of `certificate_chain` and `private_key` was set
+    #[fail(display = "certificate chain and private key must be set together")]
+    IncompleteCertificate,
+    /// The certificate was rejected by TLS
+    #[fail(display = "failed to set up TLS: {}", _0)]
+    Tls(TLSError),
+    /// A listen key wasn't 64 hex-encoded bytes
+    #[fail(display = "listen keys must be 128 hex digits")]
+    InvalidListenKey,
+    /// The settings were inconsistent
+    #[fail(display = "invalid configuration: {}", _0)]
+    Config(ConfigError),
+}
+
+impl From<ConfigError> for SettingsError {
+    fn from(x: ConfigError) -> Self {
+        SettingsError::Config(x)
+    }
+}
+
+impl EndpointSettings {
+    /// Validate the settings, load the certificate they refer to, and prepare to construct the endpoint
+    pub fn builder<'a>(&self) -> Result<EndpointBuilder<'a>, SettingsError> {
+        let mut config = ConfigBuilder::new();
+        if let Some(x) = self.accept_buffer {
+            config.accept_buffer(x);
+        }
+        if let Some(x) = self.spare_connection_ids {
+            config.spare_connection_ids(x);
+        }
+        if let Some(x) = self.use_stateless_retry {
+            config.use_stateless_retry(x);
+        }
+        if let Some(x) = self.accept_zero_rtt {
+            config.accept_zero_rtt(x);
+        }
+        config.transport(Arc::new(self.transport.build()?));
+
+        let mut builder = EndpointBuilder::from_config(config.build()?);
+        if !self.protocols.is_empty() {
+            let protocols = self
+                .protocols
+                .iter()
+                .map(|x| x.as_bytes())
+                .collect::<Vec<_>>();
+            builder.set_protocols(&protocols);
+        }
+        match (&self.certificate_chain, &self.private_key) {
+            (Some(chain), Some(key)) => {
+                let chain = load_pem(chain, pemfile::certs)?;
+                let key = load_private_key(key)?;
+                builder
+                    .set_certificate(chain, key)
+                    .map_err(SettingsError::Tls)?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(SettingsError::IncompleteCertificate);
+            }
+        }
+        if self.keylog {
+            builder.enable_keylog();
+        }
+        if let Some(ref keys) = self.listen_keys {
+            builder.listen_with_keys(keys.decode()?);
+        } else if self.listen {
+            builder.listen();
+        }
+        Ok(builder)
+    }
+}
+
+impl ListenKeySettings {
+    /// Hex-encode `keys`
+    pub fn new(keys: &ListenKeys) -> Self {
+        Self {
+            cookie: encode_hex(&keys.cookie),
+            reset: encode_hex(&keys.reset),
+        }
+    }
+
+    fn decode(&self) -> Result<ListenKeys, SettingsError> {
+        let mut keys = ListenKeys {
+            cookie: [0; 64],
+            reset: [0; 64],
+        };
+        decode_hex(&self.cookie, &mut keys.cookie)?;
+        decode_hex(&self.reset, &mut keys.reset)?;
+        Ok(keys)
+    }
+}
+
+impl TransportSettings {
+    fn build(&self) -> Result<TransportConfig, ConfigError> {
+        let mut config = TransportConfigBuilder::new();
+        macro_rules! set {
+            ($settings:expr, $builder:ident; $($field:ident),* $(,)*) => {
+                $(if let Some(x) = $settings.$field {
+                    $builder.$field(x);
+                })*
+            };
+        }
+        macro_rules! set_ms {
+            ($settings:expr, $builder:ident; $($field:ident => $setter:ident),* $(,)*) => {
+                $(if let Some(x) = $settings.$field {
+                    $builder.$setter(Duration::from_millis(x));
+                })*
+            };
+        }
+        set!(
+            self, config;
+            max_remote_bi_streams,
+            max_remote_uni_streams,
+            stream_receive_window,
+            max_stream_receive_window,
+            receive_window,
+            max_receive_window,
+            max_tlps,
+            reordering_threshold,
+            time_reordering_fraction,
+            using_time_loss_detection,
+            ack_delay_exponent,
+            default_mss,
+            initial_window,
+            minimum_window,
+            loss_reduction_factor,
+            max_mtu,
+            ecn,
+            max_datagram_frame_size,
+            key_update_packets,
+            key_update_bytes
+        );
+        set_ms!(
+            self, config;
+            idle_timeout_ms => idle_timeout,
+            min_tlp_timeout_ms => min_tlp_timeout,
+            min_rto_timeout_ms => min_rto_timeout,
+            delayed_ack_timeout_ms => delayed_ack_timeout,
+            default_initial_rtt_ms => default_initial_rtt
+        );
+        if let Some(x) = self.congestion_controller {
+            config.congestion_controller(x.into());
+        }
+        config.build()
+    }
+}
+
+fn load_pem<T, F>(path: &Path, parse: F) -> Result<Vec<T>, SettingsError>
+where
+    F: FnOnce(&mut dyn io::BufRead) -> Result<Vec<T>, ()>,
+{
+    let file =
+        fs::File::open(path).map_err(|e| SettingsError::Read(path.display().to_string(), e))?;
+    match parse(&mut BufReader::new(file)) {
+        Ok(ref x) if x.is_empty() => Err(SettingsError::Pem(path.display().to_string())),
+        Ok(x) => Ok(x),
+        Err(()) => Err(SettingsError::Pem(path.display().to_string())),
+    }
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, SettingsError> {
+    let mut keys = load_pem(path, pemfile::pkcs8_private_keys)
+        .or_else(|_| load_pem(path, pemfile::rsa_private_keys))?;
+    Ok(keys.swap_remove(0))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_hex(hex: &str, out: &mut [u8]) -> Result<(), SettingsError> {
+    if hex.len() != 2 * out.len() || !hex.is_ascii() {
+        return Err(SettingsError::InvalidListenKey);
+    }
+    for (i, x) in out.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| SettingsError::InvalidListenKey)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn round_trip() {
+        let settings = EndpointSettings {
+            accept_buffer: Some(16),
+            use_stateless_retry: Some(true),
+            protocols: vec!["hq-11".into()],
+            certificate_chain: Some("../certs/server.chain".into()),
+            private_key: Some("../certs/server.rsa".into()),
+            listen_keys: Some(ListenKeySettings::new(&ListenKeys {
+                cookie: [0xab; 64],
+                reset: [0x01; 64],
+            })),
+            transport: TransportSettings {
+                max_remote_bi_streams: Some(64),
+                idle_timeout_ms: Some(30_000),
+                min_rto_timeout_ms: Some(100),
+                congestion_controller: Some(CongestionSetting::Cubic),
+                ..TransportSettings::default()
+            },
+            ..EndpointSettings::default()
+        };
+        let text = toml::to_string(&settings).unwrap();
+        assert_eq!(toml::from_str::<EndpointSettings>(&text).unwrap(), settings);
+        let keys = settings.listen_keys.as_ref().unwrap().decode().unwrap();
+        assert_eq!(keys.cookie[..], [0xab; 64][..]);
+        assert!(settings.builder().is_ok());
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = toml::from_str::<EndpointSettings>("").unwrap();
+        assert_eq!(settings, EndpointSettings::default());
+        let text = toml::to_string(&settings).unwrap();
+        assert_eq!(toml::from_str::<EndpointSettings>(&text).unwrap(), settings);
+        assert!(settings.builder().is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        let text = "[transport]\ninitial_window = 1000\nminimum_window = 2000\n";
+        let mut settings = toml::from_str::<EndpointSettings>(text).unwrap();
+        assert_matches!(
+            settings.builder().err(),
+            Some(SettingsError::Config(
+                ConfigError::MinimumWindowExceedsInitial
+            ))
+        );
+        settings.transport = TransportSettings::default();
+        settings.private_key = Some("../certs/server.rsa".into());
+        assert_matches!(
+            settings.builder().err(),
+            Some(SettingsError::IncompleteCertificate)
+        );
+        settings.private_key = None;
+        settings.listen_keys = Some(ListenKeySettings {
+            cookie: "00".into(),
+            reset: "00".into(),
+        });
+        assert_matches!(
+            settings.builder().err(),
+            Some(SettingsError::InvalidListenKey)
+        );
+        assert!(toml::from_str::<EndpointSettings>("bogus = 1").is_err());
+    }
+}
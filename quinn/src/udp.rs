@@ -0,0 +1,107 @@
+use std::cell::Cell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV6};
+
+use futures::{Async, Poll};
+use mio;
+use tokio_reactor::{Handle, PollEvented};
+
+use platform::{RecvMeta, Transmit, UdpExt};
+
+/// Tokio-compatible UDP socket which transfers batches of datagrams along with the ECN bits of their TOS byte or
+/// traffic class
+pub struct UdpSocket {
+    io: PollEvented<mio::net::UdpSocket>,
+    max_gso_segments: Cell<usize>,
+    ipv6: bool,
+}
+
+impl UdpSocket {
+    pub fn from_std(socket: ::std::net::UdpSocket, reactor: &Handle) -> io::Result<UdpSocket> {
+        let io = mio::net::UdpSocket::from_socket(socket)?;
+        let capabilities = io.init_ext()?;
+        let ipv6 = io.local_addr()?.is_ipv6();
+        let io = PollEvented::new_with_handle(io, reactor)?;
+        Ok(UdpSocket {
+            io,
+            max_gso_segments: Cell::new(capabilities.max_gso_segments),
+            ipv6,
+        })
+    }
+
+    /// Express `addr` in the socket's own address family
+    ///
+    /// Dual-stack IPv6 sockets reach IPv4 peers through IPv4-mapped addresses.
+    pub fn native_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(x) if self.ipv6 => {
+                SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0).into()
+            }
+            _ => addr,
+        }
+    }
+
+    /// Whether datagrams can be sent to `addr` at all
+    ///
+    /// IPv4 sockets can't reach IPv6 peers, whereas IPv6 sockets are assumed to be dual-stack.
+    pub fn supports_addr(&self, addr: &SocketAddr) -> bool {
+        self.ipv6 || canonical_addr(*addr).is_ipv4()
+    }
+
+    /// Send a prefix of `transmits`, returning how many were sent
+    pub fn poll_send(&self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
+        if let Async::NotReady = self.io.poll_write_ready()? {
+            return Ok(Async::NotReady);
+        }
+        match self
+            .io
+            .get_ref()
+            .send_ext(transmits, &self.max_gso_segments)
+        {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.io.clear_write_ready()?;
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Receive up to `meta.len()` messages into equal-sized chunks of `buf`, returning how many were received
+    pub fn poll_recv(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> Poll<usize, io::Error> {
+        if let Async::NotReady = self.io.poll_read_ready(mio::Ready::readable())? {
+            return Ok(Async::NotReady);
+        }
+        match self.io.get_ref().recv_ext(buf, meta) {
+            Ok(n) => {
+                for x in &mut meta[..n] {
+                    x.addr = canonical_addr(x.addr);
+                }
+                Ok(Async::Ready(n))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.io.clear_read_ready(mio::Ready::readable())?;
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Express IPv4-mapped addresses as plain IPv4 ones, so each peer has one address regardless of the socket it's reached
+/// through
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(ref x) = addr {
+        let segments = x.ip().segments();
+        if segments[..5] == [0; 5] && segments[5] == 0xffff {
+            let ip = Ipv4Addr::new(
+                (segments[6] >> 8) as u8,
+                segments[6] as u8,
+                (segments[7] >> 8) as u8,
+                segments[7] as u8,
+            );
+            return SocketAddr::new(ip.into(), x.port());
+        }
+    }
+    addr
+}